@@ -1,6 +1,6 @@
 use proc_macro::{self, TokenStream};
 use quote::quote;
-use syn::{parse_macro_input, DeriveInput};
+use syn::{parse_macro_input, Data, DeriveInput, Fields};
 
 #[proc_macro_derive(ChoreographyLocation)]
 pub fn derive_choreography_location(input: TokenStream) -> TokenStream {
@@ -23,3 +23,42 @@ pub fn derive_choreography_location(input: TokenStream) -> TokenStream {
     };
     output.into()
 }
+
+#[proc_macro_derive(BranchLabel)]
+pub fn derive_branch_label(input: TokenStream) -> TokenStream {
+    let DeriveInput { ident, data, .. } = parse_macro_input!(input);
+    let variants = match data {
+        Data::Enum(data) => data.variants,
+        _ => {
+            return syn::Error::new(ident.span(), "BranchLabel can only be derived for enums")
+                .to_compile_error()
+                .into()
+        }
+    };
+    if let Some(variant) = variants.iter().find(|v| !matches!(v.fields, Fields::Unit)) {
+        return syn::Error::new(
+            variant.ident.span(),
+            "BranchLabel variants cannot have fields",
+        )
+        .to_compile_error()
+        .into();
+    }
+    let names: Vec<_> = variants.iter().map(|v| &v.ident).collect();
+    let indices: Vec<_> = (0..names.len() as u32).collect();
+    let output = quote! {
+        impl BranchLabel for #ident {
+            fn index(&self) -> u32 {
+                match *self {
+                    #(Self::#names => #indices,)*
+                }
+            }
+            fn from_index(index: u32) -> Option<Self> {
+                match index {
+                    #(#indices => Some(Self::#names),)*
+                    _ => None,
+                }
+            }
+        }
+    };
+    output.into()
+}
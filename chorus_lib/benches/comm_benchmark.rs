@@ -37,7 +37,7 @@ fn comm_choreography(n: u64) {
             let transport = LocalTransport::new(Alice, channel);
             let projector = Projector::new(Alice, transport);
             let c = CommChoreography { n };
-            projector.epp_and_run(c);
+            projector.epp_and_run(c).unwrap();
         }));
     }
     {
@@ -46,7 +46,7 @@ fn comm_choreography(n: u64) {
             let transport = LocalTransport::new(Bob, channel);
             let projector = Projector::new(Bob, transport);
             let c = CommChoreography { n };
-            projector.epp_and_run(c);
+            projector.epp_and_run(c).unwrap();
         }));
     }
     for handle in handles {
@@ -56,13 +56,17 @@ fn comm_choreography(n: u64) {
 
 fn comm_handwritten_alice(n: u64, transport: &LocalTransport<LocationSet!(Bob, Alice), Alice>) {
     for _ in 0..n {
-        transport.receive::<f32>(Bob::name(), Alice::name());
+        transport
+            .receive::<f32>(0, Bob::name(), Alice::name(), None)
+            .unwrap();
     }
 }
 
 fn comm_handwritten_bob(n: u64, transport: &LocalTransport<LocationSet!(Bob, Alice), Bob>) {
     for _ in 0..n {
-        transport.send::<f32>(Bob::name(), Alice::name(), &1.0);
+        transport
+            .send::<f32>(0, Bob::name(), Alice::name(), &1.0, None)
+            .unwrap();
     }
 }
 
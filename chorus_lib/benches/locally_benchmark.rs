@@ -26,7 +26,7 @@ fn add_to_n_locally_choreographic(n: f64) {
         .build();
     let transport = chorus_lib::transport::local::LocalTransport::new(Alice, channel);
     let projector = chorus_lib::core::Projector::new(Alice, transport);
-    let result = projector.epp_and_run(c);
+    let result = projector.epp_and_run(c).unwrap();
     assert_eq!(projector.unwrap(result), n);
 }
 
@@ -0,0 +1,150 @@
+extern crate chorus_lib;
+
+use std::marker::PhantomData;
+
+use chorus_lib::core::{
+    AsyncChoreoOp, AsyncChoreography, AsyncFanInChoreography, AsyncProjector, ChoreographyLocation,
+    LocationSet, Member, MultiplyLocated, Subset,
+};
+use chorus_lib::transport::local::{AsyncLocalTransport, LocalTransportChannelBuilder};
+use tokio::task::LocalSet;
+
+#[derive(ChoreographyLocation)]
+struct Coordinator;
+
+#[derive(ChoreographyLocation)]
+struct Worker1;
+
+#[derive(ChoreographyLocation)]
+struct Worker2;
+
+const INSTANCES: u32 = 200;
+
+// Each worker sends its share of the job to the coordinator
+struct Collect<L: LocationSet, QS: LocationSet, CoordinatorMemberL> {
+    job: u32,
+    phantom: PhantomData<(L, QS, CoordinatorMemberL)>,
+}
+
+impl<L: LocationSet, QS: LocationSet, CoordinatorMemberL> AsyncFanInChoreography<u32>
+    for Collect<L, QS, CoordinatorMemberL>
+where
+    Coordinator: Member<L, CoordinatorMemberL>,
+{
+    type L = L;
+    type QS = QS;
+    type RS = LocationSet!(Coordinator);
+
+    async fn run<Q: ChoreographyLocation, QSSubsetL, RSSubsetL, QMemberL, QMemberQS>(
+        &self,
+        op: &impl AsyncChoreoOp<Self::L>,
+    ) -> MultiplyLocated<u32, Self::RS>
+    where
+        Self::QS: Subset<Self::L, QSSubsetL>,
+        Self::RS: Subset<Self::L, RSSubsetL>,
+        Q: Member<Self::L, QMemberL>,
+        Q: Member<Self::QS, QMemberQS>,
+    {
+        let share = op.locally(Q::new(), |_| self.job * 10);
+        op.comm(Q::new(), Coordinator, &share).await
+    }
+}
+
+struct JobChoreography {
+    job: u32,
+}
+
+impl AsyncChoreography<u32> for JobChoreography {
+    type L = LocationSet!(Coordinator, Worker1, Worker2);
+
+    async fn run(self, op: &impl AsyncChoreoOp<Self::L>) -> u32 {
+        let shares = op
+            .fanin(
+                <LocationSet!(Worker1, Worker2)>::new(),
+                Collect {
+                    job: self.job,
+                    phantom: PhantomData,
+                },
+            )
+            .await;
+        let total = op.locally(Coordinator, |un| {
+            un.unwrap(&shares).get_map().values().sum::<u32>()
+        });
+        op.broadcast(Coordinator, total).await
+    }
+}
+
+// Runs `INSTANCES` choreographies concurrently on a single thread and returns their results
+fn run_all() -> Vec<u32> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    let local = LocalSet::new();
+    let mut handles = Vec::new();
+    for job in 0..INSTANCES {
+        let transport_channel = LocalTransportChannelBuilder::new()
+            .with(Coordinator)
+            .with(Worker1)
+            .with(Worker2)
+            .build();
+        let coordinator_projector = AsyncProjector::new(
+            Coordinator,
+            AsyncLocalTransport::new(Coordinator, transport_channel.clone()),
+        );
+        let worker1_projector = AsyncProjector::new(
+            Worker1,
+            AsyncLocalTransport::new(Worker1, transport_channel.clone()),
+        );
+        let worker2_projector = AsyncProjector::new(
+            Worker2,
+            AsyncLocalTransport::new(Worker2, transport_channel.clone()),
+        );
+        handles.push(local.spawn_local(async move {
+            coordinator_projector
+                .epp_and_run(JobChoreography { job })
+                .await
+                .unwrap()
+        }));
+        local.spawn_local(async move {
+            worker1_projector
+                .epp_and_run(JobChoreography { job })
+                .await
+                .unwrap()
+        });
+        local.spawn_local(async move {
+            worker2_projector
+                .epp_and_run(JobChoreography { job })
+                .await
+                .unwrap()
+        });
+    }
+    local.block_on(&runtime, async {
+        let mut results = Vec::new();
+        for handle in handles {
+            results.push(handle.await.unwrap());
+        }
+        results
+    })
+}
+
+fn main() {
+    let results = run_all();
+    println!(
+        "Completed {} choreographies on one thread; the last total was {}",
+        results.len(),
+        results.last().unwrap()
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_concurrent_instances() {
+        let results = run_all();
+        for (job, total) in results.into_iter().enumerate() {
+            assert_eq!(total, job as u32 * 20);
+        }
+    }
+}
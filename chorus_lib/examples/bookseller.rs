@@ -87,16 +87,20 @@ fn main() {
 
     let mut handles: Vec<thread::JoinHandle<()>> = Vec::new();
     handles.push(thread::spawn(move || {
-        seller_projector.epp_and_run(BooksellerChoreography {
-            title: seller_projector.remote(Buyer),
-            budget: seller_projector.remote(Buyer),
-        });
+        seller_projector
+            .epp_and_run(BooksellerChoreography {
+                title: seller_projector.remote(Buyer),
+                budget: seller_projector.remote(Buyer),
+            })
+            .unwrap();
     }));
     handles.push(thread::spawn(move || {
-        buyer_projector.epp_and_run(BooksellerChoreography {
-            title: buyer_projector.local(title),
-            budget: buyer_projector.local(BUDGET),
-        });
+        buyer_projector
+            .epp_and_run(BooksellerChoreography {
+                title: buyer_projector.local(title),
+                budget: buyer_projector.local(BUDGET),
+            })
+            .unwrap();
     }));
     for h in handles {
         h.join().unwrap();
@@ -123,16 +127,20 @@ mod tests {
 
         let mut handles: Vec<thread::JoinHandle<()>> = Vec::new();
         handles.push(thread::spawn(move || {
-            seller_projector.epp_and_run(BooksellerChoreography {
-                title: seller_projector.remote(Buyer),
-                budget: seller_projector.remote(Buyer),
-            });
+            seller_projector
+                .epp_and_run(BooksellerChoreography {
+                    title: seller_projector.remote(Buyer),
+                    budget: seller_projector.remote(Buyer),
+                })
+                .unwrap();
         }));
         handles.push(thread::spawn(move || {
-            buyer_projector.epp_and_run(BooksellerChoreography {
-                title: buyer_projector.local(title),
-                budget: buyer_projector.local(BUDGET),
-            });
+            buyer_projector
+                .epp_and_run(BooksellerChoreography {
+                    title: buyer_projector.local(title),
+                    budget: buyer_projector.local(BUDGET),
+                })
+                .unwrap();
         }));
         for h in handles {
             h.join().unwrap();
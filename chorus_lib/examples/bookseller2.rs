@@ -173,21 +173,25 @@ fn main() {
         let seller_projector = seller_projector.clone();
         let inventory = inventory.clone();
         handles.push(thread::spawn(move || {
-            seller_projector.epp_and_run(OneBuyerBooksellerChoreography {
-                _marker: std::marker::PhantomData,
-                inventory: seller_projector.local(inventory),
-                title: seller_projector.remote(Buyer1),
-            });
+            seller_projector
+                .epp_and_run(OneBuyerBooksellerChoreography {
+                    _marker: std::marker::PhantomData,
+                    inventory: seller_projector.local(inventory),
+                    title: seller_projector.remote(Buyer1),
+                })
+                .unwrap();
         }));
     }
     {
         let buyer1_projector = buyer1_projector.clone();
         handles.push(thread::spawn(move || {
-            let result = buyer1_projector.epp_and_run(OneBuyerBooksellerChoreography {
-                _marker: std::marker::PhantomData,
-                inventory: buyer1_projector.remote(Seller),
-                title: buyer1_projector.local("HoTT".to_string()),
-            });
+            let result = buyer1_projector
+                .epp_and_run(OneBuyerBooksellerChoreography {
+                    _marker: std::marker::PhantomData,
+                    inventory: buyer1_projector.remote(Seller),
+                    title: buyer1_projector.local("HoTT".to_string()),
+                })
+                .unwrap();
             println!(
                 "The book will be delivered on {:?}",
                 buyer1_projector.unwrap(result)
@@ -197,11 +201,13 @@ fn main() {
     {
         let buyer2_projector = buyer2_projector.clone();
         handles.push(thread::spawn(move || {
-            buyer2_projector.epp_and_run(OneBuyerBooksellerChoreography {
-                _marker: std::marker::PhantomData,
-                inventory: buyer2_projector.remote(Seller),
-                title: buyer2_projector.remote(Buyer1),
-            });
+            buyer2_projector
+                .epp_and_run(OneBuyerBooksellerChoreography {
+                    _marker: std::marker::PhantomData,
+                    inventory: buyer2_projector.remote(Seller),
+                    title: buyer2_projector.remote(Buyer1),
+                })
+                .unwrap();
         }));
     }
     for h in handles {
@@ -215,21 +221,25 @@ fn main() {
         let seller_projector = seller_projector.clone();
         let inventory = inventory.clone();
         handles.push(thread::spawn(move || {
-            seller_projector.epp_and_run(TwoBuyerBooksellerChoreography {
-                _marker: std::marker::PhantomData,
-                inventory: seller_projector.local(inventory),
-                title: seller_projector.remote(Buyer1),
-            });
+            seller_projector
+                .epp_and_run(TwoBuyerBooksellerChoreography {
+                    _marker: std::marker::PhantomData,
+                    inventory: seller_projector.local(inventory),
+                    title: seller_projector.remote(Buyer1),
+                })
+                .unwrap();
         }));
     }
     {
         let buyer1_projector = buyer1_projector.clone();
         handles.push(thread::spawn(move || {
-            let result = buyer1_projector.epp_and_run(TwoBuyerBooksellerChoreography {
-                _marker: std::marker::PhantomData,
-                inventory: buyer1_projector.remote(Seller),
-                title: buyer1_projector.local("HoTT".to_string()),
-            });
+            let result = buyer1_projector
+                .epp_and_run(TwoBuyerBooksellerChoreography {
+                    _marker: std::marker::PhantomData,
+                    inventory: buyer1_projector.remote(Seller),
+                    title: buyer1_projector.local("HoTT".to_string()),
+                })
+                .unwrap();
             println!(
                 "The book will be delivered on {:?}",
                 buyer1_projector.unwrap(result)
@@ -239,11 +249,13 @@ fn main() {
     {
         let buyer2_projector = buyer2_projector.clone();
         handles.push(thread::spawn(move || {
-            buyer2_projector.epp_and_run(TwoBuyerBooksellerChoreography {
-                _marker: std::marker::PhantomData,
-                inventory: buyer2_projector.remote(Seller),
-                title: buyer2_projector.remote(Buyer1),
-            });
+            buyer2_projector
+                .epp_and_run(TwoBuyerBooksellerChoreography {
+                    _marker: std::marker::PhantomData,
+                    inventory: buyer2_projector.remote(Seller),
+                    title: buyer2_projector.remote(Buyer1),
+                })
+                .unwrap();
         }));
     }
     for h in handles {
@@ -295,13 +295,19 @@ fn main() {
 
     let mut handles = Vec::new();
     handles.push(std::thread::spawn(move || {
-        dealer_projector.epp_and_run(Game::new(Players::new()));
+        dealer_projector
+            .epp_and_run(Game::new(Players::new()))
+            .unwrap();
     }));
     handles.push(std::thread::spawn(move || {
-        player1_projector.epp_and_run(Game::new(Players::new()));
+        player1_projector
+            .epp_and_run(Game::new(Players::new()))
+            .unwrap();
     }));
     handles.push(std::thread::spawn(move || {
-        player2_projector.epp_and_run(Game::new(Players::new()));
+        player2_projector
+            .epp_and_run(Game::new(Players::new()))
+            .unwrap();
     }));
 
     for handle in handles {
@@ -0,0 +1,156 @@
+extern crate chorus_lib;
+
+use std::thread;
+
+use chorus_lib::core::{
+    ChoreoOp, Choreography, ChoreographyLocation, Located, LocationSet, Projector, Quire,
+};
+use chorus_lib::transport::local::{LocalTransport, LocalTransportChannelBuilder};
+
+#[derive(ChoreographyLocation, Debug)]
+struct Alice;
+
+#[derive(ChoreographyLocation, Debug)]
+struct Bob;
+
+#[derive(ChoreographyLocation, Debug)]
+struct Carol;
+
+type Workers = LocationSet!(Alice, Bob, Carol);
+
+struct MainChoreography;
+impl
+    Choreography<(
+        Located<String, Bob>,
+        Located<Quire<String, Workers>, Carol>,
+        String,
+    )> for MainChoreography
+{
+    type L = Workers;
+
+    fn run(
+        self,
+        op: &impl ChoreoOp<Self::L>,
+    ) -> (
+        Located<String, Bob>,
+        Located<Quire<String, Workers>, Carol>,
+        String,
+    ) {
+        // Alice splits a sentence into one word for each worker
+        let words = op.locally(Alice, |_| {
+            Quire::new()
+                .add(Carol, String::from("world"))
+                .add(Bob, String::from("hello"))
+                .add(Alice, String::from("Alice says"))
+        });
+        let word = op.scatter(Alice, Workers::new(), &words);
+        // the words are joined at Bob in the order in which the workers are listed
+        let sentence = op.reduce(Bob, &word, |a, b| format!("{} {}", a, b));
+        op.locally(Bob, |un| println!("Bob has \"{}\"", un.unwrap(&sentence)));
+        let collected = op.gather(Carol, &word);
+        op.locally(Carol, |un| {
+            println!("Carol has {:?}", un.unwrap(&collected))
+        });
+        // every worker learns the longest word
+        let longest = op.allreduce(&word, |a, b| if b.len() > a.len() { b } else { a });
+        (sentence, collected, op.naked(longest))
+    }
+}
+
+fn main() {
+    let transport_channel = LocalTransportChannelBuilder::new()
+        .with(Alice)
+        .with(Bob)
+        .with(Carol)
+        .build();
+    let transport_alice = LocalTransport::new(Alice, transport_channel.clone());
+    let transport_bob = LocalTransport::new(Bob, transport_channel.clone());
+    let transport_carol = LocalTransport::new(Carol, transport_channel.clone());
+
+    let alice_projector = Projector::new(Alice, transport_alice);
+    let bob_projector = Projector::new(Bob, transport_bob);
+    let carol_projector = Projector::new(Carol, transport_carol);
+
+    let mut handles: Vec<thread::JoinHandle<()>> = Vec::new();
+    handles.push(
+        thread::Builder::new()
+            .name("Alice".to_string())
+            .spawn(move || {
+                alice_projector.epp_and_run(MainChoreography).unwrap();
+            })
+            .unwrap(),
+    );
+    handles.push(
+        thread::Builder::new()
+            .name("Bob".to_string())
+            .spawn(move || {
+                bob_projector.epp_and_run(MainChoreography).unwrap();
+            })
+            .unwrap(),
+    );
+    handles.push(
+        thread::Builder::new()
+            .name("Carol".to_string())
+            .spawn(move || {
+                carol_projector.epp_and_run(MainChoreography).unwrap();
+            })
+            .unwrap(),
+    );
+    for handle in handles {
+        handle.join().unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chorus_lib::core::Runner;
+
+    use super::*;
+
+    fn check_collected(collected: Quire<String, Workers>) {
+        let m = collected.into_map();
+        assert_eq!(m.len(), 3);
+        assert_eq!(m[Alice::name()], "Alice says");
+        assert_eq!(m[Bob::name()], "hello");
+        assert_eq!(m[Carol::name()], "world");
+    }
+
+    #[test]
+    fn test_projector() {
+        let transport_channel = LocalTransportChannelBuilder::new()
+            .with(Alice)
+            .with(Bob)
+            .with(Carol)
+            .build();
+        let alice_projector =
+            Projector::new(Alice, LocalTransport::new(Alice, transport_channel.clone()));
+        let bob_projector =
+            Projector::new(Bob, LocalTransport::new(Bob, transport_channel.clone()));
+        let carol_projector =
+            Projector::new(Carol, LocalTransport::new(Carol, transport_channel.clone()));
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                let (_, _, longest) = alice_projector.epp_and_run(MainChoreography).unwrap();
+                assert_eq!(longest, "Alice says");
+            });
+            s.spawn(|| {
+                let (sentence, _, longest) = bob_projector.epp_and_run(MainChoreography).unwrap();
+                assert_eq!(bob_projector.unwrap(sentence), "Alice says hello world");
+                assert_eq!(longest, "Alice says");
+            });
+            let (_, collected, longest) = carol_projector.epp_and_run(MainChoreography).unwrap();
+            check_collected(carol_projector.unwrap(collected));
+            assert_eq!(longest, "Alice says");
+        });
+    }
+
+    #[test]
+    fn test_runner() {
+        let runner = Runner::new();
+        let (sentence, collected, longest) = runner.run(MainChoreography);
+        assert_eq!(runner.unwrap(sentence), "Alice says hello world");
+        check_collected(runner.unwrap(collected));
+        assert_eq!(longest, "Alice says");
+    }
+}
@@ -120,21 +120,21 @@ fn main() {
         let transport = LocalTransport::new(Alice, transport_channel.clone());
         handles.push(std::thread::spawn(move || {
             let p = Projector::new(Alice, transport);
-            p.epp_and_run(MainChoreography);
+            p.epp_and_run(MainChoreography).unwrap();
         }));
     }
     {
         let transport = LocalTransport::new(Bob, transport_channel.clone());
         handles.push(std::thread::spawn(move || {
             let p = Projector::new(Bob, transport);
-            p.epp_and_run(MainChoreography);
+            p.epp_and_run(MainChoreography).unwrap();
         }));
     }
     {
         let transport = LocalTransport::new(Carol, transport_channel.clone());
         handles.push(std::thread::spawn(move || {
             let p = Projector::new(Carol, transport);
-            p.epp_and_run(MainChoreography);
+            p.epp_and_run(MainChoreography).unwrap();
         }));
     }
     for handle in handles {
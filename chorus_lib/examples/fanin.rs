@@ -107,7 +107,7 @@ fn main() {
         thread::Builder::new()
             .name("Alice".to_string())
             .spawn(move || {
-                alice_projector.epp_and_run(MainChoreography);
+                alice_projector.epp_and_run(MainChoreography).unwrap();
             })
             .unwrap(),
     );
@@ -115,7 +115,7 @@ fn main() {
         thread::Builder::new()
             .name("Bob".to_string())
             .spawn(move || {
-                bob_projector.epp_and_run(MainChoreography);
+                bob_projector.epp_and_run(MainChoreography).unwrap();
             })
             .unwrap(),
     );
@@ -123,7 +123,7 @@ fn main() {
         thread::Builder::new()
             .name("Carol".to_string())
             .spawn(move || {
-                carol_projector.epp_and_run(MainChoreography);
+                carol_projector.epp_and_run(MainChoreography).unwrap();
             })
             .unwrap(),
     );
@@ -158,7 +158,7 @@ mod tests {
             thread::Builder::new()
                 .name("Alice".to_string())
                 .spawn(move || {
-                    let quire_at_alice = alice_projector.epp_and_run(MainChoreography);
+                    let quire_at_alice = alice_projector.epp_and_run(MainChoreography).unwrap();
                     let m = alice_projector.unwrap(quire_at_alice).get_map();
                     assert_eq!(m.get(Bob::name()).unwrap(), "Bob says hi to Alice");
                     assert_eq!(m.get(Carol::name()).unwrap(), "Carol says hi to Alice");
@@ -169,7 +169,7 @@ mod tests {
             thread::Builder::new()
                 .name("Bob".to_string())
                 .spawn(move || {
-                    bob_projector.epp_and_run(MainChoreography);
+                    bob_projector.epp_and_run(MainChoreography).unwrap();
                 })
                 .unwrap(),
         );
@@ -177,7 +177,7 @@ mod tests {
             thread::Builder::new()
                 .name("Carol".to_string())
                 .spawn(move || {
-                    carol_projector.epp_and_run(MainChoreography);
+                    carol_projector.epp_and_run(MainChoreography).unwrap();
                 })
                 .unwrap(),
         );
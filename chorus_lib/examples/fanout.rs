@@ -105,7 +105,7 @@ fn main() {
         thread::Builder::new()
             .name("Alice".to_string())
             .spawn(move || {
-                alice_projector.epp_and_run(MainChoreography);
+                alice_projector.epp_and_run(MainChoreography).unwrap();
             })
             .unwrap(),
     );
@@ -113,7 +113,7 @@ fn main() {
         thread::Builder::new()
             .name("Bob".to_string())
             .spawn(move || {
-                bob_projector.epp_and_run(MainChoreography);
+                bob_projector.epp_and_run(MainChoreography).unwrap();
             })
             .unwrap(),
     );
@@ -121,7 +121,7 @@ fn main() {
         thread::Builder::new()
             .name("Carol".to_string())
             .spawn(move || {
-                carol_projector.epp_and_run(MainChoreography);
+                carol_projector.epp_and_run(MainChoreography).unwrap();
             })
             .unwrap(),
     );
@@ -155,7 +155,7 @@ mod tests {
             thread::Builder::new()
                 .name("Alice".to_string())
                 .spawn(move || {
-                    alice_projector.epp_and_run(MainChoreography);
+                    alice_projector.epp_and_run(MainChoreography).unwrap();
                 })
                 .unwrap(),
         );
@@ -163,7 +163,7 @@ mod tests {
             thread::Builder::new()
                 .name("Bob".to_string())
                 .spawn(move || {
-                    let v = bob_projector.epp_and_run(MainChoreography);
+                    let v = bob_projector.epp_and_run(MainChoreography).unwrap();
                     assert_eq!(bob_projector.unwrap(v.0), "Alice says hi to Bob");
                 })
                 .unwrap(),
@@ -172,7 +172,7 @@ mod tests {
             thread::Builder::new()
                 .name("Carol".to_string())
                 .spawn(move || {
-                    let v = carol_projector.epp_and_run(MainChoreography);
+                    let v = carol_projector.epp_and_run(MainChoreography).unwrap();
                     assert_eq!(carol_projector.unwrap(v.1), "Alice says hi to Carol");
                 })
                 .unwrap(),
@@ -51,14 +51,14 @@ fn main() {
         let transport = LocalTransport::new(Alice, transport_channel.clone());
         handles.push(thread::spawn(move || {
             let p = Projector::new(Alice, transport);
-            p.epp_and_run(HelloWorldChoreography);
+            p.epp_and_run(HelloWorldChoreography).unwrap();
         }));
     }
     {
         let transport = LocalTransport::new(Bob, transport_channel.clone());
         handles.push(thread::spawn(move || {
             let p = Projector::new(Bob, transport);
-            p.epp_and_run(HelloWorldChoreography);
+            p.epp_and_run(HelloWorldChoreography).unwrap();
         }));
     }
     for h in handles {
@@ -251,7 +251,7 @@ fn main() {
                 .with(Backup1, ("localhost", 9012))
                 .with(Backup2, ("localhost", 9013))
                 .build();
-            let transport = HttpTransport::new(config);
+            let transport = HttpTransport::new(config).unwrap();
             let projector = Projector::new(Client, transport);
 
             println!("Enter a command in one of the following formats:");
@@ -279,11 +279,12 @@ fn main() {
                         continue;
                     }
                 };
-                let response =
-                    projector.epp_and_run(KVS::<HCons<Backup1, HCons<Backup2, HNil>>, _, _, _> {
+                let response = projector
+                    .epp_and_run(KVS::<HCons<Backup1, HCons<Backup2, HNil>>, _, _, _> {
                         request: projector.local(request),
                         _phantoms: PhantomData,
-                    });
+                    })
+                    .unwrap();
                 println!("Response: {:?}", projector.unwrap(response));
             }
         }
@@ -294,13 +295,15 @@ fn main() {
                 .with(Backup1, ("localhost", 9012))
                 .with(Backup2, ("localhost", 9013))
                 .build();
-            let transport = HttpTransport::new(config);
+            let transport = HttpTransport::new(config).unwrap();
             let projector = Projector::new(Server, transport);
             loop {
-                projector.epp_and_run(KVS::<HCons<Backup1, HCons<Backup2, HNil>>, _, _, _> {
-                    request: projector.remote(Client),
-                    _phantoms: PhantomData,
-                });
+                projector
+                    .epp_and_run(KVS::<HCons<Backup1, HCons<Backup2, HNil>>, _, _, _> {
+                        request: projector.remote(Client),
+                        _phantoms: PhantomData,
+                    })
+                    .unwrap();
             }
         }
         "backup1" => {
@@ -310,13 +313,15 @@ fn main() {
                 .with(Server, ("localhost", 9011))
                 .with(Backup2, ("localhost", 9013))
                 .build();
-            let transport = HttpTransport::new(config);
+            let transport = HttpTransport::new(config).unwrap();
             let projector = Projector::new(Backup1, transport);
             loop {
-                projector.epp_and_run(KVS::<HCons<Backup1, HCons<Backup2, HNil>>, _, _, _> {
-                    request: projector.remote(Client),
-                    _phantoms: PhantomData,
-                });
+                projector
+                    .epp_and_run(KVS::<HCons<Backup1, HCons<Backup2, HNil>>, _, _, _> {
+                        request: projector.remote(Client),
+                        _phantoms: PhantomData,
+                    })
+                    .unwrap();
             }
         }
         "backup2" => {
@@ -326,13 +331,15 @@ fn main() {
                 .with(Server, ("localhost", 9011))
                 .with(Backup1, ("localhost", 9012))
                 .build();
-            let transport = HttpTransport::new(config);
+            let transport = HttpTransport::new(config).unwrap();
             let projector = Projector::new(Backup2, transport);
             loop {
-                projector.epp_and_run(KVS::<HCons<Backup1, HCons<Backup2, HNil>>, _, _, _> {
-                    request: projector.remote(Client),
-                    _phantoms: PhantomData,
-                });
+                projector
+                    .epp_and_run(KVS::<HCons<Backup1, HCons<Backup2, HNil>>, _, _, _> {
+                        request: projector.remote(Client),
+                        _phantoms: PhantomData,
+                    })
+                    .unwrap();
             }
         }
         _ => unreachable!(),
@@ -343,6 +350,10 @@ fn main() {
 mod tests {
     use super::*;
     use chorus_lib::transport::local::{LocalTransport, LocalTransportChannelBuilder};
+    use std::sync::Mutex;
+
+    // tests share the `.data` directory
+    static DATA: Mutex<()> = Mutex::new(());
 
     fn clear_data() {
         if Path::new(".data").exists() {
@@ -376,10 +387,12 @@ mod tests {
                 .name("Server".to_string())
                 .spawn(move || {
                     for _ in 0..n {
-                        server_projector.epp_and_run(KVS::<Locations, _, _, _> {
-                            request: server_projector.remote(Client),
-                            _phantoms: PhantomData,
-                        });
+                        server_projector
+                            .epp_and_run(KVS::<Locations, _, _, _> {
+                                request: server_projector.remote(Client),
+                                _phantoms: PhantomData,
+                            })
+                            .unwrap();
                     }
                 })
                 .unwrap(),
@@ -389,10 +402,12 @@ mod tests {
                 .name("Backup1".to_string())
                 .spawn(move || {
                     for _ in 0..n {
-                        backup1_projector.epp_and_run(KVS::<Locations, _, _, _> {
-                            request: backup1_projector.remote(Client),
-                            _phantoms: PhantomData,
-                        });
+                        backup1_projector
+                            .epp_and_run(KVS::<Locations, _, _, _> {
+                                request: backup1_projector.remote(Client),
+                                _phantoms: PhantomData,
+                            })
+                            .unwrap();
                     }
                 })
                 .unwrap(),
@@ -402,19 +417,23 @@ mod tests {
                 .name("Backup2".to_string())
                 .spawn(move || {
                     for _ in 0..n {
-                        backup2_projector.epp_and_run(KVS::<Locations, _, _, _> {
-                            request: backup2_projector.remote(Client),
-                            _phantoms: PhantomData,
-                        });
+                        backup2_projector
+                            .epp_and_run(KVS::<Locations, _, _, _> {
+                                request: backup2_projector.remote(Client),
+                                _phantoms: PhantomData,
+                            })
+                            .unwrap();
                     }
                 })
                 .unwrap(),
         );
         for (req, expected_response) in scenario {
-            let response = client_projector.epp_and_run(KVS::<Locations, _, _, _> {
-                request: client_projector.local(req),
-                _phantoms: PhantomData,
-            });
+            let response = client_projector
+                .epp_and_run(KVS::<Locations, _, _, _> {
+                    request: client_projector.local(req),
+                    _phantoms: PhantomData,
+                })
+                .unwrap();
             assert_eq!(client_projector.unwrap(response), expected_response);
         }
         for handle in handles {
@@ -424,6 +443,7 @@ mod tests {
 
     #[test]
     fn test_kvs() {
+        let _data = DATA.lock().unwrap();
         clear_data();
         handle_requests(vec![
             (Request::Get("foo".to_string()), -1),
@@ -433,4 +453,102 @@ mod tests {
             (Request::Get("foo".to_string()), 43),
         ]);
     }
+
+    #[test]
+    fn test_kvs_sessions() {
+        let _data = DATA.lock().unwrap();
+        clear_data();
+        type Locations = LocationSet!(Backup1, Backup2);
+
+        // a single transport per location serves all sessions
+        let transport_channel = LocalTransportChannelBuilder::new()
+            .with(Client)
+            .with(Server)
+            .with(Backup1)
+            .with(Backup2)
+            .build();
+        let client_projector = Projector::new(
+            Client,
+            LocalTransport::new(Client, transport_channel.clone()),
+        );
+        let server_projector = Projector::new(
+            Server,
+            LocalTransport::new(Server, transport_channel.clone()),
+        );
+        let backup1_projector = Projector::new(
+            Backup1,
+            LocalTransport::new(Backup1, transport_channel.clone()),
+        );
+        let backup2_projector = Projector::new(
+            Backup2,
+            LocalTransport::new(Backup2, transport_channel.clone()),
+        );
+
+        thread::scope(|s| {
+            for session in 1..=4 {
+                let server_projector = &server_projector;
+                let backup1_projector = &backup1_projector;
+                let backup2_projector = &backup2_projector;
+                let client_projector = &client_projector;
+                s.spawn(move || {
+                    for _ in 0..2 {
+                        server_projector
+                            .epp_and_run_session(
+                                session,
+                                KVS::<Locations, _, _, _> {
+                                    request: server_projector.remote(Client),
+                                    _phantoms: PhantomData,
+                                },
+                            )
+                            .unwrap();
+                    }
+                });
+                s.spawn(move || {
+                    for _ in 0..2 {
+                        backup1_projector
+                            .epp_and_run_session(
+                                session,
+                                KVS::<Locations, _, _, _> {
+                                    request: backup1_projector.remote(Client),
+                                    _phantoms: PhantomData,
+                                },
+                            )
+                            .unwrap();
+                    }
+                });
+                s.spawn(move || {
+                    for _ in 0..2 {
+                        backup2_projector
+                            .epp_and_run_session(
+                                session,
+                                KVS::<Locations, _, _, _> {
+                                    request: backup2_projector.remote(Client),
+                                    _phantoms: PhantomData,
+                                },
+                            )
+                            .unwrap();
+                    }
+                });
+                s.spawn(move || {
+                    let key = format!("key{}", session);
+                    let value = session as Value;
+                    for (req, expected_response) in [
+                        (Request::Put(key.clone(), value), 0),
+                        (Request::Get(key.clone()), value),
+                    ] {
+                        let response = client_projector
+                            .epp_and_run_session(
+                                session,
+                                KVS::<Locations, _, _, _> {
+                                    request: client_projector.local(req),
+                                    _phantoms: PhantomData,
+                                },
+                            )
+                            .unwrap();
+                        assert_eq!(client_projector.unwrap(response), expected_response);
+                    }
+                });
+            }
+        });
+    }
 }
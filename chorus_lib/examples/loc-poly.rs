@@ -69,7 +69,7 @@ fn main() {
         let transport = LocalTransport::new(Alice, transport_channel.clone());
         handles.push(thread::spawn(|| {
             let p = Projector::new(Alice, transport);
-            let v = p.epp_and_run(MainChoreography);
+            let v = p.epp_and_run(MainChoreography).unwrap();
             assert_eq!(p.unwrap(v), 110);
         }));
     }
@@ -77,7 +77,7 @@ fn main() {
         let transport = LocalTransport::new(Bob, transport_channel.clone());
         handles.push(thread::spawn(|| {
             let p = Projector::new(Bob, transport);
-            p.epp_and_run(MainChoreography);
+            p.epp_and_run(MainChoreography).unwrap();
         }));
     }
     for h in handles {
@@ -1,8 +1,9 @@
-use std::marker::PhantomData;
+extern crate chorus_lib;
+
 use std::thread::{self, JoinHandle};
 
 use chorus_lib::core::{
-    ChoreoOp, Choreography, ChoreographyLocation, Located, LocationSet, Projector,
+    ChoreoOp, Choreography, ChoreographyLocation, Faceted, Located, LocationSet, Projector, Quire,
 };
 use chorus_lib::transport::local::{LocalTransport, LocalTransportChannelBuilder};
 
@@ -15,201 +16,53 @@ pub struct Bob;
 #[derive(ChoreographyLocation)]
 pub struct Carol;
 
-pub struct Main;
-
-impl Choreography for Main {
-    type L = LocationSet!(Alice, Bob, Carol);
-
-    fn run(self, op: &impl ChoreoOp<Self::L>) {
-        let shuffled_list_at_alice = Located::local(vec![9, 1, 4, 7, 5, 2, 3, 0, 6, 8]);
-        let sorted_list_at_alice = op.call(Sort::<Alice, Bob, Carol>::new(shuffled_list_at_alice));
-        op.locally(Alice, |un| {
-            let sorted_list = un.unwrap(&sorted_list_at_alice);
-            println!("{sorted_list:?}");
-        });
-    }
-}
-
-pub struct Sort<A, B, C>
-where
-    A: ChoreographyLocation,
-    B: ChoreographyLocation,
-    C: ChoreographyLocation,
-{
-    list_at_a: Located<Vec<i64>, A>,
-    phantom: PhantomData<(B, C)>,
-}
+type Workers = LocationSet!(Alice, Bob, Carol);
 
-impl<'a, A, B, C> Sort<A, B, C>
-where
-    A: ChoreographyLocation,
-    B: ChoreographyLocation,
-    C: ChoreographyLocation,
-{
-    pub const fn new(list: Located<Vec<i64>, A>) -> Self {
-        Self {
-            list_at_a: list,
-            phantom: PhantomData,
-        }
-    }
+pub struct Sort {
+    list_at_alice: Located<Vec<i64>, Alice>,
 }
 
-impl<A, B, C> Choreography<Located<Vec<i64>, A>> for Sort<A, B, C>
-where
-    A: ChoreographyLocation,
-    B: ChoreographyLocation,
-    C: ChoreographyLocation,
-{
-    type L = LocationSet!(A, B, C);
-
-    fn run(self, op: &impl ChoreoOp<Self::L>) -> Located<Vec<i64>, A> {
-        #[allow(non_snake_case)]
-        let (A, B, C) = (A::new(), B::new(), C::new());
-
-        let is_list_small = op.broadcast(
-            A,
-            op.locally(A, |un| {
-                let list = un.unwrap(&self.list_at_a);
-                list.len() <= 1
-            }),
-        );
-
-        if is_list_small {
-            return self.list_at_a;
-        }
-
-        let pivot_at_a = op.locally(A, |un| {
-            let list = un.unwrap(&self.list_at_a);
-            list.len() / 2
+impl Choreography<Located<Vec<i64>, Alice>> for Sort {
+    type L = Workers;
+
+    fn run(self, op: &impl ChoreoOp<Self::L>) -> Located<Vec<i64>, Alice> {
+        // Alice splits the list into one chunk for each worker
+        let chunks_at_alice = op.locally(Alice, |un| {
+            let list = un.unwrap(&self.list_at_alice);
+            let (first, rest) = list.split_at(list.len() / 3);
+            let (second, third) = rest.split_at(rest.len() / 2);
+            Quire::new()
+                .add(Carol, third.to_vec())
+                .add(Bob, second.to_vec())
+                .add(Alice, first.to_vec())
         });
+        let chunk: Faceted<Vec<i64>, Workers> = op.scatter(Alice, Workers::new(), &chunks_at_alice);
 
-        let prefix_at_a = op.locally(A, |un| {
-            let list = un.unwrap(&self.list_at_a);
-            let pivot = un.unwrap(&pivot_at_a);
-            list[..*pivot].to_vec()
+        // every worker sorts its own chunk
+        let sorted_chunk = chunk.map(|mut chunk| {
+            chunk.sort();
+            chunk
         });
-        let prefix_at_b = op.comm(A, B, &prefix_at_a);
-        let sorted_prefix_at_b = op.call(Sort::<B, C, A>::new(prefix_at_b));
-
-        let suffix_at_a = op.locally(A, |un| {
-            let list = un.unwrap(&self.list_at_a);
-            let pivot = un.unwrap(&pivot_at_a);
-            list[*pivot..].to_vec()
-        });
-        let suffix_at_c = op.comm(A, C, &suffix_at_a);
-        let sorted_suffix_at_c = op.call(Sort::<C, A, B>::new(suffix_at_c));
-
-        op.call(Merge::new(sorted_prefix_at_b, sorted_suffix_at_c))
-    }
-}
 
-pub struct Merge<B, C>
-where
-    B: ChoreographyLocation,
-    C: ChoreographyLocation,
-{
-    prefix_at_b: Located<Vec<i64>, B>,
-    suffix_at_c: Located<Vec<i64>, C>,
-}
-
-impl<B, C> Merge<B, C>
-where
-    B: ChoreographyLocation,
-    C: ChoreographyLocation,
-{
-    pub const fn new(prefix_at_b: Located<Vec<i64>, B>, suffix_at_c: Located<Vec<i64>, C>) -> Self {
-        Self {
-            prefix_at_b,
-            suffix_at_c,
-        }
+        // the sorted chunks are merged at Alice
+        op.reduce(Alice, &sorted_chunk, merge)
     }
 }
 
-impl<A, B, C> Choreography<Located<Vec<i64>, A>> for Merge<B, C>
-where
-    A: ChoreographyLocation,
-    B: ChoreographyLocation,
-    C: ChoreographyLocation,
-{
-    type L = LocationSet!(A, B, C);
-
-    fn run(self, op: &impl ChoreoOp<Self::L>) -> Located<Vec<i64>, A> {
-        #[allow(non_snake_case)]
-        let (A, B, C) = (A::new(), B::new(), C::new());
-
-        let is_prefix_empty = op.broadcast(
-            B,
-            op.locally(B, |un| {
-                let prefix = un.unwrap(&self.prefix_at_b);
-                prefix.is_empty()
-            }),
-        );
-
-        if is_prefix_empty {
-            let suffix_at_c = op.locally(C, |un| un.unwrap(&self.suffix_at_c).to_vec());
-            return op.comm(C, A, &suffix_at_c);
-        }
-
-        let is_suffix_empty = op.broadcast(
-            C,
-            op.locally(C, |un| {
-                let suffix = un.unwrap(&self.suffix_at_c);
-                suffix.is_empty()
-            }),
-        );
-
-        if is_suffix_empty {
-            let prefix_at_b = op.locally(B, |un| un.unwrap(&self.prefix_at_b).to_vec());
-            return op.comm(B, A, &prefix_at_b);
-        }
-
-        let head_of_prefix_at_b = op.locally(B, |un| un.unwrap(&self.prefix_at_b)[0]);
-        let head_of_prefix_at_c = op.comm(B, C, &head_of_prefix_at_b);
-        let head_of_suffix_at_c = op.locally(C, |un| un.unwrap(&self.suffix_at_c)[0]);
-
-        let is_head_of_prefix_smaller = op.broadcast(
-            C,
-            op.locally(C, |un| {
-                let head_of_prefix = un.unwrap(&head_of_prefix_at_c);
-                let head_of_suffix = un.unwrap(&head_of_suffix_at_c);
-                head_of_prefix <= head_of_suffix
-            }),
-        );
-
-        if is_head_of_prefix_smaller {
-            let head_of_prefix_at_a = op.comm(B, A, &head_of_prefix_at_b);
-            let prefix_at_b = op.locally(B, |un| {
-                let prefix = un.unwrap(&self.prefix_at_b);
-                prefix[1..].to_vec()
-            });
-            let partial_at_a = op.call(Merge::new(prefix_at_b, self.suffix_at_c));
-            op.locally(A, |un| {
-                let partial = un.unwrap(&partial_at_a);
-                let head_of_prefix = un.unwrap(&head_of_prefix_at_a);
-                [head_of_prefix]
-                    .into_iter()
-                    .chain(partial)
-                    .copied()
-                    .collect()
-            })
+fn merge(prefix: Vec<i64>, suffix: Vec<i64>) -> Vec<i64> {
+    let mut merged = Vec::with_capacity(prefix.len() + suffix.len());
+    let mut prefix = prefix.into_iter().peekable();
+    let mut suffix = suffix.into_iter().peekable();
+    while let (Some(head_of_prefix), Some(head_of_suffix)) = (prefix.peek(), suffix.peek()) {
+        if head_of_prefix <= head_of_suffix {
+            merged.extend(prefix.next());
         } else {
-            let head_of_suffix_at_a = op.comm(C, A, &head_of_suffix_at_c);
-            let suffix_at_c = op.locally(C, |un| {
-                let suffix = un.unwrap(&self.suffix_at_c);
-                suffix[1..].to_vec()
-            });
-            let partial_at_a = op.call(Merge::new(self.prefix_at_b, suffix_at_c));
-            op.locally(A, |un| {
-                let partial = un.unwrap(&partial_at_a);
-                let head_of_suffix = un.unwrap(&head_of_suffix_at_a);
-                [head_of_suffix]
-                    .into_iter()
-                    .chain(partial)
-                    .copied()
-                    .collect()
-            })
+            merged.extend(suffix.next());
         }
     }
+    merged.extend(prefix);
+    merged.extend(suffix);
+    merged
 }
 
 fn main() {
@@ -224,7 +77,9 @@ fn main() {
         let transport = LocalTransport::new(Alice, transport_channel.clone());
         handles.push(thread::spawn(move || {
             let projector = Projector::new(Alice, transport);
-            projector.epp_and_run(Main);
+            let list_at_alice = projector.local(vec![9, 1, 4, 7, 5, 2, 3, 0, 6, 8]);
+            let sorted_list_at_alice = projector.epp_and_run(Sort { list_at_alice }).unwrap();
+            println!("{:?}", projector.unwrap(sorted_list_at_alice));
         }));
     }
 
@@ -232,7 +87,8 @@ fn main() {
         let transport = LocalTransport::new(Bob, transport_channel.clone());
         handles.push(thread::spawn(move || {
             let projector = Projector::new(Bob, transport);
-            projector.epp_and_run(Main);
+            let list_at_alice = projector.remote(Alice);
+            projector.epp_and_run(Sort { list_at_alice }).unwrap();
         }));
     }
 
@@ -240,9 +96,28 @@ fn main() {
         let transport = LocalTransport::new(Carol, transport_channel.clone());
         handles.push(thread::spawn(move || {
             let projector = Projector::new(Carol, transport);
-            projector.epp_and_run(Main);
+            let list_at_alice = projector.remote(Alice);
+            projector.epp_and_run(Sort { list_at_alice }).unwrap();
         }));
     }
 
     handles.into_iter().try_for_each(JoinHandle::join).unwrap();
 }
+
+#[cfg(test)]
+mod tests {
+    use chorus_lib::core::Runner;
+
+    use super::*;
+
+    #[test]
+    fn test_runner() {
+        let runner = Runner::new();
+        let list_at_alice = runner.local(vec![9, 1, 4, 7, 5, 2, 3, 0, 6, 8]);
+        let sorted_list_at_alice = runner.run(Sort { list_at_alice });
+        assert_eq!(
+            runner.unwrap(sorted_list_at_alice),
+            vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9]
+        );
+    }
+}
@@ -57,21 +57,21 @@ fn main() {
         let transport = LocalTransport::new(Alice, transport_channel.clone());
         handles.push(thread::spawn(move || {
             let p = Projector::new(Alice, transport);
-            p.epp_and_run(MulticastChoreography);
+            p.epp_and_run(MulticastChoreography).unwrap();
         }));
     }
     {
         let transport = LocalTransport::new(Bob, transport_channel.clone());
         handles.push(thread::spawn(move || {
             let p = Projector::new(Bob, transport);
-            p.epp_and_run(MulticastChoreography);
+            p.epp_and_run(MulticastChoreography).unwrap();
         }));
     }
     {
         let transport = LocalTransport::new(Carol, transport_channel.clone());
         handles.push(thread::spawn(move || {
             let p = Projector::new(Carol, transport);
-            p.epp_and_run(MulticastChoreography);
+            p.epp_and_run(MulticastChoreography).unwrap();
         }));
     }
     for h in handles {
@@ -55,13 +55,13 @@ fn main() {
 
     let mut handles: Vec<thread::JoinHandle<()>> = Vec::new();
     handles.push(thread::spawn(move || {
-        alice_projector.epp_and_run(ParallelChoreography);
+        alice_projector.epp_and_run(ParallelChoreography).unwrap();
     }));
     handles.push(thread::spawn(move || {
-        bob_projector.epp_and_run(ParallelChoreography);
+        bob_projector.epp_and_run(ParallelChoreography).unwrap();
     }));
     handles.push(thread::spawn(move || {
-        carol_projector.epp_and_run(ParallelChoreography);
+        carol_projector.epp_and_run(ParallelChoreography).unwrap();
     }));
     for handle in handles {
         handle.join().unwrap();
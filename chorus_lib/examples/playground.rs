@@ -74,17 +74,17 @@ fn main() {
             let config = HttpTransportConfigBuilder::for_target(Alpha, ("0.0.0.0", 8080))
                 .with(Beta, ("127.0.0.1", 8081))
                 .build();
-            let transport = HttpTransport::new(config);
+            let transport = HttpTransport::new(config).unwrap();
             let projector = Projector::new(Alpha, transport);
-            projector.epp_and_run(MainChoreography);
+            projector.epp_and_run(MainChoreography).unwrap();
         }
         "beta" => {
             let config = HttpTransportConfigBuilder::for_target(Beta, ("0.0.0.0", 8081))
                 .with(Alpha, ("127.0.0.1", 8080))
                 .build();
-            let transport = HttpTransport::new(config);
+            let transport = HttpTransport::new(config).unwrap();
             let projector = Projector::new(Beta, transport);
-            projector.epp_and_run(MainChoreography);
+            projector.epp_and_run(MainChoreography).unwrap();
         }
         _ => panic!("Invalid role"),
     };
@@ -300,12 +300,14 @@ fn main() {
             )
             .build();
 
-            let transport = HttpTransport::new(config);
+            let transport = HttpTransport::new(config).unwrap();
             let projector = Projector::new(PlayerX, transport);
-            projector.epp_and_run(TicTacToeChoreography {
-                brain_for_x: projector.local(brain),
-                brain_for_o: projector.remote(PlayerO),
-            });
+            projector
+                .epp_and_run(TicTacToeChoreography {
+                    brain_for_x: projector.local(brain),
+                    brain_for_o: projector.remote(PlayerO),
+                })
+                .unwrap();
         }
         'O' => {
             let config = HttpTransportConfigBuilder::for_target(
@@ -318,12 +320,14 @@ fn main() {
             )
             .build();
 
-            let transport = HttpTransport::new(config);
+            let transport = HttpTransport::new(config).unwrap();
             let projector = Projector::new(PlayerO, transport);
-            projector.epp_and_run(TicTacToeChoreography {
-                brain_for_x: projector.remote(PlayerX),
-                brain_for_o: projector.local(brain),
-            });
+            projector
+                .epp_and_run(TicTacToeChoreography {
+                    brain_for_x: projector.remote(PlayerX),
+                    brain_for_o: projector.local(brain),
+                })
+                .unwrap();
         }
         _ => {
             println!("Invalid player; must be X or O");
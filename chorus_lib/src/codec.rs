@@ -0,0 +1,159 @@
+//! Message codecs.
+//!
+//! A `Codec` turns values into bytes and back. Every built-in transport is parameterised over a codec and uses
+//! `Json` unless another one is given. `Json` is always available; the binary codecs are enabled with the
+//! `bincode`, `cbor`, and `msgpack` features.
+
+use crate::core::{Portable, TransportError};
+
+/// Encodes and decodes messages.
+///
+/// All locations that communicate with each other must use the same codec.
+pub trait Codec {
+    /// Encodes `data` into bytes.
+    fn encode<V: Portable>(&self, data: &V) -> Result<Vec<u8>, TransportError>;
+    /// Decodes a value from bytes produced by `encode`.
+    fn decode<V: Portable>(&self, bytes: &[u8]) -> Result<V, TransportError>;
+}
+
+/// Clones a value by encoding and decoding it with `codec`.
+pub(crate) fn roundtrip<V: Portable>(codec: &impl Codec, value: &V) -> Result<V, TransportError> {
+    codec.decode(&codec.encode(value)?)
+}
+
+/// The JSON codec.
+///
+/// Messages are human-readable, which is useful for debugging, but binary data and floating-point numbers are
+/// encoded inefficiently.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Json;
+
+impl Codec for Json {
+    fn encode<V: Portable>(&self, data: &V) -> Result<Vec<u8>, TransportError> {
+        serde_json::to_vec(data).map_err(|err| TransportError::Serialization(err.to_string()))
+    }
+
+    fn decode<V: Portable>(&self, bytes: &[u8]) -> Result<V, TransportError> {
+        serde_json::from_slice(bytes)
+            .map_err(|err| TransportError::Deserialization(err.to_string()))
+    }
+}
+
+/// The [bincode](https://docs.rs/bincode) codec.
+///
+/// Requires the `bincode` feature.
+#[cfg(feature = "bincode")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Bincode;
+
+#[cfg(feature = "bincode")]
+impl Codec for Bincode {
+    fn encode<V: Portable>(&self, data: &V) -> Result<Vec<u8>, TransportError> {
+        bincode::serialize(data).map_err(|err| TransportError::Serialization(err.to_string()))
+    }
+
+    fn decode<V: Portable>(&self, bytes: &[u8]) -> Result<V, TransportError> {
+        bincode::deserialize(bytes).map_err(|err| TransportError::Deserialization(err.to_string()))
+    }
+}
+
+/// The [CBOR](https://cbor.io) codec.
+///
+/// Requires the `cbor` feature.
+#[cfg(feature = "cbor")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Cbor;
+
+#[cfg(feature = "cbor")]
+impl Codec for Cbor {
+    fn encode<V: Portable>(&self, data: &V) -> Result<Vec<u8>, TransportError> {
+        let mut bytes = Vec::new();
+        ciborium::into_writer(data, &mut bytes)
+            .map_err(|err| TransportError::Serialization(err.to_string()))?;
+        Ok(bytes)
+    }
+
+    fn decode<V: Portable>(&self, bytes: &[u8]) -> Result<V, TransportError> {
+        ciborium::from_reader(bytes).map_err(|err| TransportError::Deserialization(err.to_string()))
+    }
+}
+
+/// The [MessagePack](https://msgpack.org) codec.
+///
+/// Structs are encoded as maps, so messages stay compatible when fields are reordered. Requires the `msgpack`
+/// feature.
+#[cfg(feature = "msgpack")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MessagePack;
+
+#[cfg(feature = "msgpack")]
+impl Codec for MessagePack {
+    fn encode<V: Portable>(&self, data: &V) -> Result<Vec<u8>, TransportError> {
+        rmp_serde::to_vec_named(data).map_err(|err| TransportError::Serialization(err.to_string()))
+    }
+
+    fn decode<V: Portable>(&self, bytes: &[u8]) -> Result<V, TransportError> {
+        rmp_serde::from_slice(bytes).map_err(|err| TransportError::Deserialization(err.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Message {
+        name: String,
+        payload: Vec<u8>,
+        values: Vec<f64>,
+        reply: Option<Box<Message>>,
+    }
+
+    fn message() -> Message {
+        Message {
+            name: "ping".to_string(),
+            payload: (0..=255).collect(),
+            values: vec![0.1, -2.5, f64::MAX],
+            reply: Some(Box::new(Message {
+                name: "pong".to_string(),
+                payload: Vec::new(),
+                values: Vec::new(),
+                reply: None,
+            })),
+        }
+    }
+
+    fn check(codec: impl Codec) {
+        assert_eq!(roundtrip(&codec, &message()).unwrap(), message());
+        assert!(matches!(
+            codec.decode::<Message>(&[0xff, 0x00]),
+            Err(TransportError::Deserialization(_))
+        ));
+    }
+
+    #[test]
+    fn test_json() {
+        check(Json);
+    }
+
+    #[cfg(feature = "bincode")]
+    #[test]
+    fn test_bincode() {
+        check(Bincode);
+        // binary data is not expanded into text
+        assert!(Bincode.encode(&message()).unwrap().len() < Json.encode(&message()).unwrap().len());
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn test_cbor() {
+        check(Cbor);
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn test_msgpack() {
+        check(MessagePack);
+    }
+}
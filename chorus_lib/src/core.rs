@@ -2,13 +2,36 @@
 //!
 //! This module provides core choreography constructs, such as `Choreography`, `Located`, and `Projector`.
 
-use std::{collections::HashMap, fmt::Debug, marker::PhantomData};
+use std::{
+    any::{type_name, Any},
+    collections::HashMap,
+    error::Error,
+    fmt::{Debug, Display},
+    marker::PhantomData,
+    panic::{self, AssertUnwindSafe},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use serde::de::DeserializeOwned;
+
+use crate::codec::{roundtrip, Codec, Json};
+use crate::trace;
+
 // re-export so that users can use derive macros without importing serde
 #[doc(no_inline)]
 pub use serde::{Deserialize, Serialize};
 
+mod asynchronous;
+mod tagged;
+pub(crate) use asynchronous::CatchUnwind;
+pub use asynchronous::{
+    AsyncChoreoOp, AsyncChoreography, AsyncFanInChoreography, AsyncFanOutChoreography,
+    AsyncProjector, AsyncTransport,
+};
+pub(crate) use tagged::value_type_name;
+use tagged::{Steps, Tagged, HANDSHAKE_STEP};
+
 /// Represents a location.
 ///
 /// It can be derived using `#[derive(ChoreographyLocation)]`.
@@ -34,6 +57,39 @@ pub trait ChoreographyLocation: Copy {
 pub trait Portable: Serialize + DeserializeOwned {}
 impl<T: Serialize + DeserializeOwned> Portable for T {}
 
+/// Represents the label of a branch of a choreography.
+///
+/// A location chooses a branch and tells other locations about it with `ChoreoOp::select`. Only the index of the
+/// label is sent.
+///
+/// It can be derived for enums whose variants have no fields using `#[derive(BranchLabel)]`.
+///
+/// ```
+/// # use chorus_lib::core::BranchLabel;
+/// #
+/// #[derive(BranchLabel)]
+/// enum Decision {
+///     Buy,
+///     Cancel,
+/// }
+/// ```
+pub trait BranchLabel: Sized {
+    /// Returns the index of the label.
+    fn index(&self) -> u32;
+    /// Returns the label with the given index, or `None` if there is no such label.
+    fn from_index(index: u32) -> Option<Self>;
+}
+
+/// Returns the label with the given index, aborting the choreography if there is no such label.
+fn label_from_index<Label: BranchLabel>(index: u32) -> Label {
+    Label::from_index(index).unwrap_or_else(|| {
+        abort(TransportError::Deserialization(format!(
+            "unknown branch label {}",
+            index
+        )))
+    })
+}
+
 /// Represents a value located at a location.
 pub type Located<V, L1> = MultiplyLocated<V, LocationSet!(L1)>;
 
@@ -99,6 +155,7 @@ where
 }
 
 /// Represents a mapping from location names to values
+#[derive(Serialize, Deserialize)]
 pub struct Quire<V, L>
 where
     L: LocationSet,
@@ -113,7 +170,7 @@ where
     V: Debug,
 {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_map().entries(self.value.iter()).finish()
+        f.debug_map().entries(self.iter()).finish()
     }
 }
 
@@ -144,6 +201,66 @@ where
     pub fn into_map(self) -> HashMap<String, V> {
         self.value
     }
+
+    /// Returns the value of `location`.
+    ///
+    /// A quire built with `add` has a value for every location, but a quire received from a peer may not. Returns
+    /// `None` if there is no value for `location`.
+    pub fn get<L1: ChoreographyLocation, Index>(&self, _location: L1) -> Option<&V>
+    where
+        L1: Member<L, Index>,
+    {
+        self.value.get(L1::name())
+    }
+
+    /// Returns an iterator over the names of the locations and their values.
+    ///
+    /// The locations are visited in the order in which they are listed in `L`.
+    pub fn iter(&self) -> impl Iterator<Item = (&'static str, &V)> {
+        listed::<L>()
+            .into_iter()
+            .filter_map(|location| self.value.get(location).map(|value| (location, value)))
+    }
+
+    /// Applies `f` to the value of every location.
+    pub fn map<W>(self, f: impl Fn(V) -> W) -> Quire<W, L> {
+        Quire {
+            value: self.value.into_iter().map(|(l, v)| (l, f(v))).collect(),
+            phantom: PhantomData,
+        }
+    }
+
+    /// Pairs the values of every location with the values of `other`.
+    ///
+    /// A location that has a value in only one of the quires, such as a quire received from a peer that lacks
+    /// entries, has no value in the result.
+    pub fn zip<W>(self, mut other: Quire<W, L>) -> Quire<(V, W), L> {
+        Quire {
+            value: zip_maps(self.value, &mut other.value),
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<V, L> Quire<V, L>
+where
+    L: LocationSet,
+{
+    /// Returns the value of `location`, or `ChoreographyError::MissingEntry` if there is none.
+    fn entry(&self, location: &str) -> Result<&V, ChoreographyError> {
+        self.value
+            .get(location)
+            .ok_or_else(|| ChoreographyError::MissingEntry(location.to_string()))
+    }
+
+    /// Combines the values with `f` in the order in which the locations of `L` are listed.
+    fn reduce(mut self, f: impl Fn(V, V) -> V) -> V {
+        listed::<L>()
+            .into_iter()
+            .filter_map(|location| self.value.remove(location))
+            .reduce(f)
+            .expect("no values to reduce")
+    }
 }
 
 impl<V, L> Quire<V, L>
@@ -167,6 +284,61 @@ where
     phantom: PhantomData<L>,
 }
 
+impl<V, L> Faceted<V, L>
+where
+    L: LocationSet,
+{
+    /// Applies `f` to the facet of every location.
+    ///
+    /// Each location applies `f` to its own facet, without communicating with other locations.
+    pub fn map<W>(self, f: impl Fn(V) -> W) -> Faceted<W, L> {
+        Faceted {
+            value: self.value.into_iter().map(|(l, v)| (l, f(v))).collect(),
+            phantom: PhantomData,
+        }
+    }
+
+    /// Pairs the facet of every location with its facet of `other`.
+    ///
+    /// A location that has a facet in only one of the values has no facet in the result.
+    pub fn zip<W>(self, mut other: Faceted<W, L>) -> Faceted<(V, W), L> {
+        Faceted {
+            value: zip_maps(self.value, &mut other.value),
+            phantom: PhantomData,
+        }
+    }
+
+    /// Returns the facet of `location`, or `ChoreographyError::MissingEntry` if there is none.
+    fn facet(&self, location: &str) -> Result<&V, ChoreographyError> {
+        self.value
+            .get(location)
+            .ok_or_else(|| ChoreographyError::MissingEntry(location.to_string()))
+    }
+}
+
+/// Makes the entry of each location of a quire held by all of `L` its facet.
+impl<V, L> From<MultiplyLocated<Quire<V, L>, L>> for Faceted<V, L>
+where
+    L: LocationSet,
+{
+    fn from(quire: MultiplyLocated<Quire<V, L>, L>) -> Self {
+        Faceted {
+            value: quire.value.map(Quire::into_map).unwrap_or_default(),
+            phantom: PhantomData,
+        }
+    }
+}
+
+/// Pairs the values of the locations that are in both maps.
+fn zip_maps<V, W>(
+    left: HashMap<String, V>,
+    right: &mut HashMap<String, W>,
+) -> HashMap<String, (V, W)> {
+    left.into_iter()
+        .filter_map(|(l, v)| right.remove(&l).map(|w| (l, (v, w))))
+        .collect()
+}
+
 /// Represents a value that can be unwrapped at any location in `L`
 pub trait Unwrappable<'a, V> {
     /// A location set that the value is located at
@@ -268,6 +440,13 @@ where
     }
 }
 
+/// Returns the locations of `L` in the order in which they are listed.
+fn listed<L: LocationSet>() -> Vec<&'static str> {
+    let mut locations = L::to_string_list();
+    locations.reverse();
+    locations
+}
+
 impl LocationSet for HNil {
     fn new() -> Self {
         HNil
@@ -381,9 +560,13 @@ where
 {
 }
 
+/// A resource that the endpoint of a location provides to its local computations.
+type AnyContext = dyn Any + Send + Sync;
+
 /// Provides a method to work with located values at the current location
 pub struct Unwrapper<L1: ChoreographyLocation> {
     phantom: PhantomData<L1>,
+    context: Option<Arc<AnyContext>>,
 }
 
 impl<L1: ChoreographyLocation> Unwrapper<L1> {
@@ -395,6 +578,37 @@ impl<L1: ChoreographyLocation> Unwrapper<L1> {
     {
         unwrappable.unwrap_at::<L1, Index>(L1::new())
     }
+
+    /// Takes ownership of a located value at the current location
+    ///
+    /// Use this method in `ChoreoOp::locally` to consume a located value without cloning it.
+    pub fn take<V, S: LocationSet, Index>(&self, located: MultiplyLocated<V, S>) -> V
+    where
+        L1: Member<S, Index>,
+    {
+        located.value.unwrap()
+    }
+
+    /// Returns the context of the current location
+    ///
+    /// The context is given by `Projector::with_context` or `Runner::with_context`. Panics if the current location
+    /// has no context of type `C`; use `Unwrapper::try_context` to handle a missing context.
+    pub fn context<C: Any>(&self) -> &C {
+        self.try_context().unwrap_or_else(|| {
+            panic!(
+                "no context of type `{}` at `{}`",
+                type_name::<C>(),
+                L1::name()
+            )
+        })
+    }
+
+    /// Returns the context of the current location, or `None` if it has no context of type `C`
+    pub fn try_context<C: Any>(&self) -> Option<&C> {
+        self.context
+            .as_deref()
+            .and_then(|context| context.downcast_ref())
+    }
 }
 
 /// Provides choreographic operations.
@@ -410,10 +624,13 @@ pub trait ChoreoOp<ChoreoLS: LocationSet> {
     /// - `computation` is a function that takes an `Unwrapper`. Using the `Unwrapper`, the function can access located values at the location.
     ///
     /// The `computation` can return a value of type `V` and the value will be stored in a `Located` struct at the choreography level.
+    ///
+    /// The `computation` runs at most once, so it can move captured values. It can take ownership of located values
+    /// with `Unwrapper::take` instead of cloning them.
     fn locally<V, L1: ChoreographyLocation, Index>(
         &self,
         location: L1,
-        computation: impl Fn(Unwrapper<L1>) -> V,
+        computation: impl FnOnce(Unwrapper<L1>) -> V,
     ) -> MultiplyLocated<V, LocationSet!(L1)>
     where
         L1: Member<ChoreoLS, Index>;
@@ -444,6 +661,9 @@ pub trait ChoreoOp<ChoreoLS: LocationSet> {
     ///
     /// `broadcast` broadcasts `data` from `sender` to all other locations. The `data` must be a `Located` struct at the `sender` location.
     /// The method returns the non-located value.
+    ///
+    /// The value is sent to the locations that run the choreography: the census of the choreography, or of the
+    /// caller if the choreography was run with `call`. Other locations of the transport receive nothing.
     fn broadcast<L: LocationSet, Sender: ChoreographyLocation, V: Portable, Index1, Index2>(
         &self,
         sender: Sender,
@@ -466,6 +686,20 @@ pub trait ChoreoOp<ChoreoLS: LocationSet> {
         Sender: Member<ChoreoLS, Index1>,
         D: Subset<ChoreoLS, Index2>;
 
+    /// Sends a branch label from a location to a set of locations.
+    ///
+    /// `select` tells `receivers` which branch `sender` has chosen, so that they can follow it with `branch`. Only the
+    /// index of the label is sent. Locations that are neither the sender nor a receiver do not learn the label.
+    fn select<Sender: ChoreographyLocation, Label: BranchLabel, D: LocationSet, Index1, Index2>(
+        &self,
+        sender: Sender,
+        receivers: D,
+        label: &MultiplyLocated<Label, LocationSet!(Sender)>,
+    ) -> MultiplyLocated<Label, D>
+    where
+        Sender: Member<ChoreoLS, Index1>,
+        D: Subset<ChoreoLS, Index2>;
+
     /// Obtains a normal value from a value located at all locations in the census
     fn naked<S: LocationSet, V, Index>(&self, data: MultiplyLocated<V, S>) -> V
     where
@@ -487,6 +721,24 @@ pub trait ChoreoOp<ChoreoLS: LocationSet> {
     where
         S: Subset<ChoreoLS, Index>;
 
+    /// Runs the branch chosen by `label` at the locations that know the label.
+    ///
+    /// `choreo` maps the label to the choreography of the branch, which is called on `S` like `conclave`. Locations
+    /// outside of `S` skip the branch.
+    fn branch<R, S: LocationSet, Label, C: Choreography<R, L = S>, Index>(
+        &self,
+        label: &MultiplyLocated<Label, S>,
+        choreo: impl FnOnce(&Label) -> C,
+    ) -> MultiplyLocated<R, S>
+    where
+        S: Subset<ChoreoLS, Index>,
+    {
+        match &label.value {
+            Some(label) => self.conclave(choreo(label)),
+            None => MultiplyLocated::remote(),
+        }
+    }
+
     /// Performs parallel computation.
     fn parallel<V, S: LocationSet, Index>(
         &self,
@@ -539,6 +791,75 @@ pub trait ChoreoOp<ChoreoLS: LocationSet> {
         QS: Subset<ChoreoLS, QSSubsetL>,
         RS: Subset<ChoreoLS, RSSubsetL>,
         QS: LocationSetFoldable<ChoreoLS, QS, QSFoldable>;
+
+    /// Sends each entry of a `Quire` at `sender` to its location.
+    ///
+    /// `scatter` sends the entry of each location in `locations` to that location, so that every location of
+    /// `locations` holds its own entry as a facet of the result. Aborts the choreography at `sender` with
+    /// `ChoreographyError::MissingEntry` if the quire has no entry for one of `locations`.
+    fn scatter<Sender: ChoreographyLocation, V: Portable, QS: LocationSet, Index1, Index2>(
+        &self,
+        sender: Sender,
+        locations: QS,
+        data: &MultiplyLocated<Quire<V, QS>, LocationSet!(Sender)>,
+    ) -> Faceted<V, QS>
+    where
+        Sender: Member<ChoreoLS, Index1>,
+        QS: Subset<ChoreoLS, Index2>;
+
+    /// Collects the facets of a `Faceted` value into a `Quire` at `root`.
+    ///
+    /// Every location of `QS` other than `root` sends its facet to `root`.
+    fn gather<Root: ChoreographyLocation, V: Portable, QS: LocationSet, Index1, Index2>(
+        &self,
+        root: Root,
+        data: &Faceted<V, QS>,
+    ) -> MultiplyLocated<Quire<V, QS>, LocationSet!(Root)>
+    where
+        Root: Member<ChoreoLS, Index1>,
+        QS: Subset<ChoreoLS, Index2>;
+
+    /// Combines the facets of a `Faceted` value at `root`.
+    ///
+    /// The facets are gathered at `root` and combined with `f` in the order in which the locations of `QS` are
+    /// listed. Panics at every location if `QS` is empty.
+    fn reduce<Root: ChoreographyLocation, V: Portable, QS: LocationSet, Index1, Index2>(
+        &self,
+        root: Root,
+        data: &Faceted<V, QS>,
+        f: impl Fn(V, V) -> V,
+    ) -> MultiplyLocated<V, LocationSet!(Root)>
+    where
+        Root: Member<ChoreoLS, Index1>,
+        QS: Subset<ChoreoLS, Index2>,
+    {
+        assert_census::<QS>("reduce");
+        let quire = self.gather(root, data);
+        MultiplyLocated {
+            value: quire.value.map(|quire| quire.reduce(f)),
+            phantom: PhantomData,
+        }
+    }
+
+    /// Combines the facets of a `Faceted` value and shares the result with every location of `QS`.
+    ///
+    /// The first location of `QS` combines the facets like `reduce` and sends the result to the other locations.
+    /// Panics at every location if `QS` is empty, like `reduce`.
+    fn allreduce<V: Portable, QS: LocationSet, Index>(
+        &self,
+        data: &Faceted<V, QS>,
+        f: impl Fn(V, V) -> V,
+    ) -> MultiplyLocated<V, QS>
+    where
+        QS: Subset<ChoreoLS, Index>;
+
+    /// Runs `chor` with a deadline for sending and receiving messages.
+    ///
+    /// Each location starts counting `timeout` when it reaches `with_deadline`. Receiving a message inside `chor` fails
+    /// with `TransportError::Timeout` if the message has not arrived by the deadline, and so does sending a message
+    /// to a location that cannot be reached by then, which aborts the choreography. The deadline replaces the
+    /// transport's default receive and send timeouts. Nested deadlines can only shorten it.
+    fn with_deadline<R>(&self, timeout: Duration, chor: impl FnOnce(&Self) -> R) -> R;
 }
 
 /// Special choreography for fanout
@@ -600,6 +921,283 @@ pub trait Choreography<R = ()> {
     fn run(self, op: &impl ChoreoOp<Self::L>) -> R;
 }
 
+/// Represents an error that occurred while sending or receiving a message.
+#[derive(Debug)]
+pub enum TransportError {
+    /// The transport does not know how to reach the location with the given name.
+    UnknownLocation(String),
+    /// A value could not be serialized into a message.
+    Serialization(String),
+    /// A message could not be deserialized into a value.
+    Deserialization(String),
+    /// An I/O error occurred while communicating with a peer.
+    Io(std::io::Error),
+    /// No message arrived before the deadline.
+    Timeout,
+    /// The peer with the given name closed the connection.
+    PeerClosed(String),
+    /// A message claiming to come from the location with the given name failed authentication.
+    Unauthenticated(String),
+    /// A message from the location with the given name was never delivered.
+    MessageLost(String),
+    /// Messages from the location with the given name were never received.
+    Unconsumed(String),
+}
+
+impl Display for TransportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TransportError::UnknownLocation(name) => write!(f, "unknown location `{}`", name),
+            TransportError::Serialization(msg) => write!(f, "failed to serialize: {}", msg),
+            TransportError::Deserialization(msg) => write!(f, "failed to deserialize: {}", msg),
+            TransportError::Io(err) => write!(f, "I/O error: {}", err),
+            TransportError::Timeout => write!(f, "timed out waiting for a message"),
+            TransportError::PeerClosed(name) => write!(f, "`{}` closed the connection", name),
+            TransportError::Unauthenticated(name) => {
+                write!(f, "message from `{}` failed authentication", name)
+            }
+            TransportError::MessageLost(name) => write!(f, "a message from `{}` was lost", name),
+            TransportError::Unconsumed(name) => {
+                write!(f, "messages from `{}` were never received", name)
+            }
+        }
+    }
+}
+
+impl Error for TransportError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            TransportError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for TransportError {
+    fn from(err: std::io::Error) -> Self {
+        TransportError::Io(err)
+    }
+}
+
+/// Represents an error that aborted the execution of a choreography.
+#[derive(Debug)]
+pub enum ChoreographyError {
+    /// The transport failed to send or receive a message.
+    Transport(TransportError),
+    /// A location received a message other than the one it expected at this point of the choreography.
+    ///
+    /// The expected and received messages are given as their steps and the type names of their values. Messages carry
+    /// only a hash of the type name, so a received value of another type is described by its hash.
+    ProtocolMismatch {
+        /// The location that sent the message.
+        sender: String,
+        /// The message that the receiver expected.
+        expected: (u64, String),
+        /// The message that the receiver got.
+        received: (u64, String),
+    },
+    /// A peer runs a different choreography, or a different version of it.
+    ///
+    /// Returned before the choreography starts when the projector has a version (see `Projector::with_version`).
+    FingerprintMismatch {
+        /// The peer that sent the fingerprint.
+        peer: String,
+        /// The fingerprint of the local choreography.
+        expected: Box<Fingerprint>,
+        /// The fingerprint of the choreography of the peer.
+        received: Box<Fingerprint>,
+    },
+    /// A peer aborted or panicked in its run of the choreography.
+    ///
+    /// Returned by the end-of-run barrier (see `Projector::with_end_barrier`).
+    PeerAborted(String),
+    /// The projection target is not a location of the choreography, so it has no part to run.
+    ///
+    /// Returned before the choreography starts.
+    NotInChoreography(String),
+    /// A `Quire` has no entry for a location, for example because a peer sent an incomplete quire.
+    MissingEntry(String),
+    /// A peer sent a message of the choreography instead of its fingerprint, so it has no version.
+    ///
+    /// Returned by projectors with a version (see `Projector::with_version`).
+    MissingFingerprint(String),
+    /// A peer sent its fingerprint, but the projector has no version (see `Projector::with_version`).
+    UnexpectedFingerprint(String),
+}
+
+impl Display for ChoreographyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChoreographyError::Transport(err) => write!(f, "transport error: {}", err),
+            ChoreographyError::ProtocolMismatch {
+                sender,
+                expected,
+                received,
+            } => write!(
+                f,
+                "protocol mismatch: expected step {} ({}) from `{}`, received step {} ({})",
+                expected.0, expected.1, sender, received.0, received.1
+            ),
+            ChoreographyError::FingerprintMismatch {
+                peer,
+                expected,
+                received,
+            } => write!(
+                f,
+                "fingerprint mismatch: `{}` runs {}, expected {}",
+                peer, received, expected
+            ),
+            ChoreographyError::PeerAborted(peer) => {
+                write!(f, "`{}` aborted the run of the choreography", peer)
+            }
+            ChoreographyError::NotInChoreography(location) => {
+                write!(f, "`{}` is not a location of the choreography", location)
+            }
+            ChoreographyError::MissingEntry(location) => {
+                write!(f, "quire has no entry for `{}`", location)
+            }
+            ChoreographyError::MissingFingerprint(peer) => {
+                write!(f, "`{}` did not send a fingerprint", peer)
+            }
+            ChoreographyError::UnexpectedFingerprint(peer) => write!(
+                f,
+                "`{}` sent a fingerprint, but the projector has no version",
+                peer
+            ),
+        }
+    }
+}
+
+impl Error for ChoreographyError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ChoreographyError::Transport(err) => Some(err),
+            ChoreographyError::ProtocolMismatch { .. } => None,
+            ChoreographyError::FingerprintMismatch { .. } => None,
+            ChoreographyError::PeerAborted(_) => None,
+            ChoreographyError::NotInChoreography(_) => None,
+            ChoreographyError::MissingEntry(_) => None,
+            ChoreographyError::MissingFingerprint(_) => None,
+            ChoreographyError::UnexpectedFingerprint(_) => None,
+        }
+    }
+}
+
+impl From<TransportError> for ChoreographyError {
+    fn from(err: TransportError) -> Self {
+        ChoreographyError::Transport(err)
+    }
+}
+
+/// Identifies a version of a choreography.
+///
+/// Projectors with a version exchange fingerprints with their peers before running a choreography, so that
+/// locations running different versions refuse to start instead of interoperating until they deadlock.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Fingerprint {
+    /// The type name of the choreography.
+    pub choreography: String,
+    /// The names of the locations of the choreography, in sorted order.
+    pub locations: Vec<String>,
+    /// The version of the choreography, as given to `Projector::with_version`.
+    pub version: String,
+}
+
+impl Fingerprint {
+    /// Computes the fingerprint of the choreography `C` over the locations `L` at `version`.
+    pub fn new<C, L: LocationSet>(version: &str) -> Self {
+        let mut locations: Vec<String> =
+            L::to_string_list().into_iter().map(String::from).collect();
+        locations.sort();
+        Fingerprint {
+            choreography: std::any::type_name::<C>().to_string(),
+            locations,
+            version: version.to_string(),
+        }
+    }
+}
+
+impl Display for Fingerprint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "`{}` over [{}] at version `{}`",
+            self.choreography,
+            self.locations.join(", "),
+            self.version
+        )
+    }
+}
+
+/// The message that a location sends to its peers when it finishes a run with an end-of-run barrier.
+#[derive(Serialize, Deserialize)]
+enum EndOfRun {
+    /// The location finished the run and waits for its peers.
+    Finished,
+    /// The run aborted or panicked at the location, which does not wait for its peers.
+    Aborted,
+}
+
+/// Returns the locations of `L` other than `Target`, or `None` if `Target` is not in `L`.
+fn peers<Target: ChoreographyLocation, L: LocationSet>() -> Option<Vec<&'static str>> {
+    let locations = L::to_string_list();
+    if !locations.contains(&Target::name()) {
+        return None;
+    }
+    Some(
+        locations
+            .into_iter()
+            .filter(|peer| *peer != Target::name())
+            .collect(),
+    )
+}
+
+/// Aborts the running choreography with `err`.
+///
+/// Operators in `ChoreoOp` return plain values, so errors are carried out of the choreography by unwinding.
+/// `Projector::epp_and_run` catches the unwinding and turns it back into a `ChoreographyError`.
+fn abort(err: impl Into<ChoreographyError>) -> ! {
+    panic::resume_unwind(Box::new(err.into()))
+}
+
+/// Fails a choreography run by `Runner`, which has no caller to return the error to, with a panic that names it.
+fn fail(err: impl Into<ChoreographyError>) -> ! {
+    panic!("{}", err.into())
+}
+
+/// Copies `entry` with `codec`, or fails with the error of the lookup or the codec.
+fn copy_entry<V: Portable>(
+    codec: &impl Codec,
+    entry: Result<&V, ChoreographyError>,
+) -> Result<V, ChoreographyError> {
+    Ok(roundtrip(codec, entry?)?)
+}
+
+/// Panics if `QS` has no locations, because the collective `op` would have no values to combine.
+fn assert_census<QS: LocationSet>(op: &str) {
+    assert!(
+        !QS::to_string_list().is_empty(),
+        "`{}` needs at least one location to combine",
+        op
+    );
+}
+
+/// Converts the payload of an unwinding raised with `abort` into its error.
+///
+/// Panics that were not raised with `abort` are propagated.
+fn into_abort(payload: Box<dyn Any + Send>) -> ChoreographyError {
+    match payload.downcast::<ChoreographyError>() {
+        Ok(err) => *err,
+        Err(payload) => panic::resume_unwind(payload),
+    }
+}
+
+/// Identifies a run of a choreography.
+///
+/// Messages sent in one session are only received in the same session, so independent runs of choreographies can
+/// share a transport. `Projector::epp_and_run` runs a choreography in session `0`.
+pub type SessionId = u64;
+
 /// Provides methods to send and receive messages.
 ///
 /// The trait provides methods to send and receive messages between locations. Implement this trait to define a custom transport.
@@ -608,12 +1206,80 @@ pub trait Choreography<R = ()> {
 ///
 /// The type parameter `TargetLocation` is the target `ChoreographyLocation`.
 pub trait Transport<L: LocationSet, TargetLocation: ChoreographyLocation> {
+    /// The codec used to encode messages.
+    type Codec: Codec;
+    /// Returns the codec used to encode messages.
+    ///
+    /// The projector also uses it to copy values that a location sends to itself.
+    fn codec(&self) -> &Self::Codec;
     /// Returns a list of locations.
     fn locations(&self) -> Vec<&'static str>;
-    /// Sends a message from `from` to `to`.
-    fn send<V: Portable>(&self, from: &str, to: &str, data: &V) -> ();
-    /// Receives a message from `from` to `at`.
-    fn receive<V: Portable>(&self, from: &str, at: &str) -> V;
+    /// Sends a message from `from` to `to` in `session`.
+    ///
+    /// Fails with `TransportError::Timeout` if the message cannot be handed to `to` before `deadline`, for example
+    /// because `to` is not listening. If `deadline` is `None`, the transport's default send timeout applies, if it
+    /// has one.
+    fn send<V: Portable>(
+        &self,
+        session: SessionId,
+        from: &str,
+        to: &str,
+        data: &V,
+        deadline: Option<Instant>,
+    ) -> Result<(), TransportError>;
+    /// Sends a message from `from` to `to` in `session` twice, as a network that duplicates it would.
+    ///
+    /// Transports that number their messages hand the same sealed message to the network twice, so the receiver
+    /// discards the second copy. The default implementation calls `send` twice. `FaultyTransport` uses this to
+    /// inject duplicates.
+    fn send_duplicate<V: Portable>(
+        &self,
+        session: SessionId,
+        from: &str,
+        to: &str,
+        data: &V,
+        deadline: Option<Instant>,
+    ) -> Result<(), TransportError> {
+        self.send(session, from, to, data, deadline)?;
+        self.send(session, from, to, data, deadline)
+    }
+    /// Receives a message from `from` to `at` in `session`.
+    ///
+    /// Fails with `TransportError::Timeout` if no message arrives before `deadline`. If `deadline` is `None`, the
+    /// transport's default receive timeout applies, if it has one.
+    fn receive<V: Portable>(
+        &self,
+        session: SessionId,
+        from: &str,
+        at: &str,
+        deadline: Option<Instant>,
+    ) -> Result<V, TransportError>;
+    /// Waits until every peer of the target is ready to receive messages.
+    ///
+    /// Call this before running the first choreography so that the first messages do not depend on retries. Fails
+    /// with `TransportError::Timeout` if a peer is not ready within `timeout`. Transports that do not connect to
+    /// their peers are always ready.
+    fn wait_ready(&self, timeout: Duration) -> Result<(), TransportError> {
+        _ = timeout;
+        Ok(())
+    }
+    /// Shuts the transport down after the last choreography.
+    ///
+    /// Tells every peer of the target that it will not send any more messages, waits until every peer has said the
+    /// same or `timeout` has passed, and closes the connections. Fails with `TransportError::Unconsumed` if
+    /// messages were received that no choreography consumed. Transports that do not connect to their peers only
+    /// check for unconsumed messages.
+    fn shutdown(&self, timeout: Duration) -> Result<(), TransportError> {
+        _ = timeout;
+        Ok(())
+    }
+    /// Returns the number of messages that each peer sent to the target in `session` that were never received.
+    ///
+    /// Peers without such messages are omitted. Transports that cannot inspect their queues return an empty map.
+    fn unconsumed(&self, session: SessionId) -> HashMap<&'static str, usize> {
+        _ = session;
+        HashMap::new()
+    }
 }
 
 /// Provides a method to perform end-point projection.
@@ -633,6 +1299,11 @@ pub struct Projector<
     transport: T,
     location_set: PhantomData<TransportLS>,
     index: PhantomData<Index>,
+    version: Option<String>,
+    end_barrier: bool,
+    context: Option<Arc<AnyContext>>,
+    #[cfg(feature = "tracing")]
+    runs: trace::Runs,
 }
 
 impl<
@@ -655,6 +1326,187 @@ where
             transport,
             location_set: PhantomData,
             index: PhantomData,
+            version: None,
+            end_barrier: false,
+            context: None,
+            #[cfg(feature = "tracing")]
+            runs: trace::Runs::default(),
+        }
+    }
+
+    /// Sets the context of the projection target.
+    ///
+    /// Local computations at the projection target can access the context with `Unwrapper::context`. Use the context
+    /// to provide resources such as database connections or random number generators, so that the choreography does
+    /// not need to know how each location obtains them.
+    pub fn with_context(mut self, context: impl Any + Send + Sync) -> Self {
+        self.context = Some(Arc::new(context));
+        self
+    }
+
+    /// Sets the version of the choreographies that the projector runs.
+    ///
+    /// Before running a choreography, the projector sends its `Fingerprint` to the other locations of the
+    /// choreography and checks theirs, so all of them must set the same version. `epp_and_run` returns
+    /// `ChoreographyError::FingerprintMismatch` without running the choreography if a peer runs something else.
+    ///
+    /// If a peer has no version, the projector receives a message of the choreography instead of a fingerprint and
+    /// returns `ChoreographyError::MissingFingerprint`, and the peer returns
+    /// `ChoreographyError::UnexpectedFingerprint` when it receives the fingerprint. A peer that never sends a message
+    /// to the projector is waited for until it does.
+    pub fn with_version(mut self, version: impl Into<String>) -> Self {
+        self.version = Some(version.into());
+        self
+    }
+
+    /// Exchanges the fingerprint of the choreography `C` with the peers of the projection target.
+    fn handshake<C, ChoreoLS: LocationSet>(
+        &self,
+        session: SessionId,
+    ) -> Result<(), ChoreographyError> {
+        let (Some(version), Some(peers)) = (&self.version, peers::<Target, ChoreoLS>()) else {
+            return Ok(());
+        };
+        let fingerprint = Fingerprint::new::<C, ChoreoLS>(version);
+        for peer in &peers {
+            self.transport.send(
+                session,
+                Target::name(),
+                peer,
+                &Tagged::outgoing(HANDSHAKE_STEP, &fingerprint),
+                None,
+            )?;
+        }
+        for peer in peers {
+            let received = self
+                .transport
+                .receive::<Tagged<Fingerprint>>(session, peer, Target::name(), None)?
+                .into_handshake()
+                .ok_or_else(|| ChoreographyError::MissingFingerprint(peer.to_string()))?;
+            if received != fingerprint {
+                return Err(ChoreographyError::FingerprintMismatch {
+                    peer: peer.to_string(),
+                    expected: Box::new(fingerprint),
+                    received: Box::new(received),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Enables the end-of-run barrier.
+    ///
+    /// After a location finishes a choreography, it tells the other locations of the choreography and waits until
+    /// they have finished too, so that no location exits while its peers are still sending to it. `epp_and_run`
+    /// fails with `TransportError::Unconsumed` if a peer sent messages that the choreography never received. All
+    /// locations must enable the barrier.
+    ///
+    /// If the choreography aborts or panics at a location, the location tells its peers instead of waiting for them,
+    /// and the peers fail with `ChoreographyError::PeerAborted` at the barrier. A peer that still waits for a message
+    /// from the location fails with `ChoreographyError::ProtocolMismatch`.
+    pub fn with_end_barrier(mut self) -> Self {
+        self.end_barrier = true;
+        self
+    }
+
+    /// Shuts the transport down after the last choreography.
+    ///
+    /// See `Transport::shutdown`.
+    pub fn shutdown(&self, timeout: Duration) -> Result<(), ChoreographyError> {
+        Ok(self.transport.shutdown(timeout)?)
+    }
+
+    /// Returns the number of messages that each location sent to the projection target in `session` that no
+    /// choreography received.
+    ///
+    /// Messages left over after a run usually mean that the locations disagree about the choreography, for example
+    /// because a location took another branch than its peers.
+    pub fn unconsumed(&self, session: SessionId) -> HashMap<&'static str, usize> {
+        self.transport.unconsumed(session)
+    }
+
+    /// Asserts in debug builds that every message sent to the projection target in `session` was received.
+    ///
+    /// Call it after the last run in `session`. Peers that already run the next choreography in the same session may
+    /// have sent messages that the next run will receive.
+    #[track_caller]
+    pub fn debug_assert_consumed(&self, session: SessionId) {
+        if cfg!(debug_assertions) {
+            let unconsumed = self.unconsumed(session);
+            assert!(
+                unconsumed.is_empty(),
+                "`{}` never received messages in session {}: {:?}",
+                Target::name(),
+                session,
+                unconsumed
+            );
+        }
+    }
+
+    /// Tells the peers of the projection target that the run of a choreography over `ChoreoLS` is finished, and
+    /// waits until they have finished too.
+    fn end_barrier<ChoreoLS: LocationSet>(
+        &self,
+        session: SessionId,
+        steps: &Steps,
+    ) -> Result<(), ChoreographyError> {
+        let Some(peers) = peers::<Target, ChoreoLS>().filter(|_| self.end_barrier) else {
+            return Ok(());
+        };
+        for peer in &peers {
+            let step = steps.next(Target::name(), peer);
+            self.transport.send(
+                session,
+                Target::name(),
+                peer,
+                &Tagged::outgoing(step, &EndOfRun::Finished),
+                None,
+            )?;
+        }
+        let mut result = Ok(());
+        for peer in peers {
+            // messages that arrive before the end of the run were sent by the peer but never received
+            let mut unconsumed = false;
+            let end = loop {
+                let message = self.transport.receive::<Tagged<EndOfRun>>(
+                    session,
+                    peer,
+                    Target::name(),
+                    None,
+                )?;
+                match message.into_value() {
+                    Some(end) => break end,
+                    None => unconsumed = true,
+                }
+            };
+            if result.is_ok() {
+                if let EndOfRun::Aborted = end {
+                    result = Err(ChoreographyError::PeerAborted(peer.to_string()));
+                } else if unconsumed {
+                    result = Err(TransportError::Unconsumed(peer.to_string()).into());
+                }
+            }
+        }
+        result
+    }
+
+    /// Tells the peers of the projection target that the run of a choreography over `ChoreoLS` aborted, so that
+    /// they do not wait for it at the end-of-run barrier.
+    ///
+    /// The run has already failed, so errors of the transport are ignored.
+    fn notify_aborted<ChoreoLS: LocationSet>(&self, session: SessionId, steps: &Steps) {
+        let Some(peers) = peers::<Target, ChoreoLS>().filter(|_| self.end_barrier) else {
+            return;
+        };
+        for peer in peers {
+            let step = steps.next(Target::name(), peer);
+            let _ = self.transport.send(
+                session,
+                Target::name(),
+                peer,
+                &Tagged::outgoing(step, &EndOfRun::Aborted),
+                None,
+            );
         }
     }
 
@@ -716,7 +1568,27 @@ where
         located.value.unwrap()
     }
 
+    /// Unwraps the facet of the projection target.
+    ///
+    /// Use this method to access the faceted value returned by a choreography.
+    pub fn unwrap_faceted<L: LocationSet, V, Index1, Index2>(&self, mut faceted: Faceted<V, L>) -> V
+    where
+        L: Subset<TransportLS, Index1>,
+        Target: Member<L, Index2>,
+    {
+        faceted.value.remove(Target::name()).unwrap()
+    }
+
     /// Performs end-point projection and runs a choreography.
+    ///
+    /// Returns an error if the transport fails to send or receive a message. The choreography is aborted at the
+    /// point of failure. If the projector has a version, it first checks that its peers run the same choreography.
+    ///
+    /// The choreography runs in session `0`. Use `epp_and_run_session` to run several choreographies concurrently
+    /// over the same transport.
+    ///
+    /// Returns `ChoreographyError::NotInChoreography` right away if the projection target is not a location of the
+    /// choreography.
     pub fn epp_and_run<
         'a,
         V,
@@ -727,7 +1599,32 @@ where
     >(
         &'a self,
         choreo: C,
-    ) -> V
+    ) -> Result<V, ChoreographyError>
+    where
+        ChoreoLS: Subset<TransportLS, IndexSet>,
+    {
+        self.epp_and_run_session(0, choreo)
+    }
+
+    /// Performs end-point projection and runs a choreography in `session`.
+    ///
+    /// Messages are tagged with `session`, so choreographies running in different sessions do not receive each
+    /// other's messages. All locations must run the choreography with the same session id.
+    ///
+    /// Returns `ChoreographyError::NotInChoreography` right away if the projection target is not in the location
+    /// set of the choreography. Such a location takes no part in the choreography, and the broadcasts of the
+    /// choreography do not reach it.
+    pub fn epp_and_run_session<
+        V,
+        // location set of the choreography to EPP
+        ChoreoLS: LocationSet,
+        C: Choreography<V, L = ChoreoLS>,
+        IndexSet,
+    >(
+        &self,
+        session: SessionId,
+        choreo: C,
+    ) -> Result<V, ChoreographyError>
     where
         ChoreoLS: Subset<TransportLS, IndexSet>,
     {
@@ -740,10 +1637,57 @@ where
         > {
             target: PhantomData<Target>,
             transport: &'a B,
+            session: SessionId,
+            steps: &'a Steps,
+            deadline: Option<Instant>,
+            // the locations that run the choreography, which receive its broadcasts
             locations: Vec<&'static str>,
+            context: Option<&'a Arc<AnyContext>>,
             marker: PhantomData<ChoreoLS>,
             projector_location_set: PhantomData<TransportLS>,
         }
+        impl<
+                'a,
+                ChoreoLS: LocationSet,
+                Target: ChoreographyLocation,
+                TransportLS: LocationSet,
+                B: Transport<TransportLS, Target>,
+            > EppOp<'a, ChoreoLS, Target, TransportLS, B>
+        {
+            /// Sends `value` from the target to `to` for the operator `op`.
+            fn send_to<V: Portable>(&self, op: &'static str, to: &'static str, value: &V) {
+                let step = self.steps.next(Target::name(), to);
+                self.transport
+                    .send(
+                        self.session,
+                        Target::name(),
+                        to,
+                        &Tagged::outgoing(step, value),
+                        self.deadline,
+                    )
+                    .unwrap_or_else(|e| abort(e));
+                trace::sent(op, Target::name(), to, self.transport.codec(), value);
+            }
+
+            /// Receives a value from `from` at the target for the operator `op`.
+            fn receive_from<V: Portable>(&self, op: &'static str, from: &'static str) -> V {
+                let step = self.steps.next(from, Target::name());
+                let value = self
+                    .transport
+                    .receive::<Tagged<_>>(self.session, from, Target::name(), self.deadline)
+                    .unwrap_or_else(|e| abort(e))
+                    .check(from, step)
+                    .unwrap_or_else(|e| abort(e));
+                trace::received(op, from, Target::name(), self.transport.codec(), &value);
+                value
+            }
+
+            /// Copies the facet of the target with the codec of the transport.
+            fn own_facet<V: Portable, S: LocationSet>(&self, data: &Faceted<V, S>) -> V {
+                copy_entry(self.transport.codec(), data.facet(Target::name()))
+                    .unwrap_or_else(|e| abort(e))
+            }
+        }
         impl<
                 'a,
                 ChoreoLS: LocationSet,
@@ -755,13 +1699,15 @@ where
             fn locally<V, L1: ChoreographyLocation, Index>(
                 &self,
                 _location: L1,
-                computation: impl Fn(Unwrapper<L1>) -> V,
+                computation: impl FnOnce(Unwrapper<L1>) -> V,
             ) -> MultiplyLocated<V, LocationSet!(L1)> {
                 if L1::name() == Target::name() {
                     let unwrapper = Unwrapper {
                         phantom: PhantomData,
+                        context: self.context.cloned(),
                     };
                     let value = computation(unwrapper);
+                    trace::computed("locally", L1::name());
                     MultiplyLocated::local(value)
                 } else {
                     MultiplyLocated::remote()
@@ -783,19 +1729,15 @@ where
                 data: &MultiplyLocated<V, L>,
             ) -> MultiplyLocated<V, LocationSet!(Receiver)> {
                 if Sender::name() == Target::name() && Sender::name() == Receiver::name() {
-                    let s = serde_json::to_string(data.value.as_ref().unwrap()).unwrap();
-                    return MultiplyLocated::local(serde_json::from_str(s.as_str()).unwrap());
+                    let value = roundtrip(self.transport.codec(), data.value.as_ref().unwrap())
+                        .unwrap_or_else(|e| abort(e));
+                    return MultiplyLocated::local(value);
                 }
                 if Sender::name() == Target::name() {
-                    self.transport.send(
-                        Sender::name(),
-                        Receiver::name(),
-                        data.value.as_ref().unwrap(),
-                    );
+                    self.send_to("comm", Receiver::name(), data.value.as_ref().unwrap());
                     MultiplyLocated::remote()
                 } else if Receiver::name() == Target::name() {
-                    let value = self.transport.receive(Sender::name(), Receiver::name());
-                    MultiplyLocated::local(value)
+                    MultiplyLocated::local(self.receive_from("comm", Sender::name()))
                 } else {
                     MultiplyLocated::remote()
                 }
@@ -813,18 +1755,15 @@ where
                 data: MultiplyLocated<V, L>,
             ) -> V {
                 if Sender::name() == Target::name() {
+                    let value = data.value.unwrap();
                     for dest in &self.locations {
                         if Target::name() != *dest {
-                            self.transport.send(
-                                &Target::name(),
-                                &dest,
-                                data.value.as_ref().unwrap(),
-                            );
+                            self.send_to("broadcast", dest, &value);
                         }
                     }
-                    return data.value.unwrap();
+                    value
                 } else {
-                    self.transport.receive(Sender::name(), &Target::name())
+                    self.receive_from("broadcast", Sender::name())
                 }
             }
 
@@ -841,30 +1780,52 @@ where
                 data: &MultiplyLocated<V, LocationSet!(Sender)>,
             ) -> MultiplyLocated<V, D> {
                 if Sender::name() == Target::name() {
+                    let value = data.value.as_ref().unwrap();
                     for dest in D::to_string_list() {
                         if Target::name() != dest {
-                            self.transport.send(
-                                &Target::name(),
-                                dest,
-                                data.value.as_ref().unwrap(),
-                            );
+                            self.send_to("multicast", dest, value);
                         }
                     }
-                    let s = serde_json::to_string(data.value.as_ref().unwrap()).unwrap();
-                    return MultiplyLocated::local(serde_json::from_str(s.as_str()).unwrap());
+                    let value =
+                        roundtrip(self.transport.codec(), value).unwrap_or_else(|e| abort(e));
+                    MultiplyLocated::local(value)
+                } else if D::to_string_list().contains(&Target::name()) {
+                    MultiplyLocated::local(self.receive_from("multicast", Sender::name()))
                 } else {
-                    let mut is_receiver = false;
-                    for dest in D::to_string_list() {
-                        if Target::name() == dest {
-                            is_receiver = true;
+                    MultiplyLocated::remote()
+                }
+            }
+
+            fn select<
+                Sender: ChoreographyLocation,
+                Label: BranchLabel,
+                D: LocationSet,
+                Index1,
+                Index2,
+            >(
+                &self,
+                _sender: Sender,
+                _receivers: D,
+                label: &MultiplyLocated<Label, LocationSet!(Sender)>,
+            ) -> MultiplyLocated<Label, D> {
+                let receivers = D::to_string_list();
+                let index = if Sender::name() == Target::name() {
+                    let index = label.value.as_ref().unwrap().index();
+                    for dest in &receivers {
+                        if Target::name() != *dest {
+                            self.send_to("select", dest, &index);
                         }
                     }
-                    if is_receiver {
-                        let v = self.transport.receive(Sender::name(), Target::name());
-                        return MultiplyLocated::local(v);
-                    } else {
-                        return MultiplyLocated::remote();
-                    }
+                    index
+                } else if receivers.contains(&Target::name()) {
+                    self.receive_from("select", Sender::name())
+                } else {
+                    return MultiplyLocated::remote();
+                };
+                if receivers.contains(&Target::name()) {
+                    MultiplyLocated::local(label_from_index(index))
+                } else {
+                    MultiplyLocated::remote()
                 }
             }
 
@@ -883,7 +1844,12 @@ where
                 let op: EppOp<'a, M, Target, TransportLS, B> = EppOp {
                     target: PhantomData::<Target>,
                     transport: &self.transport,
-                    locations: self.transport.locations(),
+                    session: self.session,
+                    steps: self.steps,
+                    deadline: self.deadline,
+                    // every location of the caller runs the called choreography
+                    locations: self.locations.clone(),
+                    context: self.context,
                     marker: PhantomData::<M>,
                     projector_location_set: PhantomData::<TransportLS>,
                 };
@@ -895,13 +1861,18 @@ where
                 choreo: C,
             ) -> MultiplyLocated<R, S> {
                 let locs_vec = S::to_string_list();
+                trace::entered("conclave", &locs_vec, locs_vec.contains(&Target::name()));
 
                 for location in &locs_vec {
                     if *location == Target::name().to_string() {
                         let op = EppOp {
                             target: PhantomData::<Target>,
                             transport: self.transport,
+                            session: self.session,
+                            steps: self.steps,
+                            deadline: self.deadline,
                             locations: locs_vec,
+                            context: self.context,
                             marker: PhantomData::<S>,
                             projector_location_set: PhantomData::<TransportLS>,
                         };
@@ -954,11 +1925,16 @@ where
                 let op: EppOp<ChoreoLS, Target, TransportLS, B> = EppOp {
                     target: PhantomData::<Target>,
                     transport: self.transport,
-                    locations: self.transport.locations(),
+                    session: self.session,
+                    steps: self.steps,
+                    deadline: self.deadline,
+                    locations: self.locations.clone(),
+                    context: self.context,
                     marker: PhantomData::<ChoreoLS>,
                     projector_location_set: PhantomData::<TransportLS>,
                 };
                 let values = HashMap::new();
+                trace::entered("fanout", &QS::to_string_list(), true);
 
                 struct Loop<
                     'a,
@@ -1050,10 +2026,15 @@ where
                 let op: EppOp<ChoreoLS, Target, TransportLS, B> = EppOp {
                     target: PhantomData::<Target>,
                     transport: self.transport,
-                    locations: self.transport.locations(),
+                    session: self.session,
+                    steps: self.steps,
+                    deadline: self.deadline,
+                    locations: self.locations.clone(),
+                    context: self.context,
                     marker: PhantomData::<ChoreoLS>,
                     projector_location_set: PhantomData::<TransportLS>,
                 };
+                trace::entered("fanin", &QS::to_string_list(), true);
 
                 struct Loop<
                     'a,
@@ -1141,21 +2122,161 @@ where
                     phantom: PhantomData,
                 })
             }
+
+            fn scatter<
+                Sender: ChoreographyLocation,
+                V: Portable,
+                QS: LocationSet,
+                Index1,
+                Index2,
+            >(
+                &self,
+                _sender: Sender,
+                _locations: QS,
+                data: &MultiplyLocated<Quire<V, QS>, LocationSet!(Sender)>,
+            ) -> Faceted<V, QS> {
+                let mut value = HashMap::new();
+                if Sender::name() == Target::name() {
+                    let quire = data.value.as_ref().unwrap();
+                    for dest in QS::to_string_list() {
+                        if dest == Target::name() {
+                            let entry = copy_entry(self.transport.codec(), quire.entry(dest))
+                                .unwrap_or_else(|e| abort(e));
+                            value.insert(dest.to_string(), entry);
+                        } else {
+                            let entry = quire.entry(dest).unwrap_or_else(|e| abort(e));
+                            self.send_to("scatter", dest, entry);
+                        }
+                    }
+                } else if QS::to_string_list().contains(&Target::name()) {
+                    let entry = self.receive_from("scatter", Sender::name());
+                    value.insert(Target::name().to_string(), entry);
+                }
+                Faceted {
+                    value,
+                    phantom: PhantomData,
+                }
+            }
+
+            fn gather<Root: ChoreographyLocation, V: Portable, QS: LocationSet, Index1, Index2>(
+                &self,
+                _root: Root,
+                data: &Faceted<V, QS>,
+            ) -> MultiplyLocated<Quire<V, QS>, LocationSet!(Root)> {
+                let locations = QS::to_string_list();
+                if Root::name() == Target::name() {
+                    let mut value = HashMap::new();
+                    for src in locations {
+                        let facet = if src == Target::name() {
+                            self.own_facet(data)
+                        } else {
+                            self.receive_from("gather", src)
+                        };
+                        value.insert(src.to_string(), facet);
+                    }
+                    MultiplyLocated::local(Quire {
+                        value,
+                        phantom: PhantomData,
+                    })
+                } else {
+                    if locations.contains(&Target::name()) {
+                        let facet = data.facet(Target::name()).unwrap_or_else(|e| abort(e));
+                        self.send_to("gather", Root::name(), facet);
+                    }
+                    MultiplyLocated::remote()
+                }
+            }
+
+            fn allreduce<V: Portable, QS: LocationSet, Index>(
+                &self,
+                data: &Faceted<V, QS>,
+                f: impl Fn(V, V) -> V,
+            ) -> MultiplyLocated<V, QS> {
+                assert_census::<QS>("allreduce");
+                let locations = listed::<QS>();
+                if !locations.contains(&Target::name()) {
+                    return MultiplyLocated::remote();
+                }
+                // the first location combines the facets and sends the result to the others
+                let root = locations[0];
+                if root == Target::name() {
+                    let value = locations
+                        .iter()
+                        .map(|&src| {
+                            if src == Target::name() {
+                                self.own_facet(data)
+                            } else {
+                                self.receive_from("allreduce", src)
+                            }
+                        })
+                        .reduce(f)
+                        .unwrap();
+                    for &dest in &locations[1..] {
+                        self.send_to("allreduce", dest, &value);
+                    }
+                    MultiplyLocated::local(value)
+                } else {
+                    let facet = data.facet(Target::name()).unwrap_or_else(|e| abort(e));
+                    self.send_to("allreduce", root, facet);
+                    MultiplyLocated::local(self.receive_from("allreduce", root))
+                }
+            }
+
+            fn with_deadline<R>(&self, timeout: Duration, chor: impl FnOnce(&Self) -> R) -> R {
+                let deadline = match (Instant::now().checked_add(timeout), self.deadline) {
+                    (Some(deadline), Some(outer)) => Some(deadline.min(outer)),
+                    (deadline, outer) => deadline.or(outer),
+                };
+                let op = EppOp {
+                    target: PhantomData::<Target>,
+                    transport: self.transport,
+                    session: self.session,
+                    steps: self.steps,
+                    deadline,
+                    locations: self.locations.clone(),
+                    context: self.context,
+                    marker: PhantomData::<ChoreoLS>,
+                    projector_location_set: PhantomData::<TransportLS>,
+                };
+                chor(&op)
+            }
         }
-        let op: EppOp<'a, ChoreoLS, Target, TransportLS, B> = EppOp {
+        if !ChoreoLS::to_string_list().contains(&Target::name()) {
+            return Err(ChoreographyError::NotInChoreography(
+                Target::name().to_string(),
+            ));
+        }
+        self.handshake::<C, ChoreoLS>(session)?;
+        let steps = Steps::default();
+        let op: EppOp<'_, ChoreoLS, Target, TransportLS, B> = EppOp {
             target: PhantomData::<Target>,
             transport: &self.transport,
-            locations: self.transport.locations(),
+            session,
+            steps: &steps,
+            deadline: None,
+            locations: ChoreoLS::to_string_list(),
+            context: self.context.as_ref(),
             marker: PhantomData::<ChoreoLS>,
             projector_location_set: PhantomData::<TransportLS>,
         };
-        choreo.run(&op)
+        #[cfg(feature = "tracing")]
+        let _span = trace::run_span(Target::name(), session, &self.runs).entered();
+        let value = match panic::catch_unwind(AssertUnwindSafe(|| choreo.run(&op))) {
+            Ok(value) => value,
+            Err(payload) => {
+                self.notify_aborted::<ChoreoLS>(session, &steps);
+                return Err(into_abort(payload));
+            }
+        };
+        self.end_barrier::<ChoreoLS>(session, &steps)?;
+        Ok(value)
     }
 }
 
 /// Provides a method to run a choreography without end-point projection.
 pub struct Runner<RunnerLS: LocationSet> {
     marker: PhantomData<RunnerLS>,
+    contexts: HashMap<&'static str, Arc<AnyContext>>,
 }
 
 impl<RunnerLS: LocationSet> Runner<RunnerLS> {
@@ -1163,9 +2284,26 @@ impl<RunnerLS: LocationSet> Runner<RunnerLS> {
     pub fn new() -> Self {
         Runner {
             marker: PhantomData::<RunnerLS>,
+            contexts: HashMap::new(),
         }
     }
 
+    /// Sets the context of `location`.
+    ///
+    /// See `Projector::with_context`. Runner runs the choreography at all locations, so each location can be given
+    /// its own context.
+    pub fn with_context<L1: ChoreographyLocation, Index>(
+        mut self,
+        _location: L1,
+        context: impl Any + Send + Sync,
+    ) -> Self
+    where
+        L1: Member<RunnerLS, Index>,
+    {
+        self.contexts.insert(L1::name(), Arc::new(context));
+        self
+    }
+
     /// Constructs a located value.
     ///
     /// To execute a choreography with a runner, you must provide located values at all locations
@@ -1191,24 +2329,38 @@ impl<RunnerLS: LocationSet> Runner<RunnerLS> {
     /// Unwraps a located value
     ///
     /// Runner can unwrap a located value at any location
-    pub fn unwrap<V, L1: ChoreographyLocation>(&self, located: Located<V, L1>) -> V {
+    pub fn unwrap<V, L: LocationSet>(&self, located: MultiplyLocated<V, L>) -> V {
         located.value.unwrap()
     }
 
+    /// Unwraps a faceted value
+    ///
+    /// Runner holds the facets of all locations, so it returns them as a quire
+    pub fn unwrap_faceted<V, L: LocationSet>(&self, faceted: Faceted<V, L>) -> Quire<V, L> {
+        Quire {
+            value: faceted.value,
+            phantom: PhantomData,
+        }
+    }
+
     /// Runs a choreography directly
     pub fn run<'a, V, C: Choreography<V, L = RunnerLS>>(&'a self, choreo: C) -> V {
         // Note: Technically, the location set of the choreography can be a subset of `RunnerLS`.
         // However, by using the same type, the compiler can infer `RunnerLS` for given choreography.
 
-        struct RunOp<L>(PhantomData<L>);
-        impl<L: LocationSet> ChoreoOp<L> for RunOp<L> {
+        struct RunOp<'a, L> {
+            phantom: PhantomData<L>,
+            contexts: &'a HashMap<&'static str, Arc<AnyContext>>,
+        }
+        impl<L: LocationSet> ChoreoOp<L> for RunOp<'_, L> {
             fn locally<V, L1: ChoreographyLocation, Index>(
                 &self,
                 _location: L1,
-                computation: impl Fn(Unwrapper<L1>) -> V,
+                computation: impl FnOnce(Unwrapper<L1>) -> V,
             ) -> MultiplyLocated<V, LocationSet!(L1)> {
                 let unwrapper = Unwrapper {
                     phantom: PhantomData,
+                    context: self.contexts.get(L1::name()).cloned(),
                 };
                 let value = computation(unwrapper);
                 MultiplyLocated::local(value)
@@ -1230,8 +2382,7 @@ impl<RunnerLS: LocationSet> Runner<RunnerLS> {
             ) -> MultiplyLocated<V, LocationSet!(Receiver)> {
                 // clone the value by encoding and decoding it. Requiring `Clone` could improve the performance but is not necessary.
                 // Also, this is closer to what happens to the value with end-point projection.
-                let s = serde_json::to_string(data.value.as_ref().unwrap()).unwrap();
-                MultiplyLocated::local(serde_json::from_str(s.as_str()).unwrap())
+                MultiplyLocated::local(roundtrip(&Json, data.value.as_ref().unwrap()).unwrap())
             }
 
             fn broadcast<
@@ -1260,8 +2411,25 @@ impl<RunnerLS: LocationSet> Runner<RunnerLS> {
                 _destination: D,
                 data: &MultiplyLocated<V, LocationSet!(Sender)>,
             ) -> MultiplyLocated<V, D> {
-                let s = serde_json::to_string(data.value.as_ref().unwrap()).unwrap();
-                return MultiplyLocated::local(serde_json::from_str(s.as_str()).unwrap());
+                return MultiplyLocated::local(
+                    roundtrip(&Json, data.value.as_ref().unwrap()).unwrap(),
+                );
+            }
+
+            fn select<
+                Sender: ChoreographyLocation,
+                Label: BranchLabel,
+                D: LocationSet,
+                Index1,
+                Index2,
+            >(
+                &self,
+                _sender: Sender,
+                _receivers: D,
+                label: &MultiplyLocated<Label, LocationSet!(Sender)>,
+            ) -> MultiplyLocated<Label, D> {
+                let index = label.value.as_ref().unwrap().index();
+                MultiplyLocated::local(label_from_index(index))
             }
 
             fn naked<S: LocationSet, V, Index>(&self, data: MultiplyLocated<V, S>) -> V {
@@ -1276,7 +2444,10 @@ impl<RunnerLS: LocationSet> Runner<RunnerLS> {
             where
                 M: LocationSet + Subset<L, Index>,
             {
-                let op: RunOp<M> = RunOp(PhantomData);
+                let op: RunOp<M> = RunOp {
+                    phantom: PhantomData,
+                    contexts: self.contexts,
+                };
                 choreo.run(&op)
             }
 
@@ -1284,7 +2455,10 @@ impl<RunnerLS: LocationSet> Runner<RunnerLS> {
                 &self,
                 choreo: C,
             ) -> MultiplyLocated<R, S> {
-                let op = RunOp::<S>(PhantomData);
+                let op = RunOp::<S> {
+                    phantom: PhantomData,
+                    contexts: self.contexts,
+                };
                 MultiplyLocated::local(choreo.run(&op))
             }
 
@@ -1325,10 +2499,14 @@ impl<RunnerLS: LocationSet> Runner<RunnerLS> {
                 QS: Subset<L, QSSubsetL>,
                 QS: LocationSetFoldable<L, QS, QSFoldable>,
             {
-                let op = RunOp::<L>(PhantomData);
+                let op = RunOp::<L> {
+                    phantom: PhantomData,
+                    contexts: self.contexts,
+                };
                 let values = HashMap::new();
 
                 struct Loop<
+                    'a,
                     ChoreoLS: LocationSet,
                     V,
                     QSSubsetL,
@@ -1336,18 +2514,19 @@ impl<RunnerLS: LocationSet> Runner<RunnerLS> {
                     FOC: FanOutChoreography<V, L = ChoreoLS, QS = QS>,
                 > {
                     phantom: PhantomData<(V, QSSubsetL, QS)>,
-                    op: RunOp<ChoreoLS>,
+                    op: RunOp<'a, ChoreoLS>,
                     foc: FOC,
                 }
 
                 impl<
+                        'a,
                         ChoreoLS: LocationSet,
                         V,
                         QSSubsetL,
                         QS: LocationSet + Subset<ChoreoLS, QSSubsetL>,
                         FOC: FanOutChoreography<V, L = ChoreoLS, QS = QS>,
                     > LocationSetFolder<HashMap<String, V>>
-                    for Loop<ChoreoLS, V, QSSubsetL, QS, FOC>
+                    for Loop<'a, ChoreoLS, V, QSSubsetL, QS, FOC>
                 {
                     type L = ChoreoLS;
                     type QS = QS;
@@ -1408,8 +2587,12 @@ impl<RunnerLS: LocationSet> Runner<RunnerLS> {
                 RS: Subset<L, RSSubsetL>,
                 QS: LocationSetFoldable<L, QS, QSFoldable>,
             {
-                let op: RunOp<L> = RunOp(PhantomData);
+                let op: RunOp<L> = RunOp {
+                    phantom: PhantomData,
+                    contexts: self.contexts,
+                };
                 struct Loop<
+                    'a,
                     ChoreoLS: LocationSet,
                     V,
                     QSSubsetL,
@@ -1419,10 +2602,11 @@ impl<RunnerLS: LocationSet> Runner<RunnerLS> {
                     FIC: FanInChoreography<V, L = ChoreoLS, QS = QS, RS = RS>,
                 > {
                     phantom: PhantomData<(V, QSSubsetL, QS, RSSubsetL, RS)>,
-                    op: RunOp<ChoreoLS>,
+                    op: RunOp<'a, ChoreoLS>,
                     fic: FIC,
                 }
                 impl<
+                        'a,
                         ChoreoLS: LocationSet,
                         V,
                         QSSubsetL,
@@ -1431,7 +2615,7 @@ impl<RunnerLS: LocationSet> Runner<RunnerLS> {
                         RS: LocationSet + Subset<ChoreoLS, RSSubsetL>,
                         FIC: FanInChoreography<V, L = ChoreoLS, QS = QS, RS = RS>,
                     > LocationSetFolder<HashMap<String, V>>
-                    for Loop<ChoreoLS, V, QSSubsetL, QS, RSSubsetL, RS, FIC>
+                    for Loop<'a, ChoreoLS, V, QSSubsetL, QS, RSSubsetL, RS, FIC>
                 {
                     type L = ChoreoLS;
                     type QS = QS;
@@ -1473,11 +2657,83 @@ impl<RunnerLS: LocationSet> Runner<RunnerLS> {
                     phantom: PhantomData,
                 })
             }
+
+            fn scatter<
+                Sender: ChoreographyLocation,
+                V: Portable,
+                QS: LocationSet,
+                Index1,
+                Index2,
+            >(
+                &self,
+                _sender: Sender,
+                _locations: QS,
+                data: &MultiplyLocated<Quire<V, QS>, LocationSet!(Sender)>,
+            ) -> Faceted<V, QS> {
+                let quire = data.value.as_ref().unwrap();
+                let value = QS::to_string_list()
+                    .into_iter()
+                    .map(|location| {
+                        let entry =
+                            copy_entry(&Json, quire.entry(location)).unwrap_or_else(|e| fail(e));
+                        (location.to_string(), entry)
+                    })
+                    .collect();
+                Faceted {
+                    value,
+                    phantom: PhantomData,
+                }
+            }
+
+            fn gather<Root: ChoreographyLocation, V: Portable, QS: LocationSet, Index1, Index2>(
+                &self,
+                _root: Root,
+                data: &Faceted<V, QS>,
+            ) -> MultiplyLocated<Quire<V, QS>, LocationSet!(Root)> {
+                let value = QS::to_string_list()
+                    .into_iter()
+                    .map(|location| {
+                        let facet =
+                            copy_entry(&Json, data.facet(location)).unwrap_or_else(|e| fail(e));
+                        (location.to_string(), facet)
+                    })
+                    .collect();
+                MultiplyLocated::local(Quire {
+                    value,
+                    phantom: PhantomData,
+                })
+            }
+
+            fn allreduce<V: Portable, QS: LocationSet, Index>(
+                &self,
+                data: &Faceted<V, QS>,
+                f: impl Fn(V, V) -> V,
+            ) -> MultiplyLocated<V, QS> {
+                assert_census::<QS>("allreduce");
+                let value = listed::<QS>()
+                    .into_iter()
+                    .map(|location| {
+                        copy_entry(&Json, data.facet(location)).unwrap_or_else(|e| fail(e))
+                    })
+                    .reduce(f);
+                MultiplyLocated {
+                    value,
+                    phantom: PhantomData,
+                }
+            }
+
+            fn with_deadline<R>(&self, _timeout: Duration, chor: impl FnOnce(&Self) -> R) -> R {
+                // nothing is received, so there is nothing to time out
+                chor(self)
+            }
         }
-        let op: RunOp<RunnerLS> = RunOp(PhantomData);
+        let op: RunOp<RunnerLS> = RunOp {
+            phantom: PhantomData,
+            contexts: &self.contexts,
+        };
         choreo.run(&op)
     }
 }
 
 extern crate chorus_derive;
-pub use chorus_derive::ChoreographyLocation;
+pub use chorus_derive::{BranchLabel, ChoreographyLocation};
@@ -0,0 +1,1219 @@
+//! Asynchronous choreography constructs.
+//!
+//! The traits in this module mirror `ChoreoOp`, `Choreography`, and `Transport`, except that operators that
+//! communicate return futures instead of blocking the thread. This allows many choreography instances to share a
+//! small executor.
+//!
+//! The futures are not `Send`. Run them on a single-threaded executor, such as tokio's `LocalSet` or a
+//! `current_thread` runtime.
+
+use std::{
+    any::Any,
+    collections::HashMap,
+    future::Future,
+    marker::PhantomData,
+    panic::{self, AssertUnwindSafe},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use super::{
+    abort, assert_census, copy_entry, into_abort, label_from_index, listed, peers, AnyContext,
+    BranchLabel, ChoreographyError, ChoreographyLocation, EndOfRun, Faceted, Fingerprint, Located,
+    LocationSet, LocationSetFoldable, LocationSetFolder, Member, MultiplyLocated, Portable, Quire,
+    SessionId, Steps, Subset, Tagged, TransportError, Unwrapper, HANDSHAKE_STEP,
+};
+use crate::codec::{roundtrip, Codec};
+use crate::trace;
+
+/// Provides methods to send and receive messages asynchronously.
+///
+/// This is the asynchronous counterpart of `Transport`. Implement this trait to define a custom asynchronous
+/// transport.
+///
+/// The type parameter `L` is the location set that the transport is operating on.
+///
+/// The type parameter `TargetLocation` is the target `ChoreographyLocation`.
+#[allow(async_fn_in_trait)]
+pub trait AsyncTransport<L: LocationSet, TargetLocation: ChoreographyLocation> {
+    /// The codec used to encode messages.
+    type Codec: Codec;
+    /// Returns the codec used to encode messages.
+    ///
+    /// See `Transport::codec`.
+    fn codec(&self) -> &Self::Codec;
+    /// Returns a list of locations.
+    fn locations(&self) -> Vec<&'static str>;
+    /// Sends a message from `from` to `to` in `session`.
+    async fn send<V: Portable>(
+        &self,
+        session: SessionId,
+        from: &str,
+        to: &str,
+        data: &V,
+    ) -> Result<(), TransportError>;
+    /// Receives a message from `from` to `at` in `session`.
+    async fn receive<V: Portable>(
+        &self,
+        session: SessionId,
+        from: &str,
+        at: &str,
+    ) -> Result<V, TransportError>;
+}
+
+/// Provides asynchronous choreographic operations.
+///
+/// This is the asynchronous counterpart of `ChoreoOp`. Operators that communicate between locations return futures.
+#[allow(async_fn_in_trait)]
+pub trait AsyncChoreoOp<ChoreoLS: LocationSet> {
+    /// Performs a computation at the specified location.
+    ///
+    /// See `ChoreoOp::locally`.
+    fn locally<V, L1: ChoreographyLocation, Index>(
+        &self,
+        location: L1,
+        computation: impl FnOnce(Unwrapper<L1>) -> V,
+    ) -> MultiplyLocated<V, LocationSet!(L1)>
+    where
+        L1: Member<ChoreoLS, Index>;
+
+    /// Performs a communication between two locations.
+    ///
+    /// See `ChoreoOp::comm`.
+    async fn comm<
+        L: LocationSet,
+        Sender: ChoreographyLocation,
+        Receiver: ChoreographyLocation,
+        V: Portable,
+        Index1,
+        Index2,
+        Index3,
+    >(
+        &self,
+        sender: Sender,
+        receiver: Receiver,
+        data: &MultiplyLocated<V, L>,
+    ) -> MultiplyLocated<V, LocationSet!(Receiver)>
+    where
+        L: Subset<ChoreoLS, Index1>,
+        Sender: Member<ChoreoLS, Index2>,
+        Receiver: Member<ChoreoLS, Index3>;
+
+    /// Performs a broadcast from a location to all other locations.
+    ///
+    /// See `ChoreoOp::broadcast`.
+    async fn broadcast<L: LocationSet, Sender: ChoreographyLocation, V: Portable, Index1, Index2>(
+        &self,
+        sender: Sender,
+        data: MultiplyLocated<V, L>,
+    ) -> V
+    where
+        L: Subset<ChoreoLS, Index1>,
+        Sender: Member<ChoreoLS, Index2>;
+
+    /// Performs a multicast from a location to a set of locations.
+    ///
+    /// See `ChoreoOp::multicast`.
+    async fn multicast<Sender: ChoreographyLocation, V: Portable, D: LocationSet, Index1, Index2>(
+        &self,
+        src: Sender,
+        destination: D,
+        data: &MultiplyLocated<V, LocationSet!(Sender)>,
+    ) -> MultiplyLocated<V, D>
+    where
+        Sender: Member<ChoreoLS, Index1>,
+        D: Subset<ChoreoLS, Index2>;
+
+    /// Sends a branch label from a location to a set of locations.
+    ///
+    /// See `ChoreoOp::select`.
+    async fn select<
+        Sender: ChoreographyLocation,
+        Label: BranchLabel,
+        D: LocationSet,
+        Index1,
+        Index2,
+    >(
+        &self,
+        sender: Sender,
+        receivers: D,
+        label: &MultiplyLocated<Label, LocationSet!(Sender)>,
+    ) -> MultiplyLocated<Label, D>
+    where
+        Sender: Member<ChoreoLS, Index1>,
+        D: Subset<ChoreoLS, Index2>;
+
+    /// Obtains a normal value from a value located at all locations in the census
+    fn naked<S: LocationSet, V, Index>(&self, data: MultiplyLocated<V, S>) -> V
+    where
+        ChoreoLS: Subset<S, Index>;
+
+    /// Wraps a value into a located value at the current census
+    fn unnaked<V>(&self, data: V) -> MultiplyLocated<V, ChoreoLS>;
+
+    /// Calls a choreography.
+    async fn call<R, M, Index, C: AsyncChoreography<R, L = M>>(&self, choreo: C) -> R
+    where
+        M: LocationSet + Subset<ChoreoLS, Index>;
+
+    /// Calls a choreography on a subset of locations.
+    async fn conclave<R, S: LocationSet, C: AsyncChoreography<R, L = S>, Index>(
+        &self,
+        choreo: C,
+    ) -> MultiplyLocated<R, S>
+    where
+        S: Subset<ChoreoLS, Index>;
+
+    /// Runs the branch chosen by `label` at the locations that know the label.
+    ///
+    /// See `ChoreoOp::branch`.
+    async fn branch<R, S: LocationSet, Label, C: AsyncChoreography<R, L = S>, Index>(
+        &self,
+        label: &MultiplyLocated<Label, S>,
+        choreo: impl FnOnce(&Label) -> C,
+    ) -> MultiplyLocated<R, S>
+    where
+        S: Subset<ChoreoLS, Index>,
+    {
+        match &label.value {
+            Some(label) => self.conclave(choreo(label)).await,
+            None => MultiplyLocated::remote(),
+        }
+    }
+
+    /// Performs parallel computation.
+    fn parallel<V, S: LocationSet, Index>(
+        &self,
+        locations: S,
+        computation: impl Fn() -> V,
+    ) -> Faceted<V, S>
+    where
+        S: Subset<ChoreoLS, Index>;
+
+    /// Performs fanout computation.
+    async fn fanout<
+        // return value type
+        V,
+        // locations looping over
+        QS: LocationSet,
+        // FanOut Choreography over L iterating over QS returning V
+        FOC: AsyncFanOutChoreography<V, L = ChoreoLS, QS = QS>,
+        // Proof that QS is a subset of L
+        QSSubsetL,
+        QSFoldable,
+    >(
+        &self,
+        locations: QS,
+        c: FOC,
+    ) -> Faceted<V, QS>
+    where
+        QS: Subset<ChoreoLS, QSSubsetL>,
+        QS: LocationSetFoldable<ChoreoLS, QS, QSFoldable>;
+
+    /// Performs fanin computation.
+    async fn fanin<
+        // return value type
+        V,
+        // locations looping over
+        QS: LocationSet,
+        // Recipient locations
+        RS: LocationSet,
+        // FanIn Choreography over L iterating over QS returning V
+        FIC: AsyncFanInChoreography<V, L = ChoreoLS, QS = QS, RS = RS>,
+        // Proof that QS is a subset of L
+        QSSubsetL,
+        RSSubsetL,
+        QSFoldable,
+    >(
+        &self,
+        locations: QS,
+        c: FIC,
+    ) -> MultiplyLocated<Quire<V, QS>, RS>
+    where
+        QS: Subset<ChoreoLS, QSSubsetL>,
+        RS: Subset<ChoreoLS, RSSubsetL>,
+        QS: LocationSetFoldable<ChoreoLS, QS, QSFoldable>;
+
+    /// Sends each entry of a `Quire` at `sender` to its location.
+    ///
+    /// See `ChoreoOp::scatter`.
+    async fn scatter<Sender: ChoreographyLocation, V: Portable, QS: LocationSet, Index1, Index2>(
+        &self,
+        sender: Sender,
+        locations: QS,
+        data: &MultiplyLocated<Quire<V, QS>, LocationSet!(Sender)>,
+    ) -> Faceted<V, QS>
+    where
+        Sender: Member<ChoreoLS, Index1>,
+        QS: Subset<ChoreoLS, Index2>;
+
+    /// Collects the facets of a `Faceted` value into a `Quire` at `root`.
+    ///
+    /// See `ChoreoOp::gather`.
+    async fn gather<Root: ChoreographyLocation, V: Portable, QS: LocationSet, Index1, Index2>(
+        &self,
+        root: Root,
+        data: &Faceted<V, QS>,
+    ) -> MultiplyLocated<Quire<V, QS>, LocationSet!(Root)>
+    where
+        Root: Member<ChoreoLS, Index1>,
+        QS: Subset<ChoreoLS, Index2>;
+
+    /// Combines the facets of a `Faceted` value at `root`.
+    ///
+    /// See `ChoreoOp::reduce`.
+    async fn reduce<Root: ChoreographyLocation, V: Portable, QS: LocationSet, Index1, Index2>(
+        &self,
+        root: Root,
+        data: &Faceted<V, QS>,
+        f: impl Fn(V, V) -> V,
+    ) -> MultiplyLocated<V, LocationSet!(Root)>
+    where
+        Root: Member<ChoreoLS, Index1>,
+        QS: Subset<ChoreoLS, Index2>,
+    {
+        assert_census::<QS>("reduce");
+        let quire = self.gather(root, data).await;
+        MultiplyLocated {
+            value: quire.value.map(|quire| quire.reduce(f)),
+            phantom: PhantomData,
+        }
+    }
+
+    /// Combines the facets of a `Faceted` value and shares the result with every location of `QS`.
+    ///
+    /// See `ChoreoOp::allreduce`.
+    async fn allreduce<V: Portable, QS: LocationSet, Index>(
+        &self,
+        data: &Faceted<V, QS>,
+        f: impl Fn(V, V) -> V,
+    ) -> MultiplyLocated<V, QS>
+    where
+        QS: Subset<ChoreoLS, Index>;
+}
+
+/// Special asynchronous choreography for fanout
+#[allow(async_fn_in_trait)]
+pub trait AsyncFanOutChoreography<V> {
+    /// All locations involved in the choreography
+    type L: LocationSet;
+    /// Locations looping over
+    type QS: LocationSet;
+    /// The body of the choreography defined in terms of the operators provided by `AsyncChoreoOp`
+    ///
+    /// `Q` is the location that the loop variable and is guaranteed to be a member of `L` and `QS`.
+    async fn run<Q: ChoreographyLocation, QSSubsetL, QMemberL, QMemberQS>(
+        &self,
+        op: &impl AsyncChoreoOp<Self::L>,
+    ) -> Located<V, Q>
+    where
+        Self::QS: Subset<Self::L, QSSubsetL>,
+        Q: Member<Self::L, QMemberL>,
+        Q: Member<Self::QS, QMemberQS>;
+}
+
+/// Special asynchronous choreography for fanin
+#[allow(async_fn_in_trait)]
+pub trait AsyncFanInChoreography<V> {
+    /// All locations involved in the choreography
+    type L: LocationSet;
+    /// Locations looping over
+    type QS: LocationSet;
+    /// Recipient locations
+    type RS: LocationSet;
+    /// The body of the choreography defined in terms of the operators provided by `AsyncChoreoOp`
+    ///
+    /// `Q` is the location that the loop variable and is guaranteed to be a member of `L` and `QS`.
+    async fn run<Q: ChoreographyLocation, QSSubsetL, RSSubsetL, QMemberL, QMemberQS>(
+        &self,
+        op: &impl AsyncChoreoOp<Self::L>,
+    ) -> MultiplyLocated<V, Self::RS>
+    where
+        Self::QS: Subset<Self::L, QSSubsetL>,
+        Self::RS: Subset<Self::L, RSSubsetL>,
+        Q: Member<Self::L, QMemberL>,
+        Q: Member<Self::QS, QMemberQS>;
+}
+
+/// Represents an asynchronous choreography.
+///
+/// This is the asynchronous counterpart of `Choreography`. The type parameter `R` is the return type of the
+/// choreography.
+#[allow(async_fn_in_trait)]
+pub trait AsyncChoreography<R = ()> {
+    /// Locations
+    type L: LocationSet;
+    /// A method that executes a choreography.
+    ///
+    /// The method takes an implementation of `AsyncChoreoOp`. Inside the method, you can use the operators provided
+    /// by `AsyncChoreoOp` to define a choreography.
+    async fn run(self, op: &impl AsyncChoreoOp<Self::L>) -> R;
+}
+
+/// Provides a method to perform end-point projection of asynchronous choreographies.
+///
+/// This is the asynchronous counterpart of `Projector`.
+pub struct AsyncProjector<
+    TransportLS: LocationSet,
+    Target: ChoreographyLocation,
+    T: AsyncTransport<TransportLS, Target>,
+    Index,
+> where
+    Target: Member<TransportLS, Index>,
+{
+    target: PhantomData<Target>,
+    transport: T,
+    location_set: PhantomData<TransportLS>,
+    index: PhantomData<Index>,
+    version: Option<String>,
+    end_barrier: bool,
+    context: Option<Arc<AnyContext>>,
+    #[cfg(feature = "tracing")]
+    runs: trace::Runs,
+}
+
+impl<
+        TransportLS: LocationSet,
+        Target: ChoreographyLocation,
+        B: AsyncTransport<TransportLS, Target>,
+        Index,
+    > AsyncProjector<TransportLS, Target, B, Index>
+where
+    Target: Member<TransportLS, Index>,
+{
+    /// Constructs an `AsyncProjector` struct.
+    ///
+    /// - `target` is the projection target of the choreography.
+    /// - `transport` is an implementation of `AsyncTransport`.
+    pub fn new(target: Target, transport: B) -> Self {
+        _ = target;
+        AsyncProjector {
+            target: PhantomData,
+            transport,
+            location_set: PhantomData,
+            index: PhantomData,
+            version: None,
+            end_barrier: false,
+            context: None,
+            #[cfg(feature = "tracing")]
+            runs: trace::Runs::default(),
+        }
+    }
+
+    /// Sets the version of the choreographies that the projector runs.
+    ///
+    /// See `Projector::with_version`.
+    pub fn with_version(mut self, version: impl Into<String>) -> Self {
+        self.version = Some(version.into());
+        self
+    }
+
+    /// Exchanges the fingerprint of the choreography `C` with the peers of the projection target.
+    async fn handshake<C, ChoreoLS: LocationSet>(
+        &self,
+        session: SessionId,
+    ) -> Result<(), ChoreographyError> {
+        let (Some(version), Some(peers)) = (&self.version, peers::<Target, ChoreoLS>()) else {
+            return Ok(());
+        };
+        let fingerprint = Fingerprint::new::<C, ChoreoLS>(version);
+        for peer in &peers {
+            self.transport
+                .send(
+                    session,
+                    Target::name(),
+                    peer,
+                    &Tagged::outgoing(HANDSHAKE_STEP, &fingerprint),
+                )
+                .await?;
+        }
+        for peer in peers {
+            let received = self
+                .transport
+                .receive::<Tagged<Fingerprint>>(session, peer, Target::name())
+                .await?
+                .into_handshake()
+                .ok_or_else(|| ChoreographyError::MissingFingerprint(peer.to_string()))?;
+            if received != fingerprint {
+                return Err(ChoreographyError::FingerprintMismatch {
+                    peer: peer.to_string(),
+                    expected: Box::new(fingerprint),
+                    received: Box::new(received),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Enables the end-of-run barrier.
+    ///
+    /// See `Projector::with_end_barrier`.
+    pub fn with_end_barrier(mut self) -> Self {
+        self.end_barrier = true;
+        self
+    }
+
+    /// Sets the context of the projection target.
+    ///
+    /// See `Projector::with_context`.
+    pub fn with_context(mut self, context: impl Any + Send + Sync) -> Self {
+        self.context = Some(Arc::new(context));
+        self
+    }
+
+    /// Tells the peers of the projection target that the run of a choreography over `ChoreoLS` is finished, and
+    /// waits until they have finished too.
+    async fn end_barrier<ChoreoLS: LocationSet>(
+        &self,
+        session: SessionId,
+        steps: &Steps,
+    ) -> Result<(), ChoreographyError> {
+        let Some(peers) = peers::<Target, ChoreoLS>().filter(|_| self.end_barrier) else {
+            return Ok(());
+        };
+        for peer in &peers {
+            let step = steps.next(Target::name(), peer);
+            self.transport
+                .send(
+                    session,
+                    Target::name(),
+                    peer,
+                    &Tagged::outgoing(step, &EndOfRun::Finished),
+                )
+                .await?;
+        }
+        let mut result = Ok(());
+        for peer in peers {
+            // messages that arrive before the end of the run were sent by the peer but never received
+            let mut unconsumed = false;
+            let end = loop {
+                let message = self
+                    .transport
+                    .receive::<Tagged<EndOfRun>>(session, peer, Target::name())
+                    .await?;
+                match message.into_value() {
+                    Some(end) => break end,
+                    None => unconsumed = true,
+                }
+            };
+            if result.is_ok() {
+                if let EndOfRun::Aborted = end {
+                    result = Err(ChoreographyError::PeerAborted(peer.to_string()));
+                } else if unconsumed {
+                    result = Err(TransportError::Unconsumed(peer.to_string()).into());
+                }
+            }
+        }
+        result
+    }
+
+    /// See `Projector::notify_aborted`.
+    async fn notify_aborted<ChoreoLS: LocationSet>(&self, session: SessionId, steps: &Steps) {
+        let Some(peers) = peers::<Target, ChoreoLS>().filter(|_| self.end_barrier) else {
+            return;
+        };
+        for peer in peers {
+            let step = steps.next(Target::name(), peer);
+            let _ = self
+                .transport
+                .send(
+                    session,
+                    Target::name(),
+                    peer,
+                    &Tagged::outgoing(step, &EndOfRun::Aborted),
+                )
+                .await;
+        }
+    }
+
+    /// Constructs a `Located` struct located at the projection target using the actual value.
+    pub fn local<V>(&self, value: V) -> Located<V, Target> {
+        Located::local(value)
+    }
+
+    /// Constructs a `Located` struct *NOT* located at the projection target.
+    pub fn remote<V, L: ChoreographyLocation, Index2>(&self, _: L) -> Located<V, L>
+    where
+        L: Member<<Target as Member<TransportLS, Index>>::Remainder, Index2>,
+    {
+        Located::remote()
+    }
+
+    /// Construct a `Faceted` struct owned by the projection target.
+    pub fn local_faceted<V, Owners: LocationSet, Index2>(
+        &self,
+        value: V,
+        _: Owners,
+    ) -> Faceted<V, Owners>
+    where
+        Target: Member<Owners, Index2>,
+    {
+        Faceted {
+            value: HashMap::from([(String::from(Target::name()), value)]),
+            phantom: PhantomData,
+        }
+    }
+
+    /// Construct a `Faceted` struct *NOT* owned by the projection target.
+    pub fn remote_faceted<V, Owners: LocationSet, Index2>(&self, _: Owners) -> Faceted<V, Owners>
+    where
+        Owners: Subset<<Target as Member<TransportLS, Index>>::Remainder, Index2>,
+    {
+        Faceted {
+            value: HashMap::new(),
+            phantom: PhantomData,
+        }
+    }
+
+    /// Unwraps a located value at the projection target.
+    pub fn unwrap<L: LocationSet, V, Index1, Index2>(&self, located: MultiplyLocated<V, L>) -> V
+    where
+        L: Subset<TransportLS, Index1>,
+        Target: Member<L, Index2>,
+    {
+        located.value.unwrap()
+    }
+
+    /// Unwraps the facet of the projection target.
+    ///
+    /// Use this method to access the faceted value returned by a choreography.
+    pub fn unwrap_faceted<L: LocationSet, V, Index1, Index2>(&self, mut faceted: Faceted<V, L>) -> V
+    where
+        L: Subset<TransportLS, Index1>,
+        Target: Member<L, Index2>,
+    {
+        faceted.value.remove(Target::name()).unwrap()
+    }
+
+    /// Performs end-point projection and runs an asynchronous choreography.
+    ///
+    /// Returns an error if the transport fails to send or receive a message. The choreography is aborted at the
+    /// point of failure.
+    ///
+    /// The choreography runs in session `0`. See `Projector::epp_and_run`.
+    pub async fn epp_and_run<
+        'a,
+        V,
+        // location set of the choreography to EPP
+        ChoreoLS: LocationSet,
+        C: AsyncChoreography<V, L = ChoreoLS>,
+        IndexSet,
+    >(
+        &'a self,
+        choreo: C,
+    ) -> Result<V, ChoreographyError>
+    where
+        ChoreoLS: Subset<TransportLS, IndexSet>,
+    {
+        self.epp_and_run_session(0, choreo).await
+    }
+
+    /// Performs end-point projection and runs an asynchronous choreography in `session`.
+    ///
+    /// See `Projector::epp_and_run_session`.
+    pub async fn epp_and_run_session<
+        V,
+        // location set of the choreography to EPP
+        ChoreoLS: LocationSet,
+        C: AsyncChoreography<V, L = ChoreoLS>,
+        IndexSet,
+    >(
+        &self,
+        session: SessionId,
+        choreo: C,
+    ) -> Result<V, ChoreographyError>
+    where
+        ChoreoLS: Subset<TransportLS, IndexSet>,
+    {
+        if !ChoreoLS::to_string_list().contains(&Target::name()) {
+            return Err(ChoreographyError::NotInChoreography(
+                Target::name().to_string(),
+            ));
+        }
+        self.handshake::<C, ChoreoLS>(session).await?;
+        let steps = Steps::default();
+        let op: AsyncEppOp<'_, ChoreoLS, Target, TransportLS, B> = AsyncEppOp {
+            target: PhantomData::<Target>,
+            transport: &self.transport,
+            session,
+            steps: &steps,
+            locations: ChoreoLS::to_string_list(),
+            context: self.context.as_ref(),
+            marker: PhantomData::<ChoreoLS>,
+            projector_location_set: PhantomData::<TransportLS>,
+        };
+        let future = CatchUnwind {
+            future: Box::pin(choreo.run(&op)),
+        };
+        #[cfg(feature = "tracing")]
+        let future = tracing::Instrument::instrument(
+            future,
+            trace::run_span(Target::name(), session, &self.runs),
+        );
+        let value = match future.await {
+            Ok(value) => value,
+            Err(payload) => {
+                self.notify_aborted::<ChoreoLS>(session, &steps).await;
+                return Err(into_abort(payload));
+            }
+        };
+        self.end_barrier::<ChoreoLS>(session, &steps).await?;
+        Ok(value)
+    }
+}
+
+/// Polls a future and catches an abort raised with `abort` or a panic, returning its payload.
+pub(crate) struct CatchUnwind<F: Future> {
+    pub(crate) future: Pin<Box<F>>,
+}
+
+impl<F: Future> Future for CatchUnwind<F> {
+    type Output = Result<F::Output, Box<dyn Any + Send>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match panic::catch_unwind(AssertUnwindSafe(|| self.future.as_mut().poll(cx))) {
+            Ok(Poll::Ready(value)) => Poll::Ready(Ok(value)),
+            Ok(Poll::Pending) => Poll::Pending,
+            Err(payload) => Poll::Ready(Err(payload)),
+        }
+    }
+}
+
+/// The accumulator used to fold over a location set in `fanout` and `fanin`.
+type FoldFuture<'a, V> = Pin<Box<dyn Future<Output = HashMap<String, V>> + 'a>>;
+
+struct AsyncEppOp<
+    'a,
+    ChoreoLS: LocationSet, // L is a location set associated with the choreography
+    Target: ChoreographyLocation,
+    TransportLS: LocationSet,
+    B: AsyncTransport<TransportLS, Target>,
+> {
+    target: PhantomData<Target>,
+    transport: &'a B,
+    session: SessionId,
+    steps: &'a Steps,
+    // the locations that run the choreography, which receive its broadcasts
+    locations: Vec<&'static str>,
+    context: Option<&'a Arc<AnyContext>>,
+    marker: PhantomData<ChoreoLS>,
+    projector_location_set: PhantomData<TransportLS>,
+}
+
+impl<
+        'a,
+        ChoreoLS: LocationSet,
+        Target: ChoreographyLocation,
+        TransportLS: LocationSet,
+        B: AsyncTransport<TransportLS, Target>,
+    > AsyncEppOp<'a, ChoreoLS, Target, TransportLS, B>
+{
+    /// Sends `value` from the target to `to` for the operator `op`.
+    async fn send_to<V: Portable>(&self, op: &'static str, to: &'static str, value: &V) {
+        let step = self.steps.next(Target::name(), to);
+        self.transport
+            .send(
+                self.session,
+                Target::name(),
+                to,
+                &Tagged::outgoing(step, value),
+            )
+            .await
+            .unwrap_or_else(|e| abort(e));
+        trace::sent(op, Target::name(), to, self.transport.codec(), value);
+    }
+
+    /// Receives a value from `from` at the target for the operator `op`.
+    async fn receive_from<V: Portable>(&self, op: &'static str, from: &'static str) -> V {
+        let step = self.steps.next(from, Target::name());
+        let value = self
+            .transport
+            .receive::<Tagged<_>>(self.session, from, Target::name())
+            .await
+            .unwrap_or_else(|e| abort(e))
+            .check(from, step)
+            .unwrap_or_else(|e| abort(e));
+        trace::received(op, from, Target::name(), self.transport.codec(), &value);
+        value
+    }
+
+    /// Copies the facet of the target with the codec of the transport.
+    fn own_facet<V: Portable, S: LocationSet>(&self, data: &Faceted<V, S>) -> V {
+        copy_entry(self.transport.codec(), data.facet(Target::name())).unwrap_or_else(|e| abort(e))
+    }
+}
+
+impl<
+        'a,
+        ChoreoLS: LocationSet,
+        Target: ChoreographyLocation,
+        TransportLS: LocationSet,
+        B: AsyncTransport<TransportLS, Target>,
+    > AsyncChoreoOp<ChoreoLS> for AsyncEppOp<'a, ChoreoLS, Target, TransportLS, B>
+{
+    fn locally<V, L1: ChoreographyLocation, Index>(
+        &self,
+        _location: L1,
+        computation: impl FnOnce(Unwrapper<L1>) -> V,
+    ) -> MultiplyLocated<V, LocationSet!(L1)> {
+        if L1::name() == Target::name() {
+            let unwrapper = Unwrapper {
+                phantom: PhantomData,
+                context: self.context.cloned(),
+            };
+            let value = computation(unwrapper);
+            trace::computed("locally", L1::name());
+            MultiplyLocated::local(value)
+        } else {
+            MultiplyLocated::remote()
+        }
+    }
+
+    async fn comm<
+        L: LocationSet,
+        Sender: ChoreographyLocation,
+        Receiver: ChoreographyLocation,
+        V: Portable,
+        Index1,
+        Index2,
+        Index3,
+    >(
+        &self,
+        _sender: Sender,
+        _receiver: Receiver,
+        data: &MultiplyLocated<V, L>,
+    ) -> MultiplyLocated<V, LocationSet!(Receiver)> {
+        if Sender::name() == Target::name() && Sender::name() == Receiver::name() {
+            let value = roundtrip(self.transport.codec(), data.value.as_ref().unwrap())
+                .unwrap_or_else(|e| abort(e));
+            return MultiplyLocated::local(value);
+        }
+        if Sender::name() == Target::name() {
+            self.send_to("comm", Receiver::name(), data.value.as_ref().unwrap())
+                .await;
+            MultiplyLocated::remote()
+        } else if Receiver::name() == Target::name() {
+            MultiplyLocated::local(self.receive_from("comm", Sender::name()).await)
+        } else {
+            MultiplyLocated::remote()
+        }
+    }
+
+    async fn broadcast<
+        L: LocationSet,
+        Sender: ChoreographyLocation,
+        V: Portable,
+        Index1,
+        Index2,
+    >(
+        &self,
+        _sender: Sender,
+        data: MultiplyLocated<V, L>,
+    ) -> V {
+        if Sender::name() == Target::name() {
+            let value = data.value.unwrap();
+            for dest in &self.locations {
+                if Target::name() != *dest {
+                    self.send_to("broadcast", dest, &value).await;
+                }
+            }
+            value
+        } else {
+            self.receive_from("broadcast", Sender::name()).await
+        }
+    }
+
+    async fn multicast<
+        Sender: ChoreographyLocation,
+        V: Portable,
+        D: LocationSet,
+        Index1,
+        Index2,
+    >(
+        &self,
+        _src: Sender,
+        _destination: D,
+        data: &MultiplyLocated<V, LocationSet!(Sender)>,
+    ) -> MultiplyLocated<V, D> {
+        if Sender::name() == Target::name() {
+            let value = data.value.as_ref().unwrap();
+            for dest in D::to_string_list() {
+                if Target::name() != dest {
+                    self.send_to("multicast", dest, value).await;
+                }
+            }
+            let value = roundtrip(self.transport.codec(), value).unwrap_or_else(|e| abort(e));
+            MultiplyLocated::local(value)
+        } else if D::to_string_list().contains(&Target::name()) {
+            MultiplyLocated::local(self.receive_from("multicast", Sender::name()).await)
+        } else {
+            MultiplyLocated::remote()
+        }
+    }
+
+    async fn select<
+        Sender: ChoreographyLocation,
+        Label: BranchLabel,
+        D: LocationSet,
+        Index1,
+        Index2,
+    >(
+        &self,
+        _sender: Sender,
+        _receivers: D,
+        label: &MultiplyLocated<Label, LocationSet!(Sender)>,
+    ) -> MultiplyLocated<Label, D> {
+        let receivers = D::to_string_list();
+        let index = if Sender::name() == Target::name() {
+            let index = label.value.as_ref().unwrap().index();
+            for dest in &receivers {
+                if Target::name() != *dest {
+                    self.send_to("select", dest, &index).await;
+                }
+            }
+            index
+        } else if receivers.contains(&Target::name()) {
+            self.receive_from("select", Sender::name()).await
+        } else {
+            return MultiplyLocated::remote();
+        };
+        if receivers.contains(&Target::name()) {
+            MultiplyLocated::local(label_from_index(index))
+        } else {
+            MultiplyLocated::remote()
+        }
+    }
+
+    fn naked<S: LocationSet, V, Index>(&self, data: MultiplyLocated<V, S>) -> V {
+        data.value.unwrap()
+    }
+
+    fn unnaked<V>(&self, data: V) -> MultiplyLocated<V, ChoreoLS> {
+        MultiplyLocated::local(data)
+    }
+
+    async fn call<R, M, Index, C: AsyncChoreography<R, L = M>>(&self, choreo: C) -> R
+    where
+        M: LocationSet + Subset<ChoreoLS, Index>,
+    {
+        let op: AsyncEppOp<'a, M, Target, TransportLS, B> = AsyncEppOp {
+            target: PhantomData::<Target>,
+            transport: self.transport,
+            session: self.session,
+            steps: self.steps,
+            // every location of the caller runs the called choreography
+            locations: self.locations.clone(),
+            context: self.context,
+            marker: PhantomData::<M>,
+            projector_location_set: PhantomData::<TransportLS>,
+        };
+        choreo.run(&op).await
+    }
+
+    async fn conclave<R, S: LocationSet, C: AsyncChoreography<R, L = S>, Index>(
+        &self,
+        choreo: C,
+    ) -> MultiplyLocated<R, S> {
+        let locs_vec = S::to_string_list();
+        trace::entered("conclave", &locs_vec, locs_vec.contains(&Target::name()));
+        if !locs_vec.contains(&Target::name()) {
+            return MultiplyLocated::remote();
+        }
+        let op = AsyncEppOp {
+            target: PhantomData::<Target>,
+            transport: self.transport,
+            session: self.session,
+            steps: self.steps,
+            locations: locs_vec,
+            context: self.context,
+            marker: PhantomData::<S>,
+            projector_location_set: PhantomData::<TransportLS>,
+        };
+        MultiplyLocated::local(choreo.run(&op).await)
+    }
+
+    fn parallel<V, S: LocationSet, Index>(
+        &self,
+        _locations: S,
+        computation: impl Fn() -> V,
+    ) -> Faceted<V, S>
+    where
+        S: Subset<ChoreoLS, Index>,
+    {
+        let mut values = HashMap::new();
+        if S::to_string_list().contains(&Target::name()) {
+            values.insert(String::from(Target::name()), computation());
+        }
+        Faceted {
+            value: values,
+            phantom: PhantomData,
+        }
+    }
+
+    async fn fanout<
+        V,
+        QS: LocationSet,
+        FOC: AsyncFanOutChoreography<V, L = ChoreoLS, QS = QS>,
+        QSSubsetL,
+        QSFoldable,
+    >(
+        &self,
+        _locations: QS,
+        c: FOC,
+    ) -> Faceted<V, QS>
+    where
+        QS: Subset<ChoreoLS, QSSubsetL>,
+        QS: LocationSetFoldable<ChoreoLS, QS, QSFoldable>,
+    {
+        let op: AsyncEppOp<ChoreoLS, Target, TransportLS, B> = AsyncEppOp {
+            target: PhantomData::<Target>,
+            transport: self.transport,
+            session: self.session,
+            steps: self.steps,
+            locations: self.locations.clone(),
+            context: self.context,
+            marker: PhantomData::<ChoreoLS>,
+            projector_location_set: PhantomData::<TransportLS>,
+        };
+        trace::entered("fanout", &QS::to_string_list(), true);
+
+        // Each step of the fold chains the iteration for `Q` after the iterations accumulated so far, so the
+        // locations are visited in the same order as in the synchronous `fanout`.
+        struct Loop<'a, ChoreoLS, O, V, QSSubsetL, QS, FOC> {
+            phantom: PhantomData<(ChoreoLS, V, QS, QSSubsetL)>,
+            op: &'a O,
+            foc: &'a FOC,
+        }
+
+        impl<
+                'a,
+                ChoreoLS: LocationSet,
+                O: AsyncChoreoOp<ChoreoLS>,
+                V: 'a,
+                QSSubsetL,
+                QS: LocationSet + Subset<ChoreoLS, QSSubsetL>,
+                FOC: AsyncFanOutChoreography<V, L = ChoreoLS, QS = QS>,
+            > LocationSetFolder<FoldFuture<'a, V>>
+            for Loop<'a, ChoreoLS, O, V, QSSubsetL, QS, FOC>
+        {
+            type L = ChoreoLS;
+            type QS = QS;
+            fn f<Q: ChoreographyLocation, QSSubsetL2, QMemberL, QMemberQS>(
+                &self,
+                acc: FoldFuture<'a, V>,
+                _: Q,
+            ) -> FoldFuture<'a, V>
+            where
+                Self::QS: Subset<Self::L, QSSubsetL2>,
+                Q: Member<Self::L, QMemberL>,
+                Q: Member<Self::QS, QMemberQS>,
+            {
+                let op = self.op;
+                let foc = self.foc;
+                Box::pin(async move {
+                    let mut acc = acc.await;
+                    let v = foc.run::<Q, QSSubsetL, QMemberL, QMemberQS>(op).await;
+                    if let Some(value) = v.value {
+                        acc.insert(String::from(Q::name()), value);
+                    }
+                    acc
+                })
+            }
+        }
+
+        let values = QS::foldr(
+            Loop::<ChoreoLS, _, V, QSSubsetL, QS, FOC> {
+                phantom: PhantomData,
+                op: &op,
+                foc: &c,
+            },
+            Box::pin(async { HashMap::new() }),
+        )
+        .await;
+        Faceted {
+            value: values,
+            phantom: PhantomData,
+        }
+    }
+
+    async fn fanin<
+        V,
+        QS: LocationSet,
+        RS: LocationSet,
+        FIC: AsyncFanInChoreography<V, L = ChoreoLS, QS = QS, RS = RS>,
+        QSSubsetL,
+        RSSubsetL,
+        QSFoldable,
+    >(
+        &self,
+        _locations: QS,
+        c: FIC,
+    ) -> MultiplyLocated<Quire<V, QS>, RS>
+    where
+        QS: Subset<ChoreoLS, QSSubsetL>,
+        RS: Subset<ChoreoLS, RSSubsetL>,
+        QS: LocationSetFoldable<ChoreoLS, QS, QSFoldable>,
+    {
+        let op: AsyncEppOp<ChoreoLS, Target, TransportLS, B> = AsyncEppOp {
+            target: PhantomData::<Target>,
+            transport: self.transport,
+            session: self.session,
+            steps: self.steps,
+            locations: self.locations.clone(),
+            context: self.context,
+            marker: PhantomData::<ChoreoLS>,
+            projector_location_set: PhantomData::<TransportLS>,
+        };
+        trace::entered("fanin", &QS::to_string_list(), true);
+
+        struct Loop<'a, ChoreoLS, O, V, QSSubsetL, QS, RSSubsetL, RS, FIC> {
+            phantom: PhantomData<(ChoreoLS, V, QS, QSSubsetL, RS, RSSubsetL)>,
+            op: &'a O,
+            fic: &'a FIC,
+        }
+
+        impl<
+                'a,
+                ChoreoLS: LocationSet,
+                O: AsyncChoreoOp<ChoreoLS>,
+                V: 'a,
+                QSSubsetL,
+                QS: LocationSet + Subset<ChoreoLS, QSSubsetL>,
+                RSSubsetL,
+                RS: LocationSet + Subset<ChoreoLS, RSSubsetL>,
+                FIC: AsyncFanInChoreography<V, L = ChoreoLS, QS = QS, RS = RS>,
+            > LocationSetFolder<FoldFuture<'a, V>>
+            for Loop<'a, ChoreoLS, O, V, QSSubsetL, QS, RSSubsetL, RS, FIC>
+        {
+            type L = ChoreoLS;
+            type QS = QS;
+            fn f<Q: ChoreographyLocation, QSSubsetL2, QMemberL, QMemberQS>(
+                &self,
+                acc: FoldFuture<'a, V>,
+                _: Q,
+            ) -> FoldFuture<'a, V>
+            where
+                Self::QS: Subset<Self::L, QSSubsetL2>,
+                Q: Member<Self::L, QMemberL>,
+                Q: Member<Self::QS, QMemberQS>,
+            {
+                let op = self.op;
+                let fic = self.fic;
+                Box::pin(async move {
+                    let mut acc = acc.await;
+                    let v = fic
+                        .run::<Q, QSSubsetL, RSSubsetL, QMemberL, QMemberQS>(op)
+                        .await;
+                    // if the target is in RS, `v` has a value (`Some`)
+                    if let Some(value) = v.value {
+                        acc.insert(String::from(Q::name()), value);
+                    }
+                    acc
+                })
+            }
+        }
+
+        let values = QS::foldr(
+            Loop::<ChoreoLS, _, V, QSSubsetL, QS, RSSubsetL, RS, FIC> {
+                phantom: PhantomData,
+                op: &op,
+                fic: &c,
+            },
+            Box::pin(async { HashMap::new() }),
+        )
+        .await;
+
+        MultiplyLocated::<Quire<V, QS>, RS>::local(Quire {
+            value: values,
+            phantom: PhantomData,
+        })
+    }
+
+    async fn scatter<Sender: ChoreographyLocation, V: Portable, QS: LocationSet, Index1, Index2>(
+        &self,
+        _sender: Sender,
+        _locations: QS,
+        data: &MultiplyLocated<Quire<V, QS>, LocationSet!(Sender)>,
+    ) -> Faceted<V, QS> {
+        let mut value = HashMap::new();
+        if Sender::name() == Target::name() {
+            let quire = data.value.as_ref().unwrap();
+            for dest in QS::to_string_list() {
+                if dest == Target::name() {
+                    let entry = copy_entry(self.transport.codec(), quire.entry(dest))
+                        .unwrap_or_else(|e| abort(e));
+                    value.insert(dest.to_string(), entry);
+                } else {
+                    let entry = quire.entry(dest).unwrap_or_else(|e| abort(e));
+                    self.send_to("scatter", dest, entry).await;
+                }
+            }
+        } else if QS::to_string_list().contains(&Target::name()) {
+            let entry = self.receive_from("scatter", Sender::name()).await;
+            value.insert(Target::name().to_string(), entry);
+        }
+        Faceted {
+            value,
+            phantom: PhantomData,
+        }
+    }
+
+    async fn gather<Root: ChoreographyLocation, V: Portable, QS: LocationSet, Index1, Index2>(
+        &self,
+        _root: Root,
+        data: &Faceted<V, QS>,
+    ) -> MultiplyLocated<Quire<V, QS>, LocationSet!(Root)> {
+        let locations = QS::to_string_list();
+        if Root::name() == Target::name() {
+            let mut value = HashMap::new();
+            for src in locations {
+                let facet = if src == Target::name() {
+                    self.own_facet(data)
+                } else {
+                    self.receive_from("gather", src).await
+                };
+                value.insert(src.to_string(), facet);
+            }
+            MultiplyLocated::local(Quire {
+                value,
+                phantom: PhantomData,
+            })
+        } else {
+            if locations.contains(&Target::name()) {
+                let facet = data.facet(Target::name()).unwrap_or_else(|e| abort(e));
+                self.send_to("gather", Root::name(), facet).await;
+            }
+            MultiplyLocated::remote()
+        }
+    }
+
+    async fn allreduce<V: Portable, QS: LocationSet, Index>(
+        &self,
+        data: &Faceted<V, QS>,
+        f: impl Fn(V, V) -> V,
+    ) -> MultiplyLocated<V, QS> {
+        assert_census::<QS>("allreduce");
+        let locations = listed::<QS>();
+        if !locations.contains(&Target::name()) {
+            return MultiplyLocated::remote();
+        }
+        // the first location combines the facets and sends the result to the others
+        let root = locations[0];
+        if root == Target::name() {
+            let mut value = self.own_facet(data);
+            for &src in &locations[1..] {
+                let facet = self.receive_from("allreduce", src).await;
+                value = f(value, facet);
+            }
+            for &dest in &locations[1..] {
+                self.send_to("allreduce", dest, &value).await;
+            }
+            MultiplyLocated::local(value)
+        } else {
+            let facet = data.facet(Target::name()).unwrap_or_else(|e| abort(e));
+            self.send_to("allreduce", root, facet).await;
+            MultiplyLocated::local(self.receive_from("allreduce", root).await)
+        }
+    }
+}
@@ -0,0 +1,247 @@
+//! Messages tagged with the type of their value and their step in the choreography.
+//!
+//! Every message that an operator sends is serialized as a tuple of a hash of the type name of the value, the step
+//! of the message, and the value. The hash has a fixed size, so the tag does not grow with the type name, and the
+//! readable type name is only used to report a mismatch. The step counts the messages from the sender to the receiver
+//! earlier in the same run of the choreography, so the receiver can tell when the two locations disagree about where
+//! they are in the protocol. Steps start at 1; step 0 is reserved for the fingerprint that projectors with a version
+//! send before the run.
+
+use std::any::type_name;
+use std::collections::HashMap;
+use std::fmt;
+use std::marker::PhantomData;
+use std::sync::Mutex;
+
+use serde::de::{self, IgnoredAny, SeqAccess, Visitor};
+use serde::ser::SerializeTuple;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use super::ChoreographyError;
+
+/// Returns the hash of the type name of `V` that tags messages with values of type `V`.
+///
+/// The hash is FNV-1a, which does not depend on the platform or the version of Rust.
+pub(crate) fn type_hash<V>() -> u64 {
+    type_name::<V>()
+        .bytes()
+        .fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0000_0100_0000_01b3)
+        })
+}
+
+/// Returns the type name of the value that a message of type `M` carries.
+///
+/// Operators send `Tagged` values, so the wrapper is removed from their type names. The wrapper is matched by its own
+/// type name rather than by a fixed path, so the result does not depend on where the wrapper is defined.
+pub(crate) fn value_type_name<M>() -> &'static str {
+    let name = type_name::<M>();
+    let wrapper = type_name::<Tagged<'static, ()>>();
+    wrapper
+        .strip_suffix("()>")
+        .and_then(|prefix| name.strip_prefix(prefix))
+        .and_then(|value| value.strip_suffix('>'))
+        .unwrap_or(name)
+}
+
+/// The step of the fingerprint sent by the version handshake.
+pub(crate) const HANDSHAKE_STEP: u64 = 0;
+
+/// Counts the messages between each pair of locations in a run of a choreography.
+#[derive(Default)]
+pub(crate) struct Steps(Mutex<HashMap<(&'static str, &'static str), u64>>);
+
+impl Steps {
+    /// Returns the step of the next message from `from` to `to`.
+    pub fn next(&self, from: &'static str, to: &'static str) -> u64 {
+        let mut steps = self.0.lock().unwrap();
+        let step = steps.entry((from, to)).or_insert(0);
+        *step += 1;
+        *step
+    }
+}
+
+/// A value tagged with the hash of its type name and its step.
+pub(crate) enum Tagged<'a, V> {
+    /// A message to send, which borrows its value.
+    Outgoing { step: u64, value: &'a V },
+    /// A received message, which holds its value only if the type hashes match.
+    Incoming {
+        type_hash: u64,
+        step: u64,
+        value: Option<V>,
+    },
+}
+
+impl<'a, V> Tagged<'a, V> {
+    /// Tags `value` to be sent at `step`.
+    pub fn outgoing(step: u64, value: &'a V) -> Self {
+        Tagged::Outgoing { step, value }
+    }
+
+    /// Returns the value of a received message, or `None` if it has another type.
+    pub fn into_value(self) -> Option<V> {
+        match self {
+            Tagged::Incoming { value, .. } => value,
+            Tagged::Outgoing { .. } => None,
+        }
+    }
+
+    /// Returns the value of a received handshake, or `None` if the message is not a handshake or has another type.
+    pub fn into_handshake(self) -> Option<V> {
+        match self {
+            Tagged::Incoming {
+                step: HANDSHAKE_STEP,
+                value,
+                ..
+            } => value,
+            _ => None,
+        }
+    }
+
+    /// Returns the value of a message received from `sender` if it has the expected type and `step`.
+    pub fn check(self, sender: &str, step: u64) -> Result<V, ChoreographyError> {
+        match self {
+            Tagged::Incoming {
+                step: received,
+                value: Some(value),
+                ..
+            } if received == step => Ok(value),
+            Tagged::Incoming {
+                step: HANDSHAKE_STEP,
+                ..
+            } => Err(ChoreographyError::UnexpectedFingerprint(sender.to_string())),
+            Tagged::Incoming {
+                type_hash: received_type,
+                step: received,
+                ..
+            } => {
+                // only the hash of the received type is known, unless it is the expected type
+                let received_type = if received_type == type_hash::<V>() {
+                    type_name::<V>().to_string()
+                } else {
+                    format!("unknown type {:#018x}", received_type)
+                };
+                Err(ChoreographyError::ProtocolMismatch {
+                    sender: sender.to_string(),
+                    expected: (step, type_name::<V>().to_string()),
+                    received: (received, received_type),
+                })
+            }
+            Tagged::Outgoing { .. } => unreachable!("only received messages are checked"),
+        }
+    }
+}
+
+impl<V: Serialize> Serialize for Tagged<'_, V> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut tuple = serializer.serialize_tuple(3)?;
+        match self {
+            Tagged::Outgoing { step, value } => {
+                tuple.serialize_element(&type_hash::<V>())?;
+                tuple.serialize_element(step)?;
+                tuple.serialize_element(value)?;
+            }
+            Tagged::Incoming {
+                type_hash,
+                step,
+                value,
+            } => {
+                tuple.serialize_element(type_hash)?;
+                tuple.serialize_element(step)?;
+                match value {
+                    Some(value) => tuple.serialize_element(value)?,
+                    None => tuple.serialize_element(&())?,
+                }
+            }
+        }
+        tuple.end()
+    }
+}
+
+impl<'de, V: Deserialize<'de>> Deserialize<'de> for Tagged<'_, V> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct TaggedVisitor<'a, V>(PhantomData<&'a V>);
+
+        impl<'a, 'de, V: Deserialize<'de>> Visitor<'de> for TaggedVisitor<'a, V> {
+            type Value = Tagged<'a, V>;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a tagged message")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+                let type_hash: u64 = seq
+                    .next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(0, &self))?;
+                let step: u64 = seq
+                    .next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(1, &self))?;
+                let value = if type_hash == self::type_hash::<V>() {
+                    Some(
+                        seq.next_element()?
+                            .ok_or_else(|| de::Error::invalid_length(2, &self))?,
+                    )
+                } else {
+                    // formats that are not self-describing cannot skip a value of an unknown type, but the
+                    // mismatch is reported either way
+                    let _ = seq.next_element::<IgnoredAny>();
+                    None
+                };
+                Ok(Tagged::Incoming {
+                    type_hash,
+                    step,
+                    value,
+                })
+            }
+        }
+
+        deserializer.deserialize_tuple(3, TaggedVisitor(PhantomData))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::{Codec, Json};
+    use crate::core::Portable;
+
+    fn receive<V: Portable>(message: &impl Portable, step: u64) -> Result<V, ChoreographyError> {
+        let bytes = Json.encode(message).unwrap();
+        Json.decode::<Tagged<V>>(&bytes)
+            .unwrap()
+            .check("Alice", step)
+    }
+
+    #[test]
+    fn test_tagged() {
+        let value = vec![1, 2, 3];
+        let message = Tagged::outgoing(1, &value);
+        assert_eq!(receive::<Vec<i32>>(&message, 1).unwrap(), value);
+        assert!(matches!(
+            receive::<String>(&message, 1),
+            Err(ChoreographyError::ProtocolMismatch { sender, expected, received })
+                if sender == "Alice"
+                    && expected == (1, type_name::<String>().to_string())
+                    && received == (1, format!("unknown type {:#018x}", type_hash::<Vec<i32>>()))
+        ));
+        assert!(matches!(
+            receive::<Vec<i32>>(&message, 2),
+            Err(ChoreographyError::ProtocolMismatch { expected, received, .. })
+                if expected.0 == 2 && received.0 == 1
+        ));
+        assert!(matches!(
+            receive::<Vec<i32>>(&Tagged::outgoing(HANDSHAKE_STEP, &value), 1),
+            Err(ChoreographyError::UnexpectedFingerprint(sender)) if sender == "Alice"
+        ));
+    }
+
+    #[test]
+    fn test_value_type_name() {
+        assert_eq!(
+            value_type_name::<Tagged<Vec<i32>>>(),
+            type_name::<Vec<i32>>()
+        );
+        assert_eq!(value_type_name::<i32>(), "i32");
+    }
+}
@@ -3,7 +3,10 @@
 #![deny(missing_docs)]
 #![doc(html_logo_url = "https://lsd-ucsc.github.io/ChoRus/assets/ChoRus.png")]
 
+pub mod codec;
 pub mod core;
+pub mod simulation;
 pub mod transport;
 
+mod trace;
 mod utils;
@@ -0,0 +1,600 @@
+//! Deterministic simulation of projected choreographies.
+//!
+//! A `Simulation` runs the projections of an asynchronous choreography for all locations in one thread under a
+//! seeded scheduler. Each location is an `AsyncProjector` future, and the scheduler polls one of them at a time: a
+//! location hands control back to the scheduler whenever it sends or receives a message. The scheduler then decides
+//! which location runs next and when each message in flight is delivered, delaying messages by a random number of
+//! steps. All decisions are drawn from the seed, so a run with the same seed takes the same schedule, and a failing
+//! schedule can be reproduced from its seed.
+//!
+//! Messages between two locations in a session are delivered in the order they were sent. Messages on different
+//! channels can be delivered in any order. If no location can make progress and no message is in flight, the pending
+//! receives fail with `TransportError::PeerClosed`.
+//!
+//! The futures of the locations must only wait on their simulated transports. A future that waits on anything else,
+//! such as a timer, is polled again on a later step instead of being woken up.
+//!
+//! # Examples
+//!
+//! ```
+//! # use chorus_lib::core::{AsyncChoreoOp, AsyncChoreography, AsyncProjector, ChoreographyLocation, Located, LocationSet};
+//! use chorus_lib::simulation::Simulation;
+//! #
+//! # #[derive(ChoreographyLocation)]
+//! # struct Alice;
+//! #
+//! # #[derive(ChoreographyLocation)]
+//! # struct Bob;
+//! #
+//! # struct HelloWorld;
+//! #
+//! # impl AsyncChoreography<Located<String, Bob>> for HelloWorld {
+//! #     type L = LocationSet!(Alice, Bob);
+//! #     async fn run(self, op: &impl AsyncChoreoOp<Self::L>) -> Located<String, Bob> {
+//! #         let msg = op.locally(Alice, |_| "Hello".to_string());
+//! #         op.comm(Alice, Bob, &msg).await
+//! #     }
+//! # }
+//!
+//! for seed in 0..10 {
+//!     let simulation = Simulation::<LocationSet!(Alice, Bob)>::new(seed);
+//!     let alice_projector = AsyncProjector::new(Alice, simulation.transport(Alice));
+//!     let bob_projector = AsyncProjector::new(Bob, simulation.transport(Bob));
+//!     let msg = simulation.run(|s| {
+//!         let alice = s.spawn(Alice, alice_projector.epp_and_run(HelloWorld));
+//!         let bob = s.spawn(Bob, bob_projector.epp_and_run(HelloWorld));
+//!         alice.join().unwrap();
+//!         bob.join().unwrap()
+//!     });
+//!     assert_eq!(bob_projector.unwrap(msg), "Hello");
+//! }
+//! ```
+
+use std::cell::{RefCell, RefMut};
+use std::collections::{BTreeMap, VecDeque};
+use std::future::Future;
+use std::marker::PhantomData;
+use std::panic;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll, Waker};
+use std::thread;
+
+use crate::codec::{Codec, Json};
+use crate::core::{
+    AsyncTransport, CatchUnwind, ChoreographyLocation, LocationSet, Member, Portable, SessionId,
+    TransportError,
+};
+use crate::utils::rng::Rng;
+
+/// The default maximum number of steps a message is delayed by.
+const DEFAULT_MAX_DELAY: u64 = 8;
+
+/// Messages are delivered per (session, sender, receiver).
+type Key = (SessionId, &'static str, &'static str);
+
+/// The state of a simulated location.
+enum Status {
+    /// The location can run.
+    Ready,
+    /// The location is being polled.
+    Running,
+    /// The location waits for a message on `key`.
+    Waiting { key: Key },
+    /// The location was picked with the result of its `receive`.
+    Resumed(Result<Vec<u8>, TransportError>),
+    /// The future of the location has completed.
+    Finished,
+}
+
+/// An event the scheduler can pick.
+enum Action {
+    Run(&'static str),
+    Deliver(Key),
+}
+
+struct State {
+    rng: Rng,
+    max_delay: u64,
+    clock: u64,
+    locations: BTreeMap<&'static str, Status>,
+    in_flight: BTreeMap<Key, VecDeque<(u64, Vec<u8>)>>,
+    delivered: BTreeMap<Key, VecDeque<Vec<u8>>>,
+}
+
+impl State {
+    /// Picks the next location to run, delivering messages and advancing the clock as needed.
+    ///
+    /// Returns `None` if every location has finished.
+    fn step(&mut self) -> Option<&'static str> {
+        loop {
+            let mut actions = Vec::new();
+            for (location, status) in &self.locations {
+                match status {
+                    Status::Ready => actions.push(Action::Run(location)),
+                    Status::Waiting { key }
+                        if self.delivered.get(key).is_some_and(|q| !q.is_empty()) =>
+                    {
+                        actions.push(Action::Run(location))
+                    }
+                    _ => {}
+                }
+            }
+            for (key, queue) in &self.in_flight {
+                if queue.front().is_some_and(|(at, _)| *at <= self.clock) {
+                    actions.push(Action::Deliver(*key));
+                }
+            }
+            if !actions.is_empty() {
+                let index = self.rng.below(actions.len() as u64) as usize;
+                match actions.swap_remove(index) {
+                    Action::Run(location) => return Some(location),
+                    Action::Deliver(key) => {
+                        let queue = self.in_flight.get_mut(&key).unwrap();
+                        let (_, message) = queue.pop_front().unwrap();
+                        if queue.is_empty() {
+                            self.in_flight.remove(&key);
+                        }
+                        self.delivered.entry(key).or_default().push_back(message);
+                        self.clock += 1;
+                        continue;
+                    }
+                }
+            }
+            // nothing can happen now, so let time pass
+            if let Some(next) = self.in_flight.values().map(|queue| queue[0].0).min() {
+                self.clock = next;
+                continue;
+            }
+            let stuck = self
+                .locations
+                .iter()
+                .find_map(|(location, status)| match status {
+                    Status::Waiting { key } => Some((*location, key.1)),
+                    _ => None,
+                });
+            let (location, from) = stuck?;
+            self.locations.insert(
+                location,
+                Status::Resumed(Err(TransportError::PeerClosed(from.to_string()))),
+            );
+            return Some(location);
+        }
+    }
+}
+
+/// The state shared by a simulation and its transports.
+type Scheduler = Rc<RefCell<State>>;
+
+/// A deterministic simulation of the locations in `L`.
+///
+/// See the [module documentation](self) for details.
+pub struct Simulation<L: LocationSet> {
+    seed: u64,
+    scheduler: Scheduler,
+    location_set: PhantomData<L>,
+}
+
+impl<L: LocationSet> Simulation<L> {
+    /// Creates a new simulation whose schedule is determined by `seed`.
+    pub fn new(seed: u64) -> Self {
+        Simulation {
+            seed,
+            scheduler: Rc::new(RefCell::new(State {
+                rng: Rng::new(seed),
+                max_delay: DEFAULT_MAX_DELAY,
+                clock: 0,
+                locations: BTreeMap::new(),
+                in_flight: BTreeMap::new(),
+                delivered: BTreeMap::new(),
+            })),
+            location_set: PhantomData,
+        }
+    }
+
+    /// Sets the maximum number of scheduling steps a message is delayed by.
+    ///
+    /// With `0`, messages are still delivered in a random order, but never later than the next step.
+    pub fn with_max_delay(self, max_delay: u64) -> Self {
+        self.scheduler.borrow_mut().max_delay = max_delay;
+        self
+    }
+
+    /// Returns the seed of the simulation.
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Creates the transport for `location`. Messages are encoded as JSON.
+    pub fn transport<Target: ChoreographyLocation, Index>(
+        &self,
+        location: Target,
+    ) -> SimulatedTransport<L, Target>
+    where
+        Target: Member<L, Index>,
+    {
+        self.transport_with_codec(location, Json)
+    }
+
+    /// Creates the transport for `location` that encodes messages with `codec`.
+    pub fn transport_with_codec<Target: ChoreographyLocation, C: Codec, Index>(
+        &self,
+        location: Target,
+        codec: C,
+    ) -> SimulatedTransport<L, Target, C>
+    where
+        Target: Member<L, Index>,
+    {
+        _ = location;
+        SimulatedTransport {
+            scheduler: self.scheduler.clone(),
+            codec,
+            location_set: PhantomData,
+            target_location: PhantomData,
+        }
+    }
+
+    /// Runs `f`, which spawns the locations of the simulation, and then runs the schedule until all of them return.
+    ///
+    /// The locations run on the calling thread.
+    pub fn run<'a, R>(&'a self, f: impl FnOnce(&SimulationScope<'a, L>) -> R) -> R {
+        let scope = SimulationScope {
+            simulation: self,
+            tasks: RefCell::new(BTreeMap::new()),
+        };
+        let result = f(&scope);
+        scope.drive(|_| false);
+        result
+    }
+}
+
+/// A future of a spawned location, which stores its result in the handle of the location.
+type Task<'a> = Pin<Box<dyn Future<Output = ()> + 'a>>;
+
+/// A scope in which the locations of a `Simulation` are spawned.
+pub struct SimulationScope<'a, L: LocationSet> {
+    simulation: &'a Simulation<L>,
+    tasks: RefCell<BTreeMap<&'static str, Task<'a>>>,
+}
+
+impl<'a, L: LocationSet> SimulationScope<'a, L> {
+    fn state(&self) -> RefMut<'_, State> {
+        self.simulation.scheduler.borrow_mut()
+    }
+
+    /// Spawns `location`, which polls `future` when the scheduler first picks it.
+    ///
+    /// `future` typically runs an `AsyncProjector` whose transport was created by `Simulation::transport` for the
+    /// same location.
+    pub fn spawn<Target: ChoreographyLocation, T: 'a, Index>(
+        &self,
+        location: Target,
+        future: impl Future<Output = T> + 'a,
+    ) -> SimulatedHandle<'_, 'a, L, T>
+    where
+        Target: Member<L, Index>,
+    {
+        _ = location;
+        let previous = self.state().locations.insert(Target::name(), Status::Ready);
+        assert!(
+            previous.is_none(),
+            "`{}` is already spawned",
+            Target::name()
+        );
+        let result = Rc::new(RefCell::new(None));
+        let slot = result.clone();
+        self.tasks.borrow_mut().insert(
+            Target::name(),
+            Box::pin(async move {
+                let future = CatchUnwind {
+                    future: Box::pin(future),
+                };
+                *slot.borrow_mut() = Some(future.await);
+            }),
+        );
+        SimulatedHandle {
+            scope: self,
+            location: Target::name(),
+            result,
+        }
+    }
+
+    /// Runs the schedule until `done` holds or every location has finished.
+    fn drive(&self, done: impl Fn(&State) -> bool) {
+        let mut cx = Context::from_waker(Waker::noop());
+        loop {
+            let location = {
+                let mut state = self.state();
+                if done(&state) {
+                    return;
+                }
+                let Some(location) = state.step() else {
+                    return;
+                };
+                let status = match state.locations.remove(location) {
+                    Some(Status::Waiting { key }) => {
+                        let message = state.delivered.get_mut(&key).unwrap().pop_front();
+                        Status::Resumed(Ok(message.unwrap()))
+                    }
+                    Some(Status::Resumed(result)) => Status::Resumed(result),
+                    _ => Status::Running,
+                };
+                state.locations.insert(location, status);
+                location
+            };
+            let mut tasks = self.tasks.borrow_mut();
+            let task = tasks.get_mut(location).unwrap();
+            // the task catches the panics of the location and stores them in its handle
+            let poll = task.as_mut().poll(&mut cx);
+            let mut state = self.state();
+            match poll {
+                Poll::Pending => {
+                    if let Some(status @ Status::Running) = state.locations.get_mut(location) {
+                        // the location waits on something other than its transport
+                        *status = Status::Ready;
+                    }
+                }
+                Poll::Ready(()) => {
+                    tasks.remove(location);
+                    state.locations.insert(location, Status::Finished);
+                }
+            }
+        }
+    }
+}
+
+/// A handle to a location spawned in a `Simulation`.
+pub struct SimulatedHandle<'s, 'a, L: LocationSet, T> {
+    scope: &'s SimulationScope<'a, L>,
+    location: &'static str,
+    result: Rc<RefCell<Option<thread::Result<T>>>>,
+}
+
+impl<L: LocationSet, T> SimulatedHandle<'_, '_, L, T> {
+    /// Runs the schedule until the location returns, and returns its result.
+    ///
+    /// If the location panicked, the panic is resumed.
+    pub fn join(self) -> T {
+        let location = self.location;
+        self.scope
+            .drive(|state| matches!(state.locations.get(location), Some(Status::Finished)));
+        let result = self.result.borrow_mut().take();
+        result
+            .expect("the location has finished")
+            .unwrap_or_else(|err| panic::resume_unwind(err))
+    }
+}
+
+/// Returns `Poll::Pending` once, handing control back to the scheduler.
+struct Yield(bool);
+
+impl Future for Yield {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<()> {
+        if self.0 {
+            Poll::Ready(())
+        } else {
+            self.0 = true;
+            Poll::Pending
+        }
+    }
+}
+
+/// The transport of a location in a `Simulation`.
+pub struct SimulatedTransport<L: LocationSet, Target, C: Codec = Json> {
+    scheduler: Scheduler,
+    codec: C,
+    location_set: PhantomData<L>,
+    target_location: PhantomData<Target>,
+}
+
+impl<L: LocationSet, Target: ChoreographyLocation, C: Codec> SimulatedTransport<L, Target, C> {
+    fn key(&self, session: SessionId, from: &str, to: &str) -> Result<Key, TransportError> {
+        let locations = L::to_string_list();
+        let find = |name: &str| {
+            locations
+                .iter()
+                .find(|location| **location == name)
+                .copied()
+                .ok_or_else(|| TransportError::UnknownLocation(name.to_string()))
+        };
+        Ok((session, find(from)?, find(to)?))
+    }
+}
+
+impl<L: LocationSet, Target: ChoreographyLocation, C: Codec> AsyncTransport<L, Target>
+    for SimulatedTransport<L, Target, C>
+{
+    type Codec = C;
+
+    fn codec(&self) -> &C {
+        &self.codec
+    }
+
+    fn locations(&self) -> Vec<&'static str> {
+        L::to_string_list()
+    }
+
+    async fn send<V: Portable>(
+        &self,
+        session: SessionId,
+        from: &str,
+        to: &str,
+        data: &V,
+    ) -> Result<(), TransportError> {
+        let key = self.key(session, from, to)?;
+        let message = self.codec.encode(data)?;
+        {
+            let mut state = self.scheduler.borrow_mut();
+            let max_delay = state.max_delay;
+            let at = state.clock + state.rng.below(max_delay + 1);
+            state
+                .in_flight
+                .entry(key)
+                .or_default()
+                .push_back((at, message));
+            state.locations.insert(Target::name(), Status::Ready);
+        }
+        Yield(false).await;
+        Ok(())
+    }
+
+    async fn receive<V: Portable>(
+        &self,
+        session: SessionId,
+        from: &str,
+        at: &str,
+    ) -> Result<V, TransportError> {
+        let key = self.key(session, from, at)?;
+        self.scheduler
+            .borrow_mut()
+            .locations
+            .insert(Target::name(), Status::Waiting { key });
+        Yield(false).await;
+        let status = self
+            .scheduler
+            .borrow_mut()
+            .locations
+            .insert(Target::name(), Status::Running);
+        let Some(Status::Resumed(result)) = status else {
+            unreachable!()
+        };
+        self.codec.decode(&result?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::panic::AssertUnwindSafe;
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::core::{
+        AsyncChoreoOp, AsyncChoreography, AsyncProjector, ChoreographyError, Located,
+    };
+
+    #[derive(ChoreographyLocation)]
+    struct Alice;
+
+    #[derive(ChoreographyLocation)]
+    struct Bob;
+
+    #[derive(ChoreographyLocation)]
+    struct Carol;
+
+    // Bob and Carol both report to Alice; the order in which Alice sees their reports depends on the schedule
+    struct Report<'a> {
+        log: &'a Mutex<Vec<&'static str>>,
+    }
+
+    impl AsyncChoreography<Located<i32, Alice>> for Report<'_> {
+        type L = LocationSet!(Alice, Bob, Carol);
+        async fn run(self, op: &impl AsyncChoreoOp<Self::L>) -> Located<i32, Alice> {
+            let b = op.locally(Bob, |_| {
+                self.log.lock().unwrap().push("Bob");
+                1
+            });
+            let c = op.locally(Carol, |_| {
+                self.log.lock().unwrap().push("Carol");
+                2
+            });
+            let b = op.comm(Bob, Alice, &b).await;
+            let c = op.comm(Carol, Alice, &c).await;
+            op.locally(Alice, |un| un.unwrap(&b) + un.unwrap(&c))
+        }
+    }
+
+    fn run_report(seed: u64) -> (i32, Vec<&'static str>) {
+        let log = Mutex::new(Vec::new());
+        let simulation = Simulation::<LocationSet!(Alice, Bob, Carol)>::new(seed);
+        let alice = AsyncProjector::new(Alice, simulation.transport(Alice));
+        let bob = AsyncProjector::new(Bob, simulation.transport(Bob));
+        let carol = AsyncProjector::new(Carol, simulation.transport(Carol));
+        let sum = simulation.run(|s| {
+            let a = s.spawn(Alice, alice.epp_and_run(Report { log: &log }));
+            s.spawn(Bob, bob.epp_and_run(Report { log: &log }));
+            s.spawn(Carol, carol.epp_and_run(Report { log: &log }));
+            a.join().unwrap()
+        });
+        (alice.unwrap(sum), log.into_inner().unwrap())
+    }
+
+    #[test]
+    fn test_simulation_is_deterministic() {
+        let mut orders = Vec::new();
+        for seed in 0..32 {
+            let (sum, order) = run_report(seed);
+            assert_eq!(sum, 3);
+            assert_eq!(run_report(seed).1, order);
+            orders.push(order);
+        }
+        // different seeds explore different interleavings
+        assert!(orders.contains(&vec!["Bob", "Carol"]));
+        assert!(orders.contains(&vec!["Carol", "Bob"]));
+    }
+
+    #[test]
+    fn test_simulation_runs_on_one_thread() {
+        let threads = Mutex::new(Vec::new());
+        let simulation = Simulation::<LocationSet!(Alice, Bob, Carol)>::new(0);
+        let alice = AsyncProjector::new(Alice, simulation.transport(Alice));
+        let bob = AsyncProjector::new(Bob, simulation.transport(Bob));
+        let carol = AsyncProjector::new(Carol, simulation.transport(Carol));
+        let log = Mutex::new(Vec::new());
+        let record = || threads.lock().unwrap().push(thread::current().id());
+        simulation.run(|s| {
+            s.spawn(Alice, async {
+                record();
+                alice.epp_and_run(Report { log: &log }).await
+            });
+            s.spawn(Bob, async {
+                record();
+                bob.epp_and_run(Report { log: &log }).await
+            });
+            s.spawn(Carol, async {
+                record();
+                carol.epp_and_run(Report { log: &log }).await
+            });
+        });
+        let threads = threads.into_inner().unwrap();
+        assert_eq!(threads.len(), 3);
+        assert!(threads.iter().all(|id| *id == thread::current().id()));
+    }
+
+    #[test]
+    fn test_simulation_deadlock() {
+        struct Ping;
+        impl AsyncChoreography for Ping {
+            type L = LocationSet!(Alice, Bob);
+            async fn run(self, op: &impl AsyncChoreoOp<Self::L>) {
+                let x = op.locally(Alice, |_| 1);
+                op.comm(Alice, Bob, &x).await;
+            }
+        }
+        let simulation = Simulation::<LocationSet!(Alice, Bob)>::new(0);
+        let bob = AsyncProjector::new(Bob, simulation.transport(Bob));
+        let result = simulation.run(|s| s.spawn(Bob, bob.epp_and_run(Ping)).join());
+        assert!(matches!(
+            result,
+            Err(ChoreographyError::Transport(TransportError::PeerClosed(_)))
+        ));
+    }
+
+    #[test]
+    fn test_simulation_resumes_panics() {
+        struct Panic;
+        impl AsyncChoreography for Panic {
+            type L = LocationSet!(Alice);
+            async fn run(self, op: &impl AsyncChoreoOp<Self::L>) {
+                op.locally(Alice, |_| panic!("boom"));
+            }
+        }
+        let simulation = Simulation::<LocationSet!(Alice)>::new(0);
+        let alice = AsyncProjector::new(Alice, simulation.transport(Alice));
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            simulation.run(|s| s.spawn(Alice, alice.epp_and_run(Panic)).join())
+        }));
+        assert_eq!(result.unwrap_err().downcast_ref(), Some(&"boom"));
+    }
+}
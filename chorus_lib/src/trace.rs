@@ -0,0 +1,145 @@
+//! Structured events for projected runs.
+//!
+//! With the `tracing` feature, every run of a projector is wrapped in a `choreography` span and each operator
+//! emits a `DEBUG` event through the [`tracing`](https://docs.rs/tracing) crate. Without the feature, the functions
+//! in this module do nothing.
+//!
+//! The span records the projection target, the session, and a correlation id of the form `<session>.<run>`, where
+//! `run` counts the runs of the session on the projector. All locations run the same sequence of choreographies in
+//! a session, so the events of one run share the correlation id on every location.
+
+use crate::codec::Codec;
+use crate::core::Portable;
+
+#[cfg(feature = "tracing")]
+use crate::core::SessionId;
+#[cfg(feature = "tracing")]
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Counts the runs of each session on a projector.
+#[cfg(feature = "tracing")]
+#[derive(Default)]
+pub(crate) struct Runs {
+    counters: Mutex<HashMap<SessionId, u64>>,
+}
+
+#[cfg(feature = "tracing")]
+impl Runs {
+    /// Returns the correlation id of the next run of `session`.
+    fn next(&self, session: SessionId) -> String {
+        let mut counters = self.counters.lock().unwrap();
+        let run = counters.entry(session).or_insert(0);
+        let id = format!("{}.{}", session, run);
+        *run += 1;
+        id
+    }
+}
+
+/// Creates the span of the next run of `session` at `location`.
+#[cfg(feature = "tracing")]
+pub(crate) fn run_span(location: &str, session: SessionId, runs: &Runs) -> tracing::Span {
+    tracing::debug_span!(
+        "choreography",
+        location,
+        session,
+        correlation_id = runs.next(session)
+    )
+}
+
+/// Microseconds since the Unix epoch, so that events from different hosts can be ordered.
+#[cfg(feature = "tracing")]
+fn timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_micros() as u64)
+}
+
+/// The size of `value` once encoded with `codec`.
+#[cfg(feature = "tracing")]
+fn size<V: Portable>(codec: &impl Codec, value: &V) -> usize {
+    codec.encode(value).map_or(0, |bytes| bytes.len())
+}
+
+/// Records a local computation at `location`.
+pub(crate) fn computed(op: &'static str, location: &str) {
+    #[cfg(feature = "tracing")]
+    tracing::debug!(op, location, timestamp = timestamp(), "computed");
+    #[cfg(not(feature = "tracing"))]
+    let _ = (op, location);
+}
+
+/// Records a message sent from `from` to `to`.
+pub(crate) fn sent<V: Portable>(
+    op: &'static str,
+    from: &str,
+    to: &str,
+    codec: &impl Codec,
+    value: &V,
+) {
+    #[cfg(feature = "tracing")]
+    tracing::debug!(
+        op,
+        from,
+        to,
+        bytes = size(codec, value),
+        timestamp = timestamp(),
+        "sent"
+    );
+    #[cfg(not(feature = "tracing"))]
+    let _ = (op, from, to, codec, value);
+}
+
+/// Records a message received by `at` from `from`.
+pub(crate) fn received<V: Portable>(
+    op: &'static str,
+    from: &str,
+    at: &str,
+    codec: &impl Codec,
+    value: &V,
+) {
+    #[cfg(feature = "tracing")]
+    tracing::debug!(
+        op,
+        from,
+        to = at,
+        bytes = size(codec, value),
+        timestamp = timestamp(),
+        "received"
+    );
+    #[cfg(not(feature = "tracing"))]
+    let _ = (op, from, at, codec, value);
+}
+
+/// Records the start of a sub-choreography over `locations`.
+///
+/// `participating` is `false` if the projection target skips the sub-choreography.
+pub(crate) fn entered(op: &'static str, locations: &[&str], participating: bool) {
+    #[cfg(feature = "tracing")]
+    tracing::debug!(
+        op,
+        locations = ?locations,
+        participating,
+        timestamp = timestamp(),
+        "entered"
+    );
+    #[cfg(not(feature = "tracing"))]
+    let _ = (op, locations, participating);
+}
+
+#[cfg(all(test, feature = "tracing"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_correlation_id() {
+        let runs = Runs::default();
+        assert_eq!(runs.next(0), "0.0");
+        assert_eq!(runs.next(0), "0.1");
+        assert_eq!(runs.next(7), "7.0");
+        assert_eq!(runs.next(0), "0.2");
+    }
+}
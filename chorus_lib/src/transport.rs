@@ -1,11 +1,158 @@
 //! Built-in transports.
 
+mod envelope;
+pub mod faulty;
+mod framed;
 pub mod http;
 pub mod local;
+pub mod record;
+pub mod tcp;
+#[cfg(unix)]
+pub mod uds;
 
-use crate::core::{ChoreographyLocation, HCons, LocationSet};
-use std::collections::HashMap;
+use crate::core::{ChoreographyLocation, HCons, LocationSet, TransportError};
+use retry::{delay::Exponential, OperationResult};
+use std::collections::{HashMap, HashSet};
 use std::marker::PhantomData;
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The largest message, in bytes, that a location accepts from a peer unless its config sets another limit.
+const DEFAULT_MAX_MESSAGE_SIZE: usize = 64 * 1024 * 1024;
+
+/// How long a send retries to reach a peer unless its config sets another timeout.
+const DEFAULT_SEND_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Returns the deadline for a `receive` that starts now.
+///
+/// `deadline` set by the choreography takes precedence over the transport's default `timeout`.
+fn receive_deadline(deadline: Option<Instant>, timeout: Option<Duration>) -> Option<Instant> {
+    deadline.or_else(|| timeout.and_then(|timeout| Instant::now().checked_add(timeout)))
+}
+
+/// How long a transport waits between attempts to reach a peer that is not listening yet.
+///
+/// The delay starts at `initial` and doubles after each failed attempt, up to `max`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Backoff {
+    /// The delay after the first failed attempt
+    pub initial: Duration,
+    /// The longest delay between two attempts
+    pub max: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            initial: Duration::from_millis(10),
+            max: Duration::from_secs(1),
+        }
+    }
+}
+
+impl Backoff {
+    /// Returns the delays between successive attempts.
+    fn delays(&self) -> impl Iterator<Item = Duration> {
+        let max = self.max;
+        Exponential::from_millis(self.initial.as_millis().max(1) as u64)
+            .map(move |delay| delay.min(max))
+    }
+}
+
+/// Calls `attempt` until it succeeds or fails, sleeping for each of `delays` in turn between attempts.
+///
+/// `attempt` returns `OperationResult::Retry` if the peer is not reachable yet, and `OperationResult::Err` if
+/// retrying cannot help. Fails with `TransportError::Timeout` if the next attempt would start after `deadline`.
+fn retry_until<T>(
+    delays: impl IntoIterator<Item = Duration>,
+    deadline: Option<Instant>,
+    mut attempt: impl FnMut() -> OperationResult<T, TransportError>,
+) -> Result<T, TransportError> {
+    let mut delays = delays.into_iter();
+    loop {
+        match attempt() {
+            OperationResult::Ok(value) => return Ok(value),
+            OperationResult::Err(err) => return Err(err),
+            OperationResult::Retry(_) => {}
+        }
+        let delay = delays.next().ok_or(TransportError::Timeout)?;
+        if deadline.is_some_and(|deadline| Instant::now() + delay > deadline) {
+            return Err(TransportError::Timeout);
+        }
+        thread::sleep(delay);
+    }
+}
+
+/// Returns the deadline for a `send` that starts now.
+///
+/// `deadline` set by the choreography takes precedence over the transport's default `timeout`.
+fn send_deadline(deadline: Option<Instant>, timeout: Duration) -> Option<Instant> {
+    receive_deadline(deadline, Some(timeout))
+}
+
+/// Waits until `probe` succeeds for each of `peers`, for at most `timeout` in total.
+///
+/// `probe` returns `OperationResult::Retry` if the peer is not ready yet, and `OperationResult::Err` if retrying
+/// cannot help. Attempts are spaced out according to `backoff`.
+fn wait_ready<'p>(
+    peers: impl IntoIterator<Item = &'p str>,
+    timeout: Duration,
+    backoff: Backoff,
+    probe: impl Fn(&str) -> OperationResult<(), TransportError>,
+) -> Result<(), TransportError> {
+    let deadline = Instant::now().checked_add(timeout);
+    for peer in peers {
+        retry_until(backoff.delays(), deadline, || probe(peer))?;
+    }
+    Ok(())
+}
+
+/// The peers that have announced that they will not send any more messages.
+#[derive(Default)]
+struct Departures {
+    departed: Mutex<HashSet<&'static str>>,
+    changed: Condvar,
+}
+
+impl Departures {
+    /// Records that `peer` will not send any more messages.
+    fn depart(&self, peer: &'static str) {
+        self.departed.lock().unwrap().insert(peer);
+        self.changed.notify_all();
+    }
+
+    /// Waits until all of `peers` have departed, or fails with `TransportError::Timeout` at `deadline`.
+    fn wait<'p>(
+        &self,
+        peers: impl IntoIterator<Item = &'p str>,
+        deadline: Option<Instant>,
+    ) -> Result<(), TransportError> {
+        let mut departed = self.departed.lock().unwrap();
+        for peer in peers {
+            while !departed.contains(peer) {
+                departed = match deadline {
+                    Some(deadline) => {
+                        let timeout = deadline
+                            .checked_duration_since(Instant::now())
+                            .ok_or(TransportError::Timeout)?;
+                        self.changed.wait_timeout(departed, timeout).unwrap().0
+                    }
+                    None => self.changed.wait(departed).unwrap(),
+                };
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Fails with `TransportError::Unconsumed` if one of `sources` is the source of messages that are still queued.
+fn check_consumed<'s>(sources: impl IntoIterator<Item = &'s str>) -> Result<(), TransportError> {
+    match sources.into_iter().next() {
+        Some(source) => Err(TransportError::Unconsumed(source.to_string())),
+        None => Ok(()),
+    }
+}
 
 /// A generic struct for configuration of `Transport`.
 #[derive(Clone)]
@@ -14,6 +161,18 @@ pub struct TransportConfig<'a, Target: ChoreographyLocation, TargetInfo, L: Loca
     pub info: HashMap<&'static str, Info>,
     /// The information about the target choreography
     pub target_info: (Target, TargetInfo),
+    /// How long `receive` waits for a message when the choreography does not set a deadline
+    ///
+    /// `None` means that `receive` waits forever.
+    pub receive_timeout: Option<Duration>,
+    /// How long a send retries to reach a peer before it fails with `TransportError::Timeout`
+    pub send_timeout: Duration,
+    /// The keys that the target shares with other locations to authenticate messages
+    pub keys: HashMap<&'static str, Vec<u8>>,
+    /// How long the target waits between attempts to reach a peer
+    pub backoff: Backoff,
+    /// The largest message, in bytes, that the target accepts from a peer
+    pub max_message_size: usize,
     /// The struct is parametrized by the location set (`L`).
     location_set: PhantomData<L>,
     lifetime: PhantomData<&'a ()>,
@@ -49,6 +208,11 @@ pub struct TransportConfigBuilder<
     target: (Target, TargetInfo),
     location_set: PhantomData<L>,
     info: HashMap<&'static str, Info>,
+    receive_timeout: Option<Duration>,
+    send_timeout: Duration,
+    keys: HashMap<&'static str, Vec<u8>>,
+    backoff: Backoff,
+    max_message_size: usize,
     lifetime: PhantomData<&'a ()>,
 }
 
@@ -61,6 +225,11 @@ impl<'a, Target: ChoreographyLocation, TargetInfo, Info>
             target: (target, info),
             location_set: PhantomData,
             info: HashMap::new(),
+            receive_timeout: None,
+            send_timeout: DEFAULT_SEND_TIMEOUT,
+            keys: HashMap::new(),
+            backoff: Backoff::default(),
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
             lifetime: PhantomData,
         }
     }
@@ -84,15 +253,86 @@ impl<'a, Target: ChoreographyLocation, TargetInfo, L: LocationSet, Info>
             target: self.target,
             location_set: PhantomData,
             info: new_info,
+            receive_timeout: self.receive_timeout,
+            send_timeout: self.send_timeout,
+            keys: self.keys,
+            backoff: self.backoff,
+            max_message_size: self.max_message_size,
             lifetime: PhantomData,
         }
     }
 
+    /// Sets the default receive timeout.
+    ///
+    /// A `receive` fails with `TransportError::Timeout` if no message arrives within `timeout`, unless the
+    /// choreography sets its own deadline with `ChoreoOp::with_deadline`.
+    pub fn with_receive_timeout(self, timeout: Duration) -> Self {
+        Self {
+            receive_timeout: Some(timeout),
+            ..self
+        }
+    }
+
+    /// Sets how long a send retries to reach a peer that is not listening.
+    ///
+    /// A send fails with `TransportError::Timeout` if the peer is still unreachable after `timeout`, so that a peer
+    /// that is down for good does not block the sender forever. The default is 30 seconds.
+    pub fn with_send_timeout(self, timeout: Duration) -> Self {
+        Self {
+            send_timeout: timeout,
+            ..self
+        }
+    }
+
+    /// Sets the secret key that the target shares with `peer`.
+    ///
+    /// Messages between the target and `peer` are then sent in envelopes that carry a sequence number and an
+    /// HMAC-SHA256 tag over the source, the destination, the session, the sequence number, and the message. A
+    /// `receive` fails with `TransportError::Unauthenticated` if the tag does not match, and discards a replayed
+    /// message. `peer` must set the same key for the target.
+    ///
+    /// Envelopes are also sealed for the incarnation of the receiver, a random number that it draws each time it
+    /// starts, so a message captured before the target restarts cannot be replayed to it afterwards.
+    pub fn with_key<Peer: ChoreographyLocation>(
+        mut self,
+        peer: Peer,
+        key: impl AsRef<[u8]>,
+    ) -> Self {
+        _ = peer;
+        self.keys.insert(Peer::name(), key.as_ref().to_vec());
+        self
+    }
+
+    /// Sets how long the target waits between attempts to reach a peer that is not listening yet.
+    ///
+    /// The backoff applies to `Transport::wait_ready` and to sends, which retry for at most the send timeout (see
+    /// `with_send_timeout`). The default starts at 10 ms and doubles up to 1 second.
+    pub fn with_backoff(self, backoff: Backoff) -> Self {
+        Self { backoff, ..self }
+    }
+
+    /// Sets the largest message, in bytes, that the target accepts from a peer.
+    ///
+    /// The size is checked before the message is read, so a peer cannot make the target allocate more memory. The
+    /// `http` transport rejects a larger message with `413 Payload Too Large`, which fails the send, and the `tcp`
+    /// and `uds` transports close the connection that carries it. The default is 64 MiB.
+    pub fn with_max_message_size(self, max_message_size: usize) -> Self {
+        Self {
+            max_message_size,
+            ..self
+        }
+    }
+
     /// Builds a `TransportConfig` instance.
     pub fn build<'b>(self) -> TransportConfig<'b, Target, TargetInfo, L, Info> {
         TransportConfig {
             info: self.info,
             target_info: self.target,
+            receive_timeout: self.receive_timeout,
+            send_timeout: self.send_timeout,
+            keys: self.keys,
+            backoff: self.backoff,
+            max_message_size: self.max_message_size,
             location_set: PhantomData,
             lifetime: PhantomData,
         }
@@ -0,0 +1,566 @@
+//! Message envelopes.
+//!
+//! An envelope wraps an encoded message with a header of three big-endian `u64`s: the incarnation of the sender, the
+//! incarnation of the receiver, and the sequence number of the message. An incarnation is a random number that a
+//! location draws when its transport is created, so it changes whenever the location restarts. A sender learns the
+//! incarnation of a receiver from the receiver itself, when it opens a connection or in the response to a request,
+//! and the receiver discards envelopes that are sealed for any other incarnation. A message that was captured before
+//! the receiver restarted therefore cannot be replayed to it.
+//!
+//! Sequence numbers count the messages of each session from one incarnation of a location to one incarnation of
+//! another, starting at 1, so the receiver discards a message that is delivered twice and detects a message that is
+//! never delivered. When either location restarts, sequence numbers start over. The receiver remembers the
+//! incarnations of a sender that it has seen, and discards the messages of the ones that came before.
+//!
+//! If the two locations share a key, the header is followed by an HMAC-SHA256 tag over the source, the destination,
+//! the session, the header, and the message.
+
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet};
+use std::hash::{BuildHasher, Hasher};
+use std::mem;
+use std::process;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::core::{SessionId, TransportError};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// An opened message, or the reason why it was rejected, waiting to be received.
+pub(crate) type Opened = Result<Vec<u8>, TransportError>;
+
+/// The length of the two incarnations and the sequence number.
+const HEADER_LEN: usize = 24;
+
+/// The length of an HMAC-SHA256 tag.
+const TAG_LEN: usize = 32;
+
+/// The incarnation of a peer that the location has not heard from yet. No location draws it.
+const UNKNOWN: u64 = 0;
+
+/// Draws the incarnation of a location.
+fn random_incarnation() -> u64 {
+    // the keys of `RandomState` come from the randomness of the operating system
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_nanos()),
+    );
+    hasher.write_u32(process::id());
+    hasher.finish().max(UNKNOWN + 1)
+}
+
+/// What a location knows about the messages to a peer.
+#[derive(Default)]
+struct Sent {
+    /// The incarnation of the peer, as it last told the location.
+    incarnation: u64,
+    /// The sequence number of the last message sealed for this incarnation in each session.
+    last: HashMap<SessionId, u64>,
+}
+
+/// What a location knows about the messages from a peer.
+struct Received {
+    /// The latest incarnation of the peer.
+    incarnation: u64,
+    /// The earlier incarnations of the peer.
+    retired: HashSet<u64>,
+    /// The sequence number of the last message accepted from this incarnation in each session.
+    last: HashMap<SessionId, u64>,
+}
+
+/// Seals and opens the envelopes of messages exchanged with the peers of a location.
+pub(crate) struct Envelopes {
+    incarnation: u64,
+    /// The peers, with the keys that they share with the location.
+    peers: HashMap<&'static str, Option<Vec<u8>>>,
+    sent: Mutex<HashMap<&'static str, Sent>>,
+    received: Mutex<HashMap<&'static str, Received>>,
+}
+
+impl Envelopes {
+    /// Creates envelopes for messages exchanged with `peers`, authenticated with `keys`.
+    pub fn new(
+        peers: impl IntoIterator<Item = &'static str>,
+        mut keys: HashMap<&'static str, Vec<u8>>,
+    ) -> Self {
+        Envelopes {
+            incarnation: random_incarnation(),
+            peers: peers
+                .into_iter()
+                .map(|peer| (peer, keys.remove(peer)))
+                .collect(),
+            sent: Mutex::new(HashMap::new()),
+            received: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the incarnation of the location, which it tells the peers that send messages to it.
+    pub fn incarnation(&self) -> u64 {
+        self.incarnation
+    }
+
+    /// Records that `peer` runs as `incarnation`, as the peer said when a connection to it was opened or in the
+    /// response to a request.
+    ///
+    /// If the peer has restarted, the sequence numbers of the messages to it start over.
+    pub fn learn(&self, peer: &str, incarnation: u64) {
+        let Some(peer) = self.peers.get_key_value(peer).map(|(peer, _)| *peer) else {
+            return;
+        };
+        let mut sent = self.sent.lock().unwrap();
+        let state = sent.entry(peer).or_default();
+        if state.incarnation != incarnation {
+            *state = Sent {
+                incarnation,
+                last: HashMap::new(),
+            };
+        }
+    }
+
+    /// Returns the incarnation of `peer` that messages are sealed for.
+    fn peer_incarnation(&self, peer: &str) -> u64 {
+        let sent = self.sent.lock().unwrap();
+        sent.get(peer).map_or(UNKNOWN, |state| state.incarnation)
+    }
+
+    /// Returns `true` if `envelope` from `from` is sealed for another incarnation of the location, so the sender
+    /// has to learn the current one and seal the message again.
+    pub fn is_stale(&self, from: &str, envelope: &[u8]) -> bool {
+        self.peers.contains_key(from)
+            && envelope.len() >= HEADER_LEN
+            && u64::from_be_bytes(envelope[8..16].try_into().unwrap()) != self.incarnation
+    }
+
+    /// Forgets the sequence numbers of every session, once every peer has said that it will not send any more
+    /// messages.
+    ///
+    /// A session can run again after its end-of-run barrier, and a message of the next run can arrive before the
+    /// receiver has finished the barrier, so the numbers are only dropped when the transport shuts down.
+    pub fn forget_sessions(&self) {
+        for state in self.sent.lock().unwrap().values_mut() {
+            state.last = HashMap::new();
+        }
+        for state in self.received.lock().unwrap().values_mut() {
+            state.last = HashMap::new();
+        }
+    }
+
+    /// Returns the number of sessions with sequence numbers, counted for each peer and direction.
+    #[cfg(test)]
+    pub fn sessions(&self) -> usize {
+        let sent = self.sent.lock().unwrap();
+        let received = self.received.lock().unwrap();
+        sent.values().map(|state| state.last.len()).sum::<usize>()
+            + received
+                .values()
+                .map(|state| state.last.len())
+                .sum::<usize>()
+    }
+
+    /// Computes the tag of `body` sent from `from` to `to` with `header`.
+    fn mac(
+        key: &[u8],
+        session: SessionId,
+        from: &str,
+        to: &str,
+        header: &[u8],
+        body: &[u8],
+    ) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+        for name in [from, to] {
+            mac.update(&(name.len() as u64).to_be_bytes());
+            mac.update(name.as_bytes());
+        }
+        mac.update(&session.to_be_bytes());
+        mac.update(header);
+        mac.update(body);
+        mac
+    }
+
+    /// Prepares `body` sent from `from` to `to` in `session` to be sealed.
+    ///
+    /// The message is sealed when its envelope is first needed. Messages to locations that are not peers are not
+    /// wrapped.
+    pub fn seal<'a>(
+        &'a self,
+        session: SessionId,
+        from: &'a str,
+        to: &'a str,
+        body: Vec<u8>,
+    ) -> Sealed<'a> {
+        Sealed {
+            envelopes: self,
+            session,
+            from,
+            to,
+            body,
+            envelope: None,
+        }
+    }
+
+    /// Wraps `body` with the next sequence number for the current incarnation of `to`, and returns the incarnation
+    /// with the envelope, or `None` if `to` is not a peer.
+    fn wrap(
+        &self,
+        session: SessionId,
+        from: &str,
+        to: &str,
+        body: &[u8],
+    ) -> Option<(u64, Vec<u8>)> {
+        let (peer, key) = self.peers.get_key_value(to)?;
+        let (incarnation, sequence) = {
+            let mut sent = self.sent.lock().unwrap();
+            let state = sent.entry(*peer).or_default();
+            let last = state.last.entry(session).or_insert(0);
+            *last += 1;
+            (state.incarnation, *last)
+        };
+        let mut envelope = Vec::with_capacity(HEADER_LEN + TAG_LEN + body.len());
+        envelope.extend_from_slice(&self.incarnation.to_be_bytes());
+        envelope.extend_from_slice(&incarnation.to_be_bytes());
+        envelope.extend_from_slice(&sequence.to_be_bytes());
+        if let Some(key) = key {
+            let tag = Self::mac(key, session, from, to, &envelope, body).finalize();
+            envelope.extend_from_slice(&tag.into_bytes());
+        }
+        envelope.extend_from_slice(body);
+        Some((incarnation, envelope))
+    }
+
+    /// Unwraps `envelope` received by `at` from `from` in `session`.
+    ///
+    /// Returns `None` if the message has already been accepted, is sealed for another incarnation of the location,
+    /// or comes from an earlier incarnation of `from`. Fails if the tag does not match, or if an earlier message from
+    /// `from` has not been delivered.
+    pub fn open(
+        &self,
+        session: SessionId,
+        from: &str,
+        at: &str,
+        envelope: Vec<u8>,
+    ) -> Result<Option<Vec<u8>>, TransportError> {
+        let Some((peer, key)) = self.peers.get_key_value(from) else {
+            return Ok(Some(envelope));
+        };
+        let unauthenticated = || TransportError::Unauthenticated(from.to_string());
+        if envelope.len() < HEADER_LEN + key.as_ref().map_or(0, |_| TAG_LEN) {
+            return Err(match key {
+                Some(_) => unauthenticated(),
+                None => TransportError::Deserialization(format!(
+                    "message from `{}` has no envelope",
+                    from
+                )),
+            });
+        }
+        let (header, rest) = envelope.split_at(HEADER_LEN);
+        let body = match key {
+            Some(key) => {
+                let (tag, body) = rest.split_at(TAG_LEN);
+                Self::mac(key, session, from, at, header, body)
+                    .verify_slice(tag)
+                    .map_err(|_| unauthenticated())?;
+                body
+            }
+            None => rest,
+        };
+        let field = |i: usize| u64::from_be_bytes(header[i * 8..(i + 1) * 8].try_into().unwrap());
+        let (incarnation, receiver, sequence) = (field(0), field(1), field(2));
+        if receiver != self.incarnation {
+            // sealed before the location started, or a replay of such a message
+            return Ok(None);
+        }
+
+        let mut received = self.received.lock().unwrap();
+        let state = received.entry(*peer).or_insert_with(|| Received {
+            incarnation,
+            retired: HashSet::new(),
+            last: HashMap::new(),
+        });
+        if incarnation != state.incarnation {
+            if state.retired.contains(&incarnation) {
+                return Ok(None);
+            }
+            // the peer has restarted
+            let retired = mem::replace(&mut state.incarnation, incarnation);
+            state.retired.insert(retired);
+            state.last.clear();
+        }
+        let expected = state.last.get(&session).map_or(1, |last| last + 1);
+        if sequence < expected {
+            // a duplicate, or a replay of a message with a valid tag
+            return Ok(None);
+        }
+        if sequence > expected {
+            return Err(TransportError::MessageLost(from.to_string()));
+        }
+        state.last.insert(session, sequence);
+        Ok(Some(body.to_vec()))
+    }
+}
+
+/// A message to a peer, sealed for the incarnation of the peer that the location knows of.
+pub(crate) struct Sealed<'a> {
+    envelopes: &'a Envelopes,
+    session: SessionId,
+    from: &'a str,
+    to: &'a str,
+    body: Vec<u8>,
+    /// The incarnation of the receiver that the message is sealed for, and its envelope.
+    envelope: Option<(u64, Vec<u8>)>,
+}
+
+impl Sealed<'_> {
+    /// Returns `true` if the message was sealed for another incarnation of the receiver than the one it last told.
+    pub fn is_stale(&self) -> bool {
+        self.envelope.as_ref().is_some_and(|(incarnation, _)| {
+            *incarnation != self.envelopes.peer_incarnation(self.to)
+        })
+    }
+
+    /// Returns the envelope of the message, sealing it again if the receiver has restarted since it was sealed.
+    ///
+    /// The envelope keeps its sequence number while the receiver runs the same incarnation, so that the receiver
+    /// discards a copy of the message that is delivered twice.
+    pub fn envelope(&mut self) -> &[u8] {
+        let current = self.envelopes.peer_incarnation(self.to);
+        if self
+            .envelope
+            .as_ref()
+            .is_none_or(|(incarnation, _)| *incarnation != current)
+        {
+            self.envelope = self
+                .envelopes
+                .wrap(self.session, self.from, self.to, &self.body);
+        }
+        match &self.envelope {
+            Some((_, envelope)) => envelope,
+            None => &self.body,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn envelopes(peer: &'static str, key: Option<&str>) -> Envelopes {
+        let keys = key.map(|key| (peer, key.as_bytes().to_vec()));
+        Envelopes::new([peer], keys.into_iter().collect())
+    }
+
+    /// Creates the envelopes of Alice and Bob, where Alice knows the incarnation of Bob.
+    fn pair(key: Option<&str>) -> (Envelopes, Envelopes) {
+        let alice = envelopes("Bob", key);
+        let bob = envelopes("Alice", key);
+        alice.learn("Bob", bob.incarnation());
+        (alice, bob)
+    }
+
+    /// Seals `body` from Alice to Bob in `session`.
+    fn seal(alice: &Envelopes, session: SessionId, body: &[u8]) -> Vec<u8> {
+        alice
+            .seal(session, "Alice", "Bob", body.to_vec())
+            .envelope()
+            .to_vec()
+    }
+
+    #[test]
+    fn test_envelopes() {
+        let (alice, bob) = pair(Some("secret"));
+        for (session, message) in [(0, "hello"), (1, "world"), (0, "again")] {
+            let envelope = seal(&alice, session, message.as_bytes());
+            let body = bob.open(session, "Alice", "Bob", envelope).unwrap();
+            assert_eq!(body.unwrap(), message.as_bytes());
+        }
+        // messages to and from locations that are not peers are not wrapped
+        let mut message = alice.seal(0, "Alice", "Carol", b"plain".to_vec());
+        assert_eq!(message.envelope(), b"plain");
+        assert_eq!(
+            bob.open(0, "Carol", "Bob", b"plain".to_vec()).unwrap(),
+            Some(b"plain".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_envelopes_forged() {
+        let (alice, bob) = pair(Some("secret"));
+        let mallory = envelopes("Bob", Some("guess"));
+        mallory.learn("Bob", bob.incarnation());
+
+        let envelope = seal(&mallory, 0, b"forged");
+        assert!(matches!(
+            bob.open(0, "Alice", "Bob", envelope),
+            Err(TransportError::Unauthenticated(name)) if name == "Alice"
+        ));
+
+        let mut envelope = seal(&alice, 0, b"tampered");
+        *envelope.last_mut().unwrap() ^= 1;
+        assert!(bob.open(0, "Alice", "Bob", envelope).is_err());
+
+        // the tag covers the session and the destination
+        let envelope = seal(&alice, 0, b"moved");
+        assert!(bob.open(1, "Alice", "Bob", envelope.clone()).is_err());
+        assert!(bob.open(0, "Alice", "Carol", envelope).is_err());
+    }
+
+    #[test]
+    fn test_envelopes_duplicated() {
+        for key in [None, Some("secret")] {
+            let (alice, bob) = pair(key);
+            let mut first = alice.seal(0, "Alice", "Bob", b"first".to_vec());
+            let copy = first.envelope().to_vec();
+            let second = seal(&alice, 0, b"second");
+            assert!(bob.open(0, "Alice", "Bob", copy).unwrap().is_some());
+            // a message keeps its envelope when it is sent again
+            let copy = first.envelope().to_vec();
+            assert!(bob.open(0, "Alice", "Bob", copy).unwrap().is_none());
+            assert!(bob
+                .open(0, "Alice", "Bob", second.clone())
+                .unwrap()
+                .is_some());
+            assert!(bob.open(0, "Alice", "Bob", second).unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn test_envelopes_lost() {
+        let (alice, bob) = pair(None);
+        let first = seal(&alice, 0, b"first");
+        let _second = seal(&alice, 0, b"second");
+        // sequence numbers are counted per session
+        let other = seal(&alice, 1, b"other");
+        let third = seal(&alice, 0, b"third");
+        assert!(bob.open(0, "Alice", "Bob", first).unwrap().is_some());
+        assert!(bob.open(1, "Alice", "Bob", other).unwrap().is_some());
+        assert!(matches!(
+            bob.open(0, "Alice", "Bob", third),
+            Err(TransportError::MessageLost(name)) if name == "Alice"
+        ));
+    }
+
+    #[test]
+    fn test_envelopes_unknown_receiver() {
+        let alice = envelopes("Bob", Some("secret"));
+        let bob = envelopes("Alice", Some("secret"));
+        // a message sealed before the sender knows the incarnation of the receiver is discarded
+        let mut message = alice.seal(0, "Alice", "Bob", b"early".to_vec());
+        let envelope = message.envelope().to_vec();
+        assert!(bob.is_stale("Alice", &envelope));
+        assert_eq!(bob.open(0, "Alice", "Bob", envelope).unwrap(), None);
+
+        alice.learn("Bob", bob.incarnation());
+        assert!(message.is_stale());
+        let envelope = message.envelope().to_vec();
+        assert!(!bob.is_stale("Alice", &envelope));
+        assert_eq!(
+            bob.open(0, "Alice", "Bob", envelope).unwrap(),
+            Some(b"early".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_envelopes_receiver_restarted() {
+        let (alice, bob) = pair(Some("secret"));
+        let mut captured = Vec::new();
+        for session in [0, 1] {
+            for v in [1, 2] {
+                let envelope = seal(&alice, session, &[v]);
+                captured.push((session, envelope.clone()));
+                assert!(bob
+                    .open(session, "Alice", "Bob", envelope)
+                    .unwrap()
+                    .is_some());
+            }
+        }
+        let mut pending = alice.seal(0, "Alice", "Bob", vec![3]);
+        let envelope = pending.envelope().to_vec();
+        captured.push((0, envelope));
+
+        // the restarted receiver discards every envelope that was sealed for its earlier incarnation
+        let bob = envelopes("Alice", Some("secret"));
+        for (session, envelope) in captured {
+            assert!(bob.is_stale("Alice", &envelope));
+            assert_eq!(bob.open(session, "Alice", "Bob", envelope).unwrap(), None);
+        }
+
+        // once the sender learns the new incarnation, sequence numbers start over in every session
+        alice.learn("Bob", bob.incarnation());
+        assert!(pending.is_stale());
+        let envelope = pending.envelope().to_vec();
+        assert!(!pending.is_stale());
+        assert_eq!(
+            bob.open(0, "Alice", "Bob", envelope).unwrap(),
+            Some(vec![3])
+        );
+        let envelope = seal(&alice, 1, &[1]);
+        assert_eq!(
+            bob.open(1, "Alice", "Bob", envelope).unwrap(),
+            Some(vec![1])
+        );
+        let _lost = seal(&alice, 0, &[4]);
+        let envelope = seal(&alice, 0, &[5]);
+        assert!(bob.open(0, "Alice", "Bob", envelope).is_err());
+    }
+
+    #[test]
+    fn test_envelopes_sender_restarted() {
+        let (alice, bob) = pair(None);
+        let envelope = seal(&alice, 0, &[1]);
+        assert!(bob.open(0, "Alice", "Bob", envelope).unwrap().is_some());
+        let stale = seal(&alice, 0, &[2]);
+
+        // a restarted sender starts over, and the messages of its earlier incarnation are discarded
+        let alice = envelopes("Bob", None);
+        alice.learn("Bob", bob.incarnation());
+        let envelope = seal(&alice, 0, &[1]);
+        assert_eq!(
+            bob.open(0, "Alice", "Bob", envelope).unwrap(),
+            Some(vec![1])
+        );
+        assert_eq!(bob.open(0, "Alice", "Bob", stale).unwrap(), None);
+        let _lost = seal(&alice, 0, &[2]);
+        let envelope = seal(&alice, 0, &[3]);
+        assert!(bob.open(0, "Alice", "Bob", envelope).is_err());
+    }
+
+    #[test]
+    fn test_envelopes_forget_sessions() {
+        let (alice, bob) = pair(Some("secret"));
+        for session in 0..10 {
+            let envelope = seal(&alice, session, &[1]);
+            assert!(bob
+                .open(session, "Alice", "Bob", envelope)
+                .unwrap()
+                .is_some());
+        }
+        assert_eq!(alice.sessions(), 10);
+        assert_eq!(bob.sessions(), 10);
+
+        alice.forget_sessions();
+        bob.forget_sessions();
+        assert_eq!(alice.sessions(), 0);
+        assert_eq!(bob.sessions(), 0);
+        // the incarnations are kept, and sequence numbers start over
+        let envelope = seal(&alice, 0, &[1]);
+        assert!(!bob.is_stale("Alice", &envelope));
+        assert_eq!(
+            bob.open(0, "Alice", "Bob", envelope).unwrap(),
+            Some(vec![1])
+        );
+    }
+
+    #[test]
+    fn test_incarnations_are_random() {
+        let incarnations: HashSet<u64> = (0..100)
+            .map(|_| envelopes("Bob", None).incarnation())
+            .collect();
+        assert_eq!(incarnations.len(), 100);
+        assert!(!incarnations.contains(&UNKNOWN));
+    }
+}
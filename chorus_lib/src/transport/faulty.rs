@@ -0,0 +1,500 @@
+//! A transport wrapper that injects faults.
+//!
+//! `FaultyTransport` wraps another transport and drops, delays, duplicates, reorders, or corrupts messages between
+//! chosen pairs of locations, so that the behavior of a choreography under partial failure can be tested without an
+//! unreliable network. Faults are drawn from a seeded pseudo-random number generator, so a location that runs the
+//! same choreography with the same seed sees the same faults.
+//!
+//! Most faults are injected above the wrapped transport, on typed values rather than on the bytes that it sends. They
+//! therefore show how a choreography copes with faults that reach it, not how the transport itself detects them:
+//! envelopes, sequence numbers, and authentication tags never see a dropped, reordered, or corrupted message.
+//! Duplicates are the exception: the wrapped transport sends the same sealed message twice with
+//! `Transport::send_duplicate`, so a transport that numbers its messages discards the copy.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::codec::Codec;
+use crate::core::{
+    ChoreographyLocation, LocationSet, Portable, SessionId, Transport, TransportError,
+};
+use crate::utils::rng::Rng;
+
+/// The faults injected into the messages between two locations.
+///
+/// Each probability is between `0.0` (never) and `1.0` (always). The default policy injects no faults.
+///
+/// # Examples
+///
+/// ```
+/// # use std::time::Duration;
+/// # use chorus_lib::transport::faulty::FaultPolicy;
+/// let policy = FaultPolicy::default()
+///     .with_drop(0.1)
+///     .with_delay(Duration::from_millis(50));
+/// ```
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FaultPolicy {
+    /// The probability that a message is never delivered
+    pub drop: f64,
+    /// The probability that a message is sent twice
+    pub duplicate: f64,
+    /// The probability that a message is delivered after the next message between the same locations
+    pub reorder: f64,
+    /// The probability that a bit of a received message is flipped
+    pub corrupt: f64,
+    /// The maximum time the sender waits before a message is sent
+    ///
+    /// Each message is delayed by a random duration up to this maximum.
+    pub delay: Option<Duration>,
+}
+
+impl FaultPolicy {
+    /// Sets the probability that a message is dropped.
+    pub fn with_drop(self, p: f64) -> Self {
+        Self { drop: p, ..self }
+    }
+
+    /// Sets the probability that a message is duplicated.
+    pub fn with_duplicate(self, p: f64) -> Self {
+        Self {
+            duplicate: p,
+            ..self
+        }
+    }
+
+    /// Sets the probability that a message is reordered.
+    pub fn with_reorder(self, p: f64) -> Self {
+        Self { reorder: p, ..self }
+    }
+
+    /// Sets the probability that a message is corrupted.
+    pub fn with_corrupt(self, p: f64) -> Self {
+        Self { corrupt: p, ..self }
+    }
+
+    /// Sets the maximum delay of a message.
+    pub fn with_delay(self, max: Duration) -> Self {
+        Self {
+            delay: Some(max),
+            ..self
+        }
+    }
+}
+
+/// Sends a message that was encoded with the codec of `transport`.
+///
+/// A held message is stored as its encoding together with this function instantiated for its type, so that it can be
+/// sent later without knowing the type.
+type Resend<T> = fn(&T, SessionId, &str, &str, &[u8]) -> Result<(), TransportError>;
+
+fn resend<
+    L: LocationSet,
+    TLocation: ChoreographyLocation,
+    T: Transport<L, TLocation>,
+    V: Portable,
+>(
+    transport: &T,
+    session: SessionId,
+    from: &str,
+    to: &str,
+    bytes: &[u8],
+) -> Result<(), TransportError> {
+    let data: V = transport.codec().decode(bytes)?;
+    transport.send(session, from, to, &data, None)
+}
+
+/// A message held back to be delivered after the next one.
+struct Held<T> {
+    from: String,
+    bytes: Vec<u8>,
+    resend: Resend<T>,
+}
+
+struct State<T> {
+    rng: Rng,
+    // held messages per (session, receiver)
+    held: HashMap<(SessionId, String), Held<T>>,
+}
+
+/// A transport that injects faults into the messages of another transport.
+///
+/// Drops, delays, duplicates, and reorders are applied when the wrapped location sends a message; corruption is
+/// applied when it receives one. To inject faults between two locations in both directions, wrap the transports of
+/// both.
+///
+/// A duplicate is sent with `Transport::send_duplicate`, which delivers the same sealed message twice, as a network
+/// would. A dropped message is never handed to the wrapped transport, so it leaves no gap in the sequence numbers and
+/// is not reported as `TransportError::MessageLost`. A corrupted message is decoded, re-encoded
+/// with a flipped bit, and decoded again after the transport has accepted it, so the receiver fails with
+/// `TransportError::Deserialization` or sees a damaged value, even on a transport whose messages are authenticated.
+///
+/// A reordered message is held back until the location sends the next message to the same receiver in the same
+/// session, until it waits for a message, as it does at the end-of-run barrier, or until the transport is shut down or
+/// dropped.
+///
+/// # Examples
+///
+/// ```
+/// # use chorus_lib::core::{ChoreographyLocation, LocationSet};
+/// # use chorus_lib::transport::local::{LocalTransport, LocalTransportChannelBuilder};
+/// use chorus_lib::transport::faulty::{FaultPolicy, FaultyTransport};
+/// #
+/// # #[derive(ChoreographyLocation)]
+/// # struct Alice;
+/// #
+/// # #[derive(ChoreographyLocation)]
+/// # struct Bob;
+/// #
+/// # let transport_channel = LocalTransportChannelBuilder::new().with(Alice).with(Bob).build();
+///
+/// let transport = FaultyTransport::new(LocalTransport::new(Alice, transport_channel.clone()), 42)
+///     .with_policy(Alice, Bob, FaultPolicy::default().with_drop(0.5));
+/// ```
+pub struct FaultyTransport<T> {
+    transport: T,
+    policies: HashMap<(&'static str, &'static str), FaultPolicy>,
+    default_policy: FaultPolicy,
+    state: Mutex<State<T>>,
+}
+
+impl<T> FaultyTransport<T> {
+    /// Wraps `transport`. Faults are drawn from a generator seeded with `seed`.
+    ///
+    /// No faults are injected until a policy is set.
+    pub fn new(transport: T, seed: u64) -> Self {
+        FaultyTransport {
+            transport,
+            policies: HashMap::new(),
+            default_policy: FaultPolicy::default(),
+            state: Mutex::new(State {
+                rng: Rng::new(seed),
+                held: HashMap::new(),
+            }),
+        }
+    }
+
+    /// Sets the policy for messages sent from `from` to `to`.
+    pub fn with_policy<From: ChoreographyLocation, To: ChoreographyLocation>(
+        mut self,
+        from: From,
+        to: To,
+        policy: FaultPolicy,
+    ) -> Self {
+        _ = (from, to);
+        self.policies.insert((From::name(), To::name()), policy);
+        self
+    }
+
+    /// Sets the policy for messages between locations that have no policy of their own.
+    pub fn with_default_policy(mut self, policy: FaultPolicy) -> Self {
+        self.default_policy = policy;
+        self
+    }
+
+    fn policy(&self, from: &str, to: &str) -> &FaultPolicy {
+        self.policies
+            .iter()
+            .find(|((f, t), _)| *f == from && *t == to)
+            .map_or(&self.default_policy, |(_, policy)| policy)
+    }
+
+    fn flush(
+        &self,
+        held: Option<Held<T>>,
+        session: SessionId,
+        to: &str,
+    ) -> Result<(), TransportError> {
+        match held {
+            Some(held) => (held.resend)(&self.transport, session, &held.from, to, &held.bytes),
+            None => Ok(()),
+        }
+    }
+
+    /// Delivers all held messages.
+    fn flush_all(&self) -> Result<(), TransportError> {
+        let held: Vec<_> = self.state.lock().unwrap().held.drain().collect();
+        let mut result = Ok(());
+        for ((session, to), held) in held {
+            result = result.and(self.flush(Some(held), session, &to));
+        }
+        result
+    }
+}
+
+impl<T> Drop for FaultyTransport<T> {
+    fn drop(&mut self) {
+        // a message held back at the end of the last run is late, not lost
+        let _ = self.flush_all();
+    }
+}
+
+impl<L: LocationSet, TLocation: ChoreographyLocation, T: Transport<L, TLocation>>
+    Transport<L, TLocation> for FaultyTransport<T>
+{
+    type Codec = T::Codec;
+
+    fn codec(&self) -> &T::Codec {
+        self.transport.codec()
+    }
+
+    fn locations(&self) -> Vec<&'static str> {
+        self.transport.locations()
+    }
+
+    fn send<V: Portable>(
+        &self,
+        session: SessionId,
+        from: &str,
+        to: &str,
+        data: &V,
+        deadline: Option<Instant>,
+    ) -> Result<(), TransportError> {
+        let policy = self.policy(from, to);
+        // the faults are drawn with the lock held, but the lock is released before sleeping and sending
+        let mut state = self.state.lock().unwrap();
+        if state.rng.chance(policy.drop) {
+            return Ok(());
+        }
+        let duplicate = state.rng.chance(policy.duplicate);
+        let key = (session, to.to_string());
+        if state.rng.chance(policy.reorder) {
+            let held = Held {
+                from: from.to_string(),
+                bytes: self.transport.codec().encode(data)?,
+                resend: resend::<L, TLocation, T, V>,
+            };
+            let previous = state.held.insert(key, held);
+            drop(state);
+            // the message that was held before goes first
+            return self.flush(previous, session, to);
+        }
+        let delay = policy
+            .delay
+            .map(|max| Duration::from_nanos(state.rng.below(max.as_nanos() as u64 + 1)));
+        let previous = state.held.remove(&key);
+        drop(state);
+        if let Some(delay) = delay {
+            thread::sleep(delay);
+        }
+        let sent = if duplicate {
+            self.transport
+                .send_duplicate(session, from, to, data, deadline)
+        } else {
+            self.transport.send(session, from, to, data, deadline)
+        };
+        sent.and(self.flush(previous, session, to))
+    }
+
+    fn receive<V: Portable>(
+        &self,
+        session: SessionId,
+        from: &str,
+        at: &str,
+        deadline: Option<Instant>,
+    ) -> Result<V, TransportError> {
+        // deliver held messages before waiting, since the receiver may wait for them
+        self.flush_all()?;
+        let data: V = self.transport.receive(session, from, at, deadline)?;
+        let policy = self.policy(from, at);
+        let codec = self.transport.codec();
+        let mut state = self.state.lock().unwrap();
+        if !state.rng.chance(policy.corrupt) {
+            return Ok(data);
+        }
+        let mut bytes = codec.encode(&data)?;
+        if bytes.is_empty() {
+            return Ok(data);
+        }
+        let bit = state.rng.below(bytes.len() as u64 * 8);
+        drop(state);
+        bytes[(bit / 8) as usize] ^= 1 << (bit % 8);
+        codec.decode(&bytes)
+    }
+
+    fn wait_ready(&self, timeout: Duration) -> Result<(), TransportError> {
+        self.transport.wait_ready(timeout)
+    }
+
+    fn shutdown(&self, timeout: Duration) -> Result<(), TransportError> {
+        // held messages must reach the peers before they are told that no more messages follow
+        self.flush_all()?;
+        self.transport.shutdown(timeout)
+    }
+
+    fn unconsumed(&self, session: SessionId) -> HashMap<&'static str, usize> {
+        self.transport.unconsumed(session)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{ChoreoOp, Choreography, ChoreographyError, Projector};
+    use crate::transport::local::{
+        LocalTransport, LocalTransportChannel, LocalTransportChannelBuilder,
+    };
+    use crate::transport::tcp::{TcpTransport, TcpTransportConfigBuilder};
+
+    #[derive(ChoreographyLocation)]
+    struct Alice;
+
+    #[derive(ChoreographyLocation)]
+    struct Bob;
+
+    fn channel() -> LocalTransportChannel<LocationSet!(Bob, Alice)> {
+        LocalTransportChannelBuilder::new()
+            .with(Alice)
+            .with(Bob)
+            .build()
+    }
+
+    fn receive_all<L: LocationSet>(transport: &impl Transport<L, Bob>) -> Vec<i32> {
+        let mut values = Vec::new();
+        while let Ok(v) = transport.receive::<i32>(
+            0,
+            Alice::name(),
+            Bob::name(),
+            Some(Instant::now() + Duration::from_millis(20)),
+        ) {
+            values.push(v);
+        }
+        values
+    }
+
+    #[test]
+    fn test_no_faults() {
+        let channel = channel();
+        let alice = FaultyTransport::new(LocalTransport::new(Alice, channel.clone()), 0);
+        let bob = LocalTransport::new(Bob, channel);
+        for v in 0..10 {
+            alice.send(0, Alice::name(), Bob::name(), &v, None).unwrap();
+        }
+        assert_eq!(receive_all(&bob), (0..10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_drop_and_duplicate() {
+        let channel = channel();
+        let alice = FaultyTransport::new(LocalTransport::new(Alice, channel.clone()), 0)
+            .with_policy(Alice, Bob, FaultPolicy::default().with_drop(1.0));
+        let bob = LocalTransport::new(Bob, channel.clone());
+        alice.send(0, Alice::name(), Bob::name(), &1, None).unwrap();
+        assert!(receive_all(&bob).is_empty());
+
+        let alice = FaultyTransport::new(LocalTransport::new(Alice, channel), 0)
+            .with_default_policy(FaultPolicy::default().with_duplicate(1.0));
+        alice.send(0, Alice::name(), Bob::name(), &1, None).unwrap();
+        assert_eq!(receive_all(&bob), [1, 1]);
+    }
+
+    #[test]
+    fn test_duplicate_is_sealed_once() {
+        let config = TcpTransportConfigBuilder::for_target(Alice, ("0.0.0.0", 9190))
+            .with(Bob, ("localhost", 9191))
+            .build();
+        let alice = FaultyTransport::new(TcpTransport::new(config).unwrap(), 0).with_policy(
+            Alice,
+            Bob,
+            FaultPolicy::default().with_duplicate(1.0),
+        );
+        let config = TcpTransportConfigBuilder::for_target(Bob, ("0.0.0.0", 9191))
+            .with(Alice, ("localhost", 9190))
+            .build();
+        let bob = TcpTransport::new(config).unwrap();
+        for v in 0..3 {
+            alice.send(0, Alice::name(), Bob::name(), &v, None).unwrap();
+        }
+        // Bob discards the second copy of each message like one that the network delivers twice
+        assert_eq!(receive_all(&bob), [0, 1, 2]);
+        assert!(bob.unconsumed(0).is_empty());
+    }
+
+    #[test]
+    fn test_held_message_is_sent_on_drop() {
+        let channel = channel();
+        let alice = FaultyTransport::new(LocalTransport::new(Alice, channel.clone()), 0)
+            .with_policy(Alice, Bob, FaultPolicy::default().with_reorder(1.0));
+        let bob = LocalTransport::new(Bob, channel);
+        alice.send(0, Alice::name(), Bob::name(), &1, None).unwrap();
+        assert!(receive_all(&bob).is_empty());
+        drop(alice);
+        assert_eq!(receive_all(&bob), [1]);
+    }
+
+    #[test]
+    fn test_reorder() {
+        let channel = channel();
+        let alice = FaultyTransport::new(LocalTransport::new(Alice, channel.clone()), 1)
+            .with_policy(Alice, Bob, FaultPolicy::default().with_reorder(0.5));
+        let bob = LocalTransport::new(Bob, channel);
+        for v in 0..20 {
+            alice.send(0, Alice::name(), Bob::name(), &v, None).unwrap();
+        }
+        // the last held message is delivered when Alice waits for a message
+        let _ = alice.receive::<i32>(0, Bob::name(), Alice::name(), Some(Instant::now()));
+        let mut values = receive_all(&bob);
+        assert_ne!(values, (0..20).collect::<Vec<_>>());
+        values.sort();
+        assert_eq!(values, (0..20).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_faults_are_deterministic() {
+        let run = |seed| {
+            let channel = channel();
+            let alice = FaultyTransport::new(LocalTransport::new(Alice, channel.clone()), seed)
+                .with_policy(
+                    Alice,
+                    Bob,
+                    FaultPolicy::default()
+                        .with_drop(0.2)
+                        .with_duplicate(0.2)
+                        .with_reorder(0.2),
+                );
+            let bob = LocalTransport::new(Bob, channel);
+            for v in 0..50 {
+                alice.send(0, Alice::name(), Bob::name(), &v, None).unwrap();
+            }
+            let _ = alice.receive::<i32>(0, Bob::name(), Alice::name(), Some(Instant::now()));
+            receive_all(&bob)
+        };
+        assert_eq!(run(7), run(7));
+        assert_ne!(run(7), run(8));
+    }
+
+    struct Ping;
+
+    impl Choreography<String> for Ping {
+        type L = LocationSet!(Alice, Bob);
+        fn run(self, op: &impl ChoreoOp<Self::L>) -> String {
+            let x = op.locally(Alice, |_| "ping".to_string());
+            op.broadcast(Alice, x)
+        }
+    }
+
+    #[test]
+    fn test_corrupt() {
+        let channel = channel();
+        let alice = Projector::new(Alice, LocalTransport::new(Alice, channel.clone()));
+        let bob = Projector::new(
+            Bob,
+            FaultyTransport::new(LocalTransport::new(Bob, channel), 0).with_policy(
+                Alice,
+                Bob,
+                FaultPolicy::default().with_corrupt(1.0),
+            ),
+        );
+        alice.epp_and_run(Ping).unwrap();
+        match bob.epp_and_run(Ping) {
+            Ok(msg) => assert_ne!(msg, "ping"),
+            Err(err) => assert!(matches!(
+                err,
+                ChoreographyError::Transport(TransportError::Deserialization(_))
+                    | ChoreographyError::ProtocolMismatch { .. }
+            )),
+        }
+    }
+}
@@ -0,0 +1,510 @@
+//! Framed message streams shared by the stream-based transports.
+//!
+//! Every connection starts with a handshake frame that names the connecting location, which the accepting location
+//! answers with a frame that holds its incarnation as a big-endian `u64` (see `Envelopes`). Each following frame from
+//! the connecting location carries one message: the session id as a big-endian `u64`, followed by the envelope of the
+//! message. A frame is prefixed with its length as a big-endian `u32`. An empty frame says that the connecting
+//! location will not send any more messages.
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use retry::OperationResult;
+
+use crate::core::{LocationSet, SessionId, TransportError};
+use crate::transport::envelope::{Envelopes, Opened, Sealed};
+use crate::transport::{check_consumed, retry_until, wait_ready, Backoff, Departures};
+use crate::utils::queue::QueueMap;
+
+/// Messages are queued per (session, source).
+type Key = (SessionId, &'static str);
+
+/// A bidirectional byte stream.
+pub(crate) trait Connection: Read + Write + Send + Sized + 'static {
+    fn try_clone(&self) -> io::Result<Self>;
+    fn shutdown(&self) -> io::Result<()>;
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+/// How long a connecting location waits for the accepting location to answer the handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Accepts incoming connections.
+pub(crate) trait Listener: Send + 'static {
+    type Connection: Connection;
+    fn accept(&self) -> io::Result<Self::Connection>;
+}
+
+/// Writes `payload` prefixed with its length as a big-endian `u32`.
+fn write_frame(stream: &mut impl Write, payload: &[u8]) -> io::Result<()> {
+    let len = u32::try_from(payload.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "frame is too large"))?;
+    let mut frame = Vec::with_capacity(4 + payload.len());
+    frame.extend_from_slice(&len.to_be_bytes());
+    frame.extend_from_slice(payload);
+    stream.write_all(&frame)
+}
+
+/// Reads a frame written by `write_frame`.
+///
+/// Fails without reading the payload if it is longer than `max_len`.
+fn read_frame(stream: &mut impl Read, max_len: usize) -> io::Result<Vec<u8>> {
+    let mut len = [0; 4];
+    stream.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > max_len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "frame is too large",
+        ));
+    }
+    let mut payload = vec![0; len];
+    stream.read_exact(&mut payload)?;
+    Ok(payload)
+}
+
+/// An outgoing connection, which is marked as broken when a write fails or the peer closes it.
+struct Outgoing<C: Connection> {
+    stream: C,
+    broken: Arc<AtomicBool>,
+}
+
+impl<C: Connection> Outgoing<C> {
+    /// Watches `stream` for the peer closing it.
+    ///
+    /// Writes to a connection that the peer has closed can succeed locally, so a write error alone would lose the
+    /// first message after the peer restarts. Peers only write the answer to the handshake to a connection they
+    /// accepted, so once it has been read, a read on the connection only returns when the connection is closed.
+    fn new(stream: C) -> io::Result<Self> {
+        let mut watched = stream.try_clone()?;
+        let broken = Arc::new(AtomicBool::new(false));
+        let watcher = broken.clone();
+        thread::spawn(move || {
+            let _ = watched.read(&mut [0; 1]);
+            watcher.store(true, Ordering::SeqCst);
+        });
+        Ok(Outgoing { stream, broken })
+    }
+
+    /// Writes `payload` as a frame, failing without writing if the connection is known to be broken.
+    fn write_frame(&mut self, payload: &[u8]) -> io::Result<()> {
+        if self.broken.load(Ordering::SeqCst) {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        write_frame(&mut self.stream, payload).inspect_err(|_| {
+            self.broken.store(true, Ordering::SeqCst);
+        })
+    }
+}
+
+impl<C: Connection> Drop for Outgoing<C> {
+    fn drop(&mut self) {
+        // also stops the watcher
+        let _ = self.stream.shutdown();
+    }
+}
+
+/// The accepted connections that are still being served, so that they can be closed when the endpoint closes.
+pub(crate) struct Incoming<C> {
+    streams: Mutex<(u64, HashMap<u64, C>)>,
+}
+
+impl<C: Connection> Incoming<C> {
+    pub fn new() -> Self {
+        Incoming {
+            streams: Mutex::new((0, HashMap::new())),
+        }
+    }
+
+    /// Keeps a clone of `stream` until the returned guard is dropped, which the thread that serves `stream` does
+    /// when it stops.
+    pub fn track(self: &Arc<Self>, stream: &C) -> Tracked<C> {
+        let mut streams = self.streams.lock().unwrap();
+        let id = streams.0;
+        streams.0 += 1;
+        if let Ok(clone) = stream.try_clone() {
+            streams.1.insert(id, clone);
+        }
+        Tracked {
+            incoming: self.clone(),
+            id,
+        }
+    }
+
+    /// Returns the number of connections that are still being served.
+    pub fn len(&self) -> usize {
+        self.streams.lock().unwrap().1.len()
+    }
+
+    /// Calls `close` on every connection that is still being served.
+    pub fn close_all(&self, close: impl Fn(&C)) {
+        for (_, stream) in self.streams.lock().unwrap().1.drain() {
+            close(&stream);
+        }
+    }
+}
+
+/// Removes a connection from `Incoming` when dropped.
+pub(crate) struct Tracked<C: Connection> {
+    incoming: Arc<Incoming<C>>,
+    id: u64,
+}
+
+impl<C: Connection> Drop for Tracked<C> {
+    fn drop(&mut self) {
+        self.incoming.streams.lock().unwrap().1.remove(&self.id);
+    }
+}
+
+/// Reads the handshake and then every message sent to `at` over an incoming connection.
+///
+/// The handshake is answered with the incarnation of `at`. The envelope of each message is opened as it arrives, so
+/// that a message that is delivered twice is only queued once. The connection is closed if a message is longer than
+/// `max_message_size`.
+fn serve_connection<L: LocationSet>(
+    mut stream: impl Connection,
+    queue_map: &QueueMap<Key, Opened>,
+    envelopes: &Envelopes,
+    at: &str,
+    departures: &Departures,
+    max_message_size: usize,
+) {
+    // the first frame is the name of the connecting location
+    let src = match read_frame(&mut stream, max_message_size) {
+        Ok(name) => L::to_string_list()
+            .into_iter()
+            .find(|loc| loc.as_bytes() == name.as_slice()),
+        Err(_) => None,
+    };
+    let answered =
+        src.is_some() && write_frame(&mut stream, &envelopes.incarnation().to_be_bytes()).is_ok();
+    let (Some(src), true) = (src, answered) else {
+        let _ = stream.shutdown();
+        return;
+    };
+    // the peer closes the connection when it is dropped or reconnects
+    // a message frame starts with the session
+    while let Ok(frame) = read_frame(&mut stream, max_message_size.saturating_add(8)) {
+        if frame.is_empty() {
+            departures.depart(src);
+            break;
+        }
+        if frame.len() < 8 {
+            break;
+        }
+        let (session, body) = frame.split_at(8);
+        let session = SessionId::from_be_bytes(session.try_into().unwrap());
+        if let Some(opened) = envelopes.open(session, src, at, body.to_vec()).transpose() {
+            queue_map.push((session, src), opened);
+        }
+    }
+}
+
+/// One end of a set of framed connections: a listener for incoming messages and a persistent outgoing connection
+/// per peer.
+pub(crate) struct Endpoint<L: LocationSet, C: Connection> {
+    streams: HashMap<&'static str, Mutex<Option<Outgoing<C>>>>,
+    incoming: Arc<Incoming<C>>,
+    closed: Arc<AtomicBool>,
+    join_handle: Option<thread::JoinHandle<()>>,
+    queue_map: Arc<QueueMap<Key, Opened>>,
+    departures: Arc<Departures>,
+    envelopes: Arc<Envelopes>,
+    backoff: Backoff,
+    location_set: PhantomData<L>,
+}
+
+impl<L: LocationSet, C: Connection> Endpoint<L, C> {
+    /// Starts accepting connections to `at` on `listener`, opening incoming messages with `envelopes`. Messages can
+    /// be sent to `peers`, which are reconnected to with `backoff`. Incoming messages longer than
+    /// `max_message_size` are rejected.
+    pub fn new(
+        listener: impl Listener<Connection = C>,
+        peers: impl Iterator<Item = &'static str>,
+        envelopes: Arc<Envelopes>,
+        at: &'static str,
+        backoff: Backoff,
+        max_message_size: usize,
+    ) -> Self {
+        let queue_map: Arc<QueueMap<Key, Opened>> = Arc::new(QueueMap::new());
+        let incoming = Arc::new(Incoming::new());
+        let closed = Arc::new(AtomicBool::new(false));
+        let departures = Arc::new(Departures::default());
+
+        let join_handle = Some({
+            let queue_map = queue_map.clone();
+            let incoming = incoming.clone();
+            let closed = closed.clone();
+            let departures = departures.clone();
+            let envelopes = envelopes.clone();
+
+            thread::spawn(move || loop {
+                let stream = listener.accept();
+                if closed.load(Ordering::SeqCst) {
+                    break;
+                }
+                let Ok(stream) = stream else {
+                    continue;
+                };
+                let tracked = incoming.track(&stream);
+                let queue_map = queue_map.clone();
+                let envelopes = envelopes.clone();
+                let departures = departures.clone();
+                thread::spawn(move || {
+                    let _tracked = tracked;
+                    serve_connection::<L>(
+                        stream,
+                        &queue_map,
+                        &envelopes,
+                        at,
+                        &departures,
+                        max_message_size,
+                    )
+                });
+            })
+        });
+
+        Endpoint {
+            streams: peers.map(|peer| (peer, Mutex::new(None))).collect(),
+            incoming,
+            closed,
+            join_handle,
+            queue_map,
+            departures,
+            envelopes,
+            backoff,
+            location_set: PhantomData,
+        }
+    }
+
+    /// Opens a new connection to `to` with `connect`, announces `from` on it, and learns the incarnation of `to` from
+    /// its answer.
+    fn open(
+        &self,
+        from: &str,
+        to: &str,
+        connect: impl Fn() -> io::Result<C>,
+    ) -> io::Result<Outgoing<C>> {
+        let mut stream = connect()?;
+        write_frame(&mut stream, from.as_bytes())?;
+        stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        let incarnation = read_frame(&mut stream, 8)?;
+        let incarnation = incarnation
+            .try_into()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid handshake"))?;
+        stream.set_read_timeout(None)?;
+        self.envelopes.learn(to, u64::from_be_bytes(incarnation));
+        Outgoing::new(stream)
+    }
+
+    /// Opens a connection to `to` unless one is already open.
+    ///
+    /// Returns `OperationResult::Retry` if `to` is not listening yet.
+    fn connect(
+        &self,
+        from: &str,
+        to: &str,
+        connect: impl Fn() -> io::Result<C>,
+    ) -> OperationResult<(), TransportError> {
+        let Some(stream) = self.streams.get(to) else {
+            return OperationResult::Err(TransportError::UnknownLocation(to.to_string()));
+        };
+        let mut stream = stream.lock().unwrap();
+        if stream
+            .as_ref()
+            .is_some_and(|s| !s.broken.load(Ordering::SeqCst))
+        {
+            return OperationResult::Ok(());
+        }
+        match self.open(from, to, connect) {
+            Ok(s) => {
+                *stream = Some(s);
+                OperationResult::Ok(())
+            }
+            Err(err) => OperationResult::Retry(TransportError::Io(err)),
+        }
+    }
+
+    /// Opens a connection to every peer, announcing `from`, and waits for at most `timeout` for the peers to listen.
+    ///
+    /// `connect` opens a new connection to the given peer.
+    pub fn wait_ready(
+        &self,
+        from: &str,
+        timeout: Duration,
+        connect: impl Fn(&str) -> io::Result<C>,
+    ) -> Result<(), TransportError> {
+        wait_ready(
+            self.streams.keys().copied(),
+            timeout,
+            self.backoff,
+            |peer| self.connect(from, peer, || connect(peer)),
+        )
+    }
+
+    /// Tells every peer that `from` will not send any more messages, closes the outgoing connections, and waits
+    /// until every peer has said the same, for at most `timeout` in total. The sequence numbers of the sessions are
+    /// then forgotten.
+    ///
+    /// `connect` opens a new connection to the given peer. Fails with `TransportError::Unconsumed` if messages
+    /// from a peer are still queued.
+    pub fn shutdown(
+        &self,
+        from: &str,
+        timeout: Duration,
+        connect: impl Fn(&str) -> io::Result<C>,
+    ) -> Result<(), TransportError> {
+        let deadline = Instant::now().checked_add(timeout);
+        self.wait_ready(from, timeout, connect)?;
+        for stream in self.streams.values() {
+            if let Some(mut stream) = stream.lock().unwrap().take() {
+                stream.write_frame(&[])?;
+            }
+        }
+        self.departures
+            .wait(self.streams.keys().copied(), deadline)?;
+        self.envelopes.forget_sessions();
+        check_consumed(
+            self.queue_map
+                .pending()
+                .into_iter()
+                .map(|((_, src), _)| src),
+        )
+    }
+
+    /// Returns the number of messages from each peer in `session` that were never received.
+    pub fn unconsumed(&self, session: SessionId) -> HashMap<&'static str, usize> {
+        self.queue_map
+            .pending()
+            .into_iter()
+            .filter(|((s, _), _)| *s == session)
+            .map(|((_, src), len)| (src, len))
+            .collect()
+    }
+
+    /// Sends `message` to `to` in `session`.
+    ///
+    /// `connect` opens a new connection to `to`. It is called, with retries, if there is no open connection to `to`
+    /// or the connection is broken. `from` is announced to the peer on every new connection, and the message is
+    /// sealed again if the peer has restarted. Fails with `TransportError::Timeout` if `to` cannot be reached before
+    /// `deadline`.
+    pub fn send(
+        &self,
+        session: SessionId,
+        from: &str,
+        to: &str,
+        message: &mut Sealed,
+        deadline: Option<Instant>,
+        connect: impl Fn() -> io::Result<C>,
+    ) -> Result<(), TransportError> {
+        let stream = self
+            .streams
+            .get(to)
+            .ok_or_else(|| TransportError::UnknownLocation(to.to_string()))?;
+        let frame = |message: &mut Sealed| {
+            let mut frame = session.to_be_bytes().to_vec();
+            frame.extend_from_slice(message.envelope());
+            frame
+        };
+
+        if let Some(s) = stream.lock().unwrap().as_mut() {
+            if s.write_frame(&frame(message)).is_ok() {
+                return Ok(());
+            }
+        }
+        // the connection has not been opened yet or is broken
+        // the lock is not held while retrying, so that a peer that is down does not block other sessions for long
+        retry_until(self.backoff.delays(), deadline, || {
+            let opened = self.open(from, to, &connect).and_then(|mut s| {
+                s.write_frame(&frame(message))?;
+                Ok(s)
+            });
+            match opened {
+                Ok(s) => {
+                    *stream.lock().unwrap() = Some(s);
+                    OperationResult::Ok(())
+                }
+                Err(err) => OperationResult::Retry(TransportError::Io(err)),
+            }
+        })
+    }
+
+    /// Receives the next message from `from` in `session`, waiting until `deadline` at most.
+    ///
+    /// Fails with the error of the envelope if the message was rejected when it arrived.
+    pub fn receive(
+        &self,
+        session: SessionId,
+        from: &str,
+        deadline: Option<Instant>,
+    ) -> Result<Vec<u8>, TransportError> {
+        let from = L::to_string_list()
+            .into_iter()
+            .find(|loc| *loc == from)
+            .ok_or_else(|| TransportError::UnknownLocation(from.to_string()))?;
+        self.queue_map
+            .pop((session, from), deadline)
+            .ok_or(TransportError::Timeout)?
+    }
+
+    /// Stops accepting connections and closes the incoming ones.
+    ///
+    /// `wake` must connect to the listener so that a pending `accept` returns.
+    pub fn close(&mut self, wake: impl FnOnce()) {
+        self.closed.store(true, Ordering::SeqCst);
+        wake();
+        self.join_handle.take().map(thread::JoinHandle::join);
+        self.incoming.close_all(|stream| {
+            let _ = stream.shutdown();
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::net::{TcpListener, TcpStream};
+
+    use super::*;
+
+    #[test]
+    fn test_read_frame_limit() {
+        let mut stream = Vec::new();
+        write_frame(&mut stream, &[1, 2, 3]).unwrap();
+        assert_eq!(read_frame(&mut Cursor::new(&stream), 3).unwrap(), [1, 2, 3]);
+        // the payload is not allocated, whatever length the peer announces
+        let mut stream = Cursor::new(u32::MAX.to_be_bytes());
+        let err = read_frame(&mut stream, 3).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_connections_are_released() {
+        let listener = TcpListener::bind("localhost:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+
+        // an accepted connection is only kept while it is served
+        let incoming = Arc::new(Incoming::new());
+        let tracked = incoming.track(&server);
+        assert_eq!(incoming.streams.lock().unwrap().1.len(), 1);
+        drop(tracked);
+        assert!(incoming.streams.lock().unwrap().1.is_empty());
+
+        // an outgoing connection is broken once the peer closes it
+        let mut outgoing = Outgoing::new(client).unwrap();
+        outgoing.write_frame(&[1]).unwrap();
+        drop(server);
+        let start = Instant::now();
+        while !outgoing.broken.load(Ordering::SeqCst) {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(outgoing.write_frame(&[2]).is_err());
+    }
+}
@@ -1,24 +1,48 @@
 //! The HTTP transport.
+//!
+//! By default, messages are posted over plain HTTP and the receiver trusts the source location named in the
+//! request. In HTTPS mode, every location has a certificate issued to its name: peers authenticate each other with
+//! these certificates, and a request is only accepted if the client certificate is issued to the source it claims.
 
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, io, sync::Arc};
 
 use std::marker::PhantomData;
+use std::time::{Duration, Instant};
 
-use retry::{
-    delay::{jitter, Fixed},
-    retry,
+use retry::{delay::jitter, OperationResult};
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    client::WebPkiServerVerifier,
+    crypto::ring,
+    pki_types::{pem, pem::PemObject, CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+    server::WebPkiClientVerifier,
+    CertificateError, ClientConfig, DigitallySignedStruct, RootCertStore, ServerConfig,
+    ServerConnection, SignatureScheme, StreamOwned,
 };
 use tiny_http::Server;
 use ureq::{Agent, AgentBuilder};
 
 use crate::{
-    core::{ChoreographyLocation, LocationSet, Member, Portable, Transport},
-    transport::{TransportConfig, TransportConfigBuilder},
-    utils::queue::BlockingQueue,
+    codec::{Codec, Json},
+    core::{
+        ChoreographyLocation, LocationSet, Member, Portable, SessionId, Transport, TransportError,
+    },
+    transport::envelope::{Envelopes, Opened, Sealed},
+    transport::framed::Incoming,
+    transport::{
+        check_consumed, receive_deadline, retry_until, send_deadline, wait_ready, Backoff,
+        Departures, TransportConfig, TransportConfigBuilder,
+    },
+    utils::queue::QueueMap,
 };
 
-type QueueMap = HashMap<&'static str, BlockingQueue<String>>;
+/// Messages are queued per (session, source).
+type Key = (SessionId, &'static str);
 
 /// Config for `HttpTransport`.
 pub type HttpTransportConfig<'a, L, Target> =
@@ -48,106 +72,872 @@ pub type HttpTransportConfigBuilder<'a, Target, L> =
 /// The header name for the source location.
 const HEADER_SRC: &str = "X-CHORUS-SOURCE";
 
+/// The header name for the session.
+const HEADER_SESSION: &str = "X-CHORUS-SESSION";
+
+/// The header name that marks a request that only checks that the receiver is listening.
+const HEADER_READY: &str = "X-CHORUS-READY";
+
+/// The header name that marks a request that announces that the source will not send any more messages.
+const HEADER_SHUTDOWN: &str = "X-CHORUS-SHUTDOWN";
+
+/// The header name for the incarnation of the receiver, which every response carries (see `Envelopes`).
+const HEADER_INCARNATION: &str = "X-CHORUS-INCARNATION";
+
+/// The status and reason of a response.
+type Status = (u16, &'static str);
+
+const OK: Status = (200, "OK");
+const BAD_REQUEST: Status = (400, "Bad Request");
+const FORBIDDEN: Status = (403, "Forbidden");
+/// The message is sealed for an earlier incarnation of the receiver.
+const CONFLICT: Status = (409, "Conflict");
+const PAYLOAD_TOO_LARGE: Status = (413, "Payload Too Large");
+
+/// The maximum length of the request line or of a header line of a request, including the line break.
+const MAX_LINE_LENGTH: usize = 8 * 1024;
+
+/// The maximum number of headers of a request.
+const MAX_HEADERS: usize = 64;
+
+/// The maximum number of HTTPS connections that are served at the same time.
+const MAX_CONNECTIONS: usize = 64;
+
+/// How long an HTTPS connection may stall during the handshake or while a request is read or a response is written.
+///
+/// A connection that stays idle for longer is closed, and the client opens a new one for its next request.
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The certificates of a location for the HTTPS mode of `HttpTransport`.
+///
+/// The certificate of a location must be issued to its name, as returned by `ChoreographyLocation::name()`, and to
+/// the hostnames that peers use to reach it: both are listed as DNS names in the subject alternative names.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use chorus_lib::core::{LocationSet, ChoreographyLocation};
+/// # use chorus_lib::transport::http::{HttpTransport, HttpTransportConfigBuilder, TlsConfig};
+/// #
+/// # #[derive(ChoreographyLocation)]
+/// # struct Alice;
+/// #
+/// # #[derive(ChoreographyLocation)]
+/// # struct Bob;
+/// #
+/// let transport_config = HttpTransportConfigBuilder::for_target(Alice, ("0.0.0.0", 9010))
+///   .with(Bob, ("bob.example.com", 443))
+///   .build();
+/// let tls = TlsConfig::new("alice.pem", "alice.key", "ca.pem");
+/// let transport = HttpTransport::with_tls(transport_config, &tls).unwrap();
+/// ```
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    certificate: PathBuf,
+    private_key: PathBuf,
+    trusted: PathBuf,
+}
+
+impl TlsConfig {
+    /// Creates a new `TlsConfig` from PEM files.
+    ///
+    /// `certificate` holds the certificate chain of the location, starting with its own certificate, and
+    /// `private_key` holds its key. The certificates of peers must chain to one of the certificates in `trusted`.
+    pub fn new(
+        certificate: impl AsRef<Path>,
+        private_key: impl AsRef<Path>,
+        trusted: impl AsRef<Path>,
+    ) -> Self {
+        TlsConfig {
+            certificate: certificate.as_ref().to_path_buf(),
+            private_key: private_key.as_ref().to_path_buf(),
+            trusted: trusted.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Loads the certificates into a server config and a client config for each of `peers`.
+    fn load(&self, peers: impl Iterator<Item = &'static str>) -> io::Result<Tls> {
+        let chain = CertificateDer::pem_file_iter(&self.certificate)
+            .map_err(pem_error)?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(pem_error)?;
+        let key = PrivateKeyDer::from_pem_file(&self.private_key).map_err(pem_error)?;
+        let mut roots = RootCertStore::empty();
+        for certificate in CertificateDer::pem_file_iter(&self.trusted).map_err(pem_error)? {
+            roots
+                .add(certificate.map_err(pem_error)?)
+                .map_err(invalid_data)?;
+        }
+        let roots = Arc::new(roots);
+        let provider = Arc::new(ring::default_provider());
+
+        let client_verifier =
+            WebPkiClientVerifier::builder_with_provider(roots.clone(), provider.clone())
+                .build()
+                .map_err(invalid_data)?;
+        let server = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(invalid_data)?
+            .with_client_cert_verifier(client_verifier)
+            .with_single_cert(chain.clone(), key.clone_key())
+            .map_err(invalid_data)?;
+
+        let clients = peers
+            .map(|peer| {
+                let verifier = PeerVerifier {
+                    inner: WebPkiServerVerifier::builder_with_provider(
+                        roots.clone(),
+                        provider.clone(),
+                    )
+                    .build()
+                    .map_err(invalid_data)?,
+                    peer,
+                };
+                let client = ClientConfig::builder_with_provider(provider.clone())
+                    .with_safe_default_protocol_versions()
+                    .map_err(invalid_data)?
+                    .dangerous()
+                    .with_custom_certificate_verifier(Arc::new(verifier))
+                    .with_client_auth_cert(chain.clone(), key.clone_key())
+                    .map_err(invalid_data)?;
+                Ok((peer, Arc::new(client)))
+            })
+            .collect::<io::Result<_>>()?;
+
+        Ok(Tls {
+            server: Arc::new(server),
+            clients,
+        })
+    }
+}
+
+/// The loaded TLS configuration of a location.
+struct Tls {
+    server: Arc<ServerConfig>,
+    clients: HashMap<&'static str, Arc<ClientConfig>>,
+}
+
+fn pem_error(err: pem::Error) -> io::Error {
+    match err {
+        pem::Error::Io(err) => err,
+        err => invalid_data(err),
+    }
+}
+
+fn invalid_data(err: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+}
+
+/// Returns `true` if `certificate` is issued to `location`.
+fn is_issued_to(certificate: &CertificateDer, location: &str) -> bool {
+    let Ok(name) = ServerName::try_from(location) else {
+        return false;
+    };
+    webpki::EndEntityCert::try_from(certificate)
+        .is_ok_and(|certificate| certificate.verify_is_valid_for_subject_name(&name).is_ok())
+}
+
+/// Verifies that the server is a specific peer, in addition to the usual checks for its hostname.
+#[derive(Debug)]
+struct PeerVerifier {
+    inner: Arc<WebPkiServerVerifier>,
+    peer: &'static str,
+}
+
+impl ServerCertVerifier for PeerVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        )?;
+        if !is_issued_to(end_entity, self.peer) {
+            return Err(rustls::Error::InvalidCertificate(
+                CertificateError::NotValidForName,
+            ));
+        }
+        Ok(verified)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+/// Handles a request from a peer with the given headers and body.
+///
+/// A request either checks that the receiver is listening, announces that the source will not send any more
+/// messages, or carries a message to `at`. The envelope of the message is opened right away, so that a message that
+/// is delivered twice is only queued once. Returns the status of the response: `CONFLICT` if the message is sealed
+/// for another incarnation of `at`, and `BAD_REQUEST` if the source or the session is missing or invalid.
+fn handle<'h, L: LocationSet>(
+    queue_map: &QueueMap<Key, Opened>,
+    envelopes: &Envelopes,
+    at: &str,
+    departures: &Departures,
+    header: impl Fn(&'static str) -> Option<&'h str>,
+    body: Vec<u8>,
+) -> Status {
+    let src =
+        header(HEADER_SRC).and_then(|src| L::to_string_list().into_iter().find(|loc| *loc == src));
+    let Some(src) = src else {
+        return BAD_REQUEST;
+    };
+    if header(HEADER_READY).is_some() {
+        return OK;
+    }
+    if header(HEADER_SHUTDOWN).is_some() {
+        departures.depart(src);
+        return OK;
+    }
+    match header(HEADER_SESSION).and_then(|session| session.parse().ok()) {
+        // the sender learns the incarnation from the response and seals the message again
+        Some(_) if envelopes.is_stale(src, &body) => CONFLICT,
+        Some(session) => {
+            // a message is delivered twice if the response to its request is lost
+            if let Some(opened) = envelopes.open(session, src, at, body).transpose() {
+                queue_map.push((session, src), opened);
+            }
+            OK
+        }
+        None => BAD_REQUEST,
+    }
+}
+
+/// The headers of a request, with lowercase names, and its body, or `None` if the body is too large to be read.
+type Request = (HashMap<String, String>, Option<Vec<u8>>);
+
+/// Reads a line of at most `MAX_LINE_LENGTH` bytes into `line`, including the line break.
+///
+/// Returns `false` if the client has closed the connection before the line starts.
+fn read_line(reader: &mut impl BufRead, line: &mut String) -> io::Result<bool> {
+    line.clear();
+    let read = reader
+        .by_ref()
+        .take(MAX_LINE_LENGTH as u64)
+        .read_line(line)?;
+    if read == 0 {
+        return Ok(false);
+    }
+    if !line.ends_with('\n') {
+        return Err(if read == MAX_LINE_LENGTH {
+            invalid_data("line is too long")
+        } else {
+            io::ErrorKind::UnexpectedEof.into()
+        });
+    }
+    Ok(true)
+}
+
+/// Reads the next request of an HTTP/1.1 connection.
+///
+/// Returns `None` if the client has closed the connection. A body longer than `max_len` is left unread. Fails if a
+/// line is longer than `MAX_LINE_LENGTH`, if there are more than `MAX_HEADERS` headers, or if a header is malformed.
+fn read_request(reader: &mut impl BufRead, max_len: usize) -> io::Result<Option<Request>> {
+    let mut line = String::new();
+    if !read_line(reader, &mut line)? {
+        return Ok(None);
+    }
+    let mut headers = HashMap::new();
+    loop {
+        if !read_line(reader, &mut line)? {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        if line.trim_end_matches(['\r', '\n']).is_empty() {
+            break;
+        }
+        if headers.len() == MAX_HEADERS {
+            return Err(invalid_data("too many headers"));
+        }
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| invalid_data("malformed header"))?;
+        headers.insert(name.trim().to_ascii_lowercase(), value.trim().to_string());
+    }
+    let length = headers
+        .get("content-length")
+        .and_then(|length| length.parse().ok())
+        .ok_or_else(|| invalid_data("missing Content-Length"))?;
+    if length > max_len {
+        return Ok(Some((headers, None)));
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+    Ok(Some((headers, Some(body))))
+}
+
+/// Serves the requests of an HTTPS connection.
+///
+/// The client must present a certificate, and a request is only accepted if the certificate is issued to the
+/// source location named in the request. A request with a body longer than `max_message_size` is rejected, and the
+/// connection is closed. The connection is also closed if it stalls for longer than `CONNECTION_TIMEOUT`.
+fn serve_tls_connection<L: LocationSet>(
+    mut stream: TcpStream,
+    config: Arc<ServerConfig>,
+    queue_map: &QueueMap<Key, Opened>,
+    envelopes: &Envelopes,
+    at: &str,
+    departures: &Departures,
+    max_message_size: usize,
+) -> io::Result<()> {
+    stream.set_read_timeout(Some(CONNECTION_TIMEOUT))?;
+    stream.set_write_timeout(Some(CONNECTION_TIMEOUT))?;
+    let mut connection = ServerConnection::new(config).map_err(invalid_data)?;
+    while connection.is_handshaking() {
+        connection.complete_io(&mut stream)?;
+    }
+    let certificate = connection
+        .peer_certificates()
+        .and_then(|certificates| certificates.first())
+        .cloned()
+        .ok_or_else(|| invalid_data("missing client certificate"))?;
+    let mut reader = BufReader::new(StreamOwned::new(connection, stream));
+    while let Some((headers, body)) = read_request(&mut reader, max_message_size)? {
+        let header = |name: &str| headers.get(&name.to_ascii_lowercase()).map(String::as_str);
+        let too_large = body.is_none();
+        let (status, reason) =
+            if !header(HEADER_SRC).is_some_and(|src| is_issued_to(&certificate, src)) {
+                FORBIDDEN
+            } else if let Some(body) = body {
+                handle::<L>(queue_map, envelopes, at, departures, header, body)
+            } else {
+                PAYLOAD_TOO_LARGE
+            };
+        let stream = reader.get_mut();
+        write!(
+            stream,
+            "HTTP/1.1 {} {}\r\n{}: {}\r\nContent-Length: {}\r\n\r\n{}",
+            status,
+            reason,
+            HEADER_INCARNATION,
+            envelopes.incarnation(),
+            reason.len(),
+            reason
+        )?;
+        stream.flush()?;
+        if too_large {
+            // the body is still unread, so the next request cannot be found
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Returns `false` for errors that retrying cannot fix: the peer rejected the request or failed authentication.
+fn is_transient(err: &ureq::Error) -> bool {
+    let ureq::Error::Transport(transport) = err else {
+        return false;
+    };
+    let mut source = std::error::Error::source(transport);
+    while let Some(err) = source {
+        let is_tls = err.is::<rustls::Error>()
+            || err
+                .downcast_ref::<io::Error>()
+                .and_then(io::Error::get_ref)
+                .is_some_and(|err| err.is::<rustls::Error>());
+        if is_tls {
+            return false;
+        }
+        source = err.source();
+    }
+    true
+}
+
+/// Posts `body` with `headers` to `url`, and tells `retry` whether to try again if it fails.
+///
+/// The incarnation of `peer` in the response is recorded in `envelopes`. If the peer rejects a message that is
+/// sealed for an earlier incarnation, the request is retried.
+fn post(
+    agent: &Agent,
+    url: &str,
+    headers: &[(&str, &str)],
+    body: &[u8],
+    envelopes: &Envelopes,
+    peer: &str,
+) -> OperationResult<(), TransportError> {
+    let request = headers
+        .iter()
+        .fold(agent.post(url), |request, (name, value)| {
+            request.set(name, value)
+        });
+    let learn = |response: &ureq::Response| {
+        if let Some(incarnation) = response
+            .header(HEADER_INCARNATION)
+            .and_then(|incarnation| incarnation.parse().ok())
+        {
+            envelopes.learn(peer, incarnation);
+        }
+    };
+    match request.send_bytes(body) {
+        Ok(response) => {
+            learn(&response);
+            OperationResult::Ok(())
+        }
+        Err(ureq::Error::Status(status, response)) if status == CONFLICT.0 => {
+            learn(&response);
+            OperationResult::Retry(TransportError::Io(io::Error::other(
+                "the receiver has restarted",
+            )))
+        }
+        Err(err) => {
+            let transient = is_transient(&err);
+            let err = TransportError::Io(io::Error::other(err.to_string()));
+            if transient {
+                OperationResult::Retry(err)
+            } else {
+                OperationResult::Err(err)
+            }
+        }
+    }
+}
+
+/// The server that receives messages from peers.
+enum Listener {
+    Http(Arc<Server>),
+    Https {
+        local_addr: SocketAddr,
+        closed: Arc<AtomicBool>,
+        incoming: Arc<Incoming<TcpStream>>,
+    },
+}
+
 /// The HTTP transport.
-pub struct HttpTransport<'a, L: LocationSet, TLocation> {
+pub struct HttpTransport<'a, L: LocationSet, TLocation, C: Codec = Json> {
     config: HashMap<&'static str, (&'a str, u16)>,
-    agent: Agent,
-    server: Arc<Server>,
+    agents: HashMap<&'static str, Agent>,
+    scheme: &'static str,
+    receive_timeout: Option<Duration>,
+    send_timeout: Duration,
+    backoff: Backoff,
+    envelopes: Arc<Envelopes>,
+    listener: Listener,
     join_handle: Option<thread::JoinHandle<()>>,
     location_set: PhantomData<L>,
-    queue_map: Arc<QueueMap>,
+    queue_map: Arc<QueueMap<Key, Opened>>,
+    departures: Arc<Departures>,
+    codec: C,
     target_location: PhantomData<TLocation>,
 }
 
 impl<'a, L: LocationSet, TLocation: ChoreographyLocation> HttpTransport<'a, L, TLocation> {
     /// Creates a new `HttpTransport` instance from the configuration.
-    pub fn new<Index>(http_config: HttpTransportConfig<'a, L, TLocation>) -> Self
+    ///
+    /// Messages are encoded as JSON. Fails if the server cannot listen on the address of the target, for example
+    /// because the port is already in use.
+    pub fn new<Index>(http_config: HttpTransportConfig<'a, L, TLocation>) -> io::Result<Self>
     where
         TLocation: Member<L, Index>,
     {
-        let queue_map: Arc<QueueMap> = {
-            let mut m = HashMap::new();
-            for loc in L::to_string_list() {
-                m.insert(loc, BlockingQueue::new());
-            }
-            Arc::new(m.into())
-        };
+        Self::with_codec(http_config, Json)
+    }
+
+    /// Creates a new `HttpTransport` instance that communicates over HTTPS, authenticating locations with the
+    /// certificates in `tls`.
+    ///
+    /// Messages are encoded as JSON. Fails if the certificates cannot be loaded.
+    pub fn with_tls<Index>(
+        http_config: HttpTransportConfig<'a, L, TLocation>,
+        tls: &TlsConfig,
+    ) -> io::Result<Self>
+    where
+        TLocation: Member<L, Index>,
+    {
+        Self::with_tls_and_codec(http_config, tls, Json)
+    }
+}
 
+impl<'a, L: LocationSet, TLocation: ChoreographyLocation, C: Codec>
+    HttpTransport<'a, L, TLocation, C>
+{
+    /// Creates a new `HttpTransport` instance from the configuration that encodes messages with `codec`.
+    ///
+    /// Fails if the server cannot listen on the address of the target.
+    pub fn with_codec<Index>(
+        http_config: HttpTransportConfig<'a, L, TLocation>,
+        codec: C,
+    ) -> io::Result<Self>
+    where
+        TLocation: Member<L, Index>,
+    {
         let (_, (hostname, port)) = &http_config.target_info;
-        let server = Arc::new(Server::http(format!("{}:{}", hostname, port)).unwrap());
+        let server =
+            Arc::new(Server::http(format!("{}:{}", hostname, port)).map_err(io::Error::other)?);
+        let queue_map: Arc<QueueMap<Key, Opened>> = Arc::new(QueueMap::new());
+        let departures = Arc::new(Departures::default());
+        let envelopes = Arc::new(Envelopes::new(
+            http_config.info.keys().cloned(),
+            http_config.keys,
+        ));
+        let max_message_size = http_config.max_message_size;
         let join_handle = Some({
             let server = server.clone();
             let queue_map = queue_map.clone();
+            let envelopes = envelopes.clone();
+            let departures = departures.clone();
+            let incarnation = tiny_http::Header::from_bytes(
+                HEADER_INCARNATION,
+                envelopes.incarnation().to_string(),
+            )
+            .expect("the header is ASCII");
 
             thread::spawn(move || {
                 for mut request in server.incoming_requests() {
-                    let mut body = String::new();
-                    request
-                        .as_reader()
-                        .read_to_string(&mut body)
-                        .expect("Failed to read body");
-                    let mut headers = request.headers().iter();
-                    let src = headers.find(|header| header.field.equiv(HEADER_SRC));
-                    if let Some(src) = src {
-                        let src = &src.value;
-                        queue_map.get(src.as_str()).unwrap().push(body);
-                        request
-                            .respond(tiny_http::Response::from_string("OK").with_status_code(200))
-                            .unwrap();
-                    } else {
-                        request
-                            .respond(
-                                tiny_http::Response::from_string("Bad Request")
-                                    .with_status_code(400),
-                            )
-                            .unwrap();
+                    // at most one byte past the limit is read, whatever length the peer announces
+                    let mut body = Vec::new();
+                    let read = Read::take(request.as_reader(), max_message_size as u64 + 1)
+                        .read_to_end(&mut body);
+                    if body.len() > max_message_size {
+                        let (status, reason) = PAYLOAD_TOO_LARGE;
+                        let response = tiny_http::Response::from_string(reason)
+                            .with_status_code(status)
+                            .with_header(incarnation.clone());
+                        let _ = request.respond(response);
+                        continue;
                     }
+                    let header = |name: &'static str| {
+                        request
+                            .headers()
+                            .iter()
+                            .find(|header| header.field.equiv(name))
+                            .map(|header| header.value.as_str())
+                    };
+                    let (status, reason) = match read {
+                        Ok(_) => handle::<L>(
+                            &queue_map,
+                            &envelopes,
+                            TLocation::name(),
+                            &departures,
+                            header,
+                            body,
+                        ),
+                        Err(_) => BAD_REQUEST,
+                    };
+                    let response = tiny_http::Response::from_string(reason)
+                        .with_status_code(status)
+                        .with_header(incarnation.clone());
+                    // the sender retries if it does not receive the response
+                    let _ = request.respond(response);
                 }
             })
         });
 
         let agent = AgentBuilder::new().build();
 
-        Self {
+        Ok(Self {
+            agents: http_config
+                .info
+                .keys()
+                .map(|peer| (*peer, agent.clone()))
+                .collect(),
             config: http_config.info,
-            agent,
+            scheme: "http",
+            receive_timeout: http_config.receive_timeout,
+            send_timeout: http_config.send_timeout,
+            backoff: http_config.backoff,
+            envelopes,
+            listener: Listener::Http(server),
             join_handle,
-            server,
             location_set: PhantomData,
             queue_map,
+            departures,
+            codec,
             target_location: PhantomData,
+        })
+    }
+
+    /// Creates a new `HttpTransport` instance that communicates over HTTPS and encodes messages with `codec`.
+    ///
+    /// Fails if the certificates cannot be loaded or the server cannot listen on the address of the target.
+    pub fn with_tls_and_codec<Index>(
+        http_config: HttpTransportConfig<'a, L, TLocation>,
+        tls: &TlsConfig,
+        codec: C,
+    ) -> io::Result<Self>
+    where
+        TLocation: Member<L, Index>,
+    {
+        let tls = tls.load(http_config.info.keys().cloned())?;
+        let (_, (hostname, port)) = &http_config.target_info;
+        let listener = TcpListener::bind((*hostname, *port))?;
+        let local_addr = listener.local_addr()?;
+        let queue_map: Arc<QueueMap<Key, Opened>> = Arc::new(QueueMap::new());
+        let departures = Arc::new(Departures::default());
+        let incoming = Arc::new(Incoming::new());
+        let closed = Arc::new(AtomicBool::new(false));
+        let envelopes = Arc::new(Envelopes::new(
+            http_config.info.keys().cloned(),
+            http_config.keys,
+        ));
+        let max_message_size = http_config.max_message_size;
+
+        let join_handle = Some({
+            let queue_map = queue_map.clone();
+            let envelopes = envelopes.clone();
+            let departures = departures.clone();
+            let incoming = incoming.clone();
+            let closed = closed.clone();
+            let server = tls.server;
+
+            thread::spawn(move || loop {
+                let stream = listener.accept();
+                if closed.load(Ordering::SeqCst) {
+                    break;
+                }
+                let Ok((stream, _)) = stream else {
+                    continue;
+                };
+                if incoming.len() >= MAX_CONNECTIONS {
+                    // dropping the stream closes it, and a peer retries
+                    continue;
+                }
+                let tracked = incoming.track(&stream);
+                let server = server.clone();
+                let queue_map = queue_map.clone();
+                let envelopes = envelopes.clone();
+                let departures = departures.clone();
+                thread::spawn(move || {
+                    let _tracked = tracked;
+                    serve_tls_connection::<L>(
+                        stream,
+                        server,
+                        &queue_map,
+                        &envelopes,
+                        TLocation::name(),
+                        &departures,
+                        max_message_size,
+                    )
+                });
+            })
+        });
+
+        Ok(Self {
+            agents: tls
+                .clients
+                .into_iter()
+                .map(|(peer, client)| (peer, AgentBuilder::new().tls_config(client).build()))
+                .collect(),
+            config: http_config.info,
+            scheme: "https",
+            receive_timeout: http_config.receive_timeout,
+            send_timeout: http_config.send_timeout,
+            backoff: http_config.backoff,
+            envelopes,
+            listener: Listener::Https {
+                local_addr,
+                closed,
+                incoming,
+            },
+            join_handle,
+            location_set: PhantomData,
+            queue_map,
+            departures,
+            codec,
+            target_location: PhantomData,
+        })
+    }
+}
+
+impl<'a, L: LocationSet, TLocation: ChoreographyLocation, C: Codec>
+    HttpTransport<'a, L, TLocation, C>
+{
+    /// Posts an empty request with `header` to every peer, retrying until the peer accepts it, for at most
+    /// `timeout` in total.
+    fn notify_peers(&self, header: &str, timeout: Duration) -> Result<(), TransportError> {
+        wait_ready(self.config.keys().copied(), timeout, self.backoff, |peer| {
+            let (hostname, port) = self.config[peer];
+            let url = format!("{}://{}:{}", self.scheme, hostname, port);
+            let headers = [(HEADER_SRC, TLocation::name()), (header, "1")];
+            post(
+                &self.agents[peer],
+                &url,
+                &headers,
+                &[],
+                &self.envelopes,
+                peer,
+            )
+        })
+    }
+
+    /// Seals a message once and sends it `copies` times.
+    fn send_copies<V: Portable>(
+        &self,
+        session: SessionId,
+        from: &str,
+        to: &str,
+        data: &V,
+        deadline: Option<Instant>,
+        copies: usize,
+    ) -> Result<(), TransportError> {
+        let ((hostname, port), agent) = self
+            .config
+            .get(to)
+            .zip(self.agents.get(to))
+            .ok_or_else(|| TransportError::UnknownLocation(to.to_string()))?;
+        let mut message = self
+            .envelopes
+            .seal(session, from, to, self.codec.encode(data)?);
+        let session = session.to_string();
+        let url = format!("{}://{}:{}", self.scheme, hostname, port);
+        let headers = [(HEADER_SRC, from), (HEADER_SESSION, session.as_str())];
+        let deadline = send_deadline(deadline, self.send_timeout);
+        let post = |message: &mut Sealed| {
+            post(
+                agent,
+                &url,
+                &headers,
+                message.envelope(),
+                &self.envelopes,
+                to,
+            )
+        };
+        for _ in 0..copies {
+            retry_until(self.backoff.delays().map(jitter), deadline, || {
+                match post(&mut message) {
+                    // the message was sealed before the receiver told its incarnation, so seal it again right away
+                    OperationResult::Retry(_) if message.is_stale() => post(&mut message),
+                    result => result,
+                }
+            })?;
         }
+        Ok(())
     }
 }
 
-impl<'a, L: LocationSet, TLocation> Drop for HttpTransport<'a, L, TLocation> {
+impl<'a, L: LocationSet, TLocation, C: Codec> Drop for HttpTransport<'a, L, TLocation, C> {
     fn drop(&mut self) {
-        self.server.unblock();
+        match &self.listener {
+            Listener::Http(server) => server.unblock(),
+            Listener::Https {
+                local_addr, closed, ..
+            } => {
+                closed.store(true, Ordering::SeqCst);
+                let mut addr = *local_addr;
+                if addr.ip().is_unspecified() {
+                    addr.set_ip(match addr.ip() {
+                        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+                    });
+                }
+                // wake the pending `accept`
+                let _ = TcpStream::connect(addr);
+            }
+        }
         self.join_handle.take().map(thread::JoinHandle::join);
+        if let Listener::Https { incoming, .. } = &self.listener {
+            // only stop reading, so that responses to delivered messages still reach the senders
+            incoming.close_all(|stream| {
+                let _ = stream.shutdown(Shutdown::Read);
+            });
+        }
     }
 }
 
-impl<'a, L: LocationSet, TLocation: ChoreographyLocation> Transport<L, TLocation>
-    for HttpTransport<'a, L, TLocation>
+impl<'a, L: LocationSet, TLocation: ChoreographyLocation, C: Codec> Transport<L, TLocation>
+    for HttpTransport<'a, L, TLocation, C>
 {
+    type Codec = C;
+
+    fn codec(&self) -> &C {
+        &self.codec
+    }
+
     fn locations(&self) -> Vec<&'static str> {
         self.config.keys().cloned().collect()
     }
 
-    fn send<V: Portable>(&self, from: &str, to: &str, data: &V) -> () {
-        let (hostname, port) = self.config.get(to).unwrap();
-        retry(Fixed::from_millis(1000).map(jitter), move || {
-            self.agent
-                .post(format!("http://{}:{}", hostname, port).as_str())
-                .set(HEADER_SRC, from)
-                .send_string(serde_json::to_string(data).unwrap().as_str())
-        })
-        .unwrap();
+    fn send<V: Portable>(
+        &self,
+        session: SessionId,
+        from: &str,
+        to: &str,
+        data: &V,
+        deadline: Option<Instant>,
+    ) -> Result<(), TransportError> {
+        self.send_copies(session, from, to, data, deadline, 1)
+    }
+
+    fn send_duplicate<V: Portable>(
+        &self,
+        session: SessionId,
+        from: &str,
+        to: &str,
+        data: &V,
+        deadline: Option<Instant>,
+    ) -> Result<(), TransportError> {
+        self.send_copies(session, from, to, data, deadline, 2)
+    }
+
+    fn receive<V: Portable>(
+        &self,
+        session: SessionId,
+        from: &str,
+        _at: &str,
+        deadline: Option<Instant>,
+    ) -> Result<V, TransportError> {
+        let from = L::to_string_list()
+            .into_iter()
+            .find(|loc| *loc == from)
+            .ok_or_else(|| TransportError::UnknownLocation(from.to_string()))?;
+        let deadline = receive_deadline(deadline, self.receive_timeout);
+        let body = self
+            .queue_map
+            .pop((session, from), deadline)
+            .ok_or(TransportError::Timeout)??;
+        self.codec.decode(&body)
     }
 
-    fn receive<V: Portable>(&self, from: &str, _at: &str) -> V {
-        let str = self.queue_map.get(from).unwrap().pop();
-        serde_json::from_str(&str).unwrap()
+    fn wait_ready(&self, timeout: Duration) -> Result<(), TransportError> {
+        self.notify_peers(HEADER_READY, timeout)
+    }
+
+    fn shutdown(&self, timeout: Duration) -> Result<(), TransportError> {
+        let deadline = Instant::now().checked_add(timeout);
+        self.notify_peers(HEADER_SHUTDOWN, timeout)?;
+        self.departures
+            .wait(self.config.keys().copied(), deadline)?;
+        self.envelopes.forget_sessions();
+        check_consumed(
+            self.queue_map
+                .pending()
+                .into_iter()
+                .map(|((_, src), _)| src),
+        )
+    }
+
+    fn unconsumed(&self, session: SessionId) -> HashMap<&'static str, usize> {
+        self.queue_map
+            .pending()
+            .into_iter()
+            .filter(|((s, _), _)| *s == session)
+            .map(|((_, src), len)| (src, len))
+            .collect()
     }
 }
 
@@ -157,6 +947,8 @@ mod tests {
     use std::thread::{self, sleep};
     use std::time::Duration;
 
+    use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+
     use super::*;
     use crate::core::ChoreographyLocation;
 
@@ -169,12 +961,38 @@ mod tests {
     #[derive(ChoreographyLocation)]
     struct Carol;
 
+    /// Generates a self-signed CA and a certificate issued by it for each of `locations`.
+    fn generate_certificates(test: &str, locations: &[&str]) -> Vec<TlsConfig> {
+        let dir = std::env::temp_dir().join(format!("chorus-{}-{}", std::process::id(), test));
+        std::fs::create_dir_all(&dir).unwrap();
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca_params = CertificateParams::new(Vec::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+        let trusted = dir.join("ca.pem");
+        std::fs::write(&trusted, ca.pem()).unwrap();
+        locations
+            .iter()
+            .map(|location| {
+                let key = KeyPair::generate().unwrap();
+                let names = vec![location.to_string(), "localhost".to_string()];
+                let certificate = CertificateParams::new(names)
+                    .unwrap()
+                    .signed_by(&key, &ca, &ca_key)
+                    .unwrap();
+                let certificate_path = dir.join(format!("{}.pem", location));
+                let private_key_path = dir.join(format!("{}.key", location));
+                std::fs::write(&certificate_path, certificate.pem()).unwrap();
+                std::fs::write(&private_key_path, key.serialize_pem()).unwrap();
+                TlsConfig::new(certificate_path, private_key_path, &trusted)
+            })
+            .collect()
+    }
+
     #[test]
     fn test_http_transport() {
         let v = 42;
 
-        let (signal, wait) = mpsc::channel::<()>();
-
         let mut handles = Vec::new();
         {
             let config = HttpTransportConfigBuilder::for_target(Alice, ("0.0.0.0", 9010))
@@ -182,9 +1000,11 @@ mod tests {
                 .build();
 
             handles.push(thread::spawn(move || {
-                wait.recv().unwrap(); // wait for Bob to start
-                let transport = HttpTransport::new(config);
-                transport.send::<i32>(Alice::name(), Bob::name(), &v);
+                let transport = HttpTransport::new(config).unwrap();
+                transport.wait_ready(Duration::from_secs(10)).unwrap();
+                transport
+                    .send::<i32>(0, Alice::name(), Bob::name(), &v, None)
+                    .unwrap();
             }));
         }
         {
@@ -193,9 +1013,10 @@ mod tests {
                 .build();
 
             handles.push(thread::spawn(move || {
-                let transport = HttpTransport::new(config);
-                signal.send(()).unwrap();
-                let v2 = transport.receive::<i32>(Alice::name(), Bob::name());
+                let transport = HttpTransport::new(config).unwrap();
+                let v2 = transport
+                    .receive::<i32>(0, Alice::name(), Bob::name(), None)
+                    .unwrap();
                 assert_eq!(v, v2);
             }));
         }
@@ -217,8 +1038,10 @@ mod tests {
 
             handles.push(thread::spawn(move || {
                 signal.send(()).unwrap();
-                let transport = HttpTransport::new(config);
-                transport.send::<i32>(Alice::name(), Bob::name(), &v);
+                let transport = HttpTransport::new(config).unwrap();
+                transport
+                    .send::<i32>(0, Alice::name(), Bob::name(), &v, None)
+                    .unwrap();
             }));
         }
         {
@@ -230,8 +1053,10 @@ mod tests {
                 // wait for Alice to start, which forces Alice to retry
                 wait.recv().unwrap();
                 sleep(Duration::from_millis(100));
-                let transport = HttpTransport::new(config);
-                let v2 = transport.receive::<i32>(Alice::name(), Bob::name());
+                let transport = HttpTransport::new(config).unwrap();
+                let v2 = transport
+                    .receive::<i32>(0, Alice::name(), Bob::name(), None)
+                    .unwrap();
                 assert_eq!(v, v2);
             }));
         }
@@ -239,4 +1064,328 @@ mod tests {
             handle.join().unwrap();
         }
     }
+
+    #[test]
+    fn test_http_transport_sessions() {
+        let mut handles = Vec::new();
+        {
+            let config = HttpTransportConfigBuilder::for_target(Alice, ("0.0.0.0", 9030))
+                .with(Bob, ("localhost", 9031))
+                .build();
+
+            handles.push(thread::spawn(move || {
+                let transport = HttpTransport::new(config).unwrap();
+                transport.wait_ready(Duration::from_secs(10)).unwrap();
+                for session in 1..=3 {
+                    transport
+                        .send::<u64>(session, Alice::name(), Bob::name(), &session, None)
+                        .unwrap();
+                }
+            }));
+        }
+        {
+            let config = HttpTransportConfigBuilder::for_target(Bob, ("0.0.0.0", 9031))
+                .with(Alice, ("localhost", 9030))
+                .build();
+
+            handles.push(thread::spawn(move || {
+                let transport = HttpTransport::new(config).unwrap();
+                for session in (1..=3).rev() {
+                    let v = transport
+                        .receive::<u64>(session, Alice::name(), Bob::name(), None)
+                        .unwrap();
+                    assert_eq!(v, session);
+                }
+            }));
+        }
+        for handle in handles {
+            handle.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_http_transport_send_timeout() {
+        let config = HttpTransportConfigBuilder::for_target(Alice, ("0.0.0.0", 9100))
+            .with(Bob, ("localhost", 9101))
+            .with_send_timeout(Duration::from_millis(100))
+            .build();
+        let transport = HttpTransport::new(config).unwrap();
+        // Bob never listens
+        let start = Instant::now();
+        assert!(matches!(
+            transport.send::<i32>(0, Alice::name(), Bob::name(), &1, None),
+            Err(TransportError::Timeout)
+        ));
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_http_transport_duplicates() {
+        let config = HttpTransportConfigBuilder::for_target(Bob, ("0.0.0.0", 9071))
+            .with(Alice, ("localhost", 9070))
+            .build();
+        let transport = HttpTransport::new(config).unwrap();
+
+        // post Alice's messages by hand, as if the responses to some requests were lost
+        let alice = Envelopes::new([Bob::name()], HashMap::new());
+        alice.learn(Bob::name(), transport.envelopes.incarnation());
+        let post = |body: &[u8]| {
+            ureq::post("http://localhost:9071")
+                .set(HEADER_SRC, Alice::name())
+                .set(HEADER_SESSION, "0")
+                .send_bytes(body)
+                .unwrap();
+        };
+        let seal = |v: i32| {
+            alice
+                .seal(0, Alice::name(), Bob::name(), Json.encode(&v).unwrap())
+                .envelope()
+                .to_vec()
+        };
+        let first = seal(1);
+        let _second = seal(2);
+        let third = seal(3);
+        post(&first);
+        post(&first);
+        post(&third);
+
+        assert_eq!(
+            transport
+                .receive::<i32>(0, Alice::name(), Bob::name(), None)
+                .unwrap(),
+            1
+        );
+        // the duplicate is discarded, and the second message never arrives
+        assert!(matches!(
+            transport.receive::<i32>(0, Alice::name(), Bob::name(), None),
+            Err(TransportError::MessageLost(name)) if name == Alice::name()
+        ));
+    }
+
+    #[test]
+    fn test_http_transport_retried_request_is_consumed() {
+        let config = HttpTransportConfigBuilder::for_target(Bob, ("0.0.0.0", 9081))
+            .with(Alice, ("localhost", 9080))
+            .build();
+        let transport = HttpTransport::new(config).unwrap();
+
+        let alice = Envelopes::new([Bob::name()], HashMap::new());
+        let mut message = alice.seal(0, Alice::name(), Bob::name(), Json.encode(&42).unwrap());
+        let post = |body: &[u8]| {
+            let response = match ureq::post("http://localhost:9081")
+                .set(HEADER_SRC, Alice::name())
+                .set(HEADER_SESSION, "0")
+                .send_bytes(body)
+            {
+                Ok(response) | Err(ureq::Error::Status(_, response)) => response,
+                Err(err) => panic!("{}", err),
+            };
+            let incarnation = response.header(HEADER_INCARNATION).unwrap().parse();
+            (response.status(), incarnation.unwrap())
+        };
+
+        // Bob rejects a message that is not sealed for its incarnation, and tells Alice the incarnation
+        let (status, incarnation) = post(message.envelope());
+        assert_eq!(status, 409);
+        assert_eq!(incarnation, transport.envelopes.incarnation());
+        assert!(transport.unconsumed(0).is_empty());
+        alice.learn(Bob::name(), incarnation);
+
+        // Alice retries a request whose response was lost
+        for _ in 0..2 {
+            assert_eq!(post(message.envelope()).0, 200);
+        }
+
+        assert_eq!(
+            transport
+                .receive::<i32>(0, Alice::name(), Bob::name(), None)
+                .unwrap(),
+            42
+        );
+        assert!(transport.unconsumed(0).is_empty());
+    }
+
+    #[test]
+    fn test_http_transport_max_message_size() {
+        let config = HttpTransportConfigBuilder::for_target(Bob, ("0.0.0.0", 9091))
+            .with(Alice, ("localhost", 9090))
+            .with_max_message_size(64)
+            .build();
+        let transport = HttpTransport::new(config).unwrap();
+
+        let post = |body: &[u8]| match ureq::post("http://localhost:9091")
+            .set(HEADER_SRC, Alice::name())
+            .set(HEADER_SESSION, "0")
+            .send_bytes(body)
+        {
+            Ok(response) => response.status(),
+            Err(ureq::Error::Status(status, _)) => status,
+            Err(err) => panic!("{}", err),
+        };
+        assert_eq!(post(&[0; 65]), 413);
+        assert!(transport.unconsumed(0).is_empty());
+
+        let alice = Envelopes::new([Bob::name()], HashMap::new());
+        alice.learn(Bob::name(), transport.envelopes.incarnation());
+        let mut message = alice.seal(0, Alice::name(), Bob::name(), Json.encode(&42).unwrap());
+        assert_eq!(post(message.envelope()), 200);
+        assert_eq!(
+            transport
+                .receive::<i32>(0, Alice::name(), Bob::name(), None)
+                .unwrap(),
+            42
+        );
+    }
+
+    #[test]
+    fn test_https_transport() {
+        let v = 42;
+        let tls = generate_certificates("https", &[Alice::name(), Bob::name()]);
+
+        let mut handles = Vec::new();
+        {
+            let config = HttpTransportConfigBuilder::for_target(Alice, ("0.0.0.0", 9040))
+                .with(Bob, ("localhost", 9041))
+                .build();
+            let tls = tls[0].clone();
+
+            handles.push(thread::spawn(move || {
+                let transport = HttpTransport::with_tls(config, &tls).unwrap();
+                transport.wait_ready(Duration::from_secs(10)).unwrap();
+                transport
+                    .send::<i32>(0, Alice::name(), Bob::name(), &v, None)
+                    .unwrap();
+            }));
+        }
+        {
+            let config = HttpTransportConfigBuilder::for_target(Bob, ("0.0.0.0", 9041))
+                .with(Alice, ("localhost", 9040))
+                .build();
+            let tls = tls[1].clone();
+
+            handles.push(thread::spawn(move || {
+                let transport = HttpTransport::with_tls(config, &tls).unwrap();
+                let v2 = transport
+                    .receive::<i32>(0, Alice::name(), Bob::name(), None)
+                    .unwrap();
+                assert_eq!(v, v2);
+            }));
+        }
+        for handle in handles {
+            handle.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_https_transport_forged_source() {
+        let tls = generate_certificates("forged-source", &[Bob::name(), Carol::name()]);
+
+        let bob_config = HttpTransportConfigBuilder::for_target(Bob, ("0.0.0.0", 9051))
+            .with(Alice, ("localhost", 9050))
+            .with(Carol, ("localhost", 9052))
+            .build();
+        let bob = HttpTransport::with_tls(bob_config, &tls[0]).unwrap();
+        let carol_config = HttpTransportConfigBuilder::for_target(Carol, ("0.0.0.0", 9052))
+            .with(Alice, ("localhost", 9050))
+            .with(Bob, ("localhost", 9051))
+            .build();
+        let carol = HttpTransport::with_tls(carol_config, &tls[1]).unwrap();
+
+        // Carol's certificate is not issued to Alice
+        assert!(carol
+            .send::<i32>(0, Alice::name(), Bob::name(), &1, None)
+            .is_err());
+        carol
+            .send::<i32>(0, Carol::name(), Bob::name(), &2, None)
+            .unwrap();
+        let deadline = Instant::now() + Duration::from_millis(100);
+        assert!(matches!(
+            bob.receive::<i32>(0, Alice::name(), Bob::name(), Some(deadline)),
+            Err(TransportError::Timeout)
+        ));
+        assert_eq!(
+            bob.receive::<i32>(0, Carol::name(), Bob::name(), None)
+                .unwrap(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_https_transport_impersonated_peer() {
+        let tls = generate_certificates("impersonated-peer", &[Alice::name(), Carol::name()]);
+
+        // Carol expects Bob at the address where Alice listens
+        let alice_config = HttpTransportConfigBuilder::for_target(Alice, ("0.0.0.0", 9060))
+            .with(Carol, ("localhost", 9062))
+            .build();
+        let _alice = HttpTransport::with_tls(alice_config, &tls[0]).unwrap();
+        let carol_config = HttpTransportConfigBuilder::for_target(Carol, ("0.0.0.0", 9062))
+            .with(Bob, ("localhost", 9060))
+            .build();
+        let carol = HttpTransport::with_tls(carol_config, &tls[1]).unwrap();
+
+        assert!(carol
+            .send::<i32>(0, Carol::name(), Bob::name(), &1, None)
+            .is_err());
+    }
+
+    #[test]
+    fn test_read_request_limits() {
+        let read = |request: String| read_request(&mut io::Cursor::new(request), 16);
+        let (headers, body) = read("POST / HTTP/1.1\r\nContent-Length: 2\r\n\r\nhi".to_string())
+            .unwrap()
+            .unwrap();
+        assert_eq!(headers["content-length"], "2");
+        assert_eq!(body.unwrap(), b"hi");
+
+        let long = format!(
+            "POST / HTTP/1.1\r\nX: {}\r\n\r\n",
+            "a".repeat(MAX_LINE_LENGTH)
+        );
+        assert_eq!(read(long).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        let many = format!(
+            "POST / HTTP/1.1\r\n{}\r\n",
+            (0..=MAX_HEADERS)
+                .map(|i| format!("X-{}: 1\r\n", i))
+                .collect::<String>()
+        );
+        assert_eq!(read(many).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        let malformed = "POST / HTTP/1.1\r\nContent-Length: 2\r\nmalformed\r\n\r\nhi".to_string();
+        assert_eq!(
+            read(malformed).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn test_https_transport_bounds_connections() {
+        let tls = generate_certificates("connections", &[Bob::name()]);
+        let config = HttpTransportConfigBuilder::for_target(Bob, ("0.0.0.0", 9201))
+            .with(Alice, ("localhost", 9200))
+            .build();
+        let bob = HttpTransport::with_tls(config, &tls[0]).unwrap();
+        let Listener::Https { incoming, .. } = &bob.listener else {
+            unreachable!()
+        };
+        let wait_for = |len: usize| {
+            let deadline = Instant::now() + Duration::from_secs(10);
+            while incoming.len() != len {
+                assert!(Instant::now() < deadline, "{} connections", incoming.len());
+                sleep(Duration::from_millis(10));
+            }
+        };
+        // connections that never complete the handshake
+        let idle: Vec<_> = (0..MAX_CONNECTIONS)
+            .map(|_| TcpStream::connect("localhost:9201").unwrap())
+            .collect();
+        wait_for(MAX_CONNECTIONS);
+        let mut rejected = TcpStream::connect("localhost:9201").unwrap();
+        rejected
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        assert_eq!(rejected.read(&mut [0; 1]).unwrap(), 0);
+        // closed connections are released
+        drop(idle);
+        wait_for(0);
+    }
 }
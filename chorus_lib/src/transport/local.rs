@@ -3,20 +3,25 @@
 use std::collections::HashMap;
 use std::sync::Arc;
 
-use serde_json;
-
 use std::marker::PhantomData;
+use std::time::{Duration, Instant};
 
-use crate::core::{ChoreographyLocation, HCons, LocationSet, Portable, Transport};
-use crate::utils::queue::BlockingQueue;
+use crate::codec::{Codec, Json};
+use crate::core::{
+    AsyncTransport, ChoreographyLocation, HCons, LocationSet, Portable, SessionId, Transport,
+    TransportError,
+};
+use crate::transport::{check_consumed, receive_deadline};
+use crate::utils::queue::QueueMap;
 
-type QueueMap = HashMap<String, HashMap<String, BlockingQueue<String>>>;
+/// Messages are queued per (session, sender, receiver).
+type Key = (SessionId, &'static str, &'static str);
 
 /// A Transport channel used between multiple `Transport`s.
 pub struct LocalTransportChannel<L: LocationSet> {
     /// The location set where the channel is defined on.
     location_set: std::marker::PhantomData<L>,
-    queue_map: Arc<QueueMap>,
+    queue_map: Arc<QueueMap<Key, Vec<u8>>>,
 }
 
 impl<L: LocationSet> Clone for LocalTransportChannel<L> {
@@ -49,21 +54,24 @@ impl<L: LocationSet> LocalTransportChannel<L> {
     /// let transport_channel = LocalTransportChannel::<LocationSet!(Alice, Bob)>::new();
     /// ```
     pub fn new() -> LocalTransportChannel<L> {
-        let mut queue_map: QueueMap = HashMap::new();
-        let str_list = L::to_string_list();
-        for sender in &str_list {
-            let mut n = HashMap::new();
-            for receiver in &str_list {
-                n.insert(receiver.to_string(), BlockingQueue::new());
-            }
-            queue_map.insert(sender.to_string(), n);
-        }
-
         LocalTransportChannel {
             location_set: PhantomData,
-            queue_map: queue_map.into(),
+            queue_map: Arc::new(QueueMap::new()),
         }
     }
+
+    /// Returns the key of the queue for messages sent from `from` to `to` in `session`.
+    fn key(&self, session: SessionId, from: &str, to: &str) -> Result<Key, TransportError> {
+        let locations = L::to_string_list();
+        let find = |name: &str| {
+            locations
+                .iter()
+                .find(|location| **location == name)
+                .copied()
+                .ok_or_else(|| TransportError::UnknownLocation(name.to_string()))
+        };
+        Ok((session, find(from)?, find(to)?))
+    }
 }
 
 /// A builder for `LocalTransportChannel`.
@@ -123,62 +131,217 @@ impl<L: LocationSet> LocalTransportChannelBuilder<L> {
 /// This transport uses a blocking queue to allow for communication between threads. Each location must be executed in its thread.
 ///
 /// All locations must share the same `LocalTransportChannel` instance. `LocalTransportChannel` implements `Clone` so that it can be shared across threads.
-pub struct LocalTransport<L: LocationSet, TargetLocation> {
+pub struct LocalTransport<L: LocationSet, TargetLocation, C: Codec = Json> {
     internal_locations: Vec<&'static str>,
     location_set: PhantomData<L>,
     local_channel: LocalTransportChannel<L>,
+    codec: C,
+    receive_timeout: Option<Duration>,
     target_location: PhantomData<TargetLocation>,
 }
 
 impl<L: LocationSet, TargetLocation> LocalTransport<L, TargetLocation> {
     /// Creates a new `LocalTransport` instance from a Target `ChoreographyLocation` and a `LocalTransportChannel`.
+    ///
+    /// Messages are encoded as JSON.
     pub fn new(target: TargetLocation, local_channel: LocalTransportChannel<L>) -> Self {
+        Self::with_codec(target, local_channel, Json)
+    }
+}
+
+impl<L: LocationSet, TargetLocation, C: Codec> LocalTransport<L, TargetLocation, C> {
+    /// Creates a new `LocalTransport` instance that encodes messages with `codec`.
+    ///
+    /// All transports that share the `LocalTransportChannel` must use the same codec.
+    pub fn with_codec(
+        target: TargetLocation,
+        local_channel: LocalTransportChannel<L>,
+        codec: C,
+    ) -> Self {
         _ = target;
 
         LocalTransport {
             internal_locations: L::to_string_list(),
             location_set: PhantomData,
             local_channel,
+            codec,
+            receive_timeout: None,
             target_location: PhantomData,
         }
     }
+
+    /// Sets the default receive timeout.
+    ///
+    /// A `receive` fails with `TransportError::Timeout` if no message arrives within `timeout`, unless the
+    /// choreography sets its own deadline with `ChoreoOp::with_deadline`.
+    pub fn with_receive_timeout(self, timeout: Duration) -> Self {
+        Self {
+            receive_timeout: Some(timeout),
+            ..self
+        }
+    }
 }
 
-impl<L: LocationSet, TargetLocation: ChoreographyLocation> Transport<L, TargetLocation>
-    for LocalTransport<L, TargetLocation>
+impl<L: LocationSet, TargetLocation: ChoreographyLocation, C: Codec> Transport<L, TargetLocation>
+    for LocalTransport<L, TargetLocation, C>
 {
+    type Codec = C;
+
+    fn codec(&self) -> &C {
+        &self.codec
+    }
+
     fn locations(&self) -> Vec<&'static str> {
         return self.internal_locations.clone();
     }
 
-    fn send<T: Portable>(&self, from: &str, to: &str, data: &T) -> () {
-        let data = serde_json::to_string(data).unwrap();
-        self.local_channel
-            .queue_map
-            .get(from)
-            .unwrap()
-            .get(to)
-            .unwrap()
-            .push(data)
+    fn send<T: Portable>(
+        &self,
+        session: SessionId,
+        from: &str,
+        to: &str,
+        data: &T,
+        _deadline: Option<Instant>,
+    ) -> Result<(), TransportError> {
+        let key = self.local_channel.key(session, from, to)?;
+        let data = self.codec.encode(data)?;
+        self.local_channel.queue_map.push(key, data);
+        Ok(())
     }
 
-    fn receive<T: Portable>(&self, from: &str, at: &str) -> T {
+    fn receive<T: Portable>(
+        &self,
+        session: SessionId,
+        from: &str,
+        at: &str,
+        deadline: Option<Instant>,
+    ) -> Result<T, TransportError> {
+        let key = self.local_channel.key(session, from, at)?;
+        let deadline = receive_deadline(deadline, self.receive_timeout);
         let data = self
             .local_channel
             .queue_map
-            .get(from)
-            .unwrap()
-            .get(at)
-            .unwrap()
-            .pop();
-        serde_json::from_str(&data).unwrap()
+            .pop(key, deadline)
+            .ok_or(TransportError::Timeout)?;
+        self.codec.decode(&data)
+    }
+
+    fn shutdown(&self, _timeout: Duration) -> Result<(), TransportError> {
+        // messages are queued as soon as they are sent, so there is nothing to wait for
+        check_consumed(
+            self.local_channel
+                .queue_map
+                .pending()
+                .into_iter()
+                .filter(|((_, _, to), _)| *to == TargetLocation::name())
+                .map(|((_, from, _), _)| from),
+        )
+    }
+
+    fn unconsumed(&self, session: SessionId) -> HashMap<&'static str, usize> {
+        self.local_channel
+            .queue_map
+            .pending()
+            .into_iter()
+            .filter(|((s, _, to), _)| *s == session && *to == TargetLocation::name())
+            .map(|((_, from, _), len)| (from, len))
+            .collect()
+    }
+}
+
+/// The asynchronous local transport.
+///
+/// This is the asynchronous counterpart of `LocalTransport`. Receiving a message suspends the task instead of
+/// blocking the thread, so all locations can be executed on the same executor.
+///
+/// All locations must share the same `LocalTransportChannel` instance.
+pub struct AsyncLocalTransport<L: LocationSet, TargetLocation, C: Codec = Json> {
+    internal_locations: Vec<&'static str>,
+    location_set: PhantomData<L>,
+    local_channel: LocalTransportChannel<L>,
+    codec: C,
+    target_location: PhantomData<TargetLocation>,
+}
+
+impl<L: LocationSet, TargetLocation> AsyncLocalTransport<L, TargetLocation> {
+    /// Creates a new `AsyncLocalTransport` instance from a Target `ChoreographyLocation` and a `LocalTransportChannel`.
+    ///
+    /// Messages are encoded as JSON.
+    pub fn new(target: TargetLocation, local_channel: LocalTransportChannel<L>) -> Self {
+        Self::with_codec(target, local_channel, Json)
+    }
+}
+
+impl<L: LocationSet, TargetLocation, C: Codec> AsyncLocalTransport<L, TargetLocation, C> {
+    /// Creates a new `AsyncLocalTransport` instance that encodes messages with `codec`.
+    ///
+    /// All transports that share the `LocalTransportChannel` must use the same codec.
+    pub fn with_codec(
+        target: TargetLocation,
+        local_channel: LocalTransportChannel<L>,
+        codec: C,
+    ) -> Self {
+        _ = target;
+
+        AsyncLocalTransport {
+            internal_locations: L::to_string_list(),
+            location_set: PhantomData,
+            local_channel,
+            codec,
+            target_location: PhantomData,
+        }
+    }
+}
+
+impl<L: LocationSet, TargetLocation: ChoreographyLocation, C: Codec>
+    AsyncTransport<L, TargetLocation> for AsyncLocalTransport<L, TargetLocation, C>
+{
+    type Codec = C;
+
+    fn codec(&self) -> &C {
+        &self.codec
+    }
+
+    fn locations(&self) -> Vec<&'static str> {
+        self.internal_locations.clone()
+    }
+
+    async fn send<T: Portable>(
+        &self,
+        session: SessionId,
+        from: &str,
+        to: &str,
+        data: &T,
+    ) -> Result<(), TransportError> {
+        let key = self.local_channel.key(session, from, to)?;
+        let data = self.codec.encode(data)?;
+        self.local_channel.queue_map.push(key, data);
+        Ok(())
+    }
+
+    async fn receive<T: Portable>(
+        &self,
+        session: SessionId,
+        from: &str,
+        at: &str,
+    ) -> Result<T, TransportError> {
+        let key = self.local_channel.key(session, from, at)?;
+        let data = self.local_channel.queue_map.pop_async(key).await;
+        self.codec.decode(&data)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::core::ChoreographyLocation;
+    use crate::core::{
+        AsyncChoreoOp, AsyncChoreography, AsyncProjector, ChoreoOp, Choreography,
+        ChoreographyError, ChoreographyLocation, Faceted, Located, Projector, Quire, Runner,
+        Unwrapper,
+    };
+    use serde::{Deserialize, Serialize};
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::atomic::{AtomicUsize, Ordering};
     use std::thread;
 
     #[derive(ChoreographyLocation)]
@@ -187,6 +350,9 @@ mod tests {
     #[derive(ChoreographyLocation)]
     struct Bob;
 
+    #[derive(ChoreographyLocation)]
+    struct Carol;
+
     #[test]
     fn test_local_transport() {
         let v = 42;
@@ -200,13 +366,17 @@ mod tests {
         {
             let transport = LocalTransport::new(Alice, transport_channel.clone());
             handles.push(thread::spawn(move || {
-                transport.send::<i32>(Alice::name(), Bob::name(), &v);
+                transport
+                    .send::<i32>(0, Alice::name(), Bob::name(), &v, None)
+                    .unwrap();
             }));
         }
         {
             let transport = LocalTransport::new(Bob, transport_channel.clone());
             handles.push(thread::spawn(move || {
-                let v2 = transport.receive::<i32>(Alice::name(), Bob::name());
+                let v2 = transport
+                    .receive::<i32>(0, Alice::name(), Bob::name(), None)
+                    .unwrap();
                 assert_eq!(v, v2);
             }));
         }
@@ -214,4 +384,945 @@ mod tests {
             handle.join().unwrap();
         }
     }
+
+    #[test]
+    fn test_local_transport_sessions() {
+        let transport_channel = LocalTransportChannelBuilder::new()
+            .with(Alice)
+            .with(Bob)
+            .build();
+        let alice = LocalTransport::new(Alice, transport_channel.clone());
+        let bob = LocalTransport::new(Bob, transport_channel.clone());
+        alice
+            .send::<i32>(1, Alice::name(), Bob::name(), &1, None)
+            .unwrap();
+        alice
+            .send::<i32>(2, Alice::name(), Bob::name(), &2, None)
+            .unwrap();
+        assert_eq!(
+            bob.receive::<i32>(2, Alice::name(), Bob::name(), None)
+                .unwrap(),
+            2
+        );
+        assert_eq!(
+            bob.receive::<i32>(1, Alice::name(), Bob::name(), None)
+                .unwrap(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_local_transport_unknown_location() {
+        let transport_channel = LocalTransportChannelBuilder::new().with(Alice).build();
+        let transport = LocalTransport::new(Alice, transport_channel);
+        let result = transport.send::<i32>(0, Alice::name(), Bob::name(), &42, None);
+        assert!(matches!(result, Err(TransportError::UnknownLocation(name)) if name == "Bob"));
+    }
+
+    #[test]
+    fn test_epp_and_run_returns_transport_error() {
+        struct Receive;
+        impl Choreography<Located<i32, Bob>> for Receive {
+            type L = LocationSet!(Alice, Bob);
+            fn run(self, op: &impl ChoreoOp<Self::L>) -> Located<i32, Bob> {
+                let n = op.locally(Alice, |_| 42);
+                op.comm(Alice, Bob, &n)
+            }
+        }
+
+        let transport_channel = LocalTransportChannelBuilder::new()
+            .with(Alice)
+            .with(Bob)
+            .build();
+        // Bob expects an `i32` where Alice sends a `String`
+        let key = transport_channel
+            .key(0, Alice::name(), Bob::name())
+            .unwrap();
+        transport_channel
+            .queue_map
+            .push(key, b"\"not a number\"".to_vec());
+        let transport = LocalTransport::new(Bob, transport_channel);
+        let projector = Projector::new(Bob, transport);
+        let result = projector.epp_and_run(Receive);
+        assert!(matches!(
+            result,
+            Err(ChoreographyError::Transport(
+                TransportError::Deserialization(_)
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_epp_and_run_returns_protocol_mismatch() {
+        struct Receive;
+        impl Choreography<Located<i32, Bob>> for Receive {
+            type L = LocationSet!(Alice, Bob);
+            fn run(self, op: &impl ChoreoOp<Self::L>) -> Located<i32, Bob> {
+                let n = op.locally(Alice, |_| 42);
+                op.comm(Alice, Bob, &n)
+            }
+        }
+
+        // Alice runs a different version of the choreography
+        struct Send;
+        impl Choreography for Send {
+            type L = LocationSet!(Alice, Bob);
+            fn run(self, op: &impl ChoreoOp<Self::L>) {
+                let n = op.locally(Alice, |_| 42u64);
+                op.comm(Alice, Bob, &n);
+                let s = op.locally(Alice, |_| "not a number".to_string());
+                op.comm(Alice, Bob, &s);
+            }
+        }
+
+        let transport_channel = LocalTransportChannelBuilder::new()
+            .with(Alice)
+            .with(Bob)
+            .build();
+        let alice = Projector::new(Alice, LocalTransport::new(Alice, transport_channel.clone()));
+        alice.epp_and_run(Send).unwrap();
+        let bob = Projector::new(Bob, LocalTransport::new(Bob, transport_channel));
+        let result = bob.epp_and_run(Receive);
+        assert!(matches!(
+            result,
+            Err(ChoreographyError::ProtocolMismatch { sender, expected, received })
+                if sender == "Alice"
+                    && expected == (1, "i32".to_string())
+                    && received == (1, "unknown type 0x4d35d3193e8d66f2".to_string())
+        ));
+        // the next run of Bob receives the second message of the same run of Alice
+        let result = bob.epp_and_run(Receive);
+        assert!(matches!(
+            result,
+            Err(ChoreographyError::ProtocolMismatch { received, .. })
+                if received.0 == 2 && received.1.starts_with("unknown type")
+        ));
+    }
+
+    #[test]
+    fn test_local_transport_receive_timeout() {
+        let transport_channel = LocalTransportChannelBuilder::new()
+            .with(Alice)
+            .with(Bob)
+            .build();
+        let bob = LocalTransport::new(Bob, transport_channel.clone())
+            .with_receive_timeout(Duration::from_millis(50));
+        assert!(matches!(
+            bob.receive::<i32>(0, Alice::name(), Bob::name(), None),
+            Err(TransportError::Timeout)
+        ));
+        // an explicit deadline takes precedence over the default timeout
+        let deadline = Instant::now() + Duration::from_millis(10);
+        assert!(matches!(
+            bob.receive::<i32>(0, Alice::name(), Bob::name(), Some(deadline)),
+            Err(TransportError::Timeout)
+        ));
+        assert!(Instant::now() < deadline + Duration::from_millis(40));
+    }
+
+    struct Ping;
+
+    impl Choreography<Located<i32, Bob>> for Ping {
+        type L = LocationSet!(Alice, Bob);
+        fn run(self, op: &impl ChoreoOp<Self::L>) -> Located<i32, Bob> {
+            let ping = op.locally(Alice, |_| {
+                thread::sleep(Duration::from_millis(100));
+                1
+            });
+            op.with_deadline(Duration::from_millis(500), |op| {
+                op.with_deadline(Duration::from_secs(60), |op| op.comm(Alice, Bob, &ping))
+            })
+        }
+    }
+
+    #[test]
+    fn test_with_deadline() {
+        let transport_channel = LocalTransportChannelBuilder::new()
+            .with(Alice)
+            .with(Bob)
+            .build();
+        // Alice takes longer than Bob's default timeout but sends within the deadline
+        let bob = LocalTransport::new(Bob, transport_channel.clone())
+            .with_receive_timeout(Duration::from_millis(10));
+        let alice = LocalTransport::new(Alice, transport_channel.clone());
+        thread::scope(|s| {
+            s.spawn(|| Projector::new(Alice, alice).epp_and_run(Ping).unwrap());
+            let projector = Projector::new(Bob, bob);
+            let ping = projector.epp_and_run(Ping).unwrap();
+            assert_eq!(projector.unwrap(ping), 1);
+        });
+    }
+
+    #[test]
+    fn test_with_deadline_timeout() {
+        let transport_channel = LocalTransportChannelBuilder::new()
+            .with(Alice)
+            .with(Bob)
+            .build();
+        // Alice never runs, and the inner deadline cannot extend the outer one
+        let projector = Projector::new(Bob, LocalTransport::new(Bob, transport_channel));
+        let start = Instant::now();
+        let result = projector.epp_and_run(Ping);
+        assert!(matches!(
+            result,
+            Err(ChoreographyError::Transport(TransportError::Timeout))
+        ));
+        assert!(start.elapsed() < Duration::from_secs(60));
+    }
+
+    #[test]
+    fn test_with_version() {
+        let transport_channel = LocalTransportChannelBuilder::new()
+            .with(Alice)
+            .with(Bob)
+            .build();
+        let alice = Projector::new(Alice, LocalTransport::new(Alice, transport_channel.clone()))
+            .with_version("1.0");
+        let bob = Projector::new(Bob, LocalTransport::new(Bob, transport_channel.clone()))
+            .with_version("1.0");
+        thread::scope(|s| {
+            s.spawn(|| alice.epp_and_run(Ping).unwrap());
+            let ping = bob.epp_and_run(Ping).unwrap();
+            assert_eq!(bob.unwrap(ping), 1);
+        });
+
+        // Bob refuses to run with a peer at another version
+        let bob =
+            Projector::new(Bob, LocalTransport::new(Bob, transport_channel)).with_version("2.0");
+        thread::scope(|s| {
+            s.spawn(|| alice.epp_and_run(Ping));
+            let result = bob.epp_and_run(Ping);
+            assert!(matches!(
+                result,
+                Err(ChoreographyError::FingerprintMismatch { peer, expected, received })
+                    if peer == "Alice"
+                        && expected.version == "2.0"
+                        && received.version == "1.0"
+                        && received.locations == ["Alice", "Bob"]
+                        && received.choreography.ends_with("Ping")
+            ));
+        });
+
+        // Bob reports that Alice has no version when her first message is not a fingerprint
+        let transport_channel = LocalTransportChannelBuilder::new()
+            .with(Alice)
+            .with(Bob)
+            .build();
+        let alice = Projector::new(Alice, LocalTransport::new(Alice, transport_channel.clone()));
+        let bob =
+            Projector::new(Bob, LocalTransport::new(Bob, transport_channel)).with_version("1.0");
+        thread::scope(|s| {
+            s.spawn(|| alice.epp_and_run(Ping).unwrap());
+            let result = bob.epp_and_run(Ping);
+            assert!(matches!(
+                result,
+                Err(ChoreographyError::MissingFingerprint(peer)) if peer == "Alice"
+            ));
+        });
+    }
+
+    // Alice sends a message that `Ping` at Bob never receives
+    struct PingTwice;
+
+    impl Choreography for PingTwice {
+        type L = LocationSet!(Alice, Bob);
+        fn run(self, op: &impl ChoreoOp<Self::L>) {
+            let ping = op.locally(Alice, |_| 1);
+            op.comm(Alice, Bob, &ping);
+            op.comm(Alice, Bob, &ping);
+        }
+    }
+
+    #[test]
+    fn test_with_end_barrier() {
+        let transport_channel = LocalTransportChannelBuilder::new()
+            .with(Alice)
+            .with(Bob)
+            .build();
+        let alice = Projector::new(Alice, LocalTransport::new(Alice, transport_channel.clone()))
+            .with_end_barrier();
+        let bob =
+            Projector::new(Bob, LocalTransport::new(Bob, transport_channel)).with_end_barrier();
+        thread::scope(|s| {
+            s.spawn(|| alice.epp_and_run(Ping).unwrap());
+            let ping = bob.epp_and_run(Ping).unwrap();
+            assert_eq!(bob.unwrap(ping), 1);
+        });
+        thread::scope(|s| {
+            s.spawn(|| alice.epp_and_run(PingTwice).unwrap());
+            let result = bob.epp_and_run(Ping);
+            assert!(matches!(
+                result,
+                Err(ChoreographyError::Transport(TransportError::Unconsumed(peer))) if peer == "Alice"
+            ));
+        });
+        // the barrier drains the unconsumed message, so the next run starts afresh
+        thread::scope(|s| {
+            s.spawn(|| alice.epp_and_run(Ping).unwrap());
+            let ping = bob.epp_and_run(Ping).unwrap();
+            assert_eq!(bob.unwrap(ping), 1);
+        });
+    }
+
+    // Alice panics before the end of the run
+    struct Panic;
+
+    impl Choreography for Panic {
+        type L = LocationSet!(Alice, Bob);
+        fn run(self, op: &impl ChoreoOp<Self::L>) {
+            op.locally(Alice, |_| panic!("Alice fails"));
+        }
+    }
+
+    #[test]
+    fn test_end_barrier_after_panic() {
+        let transport_channel = LocalTransportChannelBuilder::new()
+            .with(Alice)
+            .with(Bob)
+            .build();
+        let alice = Projector::new(Alice, LocalTransport::new(Alice, transport_channel.clone()))
+            .with_end_barrier();
+        let bob =
+            Projector::new(Bob, LocalTransport::new(Bob, transport_channel)).with_end_barrier();
+        thread::scope(|s| {
+            let handle = s.spawn(|| alice.epp_and_run(Panic));
+            // Bob does not wait forever for Alice at the barrier
+            assert!(matches!(
+                bob.epp_and_run(Panic),
+                Err(ChoreographyError::PeerAborted(peer)) if peer == "Alice"
+            ));
+            assert!(handle.join().is_err());
+        });
+    }
+
+    #[test]
+    fn test_local_transport_shutdown() {
+        let transport_channel = LocalTransportChannelBuilder::new()
+            .with(Alice)
+            .with(Bob)
+            .build();
+        let alice = LocalTransport::new(Alice, transport_channel.clone());
+        let bob = LocalTransport::new(Bob, transport_channel);
+        alice
+            .send::<i32>(0, Alice::name(), Bob::name(), &1, None)
+            .unwrap();
+        alice.shutdown(Duration::from_secs(1)).unwrap();
+        assert!(matches!(
+            bob.shutdown(Duration::from_secs(1)),
+            Err(TransportError::Unconsumed(peer)) if peer == "Alice"
+        ));
+        bob.receive::<i32>(0, Alice::name(), Bob::name(), None)
+            .unwrap();
+        bob.shutdown(Duration::from_secs(1)).unwrap();
+    }
+
+    #[test]
+    fn test_unconsumed() {
+        let transport_channel = LocalTransportChannelBuilder::new()
+            .with(Alice)
+            .with(Bob)
+            .build();
+        let alice = Projector::new(Alice, LocalTransport::new(Alice, transport_channel.clone()));
+        let bob = Projector::new(Bob, LocalTransport::new(Bob, transport_channel));
+        alice.epp_and_run(PingTwice).unwrap();
+        alice.epp_and_run_session(1, PingTwice).unwrap();
+        assert_eq!(bob.unconsumed(0), HashMap::from([("Alice", 2)]));
+        bob.epp_and_run_session(1, PingTwice).unwrap();
+        assert_eq!(bob.unconsumed(0), HashMap::from([("Alice", 2)]));
+        assert!(bob.unconsumed(1).is_empty());
+        assert!(alice.unconsumed(0).is_empty());
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "`Bob` never received messages in session 0")]
+    fn test_debug_assert_consumed() {
+        let transport_channel = LocalTransportChannelBuilder::new()
+            .with(Alice)
+            .with(Bob)
+            .build();
+        let alice = Projector::new(Alice, LocalTransport::new(Alice, transport_channel.clone()));
+        let bob = Projector::new(Bob, LocalTransport::new(Bob, transport_channel));
+        alice.epp_and_run(PingTwice).unwrap();
+        bob.epp_and_run(Ping).unwrap();
+        alice.debug_assert_consumed(0);
+        bob.debug_assert_consumed(0);
+    }
+
+    // Alice shares a number with the census of the choreography
+    struct Share;
+
+    impl Choreography<i32> for Share {
+        type L = LocationSet!(Alice, Bob);
+        fn run(self, op: &impl ChoreoOp<Self::L>) -> i32 {
+            op.broadcast(Alice, op.locally(Alice, |_| 1))
+        }
+    }
+
+    // Alice shares a number with every location that calls the choreography
+    struct Announce;
+
+    impl Choreography<i32> for Announce {
+        type L = LocationSet!(Alice);
+        fn run(self, op: &impl ChoreoOp<Self::L>) -> i32 {
+            op.broadcast(Alice, op.locally(Alice, |_| 1))
+        }
+    }
+
+    struct Nested;
+
+    impl Choreography<i32> for Nested {
+        type L = LocationSet!(Alice, Bob);
+        fn run(self, op: &impl ChoreoOp<Self::L>) -> i32 {
+            op.call(Share) + op.call(Announce)
+        }
+    }
+
+    struct CallShare;
+
+    impl Choreography<i32> for CallShare {
+        type L = LocationSet!(Alice, Bob);
+        fn run(self, op: &impl ChoreoOp<Self::L>) -> i32 {
+            op.call(Share) + op.call(Nested)
+        }
+    }
+
+    #[test]
+    fn test_broadcast_in_call() {
+        let transport_channel = LocalTransportChannelBuilder::new()
+            .with(Alice)
+            .with(Bob)
+            .with(Carol)
+            .build();
+        let alice = Projector::new(Alice, LocalTransport::new(Alice, transport_channel.clone()));
+        let bob = Projector::new(Bob, LocalTransport::new(Bob, transport_channel.clone()));
+        let carol = Projector::new(Carol, LocalTransport::new(Carol, transport_channel));
+        thread::scope(|s| {
+            s.spawn(|| assert_eq!(alice.epp_and_run(CallShare).unwrap(), 3));
+            assert_eq!(bob.epp_and_run(CallShare).unwrap(), 3);
+        });
+        // Carol is outside the census, so no broadcast reaches her and she cannot run the choreography
+        assert!(carol.unconsumed(0).is_empty());
+        assert!(matches!(
+            carol.epp_and_run(CallShare),
+            Err(ChoreographyError::NotInChoreography(location)) if location == "Carol"
+        ));
+        alice.debug_assert_consumed(0);
+        bob.debug_assert_consumed(0);
+    }
+
+    #[test]
+    fn test_collectives_on_subset() {
+        // Carol scatters to Alice and Bob, who share the sum of their entries without Carol
+        struct Sum;
+        impl Choreography<Located<i32, Carol>> for Sum {
+            type L = LocationSet!(Alice, Bob, Carol);
+            fn run(self, op: &impl ChoreoOp<Self::L>) -> Located<i32, Carol> {
+                let entries = op.locally(Carol, |_| Quire::new().add(Bob, 2).add(Alice, 1));
+                let entry = op.scatter(Carol, <LocationSet!(Alice, Bob)>::new(), &entries);
+                let sum = op.allreduce(&entry, |a, b| a + b);
+                let product = op.locally(Bob, |un| un.unwrap(&sum) * 10);
+                op.comm(Bob, Carol, &product)
+            }
+        }
+
+        let transport_channel = LocalTransportChannelBuilder::new()
+            .with(Alice)
+            .with(Bob)
+            .with(Carol)
+            .build();
+        let alice = Projector::new(Alice, LocalTransport::new(Alice, transport_channel.clone()));
+        let bob = Projector::new(Bob, LocalTransport::new(Bob, transport_channel.clone()));
+        let carol = Projector::new(Carol, LocalTransport::new(Carol, transport_channel.clone()));
+        thread::scope(|s| {
+            s.spawn(|| alice.epp_and_run(Sum).unwrap());
+            s.spawn(|| bob.epp_and_run(Sum).unwrap());
+            let product = carol.epp_and_run(Sum).unwrap();
+            assert_eq!(carol.unwrap(product), 30);
+        });
+        assert!(transport_channel.queue_map.pending().is_empty());
+    }
+
+    #[test]
+    fn test_faceted_from_quire() {
+        struct Facets;
+        impl Choreography<Faceted<(i32, i32), LocationSet!(Alice, Bob)>> for Facets {
+            type L = LocationSet!(Alice, Bob);
+            fn run(
+                self,
+                op: &impl ChoreoOp<Self::L>,
+            ) -> Faceted<(i32, i32), LocationSet!(Alice, Bob)> {
+                let quire = op.locally(Alice, |_| Quire::new().add(Bob, 2).add(Alice, 1));
+                let quire = op.multicast(Alice, <LocationSet!(Alice, Bob)>::new(), &quire);
+                let facet: Faceted<i32, _> = quire.into();
+                facet
+                    .map(|x| x * 10)
+                    .zip(op.parallel(<LocationSet!(Alice, Bob)>::new(), || 3))
+            }
+        }
+
+        let transport_channel = LocalTransportChannelBuilder::new()
+            .with(Alice)
+            .with(Bob)
+            .build();
+        let alice = Projector::new(Alice, LocalTransport::new(Alice, transport_channel.clone()));
+        let bob = Projector::new(Bob, LocalTransport::new(Bob, transport_channel));
+        thread::scope(|s| {
+            s.spawn(|| {
+                let facet = alice.epp_and_run(Facets).unwrap();
+                assert_eq!(alice.unwrap_faceted(facet), (10, 3));
+            });
+            let facet = bob.epp_and_run(Facets).unwrap();
+            assert_eq!(bob.unwrap_faceted(facet), (20, 3));
+        });
+    }
+
+    // a value that loses its cache when it is sent
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Word {
+        text: String,
+        #[serde(skip)]
+        cached: bool,
+    }
+
+    fn word(text: &str) -> Word {
+        Word {
+            text: text.to_string(),
+            cached: true,
+        }
+    }
+
+    type Workers = LocationSet!(Alice, Bob, Carol);
+
+    // Carol scatters one word to each worker, and the words are gathered at Alice and joined at Bob
+    struct Words;
+
+    impl
+        Choreography<(
+            Located<Quire<Word, Workers>, Alice>,
+            Located<Word, Bob>,
+            usize,
+        )> for Words
+    {
+        type L = Workers;
+        fn run(
+            self,
+            op: &impl ChoreoOp<Self::L>,
+        ) -> (
+            Located<Quire<Word, Workers>, Alice>,
+            Located<Word, Bob>,
+            usize,
+        ) {
+            let words = op.locally(Carol, |_| {
+                Quire::new()
+                    .add(Carol, word("c"))
+                    .add(Bob, word("b"))
+                    .add(Alice, word("a"))
+            });
+            let word = op.scatter(Carol, Workers::new(), &words);
+            let gathered = op.gather(Alice, &word);
+            let joined = op.reduce(Bob, &word, |a, b| Word {
+                text: a.text + &b.text,
+                cached: a.cached || b.cached,
+            });
+            let length = op.allreduce(&word.map(|word| word.text.len()), |a, b| a + b);
+            (gathered, joined, op.naked(length))
+        }
+    }
+
+    fn check_words(gathered: Quire<Word, Workers>, joined: Word) {
+        // every value is sent, even to its own location, so no cache survives
+        let gathered: Vec<_> = gathered
+            .iter()
+            .map(|(location, word)| (location, word.text.as_str(), word.cached))
+            .collect();
+        assert_eq!(
+            gathered,
+            [
+                ("Alice", "a", false),
+                ("Bob", "b", false),
+                ("Carol", "c", false)
+            ]
+        );
+        assert_eq!(
+            joined,
+            Word {
+                text: "abc".to_string(),
+                cached: false
+            }
+        );
+    }
+
+    #[test]
+    fn test_gather_and_reduce() {
+        let runner = Runner::new();
+        let (gathered, joined, length) = runner.run(Words);
+        check_words(runner.unwrap(gathered), runner.unwrap(joined));
+        assert_eq!(length, 3);
+
+        let transport_channel = LocalTransportChannelBuilder::new()
+            .with(Alice)
+            .with(Bob)
+            .with(Carol)
+            .build();
+        let alice = Projector::new(Alice, LocalTransport::new(Alice, transport_channel.clone()));
+        let bob = Projector::new(Bob, LocalTransport::new(Bob, transport_channel.clone()));
+        let carol = Projector::new(Carol, LocalTransport::new(Carol, transport_channel.clone()));
+        thread::scope(|s| {
+            let gathered = s.spawn(|| {
+                let (gathered, _, _) = alice.epp_and_run(Words).unwrap();
+                alice.unwrap(gathered)
+            });
+            let joined = s.spawn(|| {
+                let (_, joined, _) = bob.epp_and_run(Words).unwrap();
+                bob.unwrap(joined)
+            });
+            let (_, _, length) = carol.epp_and_run(Words).unwrap();
+            assert_eq!(length, 3);
+            check_words(gathered.join().unwrap(), joined.join().unwrap());
+        });
+        assert!(transport_channel.queue_map.pending().is_empty());
+    }
+
+    #[test]
+    fn test_scatter_incomplete_quire() {
+        struct Scatter;
+        impl Choreography for Scatter {
+            type L = LocationSet!(Alice, Bob);
+            fn run(self, op: &impl ChoreoOp<Self::L>) {
+                // a quire received from a peer may lack entries
+                let quire = op.locally(Alice, |_| {
+                    let bytes = Json.encode(&Quire::new().add(Alice, 1)).unwrap();
+                    Json.decode::<Quire<i32, LocationSet!(Alice, Bob)>>(&bytes)
+                        .unwrap()
+                });
+                op.scatter(Alice, <LocationSet!(Alice, Bob)>::new(), &quire);
+            }
+        }
+
+        let transport_channel = LocalTransportChannelBuilder::new()
+            .with(Alice)
+            .with(Bob)
+            .build();
+        let alice = Projector::new(Alice, LocalTransport::new(Alice, transport_channel));
+        assert!(matches!(
+            alice.epp_and_run(Scatter),
+            Err(ChoreographyError::MissingEntry(location)) if location == "Bob"
+        ));
+
+        // without projection, there is no caller to return the error to
+        assert_eq!(
+            panic_message(|| Runner::new().run(Scatter)),
+            "quire has no entry for `Bob`"
+        );
+    }
+
+    #[test]
+    fn test_incomplete_quire() {
+        // a quire received from a peer may lack entries
+        let bytes = Json.encode(&Quire::new().add(Alice, 1)).unwrap();
+        let partial = Json
+            .decode::<Quire<i32, LocationSet!(Alice, Bob)>>(&bytes)
+            .unwrap();
+        assert_eq!(partial.get(Alice), Some(&1));
+        assert_eq!(partial.get(Bob), None);
+
+        // `zip` leaves out the locations that only one of the quires has
+        let zipped = partial.zip(Quire::new().add(Bob, "b").add(Alice, "a"));
+        assert_eq!(zipped.get(Alice), Some(&(1, "a")));
+        assert_eq!(zipped.get(Bob), None);
+    }
+
+    /// Runs `f`, which must panic, and returns the message of the panic.
+    fn panic_message(f: impl FnOnce()) -> String {
+        let payload = panic::catch_unwind(AssertUnwindSafe(f)).unwrap_err();
+        *payload.downcast::<String>().unwrap()
+    }
+
+    #[test]
+    fn test_reduce_over_no_locations() {
+        struct Combine {
+            all: bool,
+        }
+        impl Choreography for Combine {
+            type L = LocationSet!(Alice);
+            fn run(self, op: &impl ChoreoOp<Self::L>) {
+                let nothing = op.parallel(<LocationSet!()>::new(), || 0);
+                if self.all {
+                    op.allreduce(&nothing, |a: i32, b| a + b);
+                } else {
+                    op.reduce(Alice, &nothing, |a: i32, b| a + b);
+                }
+            }
+        }
+
+        // `reduce` and `allreduce` both panic at every location, with or without projection
+        for (all, op) in [(false, "reduce"), (true, "allreduce")] {
+            let expected = format!("`{}` needs at least one location to combine", op);
+            assert_eq!(
+                panic_message(|| Runner::new().run(Combine { all })),
+                expected
+            );
+            let transport_channel = LocalTransportChannelBuilder::new().with(Alice).build();
+            let alice = Projector::new(Alice, LocalTransport::new(Alice, transport_channel));
+            assert_eq!(
+                panic_message(|| {
+                    let _ = alice.epp_and_run(Combine { all });
+                }),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_locally_take() {
+        // a handle that cannot be cloned
+        struct Handle(Vec<i32>);
+        struct Consume;
+        impl Choreography<Located<i32, Bob>> for Consume {
+            type L = LocationSet!(Alice, Bob);
+            fn run(self, op: &impl ChoreoOp<Self::L>) -> Located<i32, Bob> {
+                let handle = op.locally(Alice, |_| Handle(vec![1, 2, 3]));
+                let sum = op.locally(Alice, |un| {
+                    let Handle(values) = un.take(handle);
+                    values.into_iter().sum::<i32>()
+                });
+                op.comm(Alice, Bob, &sum)
+            }
+        }
+
+        let runner = Runner::new();
+        assert_eq!(runner.unwrap(runner.run(Consume)), 6);
+
+        let transport_channel = LocalTransportChannelBuilder::new()
+            .with(Alice)
+            .with(Bob)
+            .build();
+        let alice = Projector::new(Alice, LocalTransport::new(Alice, transport_channel.clone()));
+        let bob = Projector::new(Bob, LocalTransport::new(Bob, transport_channel));
+        thread::scope(|s| {
+            s.spawn(|| alice.epp_and_run(Consume).unwrap());
+            let sum = bob.epp_and_run(Consume).unwrap();
+            assert_eq!(bob.unwrap(sum), 6);
+        });
+    }
+
+    #[test]
+    fn test_context() {
+        // each location reads its greeting from its own context
+        struct Greeting(&'static str);
+        fn hello(un: Unwrapper<Alice>) -> String {
+            un.context::<Greeting>().0.to_string()
+        }
+        struct Greet;
+        impl Choreography<Located<String, Bob>> for Greet {
+            type L = LocationSet!(Alice, Bob);
+            fn run(self, op: &impl ChoreoOp<Self::L>) -> Located<String, Bob> {
+                let hello = op.locally(Alice, hello);
+                let hello = op.comm(Alice, Bob, &hello);
+                op.locally(Bob, |un| {
+                    assert!(un.try_context::<String>().is_none());
+                    let Greeting(name) = un.context();
+                    format!("{}, {}", un.unwrap(&hello), name)
+                })
+            }
+        }
+        struct CallGreet;
+        impl Choreography<Located<String, Bob>> for CallGreet {
+            type L = LocationSet!(Alice, Bob, Carol);
+            fn run(self, op: &impl ChoreoOp<Self::L>) -> Located<String, Bob> {
+                op.conclave(Greet).flatten()
+            }
+        }
+
+        let runner = Runner::new()
+            .with_context(Alice, Greeting("Hello"))
+            .with_context(Bob, Greeting("Bob"));
+        assert_eq!(runner.unwrap(runner.run(CallGreet)), "Hello, Bob");
+
+        let transport_channel = LocalTransportChannelBuilder::new()
+            .with(Alice)
+            .with(Bob)
+            .with(Carol)
+            .build();
+        let alice = Projector::new(Alice, LocalTransport::new(Alice, transport_channel.clone()))
+            .with_context(Greeting("Hello"));
+        let bob = Projector::new(Bob, LocalTransport::new(Bob, transport_channel.clone()))
+            .with_context(Greeting("Bob"));
+        let carol = Projector::new(Carol, LocalTransport::new(Carol, transport_channel));
+        thread::scope(|s| {
+            s.spawn(|| alice.epp_and_run(CallGreet).unwrap());
+            s.spawn(|| carol.epp_and_run(CallGreet).unwrap());
+            let greeting = bob.epp_and_run(CallGreet).unwrap();
+            assert_eq!(bob.unwrap(greeting), "Hello, Bob");
+        });
+    }
+
+    // Encodes values as JSON and counts how many values it has encoded
+    struct CountingCodec(Arc<AtomicUsize>);
+
+    impl Codec for CountingCodec {
+        fn encode<V: Portable>(&self, data: &V) -> Result<Vec<u8>, TransportError> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Json.encode(data)
+        }
+
+        fn decode<V: Portable>(&self, bytes: &[u8]) -> Result<V, TransportError> {
+            Json.decode(bytes)
+        }
+    }
+
+    #[test]
+    fn test_local_transport_codec() {
+        struct SelfSend;
+        impl Choreography<Located<i32, Alice>> for SelfSend {
+            type L = LocationSet!(Alice);
+            fn run(self, op: &impl ChoreoOp<Self::L>) -> Located<i32, Alice> {
+                let n = op.locally(Alice, |_| 42);
+                op.comm(Alice, Alice, &n)
+            }
+        }
+
+        let count = Arc::new(AtomicUsize::new(0));
+        let transport_channel = LocalTransportChannelBuilder::new().with(Alice).build();
+        let transport =
+            LocalTransport::with_codec(Alice, transport_channel, CountingCodec(count.clone()));
+        let projector = Projector::new(Alice, transport);
+        let n = projector.epp_and_run(SelfSend).unwrap();
+        assert_eq!(projector.unwrap(n), 42);
+        // values sent to the same location are copied with the transport's codec
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_async_local_transport() {
+        struct PingPong;
+        impl AsyncChoreography<Located<i32, Alice>> for PingPong {
+            type L = LocationSet!(Alice, Bob);
+            async fn run(self, op: &impl AsyncChoreoOp<Self::L>) -> Located<i32, Alice> {
+                let ping = op.locally(Alice, |_| 1);
+                let ping = op.comm(Alice, Bob, &ping).await;
+                let pong = op.locally(Bob, |un| un.unwrap(&ping) + 1);
+                op.comm(Bob, Alice, &pong).await
+            }
+        }
+
+        let transport_channel = LocalTransportChannelBuilder::new()
+            .with(Alice)
+            .with(Bob)
+            .build();
+        let alice_projector = AsyncProjector::new(
+            Alice,
+            AsyncLocalTransport::new(Alice, transport_channel.clone()),
+        );
+        let bob_projector = AsyncProjector::new(
+            Bob,
+            AsyncLocalTransport::new(Bob, transport_channel.clone()),
+        );
+
+        // both locations run on the same thread
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let (alice, bob) = runtime.block_on(async {
+            tokio::join!(
+                alice_projector.epp_and_run(PingPong),
+                bob_projector.epp_and_run(PingPong)
+            )
+        });
+        assert_eq!(alice_projector.unwrap(alice.unwrap()), 2);
+        bob.unwrap();
+    }
+
+    struct AsyncShare;
+
+    impl AsyncChoreography<i32> for AsyncShare {
+        type L = LocationSet!(Alice, Bob);
+        async fn run(self, op: &impl AsyncChoreoOp<Self::L>) -> i32 {
+            op.broadcast(Alice, op.locally(Alice, |_| 1)).await
+        }
+    }
+
+    struct AsyncCallShare;
+
+    impl AsyncChoreography<i32> for AsyncCallShare {
+        type L = LocationSet!(Alice, Bob);
+        async fn run(self, op: &impl AsyncChoreoOp<Self::L>) -> i32 {
+            op.call(AsyncShare).await + op.call(AsyncShare).await
+        }
+    }
+
+    #[test]
+    fn test_async_broadcast_in_call() {
+        let transport_channel = LocalTransportChannelBuilder::new()
+            .with(Alice)
+            .with(Bob)
+            .with(Carol)
+            .build();
+        let alice_projector = AsyncProjector::new(
+            Alice,
+            AsyncLocalTransport::new(Alice, transport_channel.clone()),
+        );
+        let bob_projector = AsyncProjector::new(
+            Bob,
+            AsyncLocalTransport::new(Bob, transport_channel.clone()),
+        );
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let (alice, bob) = runtime.block_on(async {
+            tokio::join!(
+                alice_projector.epp_and_run(AsyncCallShare),
+                bob_projector.epp_and_run(AsyncCallShare)
+            )
+        });
+        assert_eq!(alice.unwrap(), 2);
+        assert_eq!(bob.unwrap(), 2);
+        // Carol is outside the census, so no broadcast reaches her
+        assert!(transport_channel.queue_map.pending().is_empty());
+    }
+
+    #[test]
+    fn test_async_collectives() {
+        struct Sum;
+        impl AsyncChoreography<(Located<i32, Alice>, i32)> for Sum {
+            type L = LocationSet!(Alice, Bob);
+            async fn run(self, op: &impl AsyncChoreoOp<Self::L>) -> (Located<i32, Alice>, i32) {
+                let entries = op.locally(Alice, |_| Quire::new().add(Bob, 2).add(Alice, 1));
+                let entry = op
+                    .scatter(Alice, <LocationSet!(Alice, Bob)>::new(), &entries)
+                    .await;
+                let total = op.reduce(Alice, &entry, |a, b| a + b).await;
+                let max = op.allreduce(&entry, i32::max).await;
+                (total, op.naked(max))
+            }
+        }
+
+        let transport_channel = LocalTransportChannelBuilder::new()
+            .with(Alice)
+            .with(Bob)
+            .build();
+        let alice_projector = AsyncProjector::new(
+            Alice,
+            AsyncLocalTransport::new(Alice, transport_channel.clone()),
+        );
+        let bob_projector = AsyncProjector::new(
+            Bob,
+            AsyncLocalTransport::new(Bob, transport_channel.clone()),
+        );
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let (alice, bob) = runtime.block_on(async {
+            tokio::join!(
+                alice_projector.epp_and_run(Sum),
+                bob_projector.epp_and_run(Sum)
+            )
+        });
+        let (total, max) = alice.unwrap();
+        assert_eq!(alice_projector.unwrap(total), 3);
+        assert_eq!(max, 2);
+        assert_eq!(bob.unwrap().1, 2);
+        assert!(transport_channel.queue_map.pending().is_empty());
+    }
 }
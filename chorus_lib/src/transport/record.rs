@@ -0,0 +1,464 @@
+//! Transports that record and replay the messages of one location.
+//!
+//! `RecordingTransport` wraps another transport and appends every message that the location sends or receives to a
+//! log file. `ReplayTransport` reads the log back and delivers the recorded inbound messages to the same location,
+//! so a run can be reproduced for that location alone, for example under a debugger, without running its peers.
+//!
+//! The log is a sequence of `Record`s, one JSON object per line.
+
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::marker::PhantomData;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::codec::{Codec, Json};
+use crate::core::{
+    value_type_name, ChoreographyLocation, LocationSet, Portable, SessionId, Transport,
+    TransportError,
+};
+
+/// The direction of a recorded message, as seen from the recording location.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Direction {
+    /// The location sent the message.
+    Send,
+    /// The location received the message.
+    Receive,
+}
+
+/// A message recorded by `RecordingTransport`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Record {
+    /// The position of the message in the log, starting at `0`
+    pub sequence: u64,
+    /// The session the message was sent in
+    pub session: SessionId,
+    /// Whether the message was sent or received
+    pub direction: Direction,
+    /// The name of the sending location
+    pub from: String,
+    /// The name of the receiving location
+    pub to: String,
+    /// The Rust type of the value that the message carries
+    pub type_name: String,
+    /// The message encoded with the codec of the recorded transport
+    pub payload: Vec<u8>,
+}
+
+/// Reads the records of a log written by `RecordingTransport`.
+pub fn read_log(path: impl AsRef<Path>) -> io::Result<Vec<Record>> {
+    let reader = BufReader::new(File::open(path)?);
+    reader
+        .lines()
+        .map(|line| serde_json::from_str(&line?).map_err(io::Error::from))
+        .collect()
+}
+
+/// The open log file and the sequence number of the next record.
+struct Log {
+    file: File,
+    sequence: u64,
+}
+
+/// A transport that records every message sent or received through another transport.
+///
+/// Each message is appended to the log as soon as it has been sent or received, so the log is complete up to the
+/// point where a run fails or the process crashes.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use chorus_lib::core::{ChoreographyLocation, LocationSet};
+/// # use chorus_lib::transport::local::{LocalTransport, LocalTransportChannelBuilder};
+/// # use chorus_lib::transport::record::RecordingTransport;
+/// #
+/// # #[derive(ChoreographyLocation)]
+/// # struct Alice;
+/// #
+/// # #[derive(ChoreographyLocation)]
+/// # struct Bob;
+/// #
+/// let transport_channel = LocalTransportChannelBuilder::new().with(Alice).with(Bob).build();
+/// let transport = RecordingTransport::new(
+///     LocalTransport::new(Alice, transport_channel.clone()),
+///     "alice.log",
+/// )
+/// .unwrap();
+/// ```
+pub struct RecordingTransport<T> {
+    transport: T,
+    log: Mutex<Log>,
+}
+
+impl<T> RecordingTransport<T> {
+    /// Wraps `transport` and records its messages to the file at `path`.
+    ///
+    /// The file is created, or truncated if it exists.
+    pub fn new(transport: T, path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(RecordingTransport {
+            transport,
+            log: Mutex::new(Log {
+                file: File::create(path)?,
+                sequence: 0,
+            }),
+        })
+    }
+
+    /// Returns the wrapped transport.
+    pub fn into_inner(self) -> T {
+        self.transport
+    }
+
+    fn record<V: Portable>(
+        &self,
+        codec: &impl Codec,
+        session: SessionId,
+        direction: Direction,
+        from: &str,
+        to: &str,
+        data: &V,
+    ) -> Result<(), TransportError> {
+        let payload = codec.encode(data)?;
+        let mut log = self.log.lock().unwrap();
+        let record = Record {
+            sequence: log.sequence,
+            session,
+            direction,
+            from: from.to_string(),
+            to: to.to_string(),
+            type_name: value_type_name::<V>().to_string(),
+            payload,
+        };
+        let mut line = serde_json::to_vec(&record)
+            .map_err(|err| TransportError::Serialization(err.to_string()))?;
+        line.push(b'\n');
+        log.file.write_all(&line)?;
+        log.sequence += 1;
+        Ok(())
+    }
+}
+
+impl<L: LocationSet, TLocation: ChoreographyLocation, T: Transport<L, TLocation>>
+    Transport<L, TLocation> for RecordingTransport<T>
+{
+    type Codec = T::Codec;
+
+    fn codec(&self) -> &T::Codec {
+        self.transport.codec()
+    }
+
+    fn locations(&self) -> Vec<&'static str> {
+        self.transport.locations()
+    }
+
+    fn send<V: Portable>(
+        &self,
+        session: SessionId,
+        from: &str,
+        to: &str,
+        data: &V,
+        deadline: Option<Instant>,
+    ) -> Result<(), TransportError> {
+        self.transport.send(session, from, to, data, deadline)?;
+        self.record(
+            self.transport.codec(),
+            session,
+            Direction::Send,
+            from,
+            to,
+            data,
+        )
+    }
+
+    fn send_duplicate<V: Portable>(
+        &self,
+        session: SessionId,
+        from: &str,
+        to: &str,
+        data: &V,
+        deadline: Option<Instant>,
+    ) -> Result<(), TransportError> {
+        // the choreography sent the message once
+        self.transport
+            .send_duplicate(session, from, to, data, deadline)?;
+        self.record(
+            self.transport.codec(),
+            session,
+            Direction::Send,
+            from,
+            to,
+            data,
+        )
+    }
+
+    fn receive<V: Portable>(
+        &self,
+        session: SessionId,
+        from: &str,
+        at: &str,
+        deadline: Option<Instant>,
+    ) -> Result<V, TransportError> {
+        let data = self.transport.receive(session, from, at, deadline)?;
+        self.record(
+            self.transport.codec(),
+            session,
+            Direction::Receive,
+            from,
+            at,
+            &data,
+        )?;
+        Ok(data)
+    }
+
+    fn wait_ready(&self, timeout: Duration) -> Result<(), TransportError> {
+        self.transport.wait_ready(timeout)
+    }
+
+    fn shutdown(&self, timeout: Duration) -> Result<(), TransportError> {
+        self.transport.shutdown(timeout)
+    }
+
+    fn unconsumed(&self, session: SessionId) -> HashMap<&'static str, usize> {
+        self.transport.unconsumed(session)
+    }
+}
+
+/// Recorded messages are queued per (session, sender, receiver).
+type Key = (SessionId, String, String);
+
+/// A transport that replays the messages received by one location in a log written by `RecordingTransport`.
+///
+/// `receive` returns the recorded messages in the order they were received, without waiting for any peer.
+/// Messages sent by the location are discarded. The transport must use the same codec as the recorded transport.
+///
+/// `receive` fails with `TransportError::Deserialization` if the recorded message has a different type than the one
+/// requested, which means that the location no longer runs the same choreography, and with
+/// `TransportError::PeerClosed` if the log has no more messages from the sender.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use chorus_lib::core::{ChoreographyLocation, LocationSet};
+/// # use chorus_lib::transport::record::ReplayTransport;
+/// #
+/// # #[derive(ChoreographyLocation)]
+/// # struct Alice;
+/// #
+/// # #[derive(ChoreographyLocation)]
+/// # struct Bob;
+/// #
+/// let transport = ReplayTransport::<LocationSet!(Alice, Bob), _>::new(Alice, "alice.log").unwrap();
+/// ```
+pub struct ReplayTransport<L: LocationSet, TLocation, C: Codec = Json> {
+    queues: Mutex<HashMap<Key, VecDeque<Record>>>,
+    codec: C,
+    location_set: PhantomData<L>,
+    target_location: PhantomData<TLocation>,
+}
+
+impl<L: LocationSet, TLocation: ChoreographyLocation> ReplayTransport<L, TLocation> {
+    /// Creates a new `ReplayTransport` for `target` from the log at `path`.
+    ///
+    /// Messages are decoded as JSON.
+    pub fn new(target: TLocation, path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::with_codec(target, path, Json)
+    }
+}
+
+impl<L: LocationSet, TLocation: ChoreographyLocation, C: Codec> ReplayTransport<L, TLocation, C> {
+    /// Creates a new `ReplayTransport` for `target` from the log at `path` that decodes messages with `codec`.
+    pub fn with_codec(target: TLocation, path: impl AsRef<Path>, codec: C) -> io::Result<Self> {
+        _ = target;
+        let mut queues: HashMap<Key, VecDeque<Record>> = HashMap::new();
+        for record in read_log(path)? {
+            if record.direction == Direction::Receive && record.to == TLocation::name() {
+                let key = (record.session, record.from.clone(), record.to.clone());
+                queues.entry(key).or_default().push_back(record);
+            }
+        }
+        Ok(ReplayTransport {
+            queues: Mutex::new(queues),
+            codec,
+            location_set: PhantomData,
+            target_location: PhantomData,
+        })
+    }
+}
+
+impl<L: LocationSet, TLocation: ChoreographyLocation, C: Codec> Transport<L, TLocation>
+    for ReplayTransport<L, TLocation, C>
+{
+    type Codec = C;
+
+    fn codec(&self) -> &C {
+        &self.codec
+    }
+
+    fn locations(&self) -> Vec<&'static str> {
+        L::to_string_list()
+    }
+
+    fn send<V: Portable>(
+        &self,
+        _session: SessionId,
+        _from: &str,
+        to: &str,
+        _data: &V,
+        _deadline: Option<Instant>,
+    ) -> Result<(), TransportError> {
+        if !L::to_string_list().contains(&to) {
+            return Err(TransportError::UnknownLocation(to.to_string()));
+        }
+        Ok(())
+    }
+
+    fn receive<V: Portable>(
+        &self,
+        session: SessionId,
+        from: &str,
+        at: &str,
+        _deadline: Option<Instant>,
+    ) -> Result<V, TransportError> {
+        if !L::to_string_list().contains(&from) {
+            return Err(TransportError::UnknownLocation(from.to_string()));
+        }
+        let record = self
+            .queues
+            .lock()
+            .unwrap()
+            .get_mut(&(session, from.to_string(), at.to_string()))
+            .and_then(VecDeque::pop_front)
+            .ok_or_else(|| TransportError::PeerClosed(from.to_string()))?;
+        if record.type_name != value_type_name::<V>() {
+            return Err(TransportError::Deserialization(format!(
+                "expected a message of type `{}`, but message {} has type `{}`",
+                value_type_name::<V>(),
+                record.sequence,
+                record.type_name
+            )));
+        }
+        self.codec.decode(&record.payload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::thread;
+
+    use super::*;
+    use crate::core::{ChoreoOp, Choreography, ChoreographyError, Located, Projector};
+    use crate::transport::local::{LocalTransport, LocalTransportChannelBuilder};
+
+    #[derive(ChoreographyLocation)]
+    struct Alice;
+
+    #[derive(ChoreographyLocation)]
+    struct Bob;
+
+    fn log_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("chorus-{}-{}.log", std::process::id(), name))
+    }
+
+    struct Sum {
+        x: Located<i32, Alice>,
+    }
+
+    impl Choreography<Located<i32, Bob>> for Sum {
+        type L = LocationSet!(Alice, Bob);
+        fn run(self, op: &impl ChoreoOp<Self::L>) -> Located<i32, Bob> {
+            let x = op.comm(Alice, Bob, &self.x);
+            let y = op.locally(Bob, |un| un.unwrap(&x) * 2);
+            let y = op.comm(Bob, Alice, &y);
+            let z = op.locally(Alice, |un| un.unwrap(&y) + 1);
+            let z = op.comm(Alice, Bob, &z);
+            op.locally(Bob, |un| un.unwrap(&x) + un.unwrap(&z))
+        }
+    }
+
+    #[test]
+    fn test_record_and_replay() {
+        let path = log_path("test_record_and_replay");
+        let transport_channel = LocalTransportChannelBuilder::new()
+            .with(Alice)
+            .with(Bob)
+            .build();
+        let alice_projector =
+            Projector::new(Alice, LocalTransport::new(Alice, transport_channel.clone()));
+        let bob_projector = Projector::new(
+            Bob,
+            RecordingTransport::new(LocalTransport::new(Bob, transport_channel.clone()), &path)
+                .unwrap(),
+        );
+        let recorded = thread::scope(|s| {
+            s.spawn(|| {
+                alice_projector
+                    .epp_and_run(Sum {
+                        x: alice_projector.local(3),
+                    })
+                    .unwrap();
+            });
+            bob_projector
+                .epp_and_run(Sum {
+                    x: bob_projector.remote(Alice),
+                })
+                .unwrap()
+        });
+        assert_eq!(bob_projector.unwrap(recorded), 10);
+
+        let log = read_log(&path).unwrap();
+        let directions: Vec<Direction> = log.iter().map(|record| record.direction).collect();
+        assert_eq!(
+            directions,
+            [Direction::Receive, Direction::Send, Direction::Receive]
+        );
+        assert_eq!(
+            log.iter().map(|record| record.sequence).collect::<Vec<_>>(),
+            [0, 1, 2]
+        );
+        assert_eq!(log[0].from, "Alice");
+        // operators tag each value with the hash of its type name and its step, but the log names the type of the value
+        assert_eq!(log[0].type_name, "i32");
+        assert_eq!(log[0].payload, br#"[3094732814638223685,1,3]"#);
+
+        // Bob runs alone
+        let projector = Projector::new(
+            Bob,
+            ReplayTransport::<LocationSet!(Alice, Bob), _>::new(Bob, &path).unwrap(),
+        );
+        let replayed = projector
+            .epp_and_run(Sum {
+                x: projector.remote(Alice),
+            })
+            .unwrap();
+        assert_eq!(projector.unwrap(replayed), 10);
+
+        // the log has no more messages
+        let transport = ReplayTransport::<LocationSet!(Alice, Bob), _>::new(Bob, &path).unwrap();
+        assert!(matches!(
+            transport.receive::<String>(0, Alice::name(), Bob::name(), None),
+            Err(TransportError::Deserialization(_))
+        ));
+        let projector = Projector::new(Bob, transport);
+        // the first message was consumed above, so the choreography receives the last message first
+        assert!(matches!(
+            projector.epp_and_run(Sum {
+                x: projector.remote(Alice),
+            }),
+            Err(ChoreographyError::ProtocolMismatch { expected, received, .. })
+                if expected.0 == 1 && received.0 == 2
+        ));
+        assert!(matches!(
+            projector.epp_and_run(Sum {
+                x: projector.remote(Alice),
+            }),
+            Err(ChoreographyError::Transport(TransportError::PeerClosed(_)))
+        ));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,539 @@
+//! The TCP transport.
+
+use std::collections::HashMap;
+use std::io;
+use std::marker::PhantomData;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::{
+    codec::{Codec, Json},
+    core::{
+        ChoreographyLocation, LocationSet, Member, Portable, SessionId, Transport, TransportError,
+    },
+    transport::envelope::Envelopes,
+    transport::framed::{Connection, Endpoint, Listener},
+    transport::{receive_deadline, send_deadline, TransportConfig, TransportConfigBuilder},
+};
+
+/// Config for `TcpTransport`.
+pub type TcpTransportConfig<'a, L, Target> =
+    TransportConfig<'a, Target, (&'a str, u16), L, (&'a str, u16)>;
+
+/// A builder for `TcpTransportConfig`.
+///
+/// # Examples
+///
+/// ```
+/// # use chorus_lib::core::{LocationSet, ChoreographyLocation};
+/// # use chorus_lib::transport::tcp::TcpTransportConfigBuilder;
+/// #
+/// # #[derive(ChoreographyLocation)]
+/// # struct Alice;
+/// #
+/// # #[derive(ChoreographyLocation)]
+/// # struct Bob;
+/// #
+/// let transport_config = TcpTransportConfigBuilder::for_target(Alice, ("0.0.0.0", 9010))
+///   .with(Bob, ("example.com", 9011))
+///   .build();
+/// ```
+pub type TcpTransportConfigBuilder<'a, Target, L> =
+    TransportConfigBuilder<'a, Target, (&'a str, u16), L, (&'a str, u16)>;
+
+impl Connection for TcpStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        TcpStream::try_clone(self)
+    }
+    fn shutdown(&self) -> io::Result<()> {
+        TcpStream::shutdown(self, Shutdown::Both)
+    }
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+}
+
+/// Opens a connection to a peer listening on `hostname` and `port`.
+fn connect(hostname: &str, port: u16) -> io::Result<TcpStream> {
+    let stream = TcpStream::connect((hostname, port))?;
+    stream.set_nodelay(true)?;
+    Ok(stream)
+}
+
+impl Listener for TcpListener {
+    type Connection = TcpStream;
+    fn accept(&self) -> io::Result<TcpStream> {
+        let (stream, _) = TcpListener::accept(self)?;
+        stream.set_nodelay(true)?;
+        Ok(stream)
+    }
+}
+
+/// The TCP transport.
+///
+/// Each location listens on the address given for the target in the config. Messages to a peer are sent as
+/// length-prefixed frames over a single persistent connection, which is opened on the first send and
+/// re-established if it breaks. The first frame of every connection names the sending location.
+pub struct TcpTransport<'a, L: LocationSet, TLocation, C: Codec = Json> {
+    config: HashMap<&'static str, (&'a str, u16)>,
+    local_addr: SocketAddr,
+    receive_timeout: Option<Duration>,
+    send_timeout: Duration,
+    envelopes: Arc<Envelopes>,
+    endpoint: Endpoint<L, TcpStream>,
+    codec: C,
+    target_location: PhantomData<TLocation>,
+}
+
+impl<'a, L: LocationSet, TLocation: ChoreographyLocation> TcpTransport<'a, L, TLocation> {
+    /// Creates a new `TcpTransport` instance from the configuration.
+    ///
+    /// The transport starts listening before this method returns. Messages are encoded as JSON. Fails if the
+    /// transport cannot listen on the address of the target, for example because the port is already in use.
+    pub fn new<Index>(tcp_config: TcpTransportConfig<'a, L, TLocation>) -> io::Result<Self>
+    where
+        TLocation: Member<L, Index>,
+    {
+        Self::with_codec(tcp_config, Json)
+    }
+}
+
+impl<'a, L: LocationSet, TLocation: ChoreographyLocation, C: Codec>
+    TcpTransport<'a, L, TLocation, C>
+{
+    /// Creates a new `TcpTransport` instance from the configuration that encodes messages with `codec`.
+    ///
+    /// Fails if the transport cannot listen on the address of the target.
+    pub fn with_codec<Index>(
+        tcp_config: TcpTransportConfig<'a, L, TLocation>,
+        codec: C,
+    ) -> io::Result<Self>
+    where
+        TLocation: Member<L, Index>,
+    {
+        let (_, (hostname, port)) = &tcp_config.target_info;
+        let listener = TcpListener::bind((*hostname, *port))?;
+        let local_addr = listener.local_addr()?;
+        let envelopes = Arc::new(Envelopes::new(
+            tcp_config.info.keys().cloned(),
+            tcp_config.keys,
+        ));
+        let endpoint = Endpoint::new(
+            listener,
+            tcp_config.info.keys().cloned(),
+            envelopes.clone(),
+            TLocation::name(),
+            tcp_config.backoff,
+            tcp_config.max_message_size,
+        );
+        Ok(Self {
+            config: tcp_config.info,
+            local_addr,
+            receive_timeout: tcp_config.receive_timeout,
+            send_timeout: tcp_config.send_timeout,
+            envelopes,
+            endpoint,
+            codec,
+            target_location: PhantomData,
+        })
+    }
+
+    /// Seals a message once and sends it `copies` times.
+    fn send_copies<V: Portable>(
+        &self,
+        session: SessionId,
+        from: &str,
+        to: &str,
+        data: &V,
+        deadline: Option<Instant>,
+        copies: usize,
+    ) -> Result<(), TransportError> {
+        let (hostname, port) = self
+            .config
+            .get(to)
+            .ok_or_else(|| TransportError::UnknownLocation(to.to_string()))?;
+        let mut message = self
+            .envelopes
+            .seal(session, from, to, self.codec.encode(data)?);
+        let deadline = send_deadline(deadline, self.send_timeout);
+        for _ in 0..copies {
+            self.endpoint
+                .send(session, from, to, &mut message, deadline, || {
+                    connect(hostname, *port)
+                })?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a, L: LocationSet, TLocation, C: Codec> Drop for TcpTransport<'a, L, TLocation, C> {
+    fn drop(&mut self) {
+        let mut addr = self.local_addr;
+        if addr.ip().is_unspecified() {
+            addr.set_ip(match addr.ip() {
+                IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+            });
+        }
+        self.endpoint.close(|| {
+            let _ = TcpStream::connect(addr);
+        });
+    }
+}
+
+impl<'a, L: LocationSet, TLocation: ChoreographyLocation, C: Codec> Transport<L, TLocation>
+    for TcpTransport<'a, L, TLocation, C>
+{
+    type Codec = C;
+
+    fn codec(&self) -> &C {
+        &self.codec
+    }
+
+    fn locations(&self) -> Vec<&'static str> {
+        self.config.keys().cloned().collect()
+    }
+
+    fn send<V: Portable>(
+        &self,
+        session: SessionId,
+        from: &str,
+        to: &str,
+        data: &V,
+        deadline: Option<Instant>,
+    ) -> Result<(), TransportError> {
+        self.send_copies(session, from, to, data, deadline, 1)
+    }
+
+    fn send_duplicate<V: Portable>(
+        &self,
+        session: SessionId,
+        from: &str,
+        to: &str,
+        data: &V,
+        deadline: Option<Instant>,
+    ) -> Result<(), TransportError> {
+        self.send_copies(session, from, to, data, deadline, 2)
+    }
+
+    fn receive<V: Portable>(
+        &self,
+        session: SessionId,
+        from: &str,
+        _at: &str,
+        deadline: Option<Instant>,
+    ) -> Result<V, TransportError> {
+        let deadline = receive_deadline(deadline, self.receive_timeout);
+        let body = self.endpoint.receive(session, from, deadline)?;
+        self.codec.decode(&body)
+    }
+
+    fn wait_ready(&self, timeout: Duration) -> Result<(), TransportError> {
+        self.endpoint
+            .wait_ready(TLocation::name(), timeout, |peer| {
+                let (hostname, port) = self.config[peer];
+                connect(hostname, port)
+            })
+    }
+
+    fn shutdown(&self, timeout: Duration) -> Result<(), TransportError> {
+        self.endpoint.shutdown(TLocation::name(), timeout, |peer| {
+            let (hostname, port) = self.config[peer];
+            connect(hostname, port)
+        })
+    }
+
+    fn unconsumed(&self, session: SessionId) -> HashMap<&'static str, usize> {
+        self.endpoint.unconsumed(session)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+    use std::thread::{self, sleep};
+    use std::time::Duration;
+
+    use super::*;
+    use crate::core::{
+        ChoreoOp, Choreography, ChoreographyError, ChoreographyLocation, Located, Projector,
+    };
+    use crate::transport::Backoff;
+
+    #[derive(ChoreographyLocation)]
+    struct Alice;
+
+    #[derive(ChoreographyLocation)]
+    struct Bob;
+
+    #[derive(ChoreographyLocation)]
+    struct Carol;
+
+    #[test]
+    fn test_tcp_transport() {
+        let mut handles = Vec::new();
+        {
+            let config = TcpTransportConfigBuilder::for_target(Alice, ("0.0.0.0", 9110))
+                .with(Bob, ("localhost", 9111))
+                .build();
+
+            handles.push(thread::spawn(move || {
+                let transport = TcpTransport::new(config).unwrap();
+                transport.wait_ready(Duration::from_secs(10)).unwrap();
+                for v in 0..10 {
+                    transport
+                        .send::<i32>(0, Alice::name(), Bob::name(), &v, None)
+                        .unwrap();
+                }
+                let ack = transport
+                    .receive::<String>(0, Bob::name(), Alice::name(), None)
+                    .unwrap();
+                assert_eq!(ack, "done");
+            }));
+        }
+        {
+            let config = TcpTransportConfigBuilder::for_target(Bob, ("0.0.0.0", 9111))
+                .with(Alice, ("localhost", 9110))
+                .build();
+
+            handles.push(thread::spawn(move || {
+                let transport = TcpTransport::new(config).unwrap();
+                transport.wait_ready(Duration::from_secs(10)).unwrap();
+                for v in 0..10 {
+                    let v2 = transport
+                        .receive::<i32>(0, Alice::name(), Bob::name(), None)
+                        .unwrap();
+                    assert_eq!(v, v2);
+                }
+                transport
+                    .send(0, Bob::name(), Alice::name(), &"done".to_string(), None)
+                    .unwrap();
+            }));
+        }
+        for handle in handles {
+            handle.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_tcp_transport_reconnect() {
+        let (signal, wait) = mpsc::channel::<()>();
+
+        let mut handles = Vec::new();
+        {
+            let config = TcpTransportConfigBuilder::for_target(Alice, ("0.0.0.0", 9120))
+                .with(Bob, ("localhost", 9121))
+                .build();
+
+            handles.push(thread::spawn(move || {
+                signal.send(()).unwrap();
+                let transport = TcpTransport::new(config).unwrap();
+                // Bob is not listening yet, which forces Alice to retry
+                transport
+                    .send::<i32>(0, Alice::name(), Bob::name(), &1, None)
+                    .unwrap();
+                // Bob restarts, which breaks the connection
+                transport
+                    .receive::<()>(0, Bob::name(), Alice::name(), None)
+                    .unwrap();
+                sleep(Duration::from_millis(100));
+                transport
+                    .send::<i32>(0, Alice::name(), Bob::name(), &2, None)
+                    .unwrap();
+            }));
+        }
+        {
+            handles.push(thread::spawn(move || {
+                wait.recv().unwrap();
+                sleep(Duration::from_millis(100));
+                {
+                    let config = TcpTransportConfigBuilder::for_target(Bob, ("0.0.0.0", 9121))
+                        .with(Alice, ("localhost", 9120))
+                        .build();
+                    let transport = TcpTransport::new(config).unwrap();
+                    let v = transport
+                        .receive::<i32>(0, Alice::name(), Bob::name(), None)
+                        .unwrap();
+                    assert_eq!(v, 1);
+                    transport
+                        .send(0, Bob::name(), Alice::name(), &(), None)
+                        .unwrap();
+                }
+                let config = TcpTransportConfigBuilder::for_target(Bob, ("0.0.0.0", 9121))
+                    .with(Alice, ("localhost", 9120))
+                    .build();
+                let transport = TcpTransport::new(config).unwrap();
+                let v = transport
+                    .receive::<i32>(0, Alice::name(), Bob::name(), None)
+                    .unwrap();
+                assert_eq!(v, 2);
+            }));
+        }
+        for handle in handles {
+            handle.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_tcp_transport_receive_timeout() {
+        let config = TcpTransportConfigBuilder::for_target(Alice, ("0.0.0.0", 9130))
+            .with(Bob, ("localhost", 9131))
+            .with_receive_timeout(Duration::from_millis(50))
+            .build();
+        let transport = TcpTransport::new(config).unwrap();
+        assert!(matches!(
+            transport.receive::<i32>(0, Bob::name(), Alice::name(), None),
+            Err(TransportError::Timeout)
+        ));
+    }
+
+    #[test]
+    fn test_tcp_transport_send_timeout() {
+        let config = TcpTransportConfigBuilder::for_target(Alice, ("0.0.0.0", 9170))
+            .with(Bob, ("localhost", 9171))
+            .with_send_timeout(Duration::from_millis(100))
+            .build();
+        let transport = TcpTransport::new(config).unwrap();
+        // Bob never listens
+        let start = Instant::now();
+        assert!(matches!(
+            transport.send::<i32>(0, Alice::name(), Bob::name(), &1, None),
+            Err(TransportError::Timeout)
+        ));
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_tcp_transport_send_deadline() {
+        struct Ping;
+        impl Choreography<Located<i32, Bob>> for Ping {
+            type L = LocationSet!(Alice, Bob);
+            fn run(self, op: &impl ChoreoOp<Self::L>) -> Located<i32, Bob> {
+                let ping = op.locally(Alice, |_| 1);
+                op.with_deadline(Duration::from_millis(100), |op| op.comm(Alice, Bob, &ping))
+            }
+        }
+
+        // the send timeout is much longer than the deadline
+        let config = TcpTransportConfigBuilder::for_target(Alice, ("0.0.0.0", 9180))
+            .with(Bob, ("localhost", 9181))
+            .build();
+        let projector = Projector::new(Alice, TcpTransport::new(config).unwrap());
+        // Bob never starts
+        let start = Instant::now();
+        assert!(matches!(
+            projector.epp_and_run(Ping),
+            Err(ChoreographyError::Transport(TransportError::Timeout))
+        ));
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_tcp_transport_wait_ready() {
+        let config = TcpTransportConfigBuilder::for_target(Alice, ("0.0.0.0", 9150))
+            .with(Bob, ("localhost", 9151))
+            .with_backoff(Backoff {
+                initial: Duration::from_millis(5),
+                max: Duration::from_millis(20),
+            })
+            .build();
+        let alice = TcpTransport::new(config).unwrap();
+        // Bob is not listening
+        assert!(matches!(
+            alice.wait_ready(Duration::from_millis(100)),
+            Err(TransportError::Timeout)
+        ));
+        let config = TcpTransportConfigBuilder::for_target(Bob, ("0.0.0.0", 9151))
+            .with(Alice, ("localhost", 9150))
+            .build();
+        let bob = TcpTransport::new(config).unwrap();
+        alice.wait_ready(Duration::from_secs(10)).unwrap();
+        alice
+            .send::<i32>(0, Alice::name(), Bob::name(), &1, None)
+            .unwrap();
+        assert_eq!(
+            bob.receive::<i32>(0, Alice::name(), Bob::name(), None)
+                .unwrap(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_tcp_transport_shutdown() {
+        let config = TcpTransportConfigBuilder::for_target(Alice, ("0.0.0.0", 9160))
+            .with(Bob, ("localhost", 9161))
+            .build();
+        let alice = TcpTransport::new(config).unwrap();
+        let config = TcpTransportConfigBuilder::for_target(Bob, ("0.0.0.0", 9161))
+            .with(Alice, ("localhost", 9160))
+            .build();
+        let bob = TcpTransport::new(config).unwrap();
+        for i in 1..=2 {
+            alice
+                .send::<i32>(0, Alice::name(), Bob::name(), &i, None)
+                .unwrap();
+        }
+        bob.send::<i32>(0, Bob::name(), Alice::name(), &3, None)
+            .unwrap();
+        assert_eq!(
+            bob.receive::<i32>(0, Alice::name(), Bob::name(), None)
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            alice
+                .receive::<i32>(0, Bob::name(), Alice::name(), None)
+                .unwrap(),
+            3
+        );
+        thread::scope(|s| {
+            let handle = s.spawn(|| alice.shutdown(Duration::from_secs(10)));
+            // Bob never received the second message from Alice
+            assert!(matches!(
+                bob.shutdown(Duration::from_secs(10)),
+                Err(TransportError::Unconsumed(peer)) if peer == "Alice"
+            ));
+            handle.join().unwrap().unwrap();
+        });
+        // the sequence numbers of the session are dropped once both locations have shut down
+        assert_eq!(alice.envelopes.sessions(), 0);
+        assert_eq!(bob.envelopes.sessions(), 0);
+    }
+
+    #[test]
+    fn test_tcp_transport_keys() {
+        let bob_config = TcpTransportConfigBuilder::for_target(Bob, ("0.0.0.0", 9141))
+            .with(Alice, ("localhost", 9140))
+            .with_key(Alice, "secret")
+            .build();
+        let bob = TcpTransport::new(bob_config).unwrap();
+        let alice_config = TcpTransportConfigBuilder::for_target(Alice, ("0.0.0.0", 9140))
+            .with(Bob, ("localhost", 9141))
+            .with_key(Bob, "secret")
+            .build();
+        let alice = TcpTransport::new(alice_config).unwrap();
+        // Carol does not know the key and pretends to be Alice
+        let carol_config = TcpTransportConfigBuilder::for_target(Carol, ("0.0.0.0", 9142))
+            .with(Bob, ("localhost", 9141))
+            .with_key(Bob, "guess")
+            .build();
+        let carol = TcpTransport::new(carol_config).unwrap();
+
+        carol
+            .send::<i32>(0, Alice::name(), Bob::name(), &1, None)
+            .unwrap();
+        assert!(matches!(
+            bob.receive::<i32>(0, Alice::name(), Bob::name(), None),
+            Err(TransportError::Unauthenticated(name)) if name == Alice::name()
+        ));
+        alice
+            .send::<i32>(0, Alice::name(), Bob::name(), &2, None)
+            .unwrap();
+        assert_eq!(
+            bob.receive::<i32>(0, Alice::name(), Bob::name(), None)
+                .unwrap(),
+            2
+        );
+    }
+}
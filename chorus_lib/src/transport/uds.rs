@@ -0,0 +1,355 @@
+//! The Unix domain socket transport.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::marker::PhantomData;
+use std::net::Shutdown;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::{
+    codec::{Codec, Json},
+    core::{
+        ChoreographyLocation, LocationSet, Member, Portable, SessionId, Transport, TransportError,
+    },
+    transport::envelope::Envelopes,
+    transport::framed::{Connection, Endpoint, Listener},
+    transport::{receive_deadline, send_deadline, TransportConfig, TransportConfigBuilder},
+};
+
+/// Config for `UdsTransport`.
+pub type UdsTransportConfig<'a, L, Target> = TransportConfig<'a, Target, &'a Path, L, &'a Path>;
+
+/// A builder for `UdsTransportConfig`.
+///
+/// # Examples
+///
+/// ```
+/// # use std::path::Path;
+/// # use chorus_lib::core::{LocationSet, ChoreographyLocation};
+/// # use chorus_lib::transport::uds::UdsTransportConfigBuilder;
+/// #
+/// # #[derive(ChoreographyLocation)]
+/// # struct Alice;
+/// #
+/// # #[derive(ChoreographyLocation)]
+/// # struct Bob;
+/// #
+/// let transport_config = UdsTransportConfigBuilder::for_target(Alice, Path::new("/tmp/alice.sock"))
+///   .with(Bob, Path::new("/tmp/bob.sock"))
+///   .build();
+/// ```
+pub type UdsTransportConfigBuilder<'a, Target, L> =
+    TransportConfigBuilder<'a, Target, &'a Path, L, &'a Path>;
+
+impl Connection for UnixStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        UnixStream::try_clone(self)
+    }
+    fn shutdown(&self) -> io::Result<()> {
+        UnixStream::shutdown(self, Shutdown::Both)
+    }
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+}
+
+impl Listener for UnixListener {
+    type Connection = UnixStream;
+    fn accept(&self) -> io::Result<UnixStream> {
+        let (stream, _) = UnixListener::accept(self)?;
+        Ok(stream)
+    }
+}
+
+/// The Unix domain socket transport.
+///
+/// A lower-overhead alternative to `TcpTransport` for locations that run on the same host. Each location listens
+/// on the socket path given for the target in the config and uses the same framing and reconnection behavior as
+/// `TcpTransport`.
+///
+/// Access to a location is controlled by the filesystem permissions of its socket file and the directory that
+/// contains it: any process that can connect to the socket can send messages as any location. A stale socket file
+/// left at the target path is removed when the transport is created, and the socket file is removed when the
+/// transport is dropped.
+pub struct UdsTransport<'a, L: LocationSet, TLocation, C: Codec = Json> {
+    config: HashMap<&'static str, &'a Path>,
+    path: PathBuf,
+    receive_timeout: Option<Duration>,
+    send_timeout: Duration,
+    envelopes: Arc<Envelopes>,
+    endpoint: Endpoint<L, UnixStream>,
+    codec: C,
+    target_location: PhantomData<TLocation>,
+}
+
+impl<'a, L: LocationSet, TLocation: ChoreographyLocation> UdsTransport<'a, L, TLocation> {
+    /// Creates a new `UdsTransport` instance from the configuration.
+    ///
+    /// The transport starts listening before this method returns. Messages are encoded as JSON. Fails if the
+    /// transport cannot listen on the socket path of the target.
+    pub fn new<Index>(uds_config: UdsTransportConfig<'a, L, TLocation>) -> io::Result<Self>
+    where
+        TLocation: Member<L, Index>,
+    {
+        Self::with_codec(uds_config, Json)
+    }
+}
+
+impl<'a, L: LocationSet, TLocation: ChoreographyLocation, C: Codec>
+    UdsTransport<'a, L, TLocation, C>
+{
+    /// Creates a new `UdsTransport` instance from the configuration that encodes messages with `codec`.
+    ///
+    /// Fails if the transport cannot listen on the socket path of the target.
+    pub fn with_codec<Index>(
+        uds_config: UdsTransportConfig<'a, L, TLocation>,
+        codec: C,
+    ) -> io::Result<Self>
+    where
+        TLocation: Member<L, Index>,
+    {
+        let (_, path) = uds_config.target_info;
+        // a previous transport may have exited without removing its socket
+        match fs::remove_file(path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+        let listener = UnixListener::bind(path)?;
+        let envelopes = Arc::new(Envelopes::new(
+            uds_config.info.keys().cloned(),
+            uds_config.keys,
+        ));
+        let endpoint = Endpoint::new(
+            listener,
+            uds_config.info.keys().cloned(),
+            envelopes.clone(),
+            TLocation::name(),
+            uds_config.backoff,
+            uds_config.max_message_size,
+        );
+        Ok(Self {
+            config: uds_config.info,
+            path: path.to_path_buf(),
+            receive_timeout: uds_config.receive_timeout,
+            send_timeout: uds_config.send_timeout,
+            envelopes,
+            endpoint,
+            codec,
+            target_location: PhantomData,
+        })
+    }
+
+    /// Seals a message once and sends it `copies` times.
+    fn send_copies<V: Portable>(
+        &self,
+        session: SessionId,
+        from: &str,
+        to: &str,
+        data: &V,
+        deadline: Option<Instant>,
+        copies: usize,
+    ) -> Result<(), TransportError> {
+        let path = self
+            .config
+            .get(to)
+            .ok_or_else(|| TransportError::UnknownLocation(to.to_string()))?;
+        let mut message = self
+            .envelopes
+            .seal(session, from, to, self.codec.encode(data)?);
+        let deadline = send_deadline(deadline, self.send_timeout);
+        for _ in 0..copies {
+            self.endpoint
+                .send(session, from, to, &mut message, deadline, || {
+                    UnixStream::connect(path)
+                })?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a, L: LocationSet, TLocation, C: Codec> Drop for UdsTransport<'a, L, TLocation, C> {
+    fn drop(&mut self) {
+        let path = &self.path;
+        self.endpoint.close(|| {
+            let _ = UnixStream::connect(path);
+        });
+        let _ = fs::remove_file(path);
+    }
+}
+
+impl<'a, L: LocationSet, TLocation: ChoreographyLocation, C: Codec> Transport<L, TLocation>
+    for UdsTransport<'a, L, TLocation, C>
+{
+    type Codec = C;
+
+    fn codec(&self) -> &C {
+        &self.codec
+    }
+
+    fn locations(&self) -> Vec<&'static str> {
+        self.config.keys().cloned().collect()
+    }
+
+    fn send<V: Portable>(
+        &self,
+        session: SessionId,
+        from: &str,
+        to: &str,
+        data: &V,
+        deadline: Option<Instant>,
+    ) -> Result<(), TransportError> {
+        self.send_copies(session, from, to, data, deadline, 1)
+    }
+
+    fn send_duplicate<V: Portable>(
+        &self,
+        session: SessionId,
+        from: &str,
+        to: &str,
+        data: &V,
+        deadline: Option<Instant>,
+    ) -> Result<(), TransportError> {
+        self.send_copies(session, from, to, data, deadline, 2)
+    }
+
+    fn receive<V: Portable>(
+        &self,
+        session: SessionId,
+        from: &str,
+        _at: &str,
+        deadline: Option<Instant>,
+    ) -> Result<V, TransportError> {
+        let deadline = receive_deadline(deadline, self.receive_timeout);
+        let body = self.endpoint.receive(session, from, deadline)?;
+        self.codec.decode(&body)
+    }
+
+    fn wait_ready(&self, timeout: Duration) -> Result<(), TransportError> {
+        self.endpoint
+            .wait_ready(TLocation::name(), timeout, |peer| {
+                UnixStream::connect(self.config[peer])
+            })
+    }
+
+    fn shutdown(&self, timeout: Duration) -> Result<(), TransportError> {
+        self.endpoint.shutdown(TLocation::name(), timeout, |peer| {
+            UnixStream::connect(self.config[peer])
+        })
+    }
+
+    fn unconsumed(&self, session: SessionId) -> HashMap<&'static str, usize> {
+        self.endpoint.unconsumed(session)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::core::{ChoreoOp, Choreography, ChoreographyLocation, Projector};
+
+    #[derive(ChoreographyLocation)]
+    struct Alice;
+
+    #[derive(ChoreographyLocation)]
+    struct Bob;
+
+    fn socket_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("chorus-{}-{}.sock", std::process::id(), name))
+    }
+
+    #[test]
+    fn test_uds_transport() {
+        let alice_path = socket_path("test_uds_transport-alice");
+        let bob_path = socket_path("test_uds_transport-bob");
+
+        let mut handles = Vec::new();
+        {
+            let (alice_path, bob_path) = (alice_path.clone(), bob_path.clone());
+            handles.push(thread::spawn(move || {
+                let config = UdsTransportConfigBuilder::for_target(Alice, alice_path.as_path())
+                    .with(Bob, bob_path.as_path())
+                    .build();
+                let transport = UdsTransport::new(config).unwrap();
+                transport.wait_ready(Duration::from_secs(10)).unwrap();
+                for v in 0..10 {
+                    transport
+                        .send::<i32>(0, Alice::name(), Bob::name(), &v, None)
+                        .unwrap();
+                }
+                let ack = transport
+                    .receive::<String>(0, Bob::name(), Alice::name(), None)
+                    .unwrap();
+                assert_eq!(ack, "done");
+            }));
+        }
+        {
+            let (alice_path, bob_path) = (alice_path.clone(), bob_path.clone());
+            handles.push(thread::spawn(move || {
+                let config = UdsTransportConfigBuilder::for_target(Bob, bob_path.as_path())
+                    .with(Alice, alice_path.as_path())
+                    .build();
+                let transport = UdsTransport::new(config).unwrap();
+                transport.wait_ready(Duration::from_secs(10)).unwrap();
+                for v in 0..10 {
+                    let v2 = transport
+                        .receive::<i32>(0, Alice::name(), Bob::name(), None)
+                        .unwrap();
+                    assert_eq!(v, v2);
+                }
+                transport
+                    .send(0, Bob::name(), Alice::name(), &"done".to_string(), None)
+                    .unwrap();
+            }));
+        }
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert!(!alice_path.exists());
+        assert!(!bob_path.exists());
+    }
+
+    struct PingPong;
+
+    impl Choreography<i32> for PingPong {
+        type L = LocationSet!(Alice, Bob);
+        fn run(self, op: &impl ChoreoOp<Self::L>) -> i32 {
+            let ping = op.locally(Alice, |_| 1);
+            let ping = op.comm(Alice, Bob, &ping);
+            let pong = op.locally(Bob, |un| un.unwrap(&ping) + 1);
+            let pong = op.comm(Bob, Alice, &pong);
+            op.broadcast(Alice, pong)
+        }
+    }
+
+    #[test]
+    fn test_uds_transport_stale_socket() {
+        let alice_path = socket_path("test_uds_transport_stale_socket-alice");
+        let bob_path = socket_path("test_uds_transport_stale_socket-bob");
+        // a socket file left behind by a process that did not exit cleanly
+        drop(UnixListener::bind(&alice_path).unwrap());
+        assert!(alice_path.exists());
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                let config = UdsTransportConfigBuilder::for_target(Alice, alice_path.as_path())
+                    .with(Bob, bob_path.as_path())
+                    .build();
+                let projector = Projector::new(Alice, UdsTransport::new(config).unwrap());
+                assert_eq!(projector.epp_and_run(PingPong).unwrap(), 2);
+            });
+            s.spawn(|| {
+                let config = UdsTransportConfigBuilder::for_target(Bob, bob_path.as_path())
+                    .with(Alice, alice_path.as_path())
+                    .build();
+                let projector = Projector::new(Bob, UdsTransport::new(config).unwrap());
+                assert_eq!(projector.epp_and_run(PingPong).unwrap(), 2);
+            });
+        });
+    }
+}
@@ -1,9 +1,16 @@
-use std::collections::VecDeque;
-use std::sync::{Condvar, Mutex};
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::hash::Hash;
+use std::pin::Pin;
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::Instant;
 
 pub struct BlockingQueue<T> {
     data: Mutex<VecDeque<T>>,
     not_empty: Condvar,
+    // tasks waiting in `pop_async`; always locked while holding `data`
+    wakers: Mutex<Vec<Waker>>,
 }
 
 impl<T> BlockingQueue<T> {
@@ -11,6 +18,7 @@ impl<T> BlockingQueue<T> {
         BlockingQueue {
             data: Mutex::new(VecDeque::new()),
             not_empty: Condvar::new(),
+            wakers: Mutex::new(Vec::new()),
         }
     }
 
@@ -18,6 +26,9 @@ impl<T> BlockingQueue<T> {
         let mut queue = self.data.lock().unwrap();
         queue.push_back(item);
         self.not_empty.notify_one();
+        for waker in self.wakers.lock().unwrap().drain(..) {
+            waker.wake();
+        }
     }
 
     pub fn pop(&self) -> T {
@@ -28,6 +39,114 @@ impl<T> BlockingQueue<T> {
         let item = queue.pop_front().unwrap();
         item
     }
+
+    /// Pops the next item, or returns `None` if no item arrives before `deadline`.
+    pub fn pop_until(&self, deadline: Instant) -> Option<T> {
+        let mut queue = self.data.lock().unwrap();
+        while queue.is_empty() {
+            let timeout = deadline.checked_duration_since(Instant::now())?;
+            queue = self.not_empty.wait_timeout(queue, timeout).unwrap().0;
+        }
+        queue.pop_front()
+    }
+
+    /// Returns the number of items in the queue.
+    pub fn len(&self) -> usize {
+        self.data.lock().unwrap().len()
+    }
+
+    /// Returns a future that resolves to the next item without blocking the thread.
+    pub fn pop_async(&self) -> Pop<'_, T> {
+        Pop { queue: self }
+    }
+}
+
+pub struct Pop<'a, T> {
+    queue: &'a BlockingQueue<T>,
+}
+
+impl<'a, T> Future for Pop<'a, T> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        let mut queue = self.queue.data.lock().unwrap();
+        match queue.pop_front() {
+            Some(item) => Poll::Ready(item),
+            None => {
+                let mut wakers = self.queue.wakers.lock().unwrap();
+                if !wakers.iter().any(|waker| waker.will_wake(cx.waker())) {
+                    wakers.push(cx.waker().clone());
+                }
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// A set of `BlockingQueue`s indexed by key.
+///
+/// Queues are created when they are first used and dropped once they are drained, so keys can be drawn from an
+/// unbounded space such as session ids.
+pub struct QueueMap<K, T> {
+    queues: Mutex<HashMap<K, Arc<BlockingQueue<T>>>>,
+}
+
+impl<K: Eq + Hash + Clone, T> QueueMap<K, T> {
+    pub fn new() -> Self {
+        QueueMap {
+            queues: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn push(&self, key: K, item: T) {
+        self.get(&key).push(item);
+    }
+
+    /// Pops the next item for `key`, or returns `None` if no item arrives before `deadline`. Waits forever if
+    /// `deadline` is `None`.
+    pub fn pop(&self, key: K, deadline: Option<Instant>) -> Option<T> {
+        let queue = self.get(&key);
+        let item = match deadline {
+            Some(deadline) => queue.pop_until(deadline),
+            None => Some(queue.pop()),
+        };
+        self.release(&key, queue);
+        item
+    }
+
+    pub async fn pop_async(&self, key: K) -> T {
+        let queue = self.get(&key);
+        let item = queue.pop_async().await;
+        self.release(&key, queue);
+        item
+    }
+
+    /// Returns the keys whose queues hold items, with the number of items in each.
+    pub fn pending(&self) -> Vec<(K, usize)> {
+        let queues = self.queues.lock().unwrap();
+        queues
+            .iter()
+            .map(|(key, queue)| (key.clone(), queue.len()))
+            .filter(|(_, len)| *len > 0)
+            .collect()
+    }
+
+    fn get(&self, key: &K) -> Arc<BlockingQueue<T>> {
+        let mut queues = self.queues.lock().unwrap();
+        queues
+            .entry(key.clone())
+            .or_insert_with(|| Arc::new(BlockingQueue::new()))
+            .clone()
+    }
+
+    // Drops the queue for `key` if it is empty and nobody else holds it. Handles are only created while holding
+    // the map lock, so no other thread can be about to push to a queue that is removed here.
+    fn release(&self, key: &K, queue: Arc<BlockingQueue<T>>) {
+        let mut queues = self.queues.lock().unwrap();
+        if Arc::strong_count(&queue) == 2 && queue.data.lock().unwrap().is_empty() {
+            queues.remove(key);
+        }
+    }
 }
 
 #[cfg(test)]
@@ -62,4 +181,46 @@ mod tests {
         assert_eq!(queue.pop(), 3);
         handle.join().unwrap();
     }
+
+    #[tokio::test]
+    async fn test_blocking_queue_pop_async() {
+        let queue = std::sync::Arc::new(BlockingQueue::<i32>::new());
+        let handle = {
+            let queue = queue.clone();
+            std::thread::spawn(move || {
+                std::thread::sleep(std::time::Duration::from_millis(100));
+                queue.push(1);
+                queue.push(2);
+            })
+        };
+        assert_eq!(queue.pop_async().await, 1);
+        assert_eq!(queue.pop_async().await, 2);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_queue_map() {
+        let queues = QueueMap::<u64, i32>::new();
+        queues.push(1, 10);
+        queues.push(2, 20);
+        queues.push(1, 11);
+        assert_eq!(queues.pop(2, None), Some(20));
+        assert_eq!(queues.pop(1, None), Some(10));
+        assert_eq!(queues.pending(), [(1, 1)]);
+        assert_eq!(queues.pop(1, None), Some(11));
+        assert!(queues.pending().is_empty());
+        assert!(queues.queues.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_queue_map_deadline() {
+        let queues = QueueMap::<u64, i32>::new();
+        let deadline = Instant::now() + std::time::Duration::from_millis(50);
+        assert_eq!(queues.pop(1, Some(deadline)), None);
+        assert!(Instant::now() >= deadline);
+        // a queue that timed out is released like one that was drained
+        assert!(queues.queues.lock().unwrap().is_empty());
+        queues.push(1, 10);
+        assert_eq!(queues.pop(1, Some(deadline)), Some(10));
+    }
 }
@@ -7,8 +7,9 @@ use std::thread;
 
 use chorus_lib::{
     core::{
-        ChoreoOp, Choreography, ChoreographyLocation, Faceted, FanInChoreography, HCons, Here,
-        Located, LocationSet, LocationSetFoldable, Member, Projector, Quire, Runner, Subset, There,
+        ChoreoOp, Choreography, ChoreographyError, ChoreographyLocation, Faceted,
+        FanInChoreography, HCons, Here, Located, LocationSet, LocationSetFoldable, Member,
+        Projector, Quire, Runner, Subset, There,
     },
     transport::local::{LocalTransport, LocalTransportChannelBuilder},
 };
@@ -278,7 +279,7 @@ fn run_test(
                     budgets: seller_projector.remote_faceted(<LocationSet!(Buyer1, Buyer2)>::new()),
                     _phantoms: PhantomData,
                 };
-                seller_projector.epp_and_run(choreo)
+                seller_projector.epp_and_run(choreo).unwrap()
             }));
         }
         {
@@ -296,7 +297,7 @@ fn run_test(
                         .local_faceted(budget1, <LocationSet!(Buyer1, Buyer2)>::new()),
                     _phantoms: PhantomData,
                 };
-                buyer1_projector.epp_and_run(choreo)
+                buyer1_projector.epp_and_run(choreo).unwrap()
             }));
         }
         {
@@ -314,7 +315,7 @@ fn run_test(
                         .local_faceted(budget2, <LocationSet!(Buyer1, Buyer2)>::new()),
                     _phantoms: PhantomData,
                 };
-                buyer2_projector.epp_and_run(choreo)
+                buyer2_projector.epp_and_run(choreo).unwrap()
             }));
         }
     } else {
@@ -349,7 +350,7 @@ fn run_test(
                     budgets: seller_projector.remote(Buyer1),
                     _phantoms: PhantomData,
                 };
-                seller_projector.epp_and_run(choreo)
+                seller_projector.epp_and_run(choreo).unwrap()
             }));
         }
         {
@@ -366,7 +367,7 @@ fn run_test(
                     budgets: buyer1_projector.local(budget1),
                     _phantoms: PhantomData,
                 };
-                buyer1_projector.epp_and_run(choreo)
+                buyer1_projector.epp_and_run(choreo).unwrap()
             }));
         }
         {
@@ -383,7 +384,13 @@ fn run_test(
                     budgets: buyer2_projector.remote(Buyer1),
                     _phantoms: PhantomData,
                 };
-                buyer2_projector.epp_and_run(choreo)
+                // Buyer2 is outside the census of the choreography, so it returns right away
+                let result = buyer2_projector.epp_and_run(choreo);
+                assert!(matches!(
+                    result,
+                    Err(ChoreographyError::NotInChoreography(location)) if location == Buyer2::name()
+                ));
+                answer
             }));
         }
     }
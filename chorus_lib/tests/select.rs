@@ -0,0 +1,136 @@
+extern crate chorus_lib;
+
+use std::thread;
+
+use chorus_lib::{
+    core::{
+        BranchLabel, ChoreoOp, Choreography, ChoreographyLocation, Located, LocationSet, Projector,
+        Runner,
+    },
+    transport::local::{LocalTransport, LocalTransportChannelBuilder},
+};
+
+#[derive(ChoreographyLocation)]
+struct Buyer;
+
+#[derive(ChoreographyLocation)]
+struct Seller;
+
+#[derive(ChoreographyLocation)]
+struct Shipper;
+
+const STOCK: u32 = 10;
+const TRACKING_NUMBER: u32 = 42;
+
+#[derive(BranchLabel, Clone, Copy, Debug, PartialEq)]
+enum Decision {
+    Ship,
+    Cancel,
+}
+
+// Only the seller and the shipper take part in fulfilling the order
+struct Fulfil {
+    decision: Decision,
+}
+
+impl Choreography<Located<Option<u32>, Seller>> for Fulfil {
+    type L = LocationSet!(Seller, Shipper);
+    fn run(self, op: &impl ChoreoOp<Self::L>) -> Located<Option<u32>, Seller> {
+        match self.decision {
+            Decision::Ship => {
+                let tracking = op.locally(Shipper, |_| TRACKING_NUMBER);
+                let tracking = op.comm(Shipper, Seller, &tracking);
+                op.locally(Seller, |un| Some(*un.unwrap(&tracking)))
+            }
+            Decision::Cancel => op.locally(Seller, |_| None),
+        }
+    }
+}
+
+struct Order {
+    quantity: Located<u32, Buyer>,
+}
+
+impl Choreography<Located<Option<u32>, Seller>> for Order {
+    type L = LocationSet!(Buyer, Seller, Shipper);
+    fn run(self, op: &impl ChoreoOp<Self::L>) -> Located<Option<u32>, Seller> {
+        let quantity = op.comm(Buyer, Seller, &self.quantity);
+        let decision = op.locally(Seller, |un| {
+            if *un.unwrap(&quantity) <= STOCK {
+                Decision::Ship
+            } else {
+                Decision::Cancel
+            }
+        });
+        let decision = op.select(Seller, <LocationSet!(Seller, Shipper)>::new(), &decision);
+        let tracking = op.branch(&decision, |decision| Fulfil {
+            decision: *decision,
+        });
+        op.locally(Seller, |un| *un.unwrap(un.unwrap(&tracking)))
+    }
+}
+
+fn run_test(quantity: u32, answer: Option<u32>) {
+    let transport_channel = LocalTransportChannelBuilder::new()
+        .with(Buyer)
+        .with(Seller)
+        .with(Shipper)
+        .build();
+    let buyer_projector =
+        Projector::new(Buyer, LocalTransport::new(Buyer, transport_channel.clone()));
+    let seller_projector = Projector::new(
+        Seller,
+        LocalTransport::new(Seller, transport_channel.clone()),
+    );
+    let shipper_projector = Projector::new(
+        Shipper,
+        LocalTransport::new(Shipper, transport_channel.clone()),
+    );
+
+    let tracking = thread::scope(|s| {
+        s.spawn(|| {
+            buyer_projector
+                .epp_and_run(Order {
+                    quantity: buyer_projector.local(quantity),
+                })
+                .unwrap();
+        });
+        s.spawn(|| {
+            shipper_projector
+                .epp_and_run(Order {
+                    quantity: shipper_projector.remote(Buyer),
+                })
+                .unwrap();
+        });
+        seller_projector
+            .epp_and_run(Order {
+                quantity: seller_projector.remote(Buyer),
+            })
+            .unwrap()
+    });
+    assert_eq!(seller_projector.unwrap(tracking), answer);
+
+    let runner = Runner::new();
+    let tracking = runner.run(Order {
+        quantity: runner.local(quantity),
+    });
+    assert_eq!(runner.unwrap(tracking), answer);
+}
+
+#[test]
+fn test_select_ship() {
+    run_test(1, Some(TRACKING_NUMBER));
+}
+
+#[test]
+fn test_select_cancel() {
+    run_test(STOCK + 1, None);
+}
+
+#[test]
+fn test_branch_label() {
+    assert_eq!(Decision::Ship.index(), 0);
+    assert_eq!(Decision::Cancel.index(), 1);
+    assert_eq!(Decision::from_index(1), Some(Decision::Cancel));
+    assert_eq!(Decision::from_index(2), None);
+}
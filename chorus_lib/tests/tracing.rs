@@ -0,0 +1,148 @@
+#![cfg(feature = "tracing")]
+extern crate chorus_lib;
+
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use chorus_lib::{
+    core::{ChoreoOp, Choreography, ChoreographyLocation, Located, LocationSet, Projector},
+    transport::local::{LocalTransport, LocalTransportChannelBuilder},
+};
+use tracing::Level;
+
+#[derive(ChoreographyLocation)]
+struct Alice;
+
+#[derive(ChoreographyLocation)]
+struct Bob;
+
+#[derive(ChoreographyLocation)]
+struct Carol;
+
+struct Ping;
+
+impl Choreography<u32> for Ping {
+    type L = LocationSet!(Alice, Bob, Carol);
+    fn run(self, op: &impl ChoreoOp<Self::L>) -> u32 {
+        let ping = op.locally(Alice, |_| 1);
+        let ping = op.comm(Alice, Bob, &ping);
+        op.conclave(Pong { ping });
+        op.broadcast(Alice, op.locally(Alice, |_| 2))
+    }
+}
+
+struct Pong {
+    ping: Located<u32, Bob>,
+}
+
+impl Choreography for Pong {
+    type L = LocationSet!(Alice, Bob);
+    fn run(self, op: &impl ChoreoOp<Self::L>) {
+        let pong = op.locally(Bob, |un| un.unwrap(&self.ping) + 1);
+        op.comm(Bob, Alice, &pong);
+    }
+}
+
+/// Collects the formatted events of all threads.
+#[derive(Clone, Default)]
+struct Log(Arc<Mutex<Vec<u8>>>);
+
+impl io::Write for Log {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_tracing() {
+    let log = Log::default();
+    let subscriber = {
+        let log = log.clone();
+        tracing_subscriber::fmt()
+            .with_max_level(Level::DEBUG)
+            .with_ansi(false)
+            .with_writer(move || log.clone())
+            .finish()
+    };
+    let _guard = tracing::subscriber::set_default(subscriber);
+    let dispatch = tracing::dispatcher::get_default(|dispatch| dispatch.clone());
+
+    let transport_channel = LocalTransportChannelBuilder::new()
+        .with(Alice)
+        .with(Bob)
+        .with(Carol)
+        .build();
+    let alice_projector =
+        Projector::new(Alice, LocalTransport::new(Alice, transport_channel.clone()));
+    let bob_projector = Projector::new(Bob, LocalTransport::new(Bob, transport_channel.clone()));
+    let carol_projector =
+        Projector::new(Carol, LocalTransport::new(Carol, transport_channel.clone()));
+
+    thread::scope(|s| {
+        let dispatch = &dispatch;
+        s.spawn(move || {
+            tracing::dispatcher::with_default(dispatch, || {
+                for _ in 0..2 {
+                    assert_eq!(alice_projector.epp_and_run(Ping).unwrap(), 2);
+                }
+            })
+        });
+        s.spawn(move || {
+            tracing::dispatcher::with_default(dispatch, || {
+                for _ in 0..2 {
+                    assert_eq!(bob_projector.epp_and_run(Ping).unwrap(), 2);
+                }
+            })
+        });
+        s.spawn(move || {
+            tracing::dispatcher::with_default(dispatch, || {
+                for _ in 0..2 {
+                    assert_eq!(carol_projector.epp_and_run(Ping).unwrap(), 2);
+                }
+            })
+        });
+    });
+
+    let log = String::from_utf8(log.0.lock().unwrap().clone()).unwrap();
+    let lines: Vec<&str> = log.lines().collect();
+    let count = |location: &str, correlation_id: &str, message: &str| {
+        lines
+            .iter()
+            .filter(|line| {
+                line.contains(&format!("location=\"{}\"", location))
+                    && line.contains(&format!("correlation_id=\"{}\"", correlation_id))
+                    && line.contains(message)
+            })
+            .count()
+    };
+    for correlation_id in ["0.0", "0.1"] {
+        assert_eq!(
+            count(
+                "Alice",
+                correlation_id,
+                "sent op=\"comm\" from=\"Alice\" to=\"Bob\" bytes=1"
+            ),
+            1
+        );
+        assert_eq!(
+            count(
+                "Bob",
+                correlation_id,
+                "received op=\"comm\" from=\"Alice\" to=\"Bob\" bytes=1"
+            ),
+            1
+        );
+        assert_eq!(count("Alice", correlation_id, "sent op=\"broadcast\""), 2);
+        assert_eq!(
+            count("Carol", correlation_id, "received op=\"broadcast\""),
+            1
+        );
+        assert_eq!(count("Bob", correlation_id, "computed op=\"locally\""), 1);
+        assert_eq!(count("Carol", correlation_id, "participating=false"), 1);
+        assert_eq!(count("Bob", correlation_id, "participating=true"), 1);
+    }
+}